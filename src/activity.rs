@@ -1,26 +1,38 @@
 use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
+use std::io::{BufRead, BufReader, Cursor, Read, Seek};
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
+use clap::ValueEnum;
 use csv::StringRecord;
 use fitparser::de::{from_reader_with_options, DecodeOption};
 use fitparser::profile::MesgNum;
 use fitparser::Value;
 use flate2::read::GzDecoder;
 use geo::{EuclideanDistance, MapCoords, Simplify};
-use geo_types::{LineString, MultiLineString, Point};
-use rayon::iter::{ParallelBridge, ParallelIterator};
-use rusqlite::params;
+use geo_types::{Coord, LineString, MultiLineString, Point};
+use indicatif::{ProgressBar, ProgressStyle};
+use r2d2_sqlite::SqliteConnectionManager;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rusqlite::{params, OptionalExtension, ToSql, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicU32, Ordering};
-use time::OffsetDateTime;
+use std::sync::Mutex;
+use time::format_description::well_known::Rfc3339;
+use time::{Date, OffsetDateTime};
 use walkdir::WalkDir;
 
+use crate::apple_health;
 use crate::db;
-use crate::db::{encode_line, Database};
-use crate::tile::{BBox, LngLat, Tile, WebMercator};
+use crate::db::{decode_tracks, encode_line, encode_tracks, ActivityFilter, Database};
+use crate::dedupe;
+use crate::mask;
+use crate::mask::PrivacyMask;
+use crate::tile::{BBox, LngLat, Tile, WebMercator, WebMercatorViewport};
 
 struct TileClipper {
     zoom: u8,
@@ -124,6 +136,13 @@ pub struct RawActivity {
     pub start_time: Option<OffsetDateTime>,
     pub tracks: MultiLineString,
     pub properties: HashMap<String, serde_json::Value>,
+    /// Hex encoded SHA-1 of the file the activity was read from, used to
+    /// detect duplicates under different file names.
+    pub content_hash: Option<String>,
+    /// Strava athlete the activity belongs to, when received via webhook.
+    pub athlete_id: Option<u64>,
+    /// User who uploaded the activity, when hosting multiple users.
+    pub user_id: Option<i64>,
 }
 
 impl RawActivity {
@@ -132,12 +151,17 @@ impl RawActivity {
     ///
     const MAX_POINT_DISTANCE: f64 = 5000.0;
 
+    pub fn is_virtual(&self) -> bool {
+        self.properties.get(VIRTUAL_PROPERTY) == Some(&serde_json::Value::Bool(true))
+    }
+
     pub fn clip_to_tiles(
         &self,
-        db::Config {
+        config @ db::Config {
             ref zoom_levels,
-            ref trim_dist,
             ref tile_extent,
+            ref masks,
+            ..
         }: &db::Config,
     ) -> ClippedTiles {
         let mut clippers: Vec<_> = zoom_levels
@@ -145,60 +169,139 @@ impl RawActivity {
             .map(|z| TileClipper::new(*z, *tile_extent as u16))
             .collect();
 
-        for line in self.tracks.iter() {
-            let points: Vec<_> = line
-                .points()
-                .map(LngLat::from)
-                .filter_map(|pt| pt.xy())
-                .collect();
+        let trim_dist = trim_dist(self.properties.get(TRIM_PROPERTY), config);
+        for line in visible_lines(&self.tracks, trim_dist, masks) {
+            for pair in line.windows(2) {
+                for clip in clippers.iter_mut() {
+                    clip.add_line_segment(pair[0], pair[1]);
+                }
+            }
 
-            if points.len() < 2 {
-                continue;
+            for clip in clippers.iter_mut() {
+                clip.finish_segment();
             }
+        }
 
-            let first = &points[0].0;
-            let last = &points[points.len() - 1].0;
+        ClippedTiles(clippers)
+    }
+}
 
-            // Find points which are >= trim_dist away from start/end
-            let start_idx = points
-                .iter()
-                .enumerate()
-                .find(|(_, pt)| pt.0.euclidean_distance(first) >= *trim_dist)
-                .map(|(i, _)| i);
+/// Activity property which overrides the configured `trim_dist` (in meters)
+/// for a single activity, e.g. for rides starting somewhere other than home.
+pub const TRIM_PROPERTY: &str = "trim";
 
-            let end_idx = points
-                .iter()
-                .rev()
-                .enumerate()
-                .find(|(_, pt)| pt.0.euclidean_distance(last) >= *trim_dist)
-                .map(|(i, _)| points.len() - 1 - i);
-
-            if let Some((i, j)) = start_idx.zip(end_idx) {
-                if i >= j {
-                    continue;
-                }
+/// Activity property set to `true` for rides and runs in a virtual world
+/// (e.g. Zwift), whose tracks don't correspond to anywhere real.
+pub const VIRTUAL_PROPERTY: &str = "virtual";
 
-                let mut pairs = points[i..j].windows(2);
-                while let Some(&[p0, p1]) = pairs.next() {
-                    // Skip over large jumps
-                    let len = p0.0.euclidean_distance(&p1.0);
-                    if len > Self::MAX_POINT_DISTANCE {
-                        continue;
-                    }
+/// Whether a GPX creator or Strava activity type is a virtual activity. Not
+/// an exhaustive check.
+pub fn is_virtual_source(s: &str) -> bool {
+    let s = s.to_lowercase();
+    s.contains("zwift") || s.starts_with("virtual")
+}
 
-                    for clip in clippers.iter_mut() {
-                        clip.add_line_segment(p0, p1);
-                    }
-                }
+/// Distance to trim from the start/end of an activity, given the value of its
+/// `trim` property.
+fn trim_dist(trim_prop: Option<&serde_json::Value>, config: &db::Config) -> f64 {
+    trim_prop
+        .and_then(|v| v.as_f64())
+        .filter(|dist| *dist >= 0.0)
+        .unwrap_or(config.trim_dist)
+}
 
-                for clip in clippers.iter_mut() {
-                    clip.finish_segment();
+/// Apply start/end trimming and privacy masks to an activity's tracks,
+/// returning the remaining line segments.
+fn visible_lines(
+    tracks: &MultiLineString,
+    trim_dist: f64,
+    masks: &[PrivacyMask],
+) -> Vec<Vec<WebMercator>> {
+    let mut lines = vec![];
+
+    for line in tracks.iter() {
+        let points: Vec<_> = line
+            .points()
+            .map(LngLat::from)
+            .filter_map(|pt| pt.xy())
+            .collect();
+
+        if points.len() < 2 {
+            continue;
+        }
+
+        let first = &points[0].0;
+        let last = &points[points.len() - 1].0;
+
+        // Find points which are >= trim_dist away from start/end
+        let start_idx = points
+            .iter()
+            .enumerate()
+            .find(|(_, pt)| pt.0.euclidean_distance(first) >= trim_dist)
+            .map(|(i, _)| i);
+
+        let end_idx = points
+            .iter()
+            .rev()
+            .enumerate()
+            .find(|(_, pt)| pt.0.euclidean_distance(last) >= trim_dist)
+            .map(|(i, _)| points.len() - 1 - i);
+
+        let Some((i, j)) = start_idx.zip(end_idx) else {
+            continue;
+        };
+
+        if i >= j {
+            continue;
+        }
+
+        let mut current = vec![];
+        for pair in points[i..j].windows(2) {
+            let (p0, p1) = (pair[0], pair[1]);
+
+            // Break the line whenever it passes through a privacy mask
+            // (anywhere along the track, not only near start/end), or there's
+            // a large jump between points.
+            if mask::any_intersects(masks, &p0, &p1)
+                || p0.0.euclidean_distance(&p1.0) > RawActivity::MAX_POINT_DISTANCE
+            {
+                if current.len() >= 2 {
+                    lines.push(std::mem::take(&mut current));
                 }
+                current.clear();
+                continue;
+            }
+
+            if current.is_empty() {
+                current.push(p0);
             }
+            current.push(p1);
         }
 
-        ClippedTiles(clippers)
+        if current.len() >= 2 {
+            lines.push(current);
+        }
     }
+
+    lines
+}
+
+/// Apply start/end trimming and privacy masks to the lng/lat tracks of the
+/// given activity.
+pub fn visible_tracks(
+    tracks: &MultiLineString,
+    activity: &ActivitySummary,
+    config: &db::Config,
+) -> MultiLineString {
+    let trim_dist = trim_dist(activity.properties.get(TRIM_PROPERTY), config);
+    visible_lines(tracks, trim_dist, &config.masks)
+        .into_iter()
+        .map(|line| {
+            line.into_iter()
+                .map(|pt| pt.lnglat().0)
+                .collect::<LineString>()
+        })
+        .collect()
 }
 
 #[derive(Debug)]
@@ -206,124 +309,530 @@ pub enum MediaType {
     Gpx,
     Fit,
     Tcx,
+    Kml,
+    Kmz,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Compression {
     None,
     Gzip,
 }
 
-pub fn read<R>(rdr: R, kind: MediaType, comp: Compression) -> Result<Option<RawActivity>>
+/// Read the activities in a file. Most formats contain a single activity,
+/// but multisport FIT files have one per session (see `session_name`).
+///
+/// Files without any track data return no activities.
+pub fn read<R>(rdr: R, kind: MediaType, comp: Compression) -> Result<Vec<RawActivity>>
 where
     R: Read + 'static,
 {
-    let mut reader: BufReader<Box<dyn Read>> = BufReader::new(match comp {
+    let mut hashing = HashingReader::new(decompress(rdr, comp));
+    let activities = {
+        let mut reader = BufReader::new(&mut hashing);
+        match kind {
+            MediaType::Gpx => parse_gpx(&mut reader).map(Vec::from_iter),
+            MediaType::Fit => parse_fit(&mut reader),
+            MediaType::Tcx => parse_tcx(&mut reader).map(Vec::from_iter),
+            MediaType::Kml => parse_kml(&mut reader).map(Vec::from_iter),
+            MediaType::Kmz => parse_kmz(&mut reader).map(Vec::from_iter),
+        }?
+    };
+
+    // Parsers can stop short of the end (e.g. at the closing tag), but the
+    // hash covers the whole file.
+    std::io::copy(&mut hashing, &mut std::io::sink())?;
+    let content_hash = hashing.finish();
+
+    Ok(activities
+        .into_iter()
+        .map(|activity| RawActivity {
+            content_hash: Some(content_hash.clone()),
+            ..activity
+        })
+        .collect())
+}
+
+fn decompress<'a, R: Read + 'a>(rdr: R, comp: Compression) -> Box<dyn Read + 'a> {
+    match comp {
         Compression::None => Box::new(rdr),
         Compression::Gzip => Box::new(GzDecoder::new(rdr)),
-    });
+    }
+}
+
+/// Hex encoded SHA-1 of a file's contents (see [`RawActivity::content_hash`]),
+/// without parsing it. Hashing the decompressed data means that `foo.gpx`
+/// and `foo.gpx.gz` are considered the same.
+pub fn content_hash<R: Read>(rdr: R, comp: Compression) -> Result<String> {
+    let mut hashing = HashingReader::new(decompress(rdr, comp));
+    std::io::copy(&mut hashing, &mut std::io::sink())?;
+    Ok(hashing.finish())
+}
+
+/// Hashes everything read through it, so that files can be hashed while
+/// they're parsed rather than buffered first.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha1,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha1::new(),
+        }
+    }
+
+    fn finish(self) -> String {
+        self.hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
 
-    match kind {
-        MediaType::Gpx => parse_gpx(&mut reader),
-        MediaType::Fit => parse_fit(&mut reader),
-        MediaType::Tcx => parse_tcx(&mut reader),
+/// Name to store the `index`th activity read from a file under. The first
+/// keeps the file's name, the sessions after it get a `#2`, `#3`, ... suffix.
+pub fn session_name(name: &str, index: usize) -> String {
+    match index {
+        0 => name.to_string(),
+        i => format!("{}#{}", name, i + 1),
     }
 }
 
-pub fn read_file(p: &Path) -> Result<Option<RawActivity>> {
+/// Media type and compression of the file at `p`, or `None` if it's not a
+/// supported file type.
+fn file_type(p: &Path) -> Result<Option<(MediaType, Compression)>> {
     let Some(file_name) = p.file_name().and_then(|f| f.to_str()) else {
         return Err(anyhow!("no file name"));
     };
 
-    let Some((media_type, comp)) = get_file_type(file_name) else {
+    Ok(get_file_type(file_name))
+}
+
+pub fn read_file(p: &Path) -> Result<Vec<RawActivity>> {
+    let Some((media_type, comp)) = file_type(p)? else {
         // Just skip over unsupported file types.
-        return Ok(None);
+        return Ok(vec![]);
     };
 
     let file = File::open(p)?;
     read(file, media_type, comp)
 }
 
-fn parse_fit<R: Read>(r: &mut R) -> Result<Option<RawActivity>> {
+/// Session fields stored as activity properties, using the same names as
+/// activities synced from Strava where there is an equivalent.
+const FIT_SESSION_PROPERTIES: &[(&str, &str)] = &[
+    ("sport", "activity_type"),
+    ("sub_sport", "sub_sport"),
+    ("total_distance", "distance"),
+    ("total_elapsed_time", "elapsed_time"),
+    ("total_timer_time", "moving_time"),
+    ("total_ascent", "elevation_gain"),
+    ("total_calories", "calories"),
+    ("avg_heart_rate", "average_heartrate"),
+    ("max_heart_rate", "max_heartrate"),
+    ("avg_speed", "average_speed"),
+    ("max_speed", "max_speed"),
+    ("avg_cadence", "average_cadence"),
+    ("avg_power", "average_watts"),
+    ("normalized_power", "weighted_average_watts"),
+    ("total_strokes", "total_strokes"),
+    ("pool_length", "pool_length"),
+];
+
+/// File ID and (recording) device info fields stored as activity properties.
+const FIT_DEVICE_PROPERTIES: &[(&str, &str)] = &[
+    ("manufacturer", "device_manufacturer"),
+    ("product", "device_product"),
+    ("garmin_product", "device_product"),
+    ("product_name", "device_name"),
+    ("software_version", "device_software_version"),
+];
+
+/// Convert a FIT field value into a JSON property value. Only strings and
+/// numbers are kept.
+fn fit_property(value: &Value) -> Option<serde_json::Value> {
+    match value {
+        Value::String(s) => Some(serde_json::Value::from(s.as_str())),
+        Value::Timestamp(_) | Value::Array(_) => None,
+        value => {
+            let n: f64 = value.clone().try_into().ok()?;
+            serde_json::Number::from_f64(n).map(serde_json::Value::Number)
+        }
+    }
+}
+
+/// Copy the `mapping` fields of a FIT message into `properties`, keeping any
+/// existing value.
+fn add_fit_properties(
+    properties: &mut HashMap<String, serde_json::Value>,
+    data: &fitparser::FitDataRecord,
+    mapping: &[(&str, &str)],
+) {
+    for f in data.fields() {
+        let Some((_, key)) = mapping.iter().find(|(name, _)| *name == f.name()) else {
+            continue;
+        };
+
+        if let Some(value) = fit_property(f.value()) {
+            properties.entry(key.to_string()).or_insert(value);
+        }
+    }
+}
+
+/// Property name for a developer field, e.g. `Power` from a Stryd footpod.
+fn developer_property(name: &str) -> String {
+    name.trim().to_lowercase().replace(' ', "_")
+}
+
+/// A session of a FIT file. Multisport activities (e.g. a triathlon) have
+/// one per leg.
+#[derive(Debug, Default)]
+struct FitSession {
+    start_time: Option<i64>,
+    properties: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Default)]
+struct FitRecord {
+    time: Option<i64>,
+    point: Option<Point>,
+    /// Numeric developer fields, averaged over the session.
+    developer: Vec<(String, f64)>,
+}
+
+/// Group records by the session they belong to, i.e. the last one which
+/// started before the record. Sessions without any points are dropped.
+fn split_fit_sessions(
+    mut sessions: Vec<FitSession>,
+    records: Vec<FitRecord>,
+) -> Vec<(FitSession, Vec<FitRecord>)> {
+    if sessions.is_empty() {
+        sessions.push(FitSession::default());
+    }
+    sessions.sort_by_key(|session| session.start_time);
+
+    let mut groups: Vec<_> = sessions.into_iter().map(|s| (s, vec![])).collect();
+    let mut current = 0;
+    for record in records {
+        // Records without a timestamp stay with the previous one.
+        if let Some(time) = record.time {
+            current = groups
+                .iter()
+                .rposition(|(s, _)| s.start_time.unwrap_or(i64::MIN) <= time)
+                .unwrap_or(0);
+        }
+        groups[current].1.push(record);
+    }
+
+    groups.retain(|(_, records)| records.iter().any(|r| r.point.is_some()));
+    groups
+}
+
+fn fit_activity(
+    session: FitSession,
+    records: Vec<FitRecord>,
+    file_properties: &HashMap<String, serde_json::Value>,
+) -> RawActivity {
+    let mut properties = session.properties;
+    for (key, value) in file_properties {
+        properties
+            .entry(key.clone())
+            .or_insert_with(|| value.clone());
+    }
+
+    let mut developer_totals: HashMap<String, (f64, usize)> = HashMap::new();
+    for (name, value) in records.iter().flat_map(|r| &r.developer) {
+        let total = developer_totals.entry(name.clone()).or_default();
+        total.0 += value;
+        total.1 += 1;
+    }
+    for (name, (sum, count)) in developer_totals {
+        if let Some(avg) = serde_json::Number::from_f64(sum / count as f64) {
+            properties
+                .entry(format!("average_{}", name))
+                .or_insert(serde_json::Value::Number(avg));
+        }
+    }
+
+    let start_time = session
+        .start_time
+        .or_else(|| records.iter().find_map(|r| r.time));
+    let line = records
+        .into_iter()
+        .filter_map(|r| r.point)
+        .collect::<LineString>();
+
+    RawActivity {
+        title: None,
+        start_time: start_time.and_then(|ts| OffsetDateTime::from_unix_timestamp(ts).ok()),
+        tracks: MultiLineString::from(line),
+        properties,
+        content_hash: None,
+        athlete_id: None,
+        user_id: None,
+    }
+}
+
+/// Read the timestamp, position and any numeric developer fields of a FIT
+/// `record` message.
+fn fit_record(
+    data: &fitparser::FitDataRecord,
+    developer_fields: &HashSet<String>,
+) -> Result<FitRecord> {
     const SCALE_FACTOR: f64 = (1u64 << 32) as f64 / 360.0;
 
+    let mut record = FitRecord::default();
+    let mut lat: Option<i64> = None;
+    let mut lng: Option<i64> = None;
+
+    for f in data.fields() {
+        match f.name() {
+            "position_lat" => lat = f.value().try_into().ok(),
+            "position_long" => lng = f.value().try_into().ok(),
+            "timestamp" => record.time = Some(f.value().try_into()?),
+            name if developer_fields.contains(name) => {
+                if let Ok(value) = f.value().clone().try_into() {
+                    record.developer.push((developer_property(name), value));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let (Some(lat), Some(lng)) = (lat, lng) {
+        record.point = Some(Point::new(lng as f64, lat as f64) / SCALE_FACTOR);
+    }
+    Ok(record)
+}
+
+fn parse_fit<R: Read>(r: &mut R) -> Result<Vec<RawActivity>> {
     let opts = [
         DecodeOption::SkipDataCrcValidation,
         DecodeOption::SkipHeaderCrcValidation,
     ]
     .into();
 
-    let mut start_time = None;
-    let mut points = vec![];
+    let mut records = vec![];
+    let mut sessions = vec![];
+    let mut properties = HashMap::new();
+    // Names of developer fields, from their `field_description` messages.
+    let mut developer_fields = HashSet::new();
     for data in from_reader_with_options(r, &opts)? {
         match data.kind() {
             MesgNum::FileId => {
+                let is_zwift = data.fields().iter().any(|f| {
+                    f.name() == "manufacturer"
+                        && matches!(f.value(), Value::String(s) if s.as_str() == "zwift")
+                });
+                if is_zwift {
+                    properties.insert(VIRTUAL_PROPERTY.to_string(), true.into());
+                }
+
+                add_fit_properties(&mut properties, &data, FIT_DEVICE_PROPERTIES);
+            }
+            MesgNum::FieldDescription => {
                 for f in data.fields() {
-                    // Skip over virtual rides (not an exhaustive check)
-                    if f.name() == "manufacturer" {
-                        match f.value() {
-                            Value::String(val) if val.as_str() == "zwift" => return Ok(None),
-                            _ => {}
-                        }
+                    if let ("field_name", Value::String(name)) = (f.name(), f.value()) {
+                        developer_fields.insert(name.clone());
                     }
                 }
             }
-            MesgNum::Record => {
-                let mut lat: Option<i64> = None;
-                let mut lng: Option<i64> = None;
+            MesgNum::Session => {
+                let mut session = FitSession::default();
+                add_fit_properties(&mut session.properties, &data, FIT_SESSION_PROPERTIES);
+
+                // Other apps (e.g. TrainerRoad) mark the session instead.
+                if session.properties.get("sub_sport") == Some(&"virtual_activity".into()) {
+                    session
+                        .properties
+                        .insert(VIRTUAL_PROPERTY.to_string(), true.into());
+                }
 
                 for f in data.fields() {
-                    match f.name() {
-                        "position_lat" => lat = f.value().try_into().ok(),
-                        "position_long" => lng = f.value().try_into().ok(),
-                        "timestamp" => {
-                            if start_time.is_none() {
-                                let ts: i64 = f.value().try_into()?;
-                                start_time = Some(ts);
-                            }
+                    if f.name() == "start_time" {
+                        session.start_time = f.value().try_into().ok();
+                    } else if developer_fields.contains(f.name()) {
+                        if let Some(value) = fit_property(f.value()) {
+                            session
+                                .properties
+                                .insert(developer_property(f.name()), value);
                         }
-                        _ => {}
                     }
                 }
 
-                if let (Some(lat), Some(lng)) = (lat, lng) {
-                    let pt = Point::new(lng as f64, lat as f64) / SCALE_FACTOR;
-                    points.push(pt);
+                sessions.push(session);
+            }
+            // Files also list connected sensors here, which we don't care about.
+            MesgNum::DeviceInfo => {
+                let is_creator = data.fields().iter().any(|f| {
+                    f.name() == "device_index"
+                        && matches!(f.value(), Value::String(s) if s.as_str() == "creator")
+                });
+
+                if is_creator {
+                    add_fit_properties(&mut properties, &data, FIT_DEVICE_PROPERTIES);
                 }
             }
+            MesgNum::Record => records.push(fit_record(&data, &developer_fields)?),
             _ => {}
         }
     }
 
-    if points.is_empty() {
+    Ok(split_fit_sessions(sessions, records)
+        .into_iter()
+        .map(|(session, records)| fit_activity(session, records, &properties))
+        .collect())
+}
+
+fn parse_gpx<R: Read>(reader: &mut R) -> Result<Option<RawActivity>> {
+    // The `gpx` crate skips over extensions, so we need to look at the
+    // document a second time for those.
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+
+    let gpx = gpx::read(bytes.as_slice())?;
+
+    let Some((title, tracks)) = gpx_geometry(&gpx) else {
         return Ok(None);
+    };
+
+    // Only a summary, so not worth failing the import over.
+    let extensions = std::str::from_utf8(&bytes)
+        .map_err(anyhow::Error::from)
+        .and_then(gpx_extension_properties);
+    let mut properties = extensions.unwrap_or_else(|err| {
+        tracing::warn!(?err, "skipping GPX extensions");
+        HashMap::default()
+    });
+    if let Some(creator) = gpx.creator {
+        if is_virtual_source(&creator) {
+            properties.insert(VIRTUAL_PROPERTY.to_string(), true.into());
+        }
+        properties.insert("creator".to_string(), creator.into());
+    }
+
+    let metadata = gpx.metadata.unwrap_or_default();
+    if let Some(keywords) = metadata.keywords {
+        properties.insert("keywords".to_string(), keywords.into());
     }
 
-    let line = points.into_iter().collect::<LineString>();
     Ok(Some(RawActivity {
-        title: None,
-        start_time: start_time.map(|ts| OffsetDateTime::from_unix_timestamp(ts).unwrap()),
-        tracks: MultiLineString::from(line),
-        properties: HashMap::new(),
+        start_time: metadata.time.map(OffsetDateTime::from),
+        title,
+        tracks,
+        properties,
+        content_hash: None,
+        athlete_id: None,
+        user_id: None,
     }))
 }
 
-fn parse_gpx<R: Read>(reader: &mut R) -> Result<Option<RawActivity>> {
-    let gpx = gpx::read(reader)?;
+/// Title and geometry of a GPX file.
+///
+/// Segments of all tracks are combined, with the title taken from the first
+/// one. Files with planned routes may not have any tracks, in which case we
+/// use the first route, or failing that the waypoints if they're all
+/// timestamped (otherwise they're more likely to be unrelated points of
+/// interest than a path).
+fn gpx_geometry(gpx: &gpx::Gpx) -> Option<(Option<String>, MultiLineString)> {
+    if let Some(track) = gpx.tracks.first() {
+        let tracks = gpx
+            .tracks
+            .iter()
+            .flat_map(|track| &track.segments)
+            .map(|segment| segment.linestring())
+            .filter(|line| !line.0.is_empty())
+            .collect::<MultiLineString>();
 
-    // Just take the first track (generally the only one).
-    let Some(track) = gpx.tracks.first() else {
-        return Ok(None);
+        return Some((track.name.clone(), tracks));
+    }
+
+    if let Some(route) = gpx.routes.iter().find(|r| !r.points.is_empty()) {
+        return Some((route.name.clone(), route.linestring().into()));
+    }
+
+    let mut waypoints = gpx
+        .waypoints
+        .iter()
+        .map(|wpt| wpt.time.map(|ts| (OffsetDateTime::from(ts), wpt.point())))
+        .collect::<Option<Vec<_>>>()?;
+
+    if waypoints.len() < 2 {
+        return None;
+    }
+
+    waypoints.sort_by_key(|(ts, _)| *ts);
+    let line = waypoints
+        .into_iter()
+        .map(|(_, pt)| pt)
+        .collect::<LineString>();
+    let title = gpx.metadata.as_ref().and_then(|m| m.name.clone());
+
+    Some((title, line.into()))
+}
+
+/// Summarize the per-point sensor data found in track point extensions,
+/// such as Garmin's `<gpxtpx:TrackPointExtension>`.
+///
+/// Property names match the ones used for Strava activities.
+fn gpx_extension_properties(text: &str) -> Result<HashMap<String, serde_json::Value>> {
+    let doc = roxmltree::Document::parse(text)?;
+
+    let mut heart_rate = vec![];
+    let mut cadence = vec![];
+    let mut temperature = vec![];
+
+    let extensions = doc
+        .descendants()
+        .filter(|n| n.has_tag_name("trkpt"))
+        .filter_map(|pt| pt.children().find(|n| n.tag_name().name() == "extensions"));
+
+    for node in extensions.flat_map(|ext| ext.descendants()) {
+        let values = match node.tag_name().name() {
+            "hr" | "heartrate" => &mut heart_rate,
+            "cad" | "cadence" => &mut cadence,
+            "atemp" | "temp" => &mut temperature,
+            _ => continue,
+        };
+
+        if let Some(value) = node.text().and_then(|t| t.trim().parse::<f64>().ok()) {
+            values.push(value);
+        }
+    }
+
+    let mut properties = HashMap::new();
+    let mut insert = |key: &str, value: Option<f64>| {
+        if let Some(value) = value.and_then(serde_json::Number::from_f64) {
+            properties.insert(key.to_string(), serde_json::Value::Number(value));
+        }
     };
 
-    let start_time = gpx.metadata.and_then(|m| m.time).map(OffsetDateTime::from);
+    // Rounded to one decimal place, which is plenty for all of these.
+    let mean = |values: &[f64]| {
+        (!values.is_empty()).then(|| {
+            let avg = values.iter().sum::<f64>() / values.len() as f64;
+            (avg * 10.0).round() / 10.0
+        })
+    };
 
-    Ok(Some(RawActivity {
-        start_time,
-        title: track.name.clone(),
-        tracks: track.multilinestring(),
-        properties: HashMap::new(),
-    }))
+    insert("average_heartrate", mean(&heart_rate));
+    insert("max_heartrate", heart_rate.iter().copied().reduce(f64::max));
+    insert("average_cadence", mean(&cadence));
+    insert("average_temp", mean(&temperature));
+
+    Ok(properties)
 }
 
 // FIXME: this is a mess
@@ -374,6 +883,121 @@ fn parse_tcx<R: Read>(reader: &mut BufReader<R>) -> Result<Option<RawActivity>>
         tracks,
         title: None,
         properties: HashMap::new(),
+        content_hash: None,
+        athlete_id: None,
+        user_id: None,
+    }))
+}
+
+fn parse_kmz<R: Read>(reader: &mut R) -> Result<Option<RawActivity>> {
+    // Zip archives need to be seekable, so buffer the whole thing.
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+
+    // The main document is usually `doc.kml`, but the spec only requires
+    // it to be the first `.kml` file in the archive.
+    let Some(name) = archive
+        .file_names()
+        .find(|name| name.ends_with(".kml"))
+        .map(String::from)
+    else {
+        return Ok(None);
+    };
+
+    let mut file = archive.by_name(&name)?;
+    parse_kml(&mut file)
+}
+
+fn parse_kml<R: Read>(reader: &mut R) -> Result<Option<RawActivity>> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+
+    let doc = roxmltree::Document::parse(&text)?;
+
+    let parse_time = |node: roxmltree::Node| {
+        node.text()
+            .and_then(|ts| OffsetDateTime::parse(ts.trim(), &Rfc3339).ok())
+    };
+
+    let mut lines = vec![];
+    let mut start_time = None;
+
+    for node in doc.descendants().filter(|n| n.is_element()) {
+        match node.tag_name().name() {
+            // <LineString><coordinates>lng,lat[,alt] lng,lat[,alt] ...</coordinates></LineString>
+            "LineString" => {
+                let coords = node
+                    .children()
+                    .find(|n| n.has_tag_name("coordinates"))
+                    .and_then(|n| n.text())
+                    .unwrap_or_default();
+
+                let line = coords
+                    .split_whitespace()
+                    .filter_map(|tuple| {
+                        let mut parts = tuple.split(',').map(|v| v.parse::<f64>());
+                        match (parts.next(), parts.next()) {
+                            (Some(Ok(lng)), Some(Ok(lat))) => Some(Point::new(lng, lat)),
+                            _ => None,
+                        }
+                    })
+                    .collect::<LineString>();
+
+                lines.push(line);
+            }
+
+            // <gx:Track><when>...</when><gx:coord>lng lat alt</gx:coord></gx:Track>
+            "Track" => {
+                let line = node
+                    .children()
+                    .filter(|n| n.tag_name().name() == "coord")
+                    .filter_map(|n| {
+                        let mut parts = n.text()?.split_whitespace().map(|v| v.parse::<f64>());
+                        match (parts.next(), parts.next()) {
+                            (Some(Ok(lng)), Some(Ok(lat))) => Some(Point::new(lng, lat)),
+                            _ => None,
+                        }
+                    })
+                    .collect::<LineString>();
+
+                lines.push(line);
+            }
+
+            // Covers both `<TimeStamp><when>` and `<gx:Track><when>`
+            "when" | "begin" if start_time.is_none() => start_time = parse_time(node),
+
+            _ => {}
+        }
+    }
+
+    let tracks = lines
+        .into_iter()
+        .filter(|line| !line.0.is_empty())
+        .collect::<MultiLineString>();
+
+    if tracks.0.is_empty() {
+        return Ok(None);
+    }
+
+    // Prefer the name of the placemark over the name of the document.
+    let title = doc
+        .descendants()
+        .find(|n| n.has_tag_name("Placemark"))
+        .and_then(|n| n.children().find(|c| c.has_tag_name("name")))
+        .or_else(|| doc.descendants().find(|n| n.has_tag_name("name")))
+        .and_then(|n| n.text())
+        .map(|s| s.trim().to_string());
+
+    Ok(Some(RawActivity {
+        title,
+        start_time,
+        tracks,
+        properties: HashMap::new(),
+        content_hash: None,
+        athlete_id: None,
+        user_id: None,
     }))
 }
 
@@ -390,58 +1014,454 @@ pub fn get_file_type(file_name: &str) -> Option<(MediaType, Compression)> {
         "gpx" => Some((MediaType::Gpx, comp)),
         "fit" => Some((MediaType::Fit, comp)),
         "tcx" => Some((MediaType::Tcx, comp)),
+        "kml" => Some((MediaType::Kml, comp)),
+        "kmz" => Some((MediaType::Kmz, comp)),
         _ => None,
     }
 }
 
+/// Tolerance (in degrees) when simplifying stored tracks, roughly 1 meter.
+const TRACK_SIMPLIFY_EPSILON: f64 = 0.00001;
+
+#[derive(Debug, PartialEq)]
+pub enum Upserted {
+    Inserted(i64),
+    /// The activity was already stored under a different name (e.g. from
+    /// another source), so its properties were merged into that one instead.
+    Linked(i64),
+    /// Virtual activities aren't stored unless `include_virtual` is set.
+    Excluded,
+}
+
+/// Insert or replace the activity stored as `name`.
+///
+/// If the same activity already exists under another name, it's linked to
+/// that one rather than stored twice, see [`dedupe`].
 pub fn upsert(
     conn: &mut rusqlite::Connection,
     name: &str,
     activity: &RawActivity,
     config: &db::Config,
-) -> Result<i64> {
-    let mut insert_tile = conn.prepare_cached(
-        "\
-        INSERT INTO activity_tiles (activity_id, z, x, y, coords) \
-        VALUES (?, ?, ?, ?, ?)",
-    )?;
+) -> Result<Upserted> {
+    let activity = &*config.hooks.apply(name, activity)?;
 
-    let num_rows = conn.execute(
+    if activity.is_virtual() && !config.include_virtual {
+        tracing::debug!(name, "skipping virtual activity");
+        return Ok(Upserted::Excluded);
+    }
+
+    // Do the expensive work up front, so that we hold the write lock for as
+    // short as possible when importing in parallel.
+    let properties = serde_json::to_string(&activity.properties)?;
+    let polylines = encode_tracks(&activity.tracks.simplify(&TRACK_SIMPLIFY_EPSILON))?;
+
+    let mut tiles = vec![];
+    for (tile, line) in activity.clip_to_tiles(config).iter() {
+        // Have to type-dance a bit because geo::Simplify requires f64
+        let simplified_line = line
+            .map_coords(|c| (c.x as f64, c.y as f64).into())
+            .simplify(&4.0);
+
+        tiles.push((*tile, encode_line(&simplified_line)?));
+    }
+
+    // Take the write lock immediately, otherwise concurrent imports can fail
+    // with `SQLITE_BUSY` when upgrading from a read transaction.
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    if let Some(id) = dedupe::find_duplicate(&tx, name, activity)? {
+        tracing::info!(name, id, "linking duplicate activity");
+        let properties = activity.properties.clone().into_iter().collect();
+        dedupe::link(&tx, name, id, &properties)?;
+        tx.commit()?;
+
+        return Ok(Upserted::Linked(id));
+    }
+
+    // No longer a duplicate, if it was one before.
+    tx.prepare_cached("DELETE FROM linked_files WHERE file = ?")?
+        .execute(params![name])?;
+
+    // `INSERT OR REPLACE` assigns a new ID when replacing, so clean up the
+    // data attached to the existing activity first.
+    tx.prepare_cached(
         "\
-        INSERT OR REPLACE \
-        INTO activities (file, title, start_time, properties) \
-        VALUES (?, ?, ?, ?)",
-        params![
+        DELETE FROM activity_tiles \
+        WHERE activity_id IN (SELECT id FROM activities WHERE file = ?)",
+    )?
+    .execute(params![name])?;
+    tx.prepare_cached(
+        "\
+        DELETE FROM activity_tracks \
+        WHERE activity_id IN (SELECT id FROM activities WHERE file = ?)",
+    )?
+    .execute(params![name])?;
+
+    let activity_id = tx
+        .prepare_cached(
+            "\
+            INSERT OR REPLACE \
+            INTO activities \
+                (file, title, start_time, properties, content_hash, athlete_id, user_id) \
+            VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )?
+        .insert(params![
             name,
             activity.title,
             activity.start_time,
-            serde_json::to_string(&activity.properties)?,
-        ],
-    )?;
+            properties,
+            activity.content_hash,
+            activity.athlete_id,
+            activity.user_id,
+        ])?;
+
+    // Keep a (lightly simplified) copy of the original geometry around, so
+    // that we can re-derive tiles or export it later on.
+    tx.prepare_cached(
+        "\
+        INSERT INTO activity_tracks (activity_id, polylines) \
+        VALUES (?, ?)",
+    )?
+    .execute(params![activity_id, polylines])?;
+
+    {
+        let mut insert_tile = tx.prepare_cached(
+            "\
+            INSERT INTO activity_tiles (activity_id, z, x, y, coords) \
+            VALUES (?, ?, ?, ?, ?)",
+        )?;
 
-    let activity_id = conn.last_insert_rowid();
+        for (tile, coords) in tiles {
+            insert_tile.execute(params![activity_id, tile.z, tile.x, tile.y, coords])?;
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(Upserted::Inserted(activity_id))
+}
+
+/// Remove an activity and all of its tiles.
+///
+/// Returns `false` if no activity with the given name exists.
+pub fn delete(conn: &mut rusqlite::Connection, name: &str) -> Result<bool> {
+    delete_where(conn, "file = ?", &name)
+}
+
+/// Same as [`delete`], but using the activity's ID.
+pub fn delete_by_id(conn: &mut rusqlite::Connection, id: i64) -> Result<bool> {
+    delete_where(conn, "id = ?", &id)
+}
 
-    // If we've affected more than one row, we've replaced an existing one... so we need to
-    // delete the existing tiles.
-    if num_rows != 1 {
-        conn.execute(
-            "DELETE FROM activity_tiles WHERE activity_id = ?",
-            params![activity_id],
+fn delete_where(conn: &mut rusqlite::Connection, clause: &str, param: &dyn ToSql) -> Result<bool> {
+    let tx = conn.transaction()?;
+
+    for table in ["activity_tiles", "activity_tracks"] {
+        tx.execute(
+            &format!(
+                "\
+                DELETE FROM {} \
+                WHERE activity_id IN (SELECT id FROM activities WHERE {})",
+                table, clause
+            ),
+            [param],
         )?;
     }
+    tx.execute(
+        &format!(
+            "\
+            DELETE FROM linked_files \
+            WHERE activity_file IN (SELECT file FROM activities WHERE {})",
+            clause
+        ),
+        [param],
+    )?;
+    let num_rows = tx.execute(&format!("DELETE FROM activities WHERE {}", clause), [param])?;
 
-    let tiles = activity.clip_to_tiles(config);
-    for (tile, line) in tiles.iter() {
-        // Have to type-dance a bit because geo::Simplify requires f64
-        let simplified_line = line
-            .map_coords(|c| (c.x as f64, c.y as f64).into())
-            .simplify(&4.0);
+    tx.commit()?;
+
+    Ok(num_rows > 0)
+}
+
+/// Update title and properties of an existing activity, leaving the tracks untouched.
+///
+/// Returns `false` if no activity with the given name exists.
+pub fn update_metadata(
+    conn: &mut rusqlite::Connection,
+    name: &str,
+    title: Option<&str>,
+    properties: &HashMap<String, serde_json::Value>,
+) -> Result<bool> {
+    let num_rows = conn.execute(
+        "\
+        UPDATE activities \
+        SET title = ?, properties = ? \
+        WHERE file = ?",
+        params![title, serde_json::to_string(properties)?, name],
+    )?;
+
+    Ok(num_rows > 0)
+}
+
+/// What values an activity property takes on across all activities, for
+/// building filter UIs.
+#[derive(Debug, Serialize)]
+pub struct PropertySummary {
+    pub key: String,
+    /// Number of activities which have this property.
+    pub activity_count: usize,
+    /// JSON types the property has been seen with, e.g. `text` or `integer`.
+    pub types: Vec<String>,
+    pub distinct_values: usize,
+    /// Range of numeric values, if there are any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+}
+
+/// Earliest and latest activity dates matching the filter.
+pub fn date_range(db: &Database, filter: &ActivityFilter) -> Result<Option<(Date, Date)>> {
+    let mut params = vec![];
+    let query = format!(
+        "SELECT min(start_time), max(start_time) FROM activities WHERE {}",
+        filter.to_query(&mut params)
+    );
+
+    let conn = db.connection()?;
+    let (min, max): (Option<OffsetDateTime>, Option<OffsetDateTime>) =
+        conn.query_row(&query, params.as_slice(), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+
+    Ok(min.zip(max).map(|(min, max)| (min.date(), max.date())))
+}
+
+/// Summarize every property key used by any activity, ordered by key.
+pub fn describe_properties(db: &Database) -> Result<Vec<PropertySummary>> {
+    let conn = db.connection()?;
+    let mut stmt = conn.prepare(
+        "\
+        SELECT \
+            prop.key, \
+            prop.type, \
+            count(*), \
+            count(DISTINCT prop.value), \
+            min(prop.value), \
+            max(prop.value) \
+        FROM activities, json_each(activities.properties) prop \
+        GROUP BY 1, 2 \
+        ORDER BY 1, 2",
+    )?;
+
+    let mut properties: Vec<PropertySummary> = vec![];
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let key: String = row.get_unwrap(0);
+        let json_type: String = row.get_unwrap(1);
+
+        // Each key appears once per activity, so results for the same key
+        // with different types can be added up.
+        if !matches!(properties.last(), Some(prop) if prop.key == key) {
+            properties.push(PropertySummary {
+                key,
+                activity_count: 0,
+                types: vec![],
+                distinct_values: 0,
+                min: None,
+                max: None,
+            });
+        }
+        let prop = properties.last_mut().expect("just pushed");
+
+        prop.activity_count += row.get_unwrap::<_, usize>(2);
+        prop.distinct_values += row.get_unwrap::<_, usize>(3);
+
+        let json_type = match json_type.as_str() {
+            "true" | "false" => "boolean".to_string(),
+            _ => json_type,
+        };
+
+        if json_type == "integer" || json_type == "real" {
+            let (min, max): (f64, f64) = (row.get_unwrap(4), row.get_unwrap(5));
+            prop.min = Some(prop.min.map_or(min, |v| v.min(min)));
+            prop.max = Some(prop.max.map_or(max, |v| v.max(max)));
+        }
+
+        if !prop.types.contains(&json_type) {
+            prop.types.push(json_type);
+        }
+    }
+
+    Ok(properties)
+}
+
+/// Activity metadata as stored in the database (without any track data).
+#[derive(Clone, Debug, Serialize)]
+pub struct ActivitySummary {
+    pub id: i64,
+    pub file: String,
+    pub title: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub start_time: Option<OffsetDateTime>,
+    pub properties: serde_json::Map<String, serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub athlete_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<i64>,
+}
+
+impl ActivitySummary {
+    fn from_row(row: &rusqlite::Row) -> Result<Self> {
+        let properties: String = row.get_unwrap(4);
+
+        Ok(ActivitySummary {
+            id: row.get_unwrap(0),
+            file: row.get_unwrap(1),
+            title: row.get_unwrap(2),
+            start_time: row.get_unwrap(3),
+            properties: serde_json::from_str(&properties)?,
+            athlete_id: row.get_unwrap(5),
+            user_id: row.get_unwrap(6),
+        })
+    }
+}
+
+/// Look up a single activity by ID.
+pub fn get(db: &Database, id: i64) -> Result<Option<ActivitySummary>> {
+    let conn = db.connection()?;
+    let mut stmt = conn.prepare(
+        "\
+        SELECT id, file, title, start_time, properties, athlete_id, user_id \
+        FROM activities \
+        WHERE id = ?",
+    )?;
+
+    let mut rows = stmt.query([id])?;
+    rows.next()?.map(ActivitySummary::from_row).transpose()
+}
+
+/// Load the stored (simplified) tracks of an activity, if available.
+pub fn load_tracks(db: &Database, activity_id: i64) -> Result<Option<MultiLineString>> {
+    let conn = db.connection()?;
+    let polylines: Option<String> = conn
+        .query_row(
+            "SELECT polylines FROM activity_tracks WHERE activity_id = ?",
+            [activity_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    polylines.map(|p| decode_tracks(&p)).transpose()
+}
+
+/// Order in which activities are listed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// Oldest first, by start time
+    #[default]
+    Oldest,
+    /// Newest first, by start time
+    Newest,
+    /// Alphabetically by title
+    Title,
+}
+
+impl SortOrder {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            SortOrder::Oldest => "start_time, id",
+            SortOrder::Newest => "start_time DESC, id DESC",
+            SortOrder::Title => "title, id",
+        }
+    }
+}
+
+/// List the activities matching the given filter, ordered by start time.
+pub fn list(
+    db: &Database,
+    filter: &ActivityFilter,
+    limit: Option<u32>,
+) -> Result<Vec<ActivitySummary>> {
+    list_page(db, filter, SortOrder::Oldest, limit, 0)
+}
 
-        let coords = encode_line(&simplified_line)?;
-        insert_tile.insert(params![activity_id, tile.z, tile.x, tile.y, coords])?;
+/// List up to `limit` activities matching the given filter, skipping the
+/// first `offset`.
+pub fn list_page(
+    db: &Database,
+    filter: &ActivityFilter,
+    sort: SortOrder,
+    limit: Option<u32>,
+    offset: u32,
+) -> Result<Vec<ActivitySummary>> {
+    let mut params = vec![];
+    let filter_clause = filter.to_query(&mut params);
+
+    let limit = limit.map(|n| n as i64).unwrap_or(-1);
+    params.push(&limit);
+    params.push(&offset);
+
+    let conn = db.connection()?;
+    let mut stmt = conn.prepare(&format!(
+        "\
+        SELECT id, file, title, start_time, properties, athlete_id, user_id \
+        FROM activities \
+        WHERE {} \
+        ORDER BY {} \
+        LIMIT ? OFFSET ?",
+        filter_clause,
+        sort.as_sql(),
+    ))?;
+
+    let mut rows = stmt.query(params.as_slice())?;
+    let mut activities = vec![];
+    while let Some(row) = rows.next()? {
+        activities.push(ActivitySummary::from_row(row)?);
     }
 
-    Ok(activity_id)
+    Ok(activities)
+}
+
+/// Whether any of the (lng/lat) tracks comes within `radius` meters of
+/// `point`.
+fn passes_near(tracks: &MultiLineString, point: Coord, radius: f64) -> bool {
+    let origin = Point::new(0.0, 0.0);
+    dedupe::to_meters(tracks, point)
+        .iter()
+        .any(|line| origin.euclidean_distance(line) <= radius)
+}
+
+/// List the activities matching the given filter whose visible tracks pass
+/// within `radius` meters of `point`, ordered by start time.
+pub fn list_near(
+    db: &Database,
+    filter: ActivityFilter,
+    point: Coord,
+    radius: f64,
+) -> Result<Vec<ActivitySummary>> {
+    let Some(area) = WebMercatorViewport::around(LngLat(point.into()), radius) else {
+        return Ok(vec![]);
+    };
+
+    // The tile index narrows it down to a few candidates, which are then
+    // checked against the stored tracks.
+    let candidates = list(db, &filter.within(&area, &db.config), None)?;
+    let mut activities = vec![];
+    for activity in candidates {
+        let Some(tracks) = load_tracks(db, activity.id)? else {
+            continue;
+        };
+
+        // Trimmed or masked parts of a track shouldn't give it away.
+        let tracks = visible_tracks(&tracks, &activity, &db.config);
+        if passes_near(&tracks, point, radius) {
+            activities.push(activity);
+        }
+    }
+
+    Ok(activities)
 }
 
 pub struct PropertySource {
@@ -460,11 +1480,15 @@ impl Default for PropertySource {
 
 impl PropertySource {
     pub(crate) fn from_csv(csv_path: &Path) -> Result<Self> {
-        const JOIN_COL: &str = "filename";
-
         let base_dir = csv_path.parent().unwrap_or(Path::new("/")).canonicalize()?;
+        Self::from_reader(File::open(csv_path)?, base_dir)
+    }
+
+    /// Read properties from CSV data, with file names relative to `base_dir`.
+    fn from_reader<R: Read>(reader: R, base_dir: PathBuf) -> Result<Self> {
+        const JOIN_COL: &str = "filename";
 
-        let mut rdr = csv::Reader::from_path(csv_path)?;
+        let mut rdr = csv::Reader::from_reader(reader);
         let mut path_props = HashMap::new();
 
         // Normalize header naming
@@ -505,6 +1529,25 @@ impl PropertySource {
         })
     }
 
+    /// Read workout properties from an Apple Health export's `export.xml`,
+    /// where `export_dir` is the directory containing it, relative to
+    /// `base_dir`.
+    pub(crate) fn from_apple_health<R: BufRead>(
+        reader: R,
+        base_dir: PathBuf,
+        export_dir: &Path,
+    ) -> Result<Self> {
+        let path_props = apple_health::route_properties(reader)?
+            .into_iter()
+            .map(|(route, props)| (export_dir.join(route), props))
+            .collect();
+
+        Ok(Self {
+            base_dir,
+            path_props,
+        })
+    }
+
     /// Merge properties from the attribute source into the activity.
     fn enrich(&self, path: &Path, activity: &mut RawActivity) {
         let path = path.strip_prefix(&self.base_dir).ok();
@@ -520,60 +1563,755 @@ impl PropertySource {
     }
 }
 
-pub fn import_path(p: &Path, db: &Database, prop_source: &PropertySource) -> Result<()> {
-    let conn = db.connection()?;
+/// Whether `name` was imported already, either as an activity of its own or
+/// linked to an existing one (see [`dedupe::link`]).
+pub fn is_known(conn: &rusqlite::Connection, name: &str) -> Result<bool> {
+    Ok(conn.query_row(
+        "\
+        SELECT EXISTS (SELECT 1 FROM activities WHERE file = ?1) \
+            OR EXISTS (SELECT 1 FROM linked_files WHERE file = ?1)",
+        params![name],
+        |row| row.get(0),
+    )?)
+}
 
-    // Skip any files that are already in the database.
-    let known_files: HashSet<String> = conn
-        .prepare("SELECT file FROM activities")?
+fn known_files(conn: &rusqlite::Connection) -> Result<HashSet<String>> {
+    Ok(conn
+        .prepare("SELECT file FROM activities UNION ALL SELECT file FROM linked_files")?
+        .query_map([], |row| row.get(0))?
+        .filter_map(|n| n.ok())
+        .collect())
+}
+
+/// Remember that `name` has the same contents as an existing activity, so
+/// that later imports skip it without reading it again.
+fn link_same_contents(conn: &rusqlite::Connection, name: &str, content_hash: &str) -> Result<()> {
+    conn.execute(
+        "\
+        INSERT OR IGNORE INTO linked_files (file, activity_file) \
+        SELECT ?, file FROM activities WHERE content_hash = ? LIMIT 1",
+        params![name, content_hash],
+    )?;
+
+    Ok(())
+}
+
+fn known_hashes(conn: &rusqlite::Connection) -> Result<HashSet<String>> {
+    Ok(conn
+        .prepare("SELECT content_hash FROM activities WHERE content_hash IS NOT NULL")?
         .query_map([], |row| row.get(0))?
         .filter_map(|n| n.ok())
+        .collect())
+}
+
+/// How to decide whether a file has already been imported.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum DedupeBy {
+    /// Skip files with the same contents as an existing activity, even if
+    /// they were renamed or moved (files with a known path are skipped too)
+    #[default]
+    Hash,
+    /// Only skip files whose path was already imported
+    Name,
+}
+
+/// Import all activity files below the given path, returning what happened
+/// to each file.
+///
+/// With `show_progress`, a progress bar is drawn to the terminal.
+pub fn import_path(
+    p: &Path,
+    db: &Database,
+    prop_source: &PropertySource,
+    dedupe_by: DedupeBy,
+    show_progress: bool,
+) -> Result<ImportSummary> {
+    let conn = db.connection()?;
+
+    // Skip any files that are already in the database.
+    let known_files = known_files(&conn)?;
+
+    // Also catches duplicates within the files being imported.
+    let seen_hashes = match dedupe_by {
+        DedupeBy::Hash => Some(Mutex::new(known_hashes(&conn)?)),
+        DedupeBy::Name => None,
+    };
+
+    // Listing files up front is cheap compared to parsing them, and lets us
+    // show an accurate ETA.
+    let paths: Vec<PathBuf> = WalkDir::new(p)
+        .into_iter()
+        .filter_map(|dir| dir.ok())
+        .filter(|dir| dir.file_type().is_file())
+        .map(|dir| dir.into_path())
         .collect();
 
     tracing::info!(
         path = ?p,
+        num_files = paths.len(),
         num_known = known_files.len(),
         "starting activity import"
     );
 
+    let progress = if show_progress {
+        ProgressBar::new(paths.len() as u64)
+    } else {
+        ProgressBar::hidden()
+    }
+    .with_style(
+        ProgressStyle::with_template(
+            "{wide_bar} {pos}/{len} files ({per_sec}, {eta} remaining) {msg}",
+        )
+        .expect("valid template"),
+    );
+
     let num_imported = AtomicU32::new(0);
-    WalkDir::new(p)
-        .into_iter()
-        .par_bridge()
-        .filter_map(|dir| {
-            let dir = dir.ok()?;
-            let path = dir.path();
-
-            if !known_files.contains(path.to_str()?) {
-                Some(path.to_owned())
-            } else {
-                None
-            }
-        })
-        .filter_map(|path| {
-            let activity = read_file(&path)
-                .map_err(|err| tracing::error!(?path, ?err, "failed to read activity"))
-                .ok()??;
+    let num_failed = AtomicU32::new(0);
 
-            Some((path, activity))
-        })
-        .for_each_init(
+    let outcomes: Vec<_> = paths
+        .into_par_iter()
+        .map_init(
             || db.shared_pool(),
-            |pool, (path, mut activity)| {
-                tracing::debug!(?path, "importing activity");
+            |pool, path| {
+                let file = path.to_string_lossy().into_owned();
+                let outcome = if known_files.contains(&file) {
+                    ImportOutcome::Skipped
+                } else {
+                    import_file(pool, &path, &file, db, prop_source, seen_hashes.as_ref())
+                };
+
+                match outcome {
+                    ImportOutcome::Imported => num_imported.fetch_add(1, Ordering::Relaxed),
+                    ImportOutcome::Failed(_) => num_failed.fetch_add(1, Ordering::Relaxed),
+                    _ => 0,
+                };
+
+                progress.set_message(format!(
+                    "{} imported, {} failed",
+                    num_imported.load(Ordering::Relaxed),
+                    num_failed.load(Ordering::Relaxed)
+                ));
+                progress.inc(1);
+
+                (file, outcome)
+            },
+        )
+        .collect();
+
+    progress.finish();
+
+    let mut summary = ImportSummary::default();
+    for (file, outcome) in outcomes {
+        match outcome {
+            ImportOutcome::Imported => summary.imported.push(file),
+            ImportOutcome::Skipped => summary.skipped.push(file),
+            ImportOutcome::SameContents(hash) => {
+                // Only now that the files it duplicates have been stored.
+                link_same_contents(&conn, &file, &hash)?;
+                summary.skipped.push(file);
+            }
+            ImportOutcome::Unsupported => summary.unsupported.push(file),
+            ImportOutcome::Failed(err) => {
+                tracing::error!(file, ?err, "failed to import activity");
+                summary.failed.push(ImportFailure {
+                    file,
+                    error: format!("{:#}", err),
+                });
+            }
+        }
+    }
+
+    tracing::info!(
+        num_imported = summary.imported.len(),
+        num_skipped = summary.skipped.len(),
+        num_unsupported = summary.unsupported.len(),
+        num_failed = summary.failed.len(),
+        "finished import"
+    );
+
+    Ok(summary)
+}
 
-                // Merge with activity properties
-                prop_source.enrich(&path, &mut activity);
+enum ImportOutcome {
+    Imported,
+    Skipped,
+    /// Skipped, since a file with this content hash was imported already.
+    SameContents(String),
+    Unsupported,
+    Failed(anyhow::Error),
+}
 
-                let mut conn = pool.get().expect("db connection pool timed out");
-                upsert(&mut conn, path.to_str().unwrap(), &activity, &db.config)
-                    .expect("insert activity");
+fn import_file(
+    pool: &r2d2::Pool<SqliteConnectionManager>,
+    path: &Path,
+    file: &str,
+    db: &Database,
+    prop_source: &PropertySource,
+    seen_hashes: Option<&Mutex<HashSet<String>>>,
+) -> ImportOutcome {
+    let comp = match file_type(path) {
+        Ok(Some((_, comp))) => comp,
+        Ok(None) => return ImportOutcome::Unsupported,
+        Err(err) => return ImportOutcome::Failed(err),
+    };
+
+    // Hashing is cheap compared to parsing, so make a first pass over the
+    // file to skip known contents early.
+    if let Some(seen) = seen_hashes {
+        let hash = File::open(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| content_hash(file, comp));
+        let hash = match hash {
+            Ok(hash) => hash,
+            Err(err) => return ImportOutcome::Failed(err),
+        };
+
+        if !seen.lock().expect("poisoned").insert(hash.clone()) {
+            tracing::debug!(?path, "skipping duplicate activity");
+            return ImportOutcome::SameContents(hash);
+        }
+    }
+
+    let activities = match read_file(path) {
+        Ok(activities) if activities.is_empty() => return ImportOutcome::Unsupported,
+        Ok(activities) => activities,
+        Err(err) => return ImportOutcome::Failed(err),
+    };
+
+    tracing::debug!(?path, "importing activity");
+
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(err) => return ImportOutcome::Failed(err.into()),
+    };
+
+    let mut outcome = ImportOutcome::Skipped;
+    for (i, mut activity) in activities.into_iter().enumerate() {
+        // Merge with activity properties
+        prop_source.enrich(path, &mut activity);
+
+        match upsert(&mut conn, &session_name(file, i), &activity, &db.config) {
+            Ok(Upserted::Inserted(_)) => outcome = ImportOutcome::Imported,
+            Ok(Upserted::Linked(_) | Upserted::Excluded) => {}
+            Err(err) => return ImportOutcome::Failed(err),
+        }
+    }
+
+    outcome
+}
+
+/// Outcome of importing a batch of files, e.g. from an uploaded archive.
+#[derive(Debug, Default, Serialize)]
+pub struct ImportSummary {
+    pub imported: Vec<String>,
+    /// Files which were already imported (or duplicate another file).
+    pub skipped: Vec<String>,
+    /// Files which aren't a supported activity format, or don't contain any
+    /// track data.
+    pub unsupported: Vec<String>,
+    pub failed: Vec<ImportFailure>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportFailure {
+    pub file: String,
+    pub error: String,
+}
+
+impl ImportSummary {
+    pub fn extend(&mut self, other: ImportSummary) {
+        self.imported.extend(other.imported);
+        self.skipped.extend(other.skipped);
+        self.unsupported.extend(other.unsupported);
+        self.failed.extend(other.failed);
+    }
+}
+
+/// Import all activity files contained in a zip archive, such as a Strava
+/// bulk export.
+///
+/// If the archive has an `activities.csv` file at its root, it's used to add
+/// properties to the matching activities, same as `import --join`. Activities
+/// are stored as `{prefix}{path in archive}`, and skipped if already present
+/// (by name or content).
+pub fn import_zip<R: Read + Seek>(
+    reader: R,
+    prefix: &str,
+    user_id: Option<i64>,
+    db: &Database,
+) -> Result<ImportSummary> {
+    const PROPERTIES_FILE: &str = "activities.csv";
+
+    let mut archive = zip::ZipArchive::new(reader)?;
+
+    // Apple Health exports have everything in an `apple_health_export/`
+    // directory, so look for `export.xml` anywhere.
+    let apple_health_export = archive
+        .file_names()
+        .map(Path::new)
+        .find(|path| path.file_name() == Some(OsStr::new(apple_health::EXPORT_FILE)))
+        .map(Path::to_path_buf);
+
+    let csv_source = match archive.by_name(PROPERTIES_FILE) {
+        Ok(file) => Some(PropertySource::from_reader(file, PathBuf::new())?),
+        Err(zip::result::ZipError::FileNotFound) => None,
+        Err(err) => return Err(err.into()),
+    };
+
+    let prop_source = match (csv_source, apple_health_export) {
+        (Some(source), _) => source,
+        (None, Some(export)) => {
+            let file = archive.by_name(&export.to_string_lossy())?;
+            let export_dir = export.parent().unwrap_or(Path::new(""));
+            PropertySource::from_apple_health(BufReader::new(file), PathBuf::new(), export_dir)?
+        }
+        (None, None) => PropertySource::default(),
+    };
+
+    let mut conn = db.connection()?;
+    let known_files = known_files(&conn)?;
+    let mut known_hashes = known_hashes(&conn)?;
+    let mut summary = ImportSummary::default();
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+
+        let file = entry.name().to_string();
+        let name = format!("{}{}", prefix, file);
+
+        // Already used above, not an activity.
+        if file == PROPERTIES_FILE {
+            continue;
+        }
+
+        let Some((media_type, comp)) = get_file_type(&file) else {
+            summary.unsupported.push(file);
+            continue;
+        };
+
+        if known_files.contains(&name) {
+            summary.skipped.push(file);
+            continue;
+        }
+
+        let mut bytes = vec![];
+        entry.read_to_end(&mut bytes)?;
+
+        let hash = match content_hash(bytes.as_slice(), comp) {
+            Ok(hash) => hash,
+            Err(err) => {
+                summary.failed.push(ImportFailure {
+                    file,
+                    error: err.to_string(),
+                });
+                continue;
+            }
+        };
+        if !known_hashes.insert(hash.clone()) {
+            link_same_contents(&conn, &name, &hash)?;
+            summary.skipped.push(file);
+            continue;
+        }
 
-                num_imported.fetch_add(1, Ordering::Relaxed);
+        let activities = match read(Cursor::new(bytes), media_type, comp) {
+            Ok(activities) if activities.is_empty() => {
+                summary.unsupported.push(file);
+                continue;
+            }
+            Ok(activities) => activities,
+            Err(err) => {
+                summary.failed.push(ImportFailure {
+                    file,
+                    error: err.to_string(),
+                });
+                continue;
+            }
+        };
+
+        let mut inserted = false;
+        for (i, mut activity) in activities.into_iter().enumerate() {
+            prop_source.enrich(Path::new(&file), &mut activity);
+            activity.user_id = user_id;
+            if let Upserted::Inserted(_) =
+                upsert(&mut conn, &session_name(&name, i), &activity, &db.config)?
+            {
+                inserted = true;
+            }
+        }
+
+        if inserted {
+            summary.imported.push(file);
+        } else {
+            summary.skipped.push(file);
+        }
+    }
+
+    tracing::info!(
+        num_imported = summary.imported.len(),
+        num_skipped = summary.skipped.len(),
+        num_unsupported = summary.unsupported.len(),
+        num_failed = summary.failed.len(),
+        "finished archive import"
+    );
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trim_dist() {
+        let config = db::Config::default();
+        assert_eq!(trim_dist(None, &config), config.trim_dist);
+        assert_eq!(trim_dist(Some(&serde_json::json!(500)), &config), 500.0);
+        assert_eq!(trim_dist(Some(&serde_json::json!(0.0)), &config), 0.0);
+
+        // Invalid values fall back to the configured distance.
+        assert_eq!(
+            trim_dist(Some(&serde_json::json!(-1)), &config),
+            config.trim_dist
+        );
+        assert_eq!(
+            trim_dist(Some(&serde_json::json!("far")), &config),
+            config.trim_dist
+        );
+    }
+
+    #[test]
+    fn test_is_virtual_source() {
+        assert!(is_virtual_source("Zwift"));
+        assert!(is_virtual_source("VirtualRide"));
+        assert!(!is_virtual_source("Ride"));
+        assert!(!is_virtual_source("StravaGPX iPhone"));
+    }
+
+    #[test]
+    fn test_passes_near() {
+        // Heading east along 52.5°N, ~680m per 0.01°.
+        let tracks =
+            MultiLineString::new(vec![LineString::from(vec![(13.40, 52.5), (13.42, 52.5)])]);
+
+        // ~55m north of the middle of the track.
+        let point = Coord {
+            x: 13.41,
+            y: 52.5005,
+        };
+        assert!(passes_near(&tracks, point, 60.0));
+        assert!(!passes_near(&tracks, point, 50.0));
+
+        // Past the end of the track.
+        assert!(!passes_near(&tracks, Coord { x: 13.43, y: 52.5 }, 100.0));
+    }
+
+    #[test]
+    fn test_parse_kml() {
+        let kml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<kml xmlns="http://www.opengis.net/kml/2.2" xmlns:gx="http://www.google.com/kml/ext/2.2">
+  <Document>
+    <name>Document name</name>
+    <Placemark>
+      <name>Morning Ride</name>
+      <gx:Track>
+        <when>2023-06-01T08:00:00Z</when>
+        <when>2023-06-01T08:00:05Z</when>
+        <gx:coord>-122.1 37.1 10</gx:coord>
+        <gx:coord>-122.2 37.2 12</gx:coord>
+      </gx:Track>
+    </Placemark>
+    <Placemark>
+      <LineString>
+        <coordinates>
+          -122.3,37.3,0 -122.4,37.4,0
+          -122.5,37.5
+        </coordinates>
+      </LineString>
+    </Placemark>
+  </Document>
+</kml>"#;
+
+        let activity = parse_kml(&mut kml.as_bytes()).unwrap().unwrap();
+
+        assert_eq!(activity.title.as_deref(), Some("Morning Ride"));
+        assert_eq!(
+            activity.start_time,
+            Some(OffsetDateTime::parse("2023-06-01T08:00:00Z", &Rfc3339).unwrap())
+        );
+        assert_eq!(activity.tracks.0.len(), 2);
+        assert_eq!(activity.tracks.0[0].0.len(), 2);
+        assert_eq!(activity.tracks.0[1].0.len(), 3);
+        assert_eq!(activity.tracks.0[1].0[2], (-122.5, 37.5).into());
+    }
+
+    #[test]
+    fn test_gpx_geometry() {
+        let mut gpx = gpx::Gpx::default();
+        assert!(gpx_geometry(&gpx).is_none());
+
+        let waypoint = |lng: f64, ts: &str| {
+            let mut wpt = gpx::Waypoint::new(Point::new(lng, 37.0));
+            wpt.time = Some(OffsetDateTime::parse(ts, &Rfc3339).unwrap().into());
+            wpt
+        };
+
+        gpx.waypoints = vec![
+            waypoint(-122.2, "2023-06-01T08:05:00Z"),
+            waypoint(-122.1, "2023-06-01T08:00:00Z"),
+        ];
+        let (_, tracks) = gpx_geometry(&gpx).unwrap();
+        assert_eq!(tracks.0[0].0[0], (-122.1, 37.0).into());
+
+        // Untimestamped waypoints are ignored.
+        gpx.waypoints
+            .push(gpx::Waypoint::new(Point::new(-122.3, 37.0)));
+        assert!(gpx_geometry(&gpx).is_none());
+
+        gpx.routes = vec![gpx::Route {
+            name: Some("Planned".to_string()),
+            points: vec![
+                gpx::Waypoint::new(Point::new(-122.0, 37.0)),
+                gpx::Waypoint::new(Point::new(-122.1, 37.1)),
+            ],
+            ..Default::default()
+        }];
+        let (title, tracks) = gpx_geometry(&gpx).unwrap();
+        assert_eq!(title.as_deref(), Some("Planned"));
+        assert_eq!(tracks.0[0].0.len(), 2);
+
+        // Tracks take precedence, keeping segments of each one separate.
+        let segment = |lng: f64| gpx::TrackSegment {
+            points: vec![
+                gpx::Waypoint::new(Point::new(lng, 37.0)),
+                gpx::Waypoint::new(Point::new(lng, 37.1)),
+            ],
+        };
+        gpx.tracks = vec![
+            gpx::Track {
+                name: Some("First".to_string()),
+                segments: vec![segment(-122.0), segment(-122.1)],
+                ..Default::default()
             },
+            gpx::Track {
+                segments: vec![segment(-122.2), gpx::TrackSegment::default()],
+                ..Default::default()
+            },
+        ];
+        let (title, tracks) = gpx_geometry(&gpx).unwrap();
+        assert_eq!(title.as_deref(), Some("First"));
+        assert_eq!(tracks.0.len(), 3);
+        assert_eq!(tracks.0[2].0[0], (-122.2, 37.0).into());
+    }
+
+    #[test]
+    fn test_gpx_extension_properties() {
+        let gpx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx creator="Garmin Connect" version="1.1"
+  xmlns="http://www.topografix.com/GPX/1/1"
+  xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v1">
+  <trk>
+    <trkseg>
+      <trkpt lat="37.1" lon="-122.1">
+        <extensions>
+          <gpxtpx:TrackPointExtension>
+            <gpxtpx:hr>120</gpxtpx:hr>
+            <gpxtpx:cad>80</gpxtpx:cad>
+          </gpxtpx:TrackPointExtension>
+        </extensions>
+      </trkpt>
+      <trkpt lat="37.2" lon="-122.2">
+        <extensions>
+          <gpxtpx:TrackPointExtension>
+            <gpxtpx:hr>151</gpxtpx:hr>
+          </gpxtpx:TrackPointExtension>
+        </extensions>
+      </trkpt>
+    </trkseg>
+  </trk>
+</gpx>"#;
+
+        let properties = gpx_extension_properties(gpx).unwrap();
+
+        assert_eq!(properties["average_heartrate"], serde_json::json!(135.5));
+        assert_eq!(properties["max_heartrate"], serde_json::json!(151.0));
+        assert_eq!(properties["average_cadence"], serde_json::json!(80.0));
+        assert!(!properties.contains_key("average_temp"));
+
+        // Not UTF-8, so extensions are skipped, but the track is still read.
+        let latin1 = gpx
+            .replace("UTF-8", "ISO-8859-1")
+            .replace("<trk>", "<trk><name>Caf\u{e9}</name>");
+        let bytes: Vec<u8> = latin1.chars().map(|c| c as u8).collect();
+        let activity = parse_gpx(&mut bytes.as_slice()).unwrap().unwrap();
+        assert_eq!(activity.title.as_deref(), Some("Caf\u{e9}"));
+        assert!(!activity.properties.contains_key("average_heartrate"));
+    }
+
+    #[test]
+    fn test_import_same_contents() {
+        let gpx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+  <trk><trkseg>
+    <trkpt lat="37.1" lon="-122.1"></trkpt>
+    <trkpt lat="37.2" lon="-122.2"></trkpt>
+  </trkseg></trk>
+</gpx>"#;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("db.sqlite3")).unwrap();
+        let files = dir.path().join("files");
+        std::fs::create_dir(&files).unwrap();
+        std::fs::write(files.join("a.gpx"), gpx).unwrap();
+        std::fs::write(files.join("b.gpx"), gpx).unwrap();
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        std::io::Write::write_all(&mut gz, gpx.as_bytes()).unwrap();
+        std::fs::write(files.join("c.gpx.gz"), gz.finish().unwrap()).unwrap();
+
+        // Hashed the same whether read in full or only hashed.
+        let activities = read_file(&files.join("c.gpx.gz")).unwrap();
+        assert_eq!(
+            activities[0].content_hash.as_deref(),
+            Some(
+                content_hash(gpx.as_bytes(), Compression::None)
+                    .unwrap()
+                    .as_str()
+            )
         );
 
-    conn.execute_batch("VACUUM")?;
-    tracing::info!(?num_imported, "finished import");
-    Ok(())
+        let import = || {
+            import_path(
+                &files,
+                &db,
+                &PropertySource::default(),
+                DedupeBy::Hash,
+                false,
+            )
+            .unwrap()
+        };
+        let summary = import();
+        assert_eq!(summary.imported.len(), 1);
+        assert_eq!(summary.skipped.len(), 2);
+
+        // Known by name from now on, so it isn't even read again.
+        std::fs::write(&summary.skipped[0], "not a gpx file").unwrap();
+        let summary = import();
+        assert!(summary.imported.is_empty() && summary.failed.is_empty());
+        assert_eq!(summary.skipped.len(), 3);
+    }
+
+    #[test]
+    fn test_fit_properties() {
+        let field = |name: &str, value| {
+            fitparser::FitDataField::new(name.to_string(), 0, value, String::new())
+        };
+        let mut session = fitparser::FitDataRecord::new(MesgNum::Session);
+        session.push(field("sport", Value::String("cycling".into())));
+        session.push(field("total_distance", Value::Float64(42195.0)));
+        session.push(field("avg_heart_rate", Value::UInt8(142)));
+        session.push(field("total_training_effect", Value::Float32(3.2)));
+
+        let mut properties = HashMap::new();
+        add_fit_properties(&mut properties, &session, FIT_SESSION_PROPERTIES);
+        assert_eq!(properties["activity_type"], "cycling");
+        assert_eq!(properties["distance"], 42195.0);
+        assert_eq!(properties["average_heartrate"], 142.0);
+        assert!(!properties.contains_key("total_training_effect"));
+
+        // Both product fields map to the same property, the first one wins.
+        let mut device = fitparser::FitDataRecord::new(MesgNum::DeviceInfo);
+        device.push(field("manufacturer", Value::String("garmin".into())));
+        device.push(field("garmin_product", Value::String("fenix7".into())));
+        device.push(field("product", Value::UInt16(3906)));
+        device.push(field("software_version", Value::Float64(12.5)));
+
+        let mut properties = HashMap::new();
+        add_fit_properties(&mut properties, &device, FIT_DEVICE_PROPERTIES);
+        assert_eq!(properties["device_manufacturer"], "garmin");
+        assert_eq!(properties["device_product"], "fenix7");
+        assert_eq!(properties["device_software_version"], 12.5);
+
+        assert_eq!(fit_property(&Value::Array(vec![])), None);
+    }
+
+    #[test]
+    fn test_fit_record_developer_fields() {
+        let field = |name: &str, value| {
+            fitparser::FitDataField::new(name.to_string(), 0, value, String::new())
+        };
+        let mut data = fitparser::FitDataRecord::new(MesgNum::Record);
+        data.push(field("timestamp", Value::UInt32(1_000)));
+        data.push(field("position_lat", Value::SInt32(1 << 29)));
+        data.push(field("position_long", Value::SInt32(-(1 << 29))));
+        data.push(field("Stryd Power", Value::UInt16(250)));
+        data.push(field("Leg Spring Stiffness", Value::Float32(9.5)));
+        data.push(field("Undescribed", Value::UInt16(1)));
+
+        let developer_fields = HashSet::from([
+            "Stryd Power".to_string(),
+            "Leg Spring Stiffness".to_string(),
+        ]);
+        let record = fit_record(&data, &developer_fields).unwrap();
+        assert_eq!(record.time, Some(1_000));
+        assert_eq!(record.point, Some(Point::new(-45.0, 45.0)));
+        assert_eq!(
+            record.developer,
+            vec![
+                (developer_property("Stryd Power"), 250.0),
+                (developer_property("Leg Spring Stiffness"), 9.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_fit_sessions() {
+        let session = |start, sport: &str| FitSession {
+            start_time: Some(start),
+            properties: HashMap::from([("activity_type".to_string(), sport.into())]),
+        };
+        let record = |time, power| FitRecord {
+            time: Some(time),
+            point: Some(Point::new(13.4, 52.5)),
+            developer: vec![("power".to_string(), power)],
+        };
+
+        // Sessions are written at the end of the file, in any order.
+        let sessions = vec![
+            session(200, "running"),
+            session(0, "swimming"),
+            session(100, "cycling"),
+        ];
+        let records = vec![
+            record(110, 200.0),
+            record(120, 220.0),
+            record(210, 250.0),
+            record(220, 270.0),
+        ];
+
+        let groups = split_fit_sessions(sessions, records);
+        // The pool swim has no points.
+        assert_eq!(groups.len(), 2);
+
+        let file_properties = HashMap::from([("device_name".to_string(), "Watch".into())]);
+        let activities: Vec<_> = groups
+            .into_iter()
+            .map(|(session, records)| fit_activity(session, records, &file_properties))
+            .collect();
+
+        let ride = &activities[0];
+        assert_eq!(ride.properties["activity_type"], "cycling");
+        assert_eq!(ride.properties["device_name"], "Watch");
+        assert_eq!(ride.properties["average_power"], 210.0);
+        assert_eq!(ride.start_time.unwrap().unix_timestamp(), 100);
+        assert_eq!(ride.tracks.0[0].0.len(), 2);
+
+        let run = &activities[1];
+        assert_eq!(run.properties["activity_type"], "running");
+        assert_eq!(run.properties["average_power"], 260.0);
+
+        assert_eq!(session_name("tri.fit", 0), "tri.fit");
+        assert_eq!(session_name("tri.fit", 1), "tri.fit#2");
+    }
 }
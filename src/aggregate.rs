@@ -0,0 +1,245 @@
+//! Precomputed visit counts for low zoom tiles.
+//!
+//! Rendering a tile of the whole world means drawing every single activity,
+//! which gets slow for large databases. Since most requests for these tiles
+//! don't filter activities, their counts are kept in `aggregate_tiles` and
+//! only colored in when rendering.
+//!
+//! Triggers mark the aggregates covering any changed activity tiles as
+//! stale, and `update` redraws them in the background. Until then, tiles are
+//! rendered from scratch as usual.
+
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use image::{ImageBuffer, ImageOutputFormat, Luma};
+use rusqlite::params;
+
+use crate::db::{ActivityFilter, Database};
+use crate::raster::{self, ColorBy, Stroke};
+use crate::tile::Tile;
+
+/// Highest zoom level aggregates are kept for. Must match the triggers
+/// created by the `aggregate_tiles` migration.
+pub const MAX_ZOOM: u8 = 6;
+
+/// Tile sizes aggregates are kept for: those used for `/render` images, and
+/// the default for map tiles. Must match the migration too.
+const WIDTHS: [u32; 2] = [256, 512];
+
+/// How often to redraw stale aggregates when serving.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether the aggregate for `tile` can stand in for rendering it with the
+/// given options.
+pub fn covers(
+    tile: &Tile,
+    stroke: Stroke,
+    color_by: Option<&ColorBy>,
+    width: u32,
+    filter: &ActivityFilter,
+) -> bool {
+    tile.z <= MAX_ZOOM
+        && WIDTHS.contains(&width)
+        && stroke == Stroke::default()
+        && color_by.is_none()
+        && filter.is_empty()
+}
+
+/// Precomputed counts for `tile` at the given size, unless they're missing
+/// or stale. Tiles without any activities have no counts.
+pub fn load(conn: &rusqlite::Connection, tile: &Tile, width: u32) -> Result<Option<Vec<u16>>> {
+    let mut stmt = conn.prepare_cached(
+        "\
+        SELECT counts FROM aggregate_tiles \
+        WHERE z = ? AND x = ? AND y = ? AND width = ? AND counts IS NOT NULL",
+    )?;
+
+    let mut rows = stmt.query(params![tile.z, tile.x, tile.y, width])?;
+    let Some(row) = rows.next()? else {
+        return Ok(None);
+    };
+
+    let bytes: Vec<u8> = row.get_unwrap(0);
+    decode(&bytes, width).map(Some)
+}
+
+/// Redraw stale aggregates (and any which were never drawn), returning how
+/// many were updated.
+pub fn update(db: &Database) -> Result<usize> {
+    let conn = db.connection()?;
+
+    // Tiles are only marked as stale once they exist, so add those covering
+    // activities imported before aggregates were introduced.
+    for z in 0..=MAX_ZOOM {
+        let source = db
+            .config
+            .source_level(z)
+            .unwrap_or(*db.config.zoom_range().end() as u8);
+        let shift = source - z;
+
+        for width in WIDTHS {
+            conn.execute(
+                "\
+                INSERT OR IGNORE INTO aggregate_tiles (z, x, y, width) \
+                SELECT DISTINCT ?, x >> ?, y >> ?, ? FROM activity_tiles WHERE z = ?",
+                params![z, shift, shift, width, source],
+            )?;
+        }
+    }
+
+    let stale = conn
+        .prepare("SELECT z, x, y, width, version FROM aggregate_tiles WHERE counts IS NULL")?
+        .query_map([], |row| {
+            let tile = Tile::new(row.get(1)?, row.get(2)?, row.get(0)?);
+            Ok((tile, row.get::<_, u32>(3)?, row.get::<_, i64>(4)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut num_updated = 0;
+    for (tile, width, version) in stale {
+        let counts = raster::count_tile(tile, width, db)?;
+        let bytes = match counts {
+            Some(counts) => encode(counts, width)?,
+            None => vec![],
+        };
+
+        // If activities changed while drawing, the version will have been
+        // bumped and the tile is left for the next update.
+        num_updated += conn.execute(
+            "\
+            UPDATE aggregate_tiles SET counts = ? \
+            WHERE z = ? AND x = ? AND y = ? AND width = ? AND version = ?",
+            params![bytes, tile.z, tile.x, tile.y, width, version],
+        )?;
+    }
+
+    Ok(num_updated)
+}
+
+/// Periodically redraw stale aggregates. Runs forever.
+pub async fn update_periodically(db: impl Fn() -> Arc<Database>) {
+    loop {
+        let task_db = db();
+        let result = tokio::task::spawn_blocking(move || update(&task_db))
+            .await
+            .expect("aggregate task panicked");
+
+        match result {
+            Ok(0) => {}
+            Ok(num_tiles) => tracing::info!(num_tiles, "updated aggregate tiles"),
+            Err(err) => tracing::error!(?err, "failed to update aggregate tiles"),
+        }
+
+        tokio::time::sleep(UPDATE_INTERVAL).await;
+    }
+}
+
+/// Store counts as a 16 bit grayscale PNG, which compresses the mostly empty
+/// tiles well.
+fn encode(counts: Vec<u16>, width: u32) -> Result<Vec<u8>> {
+    let image: ImageBuffer<Luma<u16>, _> = ImageBuffer::from_raw(width, width, counts)
+        .ok_or_else(|| anyhow!("wrong number of counts for aggregate tile"))?;
+
+    let mut bytes = Cursor::new(vec![]);
+    image.write_to(&mut bytes, ImageOutputFormat::Png)?;
+    Ok(bytes.into_inner())
+}
+
+fn decode(bytes: &[u8], width: u32) -> Result<Vec<u16>> {
+    if bytes.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let image = image::load_from_memory_with_format(bytes, image::ImageFormat::Png)?;
+    if image.width() != width || image.height() != width {
+        return Err(anyhow!("aggregate tile has the wrong size"));
+    }
+
+    Ok(image.into_luma16().into_raw())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo_types::Coord;
+
+    use crate::db::encode_line;
+
+    #[test]
+    fn test_aggregate_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("db.sqlite3")).unwrap();
+        let conn = db.connection().unwrap();
+
+        let insert = |z: u8, x: u32, y: u32, line: &[(u32, u32)]| {
+            let line: Vec<Coord<u32>> = line.iter().map(|&(x, y)| Coord { x, y }).collect();
+            conn.execute(
+                "\
+                INSERT INTO activity_tiles (activity_id, z, x, y, coords) \
+                VALUES (1, ?, ?, ?, ?)",
+                params![z, x, y, encode_line(&line.into()).unwrap()],
+            )
+            .unwrap();
+        };
+        conn.execute("INSERT INTO activities (id, file) VALUES (1, 'a.gpx')", [])
+            .unwrap();
+
+        // Stored at z2, and a single z6 tile in the top left of it.
+        let line = [(0, 1024), (2047, 1024)];
+        insert(2, 0, 0, &line);
+        insert(6, 0, 0, &line);
+        insert(10, 0, 0, &line);
+        let z0 = Tile::new(0, 0, 0);
+        let z6 = Tile::new(0, 0, 6);
+
+        // Triggers only add tiles covering the first two, at each size.
+        let num_tiles: usize = conn
+            .query_row("SELECT count(*) FROM aggregate_tiles", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(num_tiles, 14);
+        assert_eq!(load(&conn, &z0, 256).unwrap(), None);
+
+        assert_eq!(update(&db).unwrap(), 14);
+        assert_eq!(update(&db).unwrap(), 0);
+
+        for width in WIDTHS {
+            let expected = raster::count_tile(z6, width, &db).unwrap().unwrap();
+            assert_eq!(load(&conn, &z6, width).unwrap(), Some(expected));
+        }
+        assert!(load(&conn, &z0, 512).unwrap().is_some());
+
+        // Deleting activities marks the tiles covering them as stale again.
+        conn.execute("DELETE FROM activity_tiles WHERE z = 6", [])
+            .unwrap();
+        assert_eq!(load(&conn, &z6, 256).unwrap(), None);
+
+        assert_eq!(update(&db).unwrap(), 14);
+        assert_eq!(load(&conn, &z6, 256).unwrap(), Some(vec![]));
+        assert!(!load(&conn, &z0, 256).unwrap().unwrap().is_empty());
+
+        assert!(covers(
+            &z6,
+            Stroke::default(),
+            None,
+            256,
+            &ActivityFilter::default()
+        ));
+        assert!(!covers(
+            &Tile::new(0, 0, 7),
+            Stroke::default(),
+            None,
+            256,
+            &ActivityFilter::default()
+        ));
+        assert!(!covers(
+            &z6,
+            Stroke::default(),
+            None,
+            1024,
+            &ActivityFilter::default()
+        ));
+    }
+}
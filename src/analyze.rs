@@ -0,0 +1,177 @@
+//! Reports built on top of the visit counts in [`crate::heat`], such as the
+//! most traveled stretches of road.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use geo_types::Point;
+use serde::Serialize;
+
+use crate::db::{ActivityFilter, Database};
+use crate::heat::{self, HeatCell};
+use crate::tile::{BBox, Tile, WebMercator, WebMercatorViewport};
+
+/// A run of neighboring cells which were all visited by the same number of
+/// activities, which usually means a single stretch of road or trail.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Segment {
+    /// Number of distinct activities passing through.
+    pub count: u32,
+    /// Rough length in meters, based on the number of cells covered.
+    pub length: f64,
+    /// Cell closest to the middle of the segment.
+    pub lng: f64,
+    pub lat: f64,
+    /// `[west, south, east, north]`
+    pub bounds: [f64; 4],
+}
+
+impl Segment {
+    fn new(z: u8, count: u32, members: &[(u32, u32)]) -> Self {
+        let n = members.len() as f64;
+        let (cx, cy) = members.iter().fold((0.0, 0.0), |(sx, sy), &(x, y)| {
+            (sx + x as f64 / n, sy + y as f64 / n)
+        });
+        let dist = |&(x, y): &(u32, u32)| (x as f64 - cx).powi(2) + (y as f64 - cy).powi(2);
+        let &(mx, my) = members
+            .iter()
+            .min_by(|a, b| dist(a).total_cmp(&dist(b)))
+            .expect("segments have at least one cell");
+
+        let middle = Tile::new(mx, my, z).xy_bounds();
+        let center = WebMercator(Point::new(
+            (middle.left + middle.right) / 2.0,
+            (middle.top + middle.bot) / 2.0,
+        ))
+        .lnglat()
+        .0;
+
+        let bbox = members
+            .iter()
+            .map(|&(x, y)| Tile::new(x, y, z).xy_bounds())
+            .reduce(|a, b| BBox {
+                left: a.left.min(b.left),
+                right: a.right.max(b.right),
+                bot: a.bot.min(b.bot),
+                top: a.top.max(b.top),
+            })
+            .expect("segments have at least one cell");
+        let sw = WebMercator(Point::new(bbox.left, bbox.bot)).lnglat().0;
+        let ne = WebMercator(Point::new(bbox.right, bbox.top)).lnglat().0;
+
+        // Web Mercator cells shrink by cos(lat) on the ground.
+        let cell_size = (middle.right - middle.left) * center.y().to_radians().cos();
+
+        Segment {
+            count,
+            length: n * cell_size,
+            lng: center.x(),
+            lat: center.y(),
+            bounds: [sw.x(), sw.y(), ne.x(), ne.y()],
+        }
+    }
+}
+
+/// Group neighboring cells with the same count into segments, ordered by
+/// count and then length.
+fn group_segments(z: u8, cells: &[HeatCell]) -> Vec<Segment> {
+    let counts: HashMap<(u32, u32), u32> = cells.iter().map(|c| ((c.x, c.y), c.count)).collect();
+
+    let mut seen = HashSet::new();
+    let mut segments = vec![];
+    for cell in cells {
+        if !seen.insert((cell.x, cell.y)) {
+            continue;
+        }
+
+        // Flood fill over the 8 neighbors of each cell.
+        let mut members = vec![];
+        let mut stack = vec![(cell.x, cell.y)];
+        while let Some((x, y)) = stack.pop() {
+            members.push((x, y));
+
+            for (dx, dy) in (-1..=1).flat_map(|dx| (-1..=1).map(move |dy| (dx, dy))) {
+                let (Some(nx), Some(ny)) = (x.checked_add_signed(dx), y.checked_add_signed(dy))
+                else {
+                    continue;
+                };
+
+                if counts.get(&(nx, ny)) == Some(&cell.count) && seen.insert((nx, ny)) {
+                    stack.push((nx, ny));
+                }
+            }
+        }
+
+        segments.push(Segment::new(z, cell.count, &members));
+    }
+
+    segments.sort_by(|a, b| b.count.cmp(&a.count).then(b.length.total_cmp(&a.length)));
+    segments
+}
+
+/// The `limit` most traveled segments within `viewport`, binning tracks into
+/// cells at zoom level `z`.
+pub fn top_segments(
+    db: &Database,
+    filter: &ActivityFilter,
+    viewport: &WebMercatorViewport,
+    z: u8,
+    limit: usize,
+) -> Result<Vec<Segment>> {
+    let heat_map = heat::count_visits(db, filter, viewport, z)?;
+
+    let mut segments = group_segments(z, &heat_map.cells());
+    segments.truncate(limit);
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_segments() {
+        let cell = |x, y, count| HeatCell {
+            x,
+            y,
+            lng: 0.0,
+            lat: 0.0,
+            count,
+        };
+
+        // A diagonal road ridden 3 times, then a separate longer one ridden
+        // twice, with one cell of the former shared by a third road. Just
+        // south of the equator, so cells are about square on the ground.
+        let y = 1 << 15;
+        let cells = vec![
+            cell(10, y, 3),
+            cell(11, y + 1, 3),
+            cell(12, y + 2, 3),
+            cell(20, y, 2),
+            cell(21, y, 2),
+            cell(22, y, 2),
+            cell(23, y, 2),
+            cell(13, y + 2, 1),
+        ];
+
+        let segments = group_segments(16, &cells);
+        assert_eq!(
+            segments.iter().map(|s| s.count).collect::<Vec<_>>(),
+            vec![3, 2, 1]
+        );
+
+        // Cells at z16 are ~611m across at the equator.
+        assert!((segments[0].length - 3.0 * 611.5).abs() < 1.0);
+        assert!((segments[1].length - 4.0 * 611.5).abs() < 1.0);
+
+        let middle = Tile::new(11, y + 1, 16).xy_bounds();
+        let middle = WebMercator(Point::new(
+            (middle.left + middle.right) / 2.0,
+            (middle.top + middle.bot) / 2.0,
+        ))
+        .lnglat()
+        .0;
+        assert_eq!((segments[0].lng, segments[0].lat), (middle.x(), middle.y()));
+    }
+}
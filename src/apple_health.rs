@@ -0,0 +1,198 @@
+//! Workout metadata from an Apple Health export (`export.zip`).
+//!
+//! The export contains each workout's route as a GPX file under
+//! `workout-routes/`, while the type, duration, and distance of the workout
+//! only appear in `export.xml`. That file also contains every other health
+//! record (heart rate, steps, ...) and easily reaches gigabytes, so it's
+//! streamed rather than parsed into a tree.
+
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde_json::Value;
+
+/// Name of the file with workout metadata, at the root of the export.
+pub const EXPORT_FILE: &str = "export.xml";
+
+const WORKOUT_TYPE_PREFIX: &str = "HKWorkoutActivityType";
+
+struct Workout {
+    properties: HashMap<String, Value>,
+    routes: Vec<PathBuf>,
+}
+
+fn attributes(elem: &BytesStart) -> Result<HashMap<String, String>> {
+    let mut attrs = HashMap::new();
+    for attr in elem.attributes() {
+        let attr = attr?;
+        attrs.insert(
+            String::from_utf8_lossy(attr.key.as_ref()).into_owned(),
+            attr.unescape_value()?.into_owned(),
+        );
+    }
+    Ok(attrs)
+}
+
+/// Convert a duration to seconds.
+fn duration_secs(value: f64, unit: &str) -> Option<f64> {
+    match unit {
+        "s" => Some(value),
+        "min" => Some(value * 60.0),
+        "hr" | "h" => Some(value * 3600.0),
+        _ => None,
+    }
+}
+
+/// Convert a distance to meters.
+fn distance_meters(value: f64, unit: &str) -> Option<f64> {
+    match unit {
+        "m" => Some(value),
+        "km" => Some(value * 1000.0),
+        "mi" => Some(value * 1609.344),
+        "yd" => Some(value * 0.9144),
+        _ => None,
+    }
+}
+
+fn parse_number(attrs: &HashMap<String, String>, key: &str) -> Option<f64> {
+    attrs.get(key)?.parse().ok()
+}
+
+impl Workout {
+    fn from_attributes(attrs: &HashMap<String, String>) -> Self {
+        let mut properties = HashMap::new();
+
+        if let Some(kind) = attrs.get("workoutActivityType") {
+            let kind = kind.strip_prefix(WORKOUT_TYPE_PREFIX).unwrap_or(kind);
+            properties.insert("activity_type".to_string(), kind.into());
+        }
+
+        if let Some(device) = attrs.get("sourceName") {
+            properties.insert("device".to_string(), device.as_str().into());
+        }
+
+        let unit = attrs.get("durationUnit").map_or("min", String::as_str);
+        if let Some(secs) = parse_number(attrs, "duration").and_then(|d| duration_secs(d, unit)) {
+            properties.insert("elapsed_time".to_string(), secs.into());
+        }
+
+        // Older exports. Newer ones use `WorkoutStatistics` instead.
+        let unit = attrs.get("totalDistanceUnit").map_or("km", String::as_str);
+        if let Some(dist) =
+            parse_number(attrs, "totalDistance").and_then(|d| distance_meters(d, unit))
+        {
+            properties.insert("distance".to_string(), dist.into());
+        }
+
+        Workout {
+            properties,
+            routes: vec![],
+        }
+    }
+
+    fn add_statistics(&mut self, attrs: &HashMap<String, String>) {
+        let is_distance = attrs
+            .get("type")
+            .is_some_and(|kind| kind.starts_with("HKQuantityTypeIdentifierDistance"));
+        if !is_distance {
+            return;
+        }
+
+        let unit = attrs.get("unit").map_or("km", String::as_str);
+        if let Some(dist) = parse_number(attrs, "sum").and_then(|d| distance_meters(d, unit)) {
+            self.properties.insert("distance".to_string(), dist.into());
+        }
+    }
+}
+
+/// Read the properties of each workout from `export.xml`, keyed by the path
+/// of its route (relative to the export's root directory).
+pub fn route_properties<R: BufRead>(reader: R) -> Result<HashMap<PathBuf, HashMap<String, Value>>> {
+    let mut reader = Reader::from_reader(reader);
+    let mut buf = vec![];
+
+    let mut workout: Option<Workout> = None;
+    let mut routes = HashMap::new();
+
+    loop {
+        buf.clear();
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(elem) if elem.name().as_ref() == b"Workout" => {
+                workout = Some(Workout::from_attributes(&attributes(&elem)?));
+            }
+            Event::End(elem) if elem.name().as_ref() == b"Workout" => {
+                if let Some(workout) = workout.take() {
+                    for route in workout.routes {
+                        routes.insert(route, workout.properties.clone());
+                    }
+                }
+            }
+            Event::Start(elem) | Event::Empty(elem) => {
+                let Some(ref mut workout) = workout else {
+                    continue;
+                };
+
+                match elem.name().as_ref() {
+                    b"WorkoutStatistics" => workout.add_statistics(&attributes(&elem)?),
+                    b"FileReference" => {
+                        if let Some(path) = attributes(&elem)?.get("path") {
+                            workout
+                                .routes
+                                .push(PathBuf::from(path.trim_start_matches('/')));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_properties() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<HealthData locale="en_US">
+ <Record type="HKQuantityTypeIdentifierHeartRate" unit="count/min" value="62"/>
+ <Workout workoutActivityType="HKWorkoutActivityTypeRunning" duration="30" durationUnit="min" sourceName="Apple Watch" startDate="2023-05-01 07:00:00 +0200">
+  <MetadataEntry key="HKIndoorWorkout" value="0"/>
+  <WorkoutStatistics type="HKQuantityTypeIdentifierActiveEnergyBurned" sum="300" unit="kcal"/>
+  <WorkoutStatistics type="HKQuantityTypeIdentifierDistanceWalkingRunning" sum="5.5" unit="km"/>
+  <WorkoutRoute sourceName="Apple Watch">
+   <FileReference path="/workout-routes/route_2023-05-01_7.00am.gpx"/>
+  </WorkoutRoute>
+ </Workout>
+ <Workout workoutActivityType="HKWorkoutActivityTypeCycling" duration="1" durationUnit="hr" totalDistance="20" totalDistanceUnit="mi"/>
+ <Workout workoutActivityType="HKWorkoutActivityTypeHiking" duration="120" durationUnit="min" totalDistance="8" totalDistanceUnit="km">
+  <WorkoutRoute>
+   <FileReference path="/workout-routes/route_2023-05-02_9.00am.gpx"/>
+  </WorkoutRoute>
+ </Workout>
+</HealthData>
+"#;
+
+        let routes = route_properties(xml.as_bytes()).unwrap();
+        assert_eq!(routes.len(), 2);
+
+        let run = &routes[&PathBuf::from("workout-routes/route_2023-05-01_7.00am.gpx")];
+        assert_eq!(run["activity_type"], "Running");
+        assert_eq!(run["device"], "Apple Watch");
+        assert_eq!(run["elapsed_time"], 1800.0);
+        assert_eq!(run["distance"], 5500.0);
+
+        let hike = &routes[&PathBuf::from("workout-routes/route_2023-05-02_9.00am.gpx")];
+        assert_eq!(hike["activity_type"], "Hiking");
+        assert_eq!(hike["distance"], 8000.0);
+    }
+}
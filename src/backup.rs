@@ -0,0 +1,238 @@
+//! Consistent copies of the database, made with SQLite's online backup API
+//! so that it's safe to do while the server or an import is writing to it.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use rusqlite::backup::Backup;
+use rusqlite::{Connection, DatabaseName, OpenFlags};
+use time::OffsetDateTime;
+
+use crate::db::{self, Database};
+
+/// Pages to copy at a time. Writers are only blocked while a step runs.
+const PAGES_PER_STEP: i32 = 1024;
+const STEP_PAUSE: Duration = Duration::from_millis(10);
+
+const FILE_PREFIX: &str = "hotpot-";
+const FILE_SUFFIX: &str = ".sqlite3";
+
+/// Periodic backups while serving.
+#[derive(Clone)]
+pub struct Schedule {
+    pub dir: PathBuf,
+    pub interval: Duration,
+    /// Number of backups to keep, older ones are removed.
+    pub keep: usize,
+}
+
+/// Copy the database to `dest`, replacing it if it exists.
+///
+/// The copy is written next to `dest` first, so a backup which fails
+/// halfway doesn't clobber the previous one.
+pub fn backup(db: &Database, dest: &Path) -> Result<()> {
+    let dir = match dest.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let tmp = tempfile::Builder::new()
+        .prefix(".hotpot-backup")
+        .tempfile_in(dir)
+        .map_err(|err| anyhow!("failed to create backup in {}: {}", dir.display(), err))?;
+
+    let src = db.connection()?;
+    let mut dst = Connection::open(tmp.path())?;
+    Backup::new(&src, &mut dst)?.run_to_completion(PAGES_PER_STEP, STEP_PAUSE, None)?;
+
+    // Keep everything in a single file, rather than carrying over WAL mode.
+    dst.pragma_update(None, "journal_mode", "DELETE")?;
+    dst.close().map_err(|(_, err)| err)?;
+
+    tmp.persist(dest)?;
+    Ok(())
+}
+
+/// Replace the database at `path` with the backup at `src`, which may be
+/// from an older version of hotpot.
+pub fn restore(src: &Path, path: &Path) -> Result<()> {
+    if !src.exists() {
+        anyhow::bail!("backup does not exist: {}", src.display());
+    }
+
+    let backup = Connection::open_with_flags(src, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    check(&backup).map_err(|err| anyhow!("can't restore from {}: {}", src.display(), err))?;
+    drop(backup);
+
+    let mut conn = Connection::open(path)?;
+    conn.restore(
+        DatabaseName::Main,
+        src,
+        None::<fn(rusqlite::backup::Progress)>,
+    )?;
+
+    Ok(())
+}
+
+/// Make sure a backup is a hotpot database we can use.
+fn check(conn: &Connection) -> Result<()> {
+    let result: String = conn.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+    if result != "ok" {
+        return Err(anyhow!("backup is corrupt: {}", result));
+    }
+
+    let has_activities = conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'activities'")?
+        .exists([])?;
+    if !has_activities {
+        return Err(anyhow!("not a hotpot database"));
+    }
+
+    let version = db::schema_version(conn)?;
+    if version > db::SCHEMA_VERSION {
+        return Err(anyhow!(
+            "backup is from a newer version of hotpot (schema version {})",
+            version
+        ));
+    }
+
+    Ok(())
+}
+
+/// Back up the database into `schedule.dir` at the given interval, removing
+/// the oldest backups beyond `schedule.keep`. Runs forever.
+pub async fn backup_periodically(db: Arc<Database>, schedule: Schedule) {
+    // Carry on from the last backup, so that restarting the server doesn't
+    // put off (or bring forward) the next one.
+    let since_last = latest(&schedule.dir)
+        .and_then(|modified| modified.elapsed().ok())
+        .unwrap_or(schedule.interval);
+    tokio::time::sleep(schedule.interval.saturating_sub(since_last)).await;
+
+    loop {
+        let (task_db, task_schedule) = (db.clone(), schedule.clone());
+        let result = tokio::task::spawn_blocking(move || run_scheduled(&task_db, &task_schedule))
+            .await
+            .expect("backup task panicked");
+
+        match result {
+            Ok(path) => tracing::info!(?path, "backed up database"),
+            Err(err) => tracing::error!(?err, "failed to back up database"),
+        }
+
+        tokio::time::sleep(schedule.interval).await;
+    }
+}
+
+fn run_scheduled(db: &Database, schedule: &Schedule) -> Result<PathBuf> {
+    std::fs::create_dir_all(&schedule.dir)?;
+
+    let now = OffsetDateTime::now_utc();
+    let name = format!(
+        "{}{:04}{:02}{:02}-{:02}{:02}{:02}{}",
+        FILE_PREFIX,
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second(),
+        FILE_SUFFIX
+    );
+    let path = schedule.dir.join(name);
+    backup(db, &path)?;
+
+    let backups = list(&schedule.dir)?;
+    let num_old = backups.len().saturating_sub(schedule.keep.max(1));
+    for old in &backups[..num_old] {
+        tracing::info!(path = ?old, "removing old backup");
+        std::fs::remove_file(old)?;
+    }
+
+    Ok(path)
+}
+
+/// Scheduled backups in `dir`, oldest first.
+fn list(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut backups = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_backup = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(FILE_PREFIX) && name.ends_with(FILE_SUFFIX));
+
+        if is_backup {
+            backups.push(path);
+        }
+    }
+
+    // Timestamps in the names sort in order.
+    backups.sort();
+    Ok(backups)
+}
+
+/// When the most recent scheduled backup in `dir` was made.
+fn latest(dir: &Path) -> Option<SystemTime> {
+    let backups = list(dir).ok()?;
+    std::fs::metadata(backups.last()?).ok()?.modified().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.sqlite3");
+        let db = Database::new(&path).unwrap();
+
+        let count = |db: &Database| -> u32 {
+            db.connection()
+                .unwrap()
+                .query_row("SELECT count(*) FROM activities", [], |row| row.get(0))
+                .unwrap()
+        };
+        let execute = |db: &Database, sql: &str| {
+            db.connection().unwrap().execute_batch(sql).unwrap();
+        };
+        execute(&db, "INSERT INTO activities (id, file) VALUES (1, 'a.gpx')");
+
+        let backup_path = dir.path().join("backup.sqlite3");
+        backup(&db, &backup_path).unwrap();
+        execute(&db, "INSERT INTO activities (id, file) VALUES (2, 'b.gpx')");
+        assert_eq!(count(&Database::open(&backup_path).unwrap()), 1);
+
+        restore(&backup_path, &path).unwrap();
+        assert_eq!(count(&db), 1);
+
+        assert!(restore(&dir.path().join("missing.sqlite3"), &path).is_err());
+        std::fs::write(dir.path().join("junk.sqlite3"), b"").unwrap();
+        assert!(restore(&dir.path().join("junk.sqlite3"), &path).is_err());
+
+        // Only the newest scheduled backups are kept.
+        let schedule = Schedule {
+            dir: dir.path().join("backups"),
+            interval: Duration::from_secs(60),
+            keep: 2,
+        };
+        std::fs::create_dir(&schedule.dir).unwrap();
+        for name in [
+            "hotpot-20200101-000000.sqlite3",
+            "hotpot-20200102-000000.sqlite3",
+        ] {
+            std::fs::write(schedule.dir.join(name), b"").unwrap();
+        }
+        std::fs::write(schedule.dir.join("other.sqlite3"), b"").unwrap();
+
+        let latest = run_scheduled(&db, &schedule).unwrap();
+        let backups = list(&schedule.dir).unwrap();
+        assert_eq!(
+            backups,
+            vec![schedule.dir.join("hotpot-20200102-000000.sqlite3"), latest]
+        );
+        assert!(schedule.dir.join("other.sqlite3").exists());
+    }
+}
@@ -0,0 +1,149 @@
+//! Fetch raster tiles from an XYZ tile server, to use as a background for
+//! rendered heatmaps.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use anyhow::{anyhow, Result};
+use image::imageops::{self, FilterType};
+use image::{Rgba, RgbaImage};
+use reqwest::StatusCode;
+use tokio::runtime::Runtime;
+use tokio::task::JoinSet;
+
+use crate::raster;
+use crate::text;
+use crate::tile::{Tile, TileBounds, WebMercatorViewport};
+
+pub const DEFAULT_ATTRIBUTION: &str = "© OpenStreetMap contributors";
+
+/// Most public tile servers require a descriptive user agent.
+const USER_AGENT: &str = concat!("hotpot/", env!("CARGO_PKG_VERSION"));
+
+/// Substitute the tile coordinates into a URL template such as
+/// `https://tile.openstreetmap.org/{z}/{x}/{y}.png`.
+fn tile_url(template: &str, tile: &Tile) -> String {
+    template
+        .replace("{s}", "a")
+        .replace("{z}", &tile.z.to_string())
+        .replace("{x}", &tile.x.to_string())
+        .replace("{y}", &tile.y.to_string())
+}
+
+async fn fetch_tile(client: &reqwest::Client, url: &str) -> Result<Option<RgbaImage>> {
+    let res = client.get(url).send().await?;
+
+    // Tile servers commonly 404 outside of their coverage area.
+    if res.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    let bytes = res.error_for_status()?.bytes().await?;
+    let image = image::load_from_memory(&bytes)?.into_rgba8();
+
+    // Normalize "retina" tile servers to our grid.
+    if image.dimensions() != (256, 256) {
+        return Ok(Some(imageops::resize(
+            &image,
+            256,
+            256,
+            FilterType::Triangle,
+        )));
+    }
+
+    Ok(Some(image))
+}
+
+/// Render `viewport` from the tile server at `url_template`, choosing the
+/// zoom level the same way as [`raster::render_view`] so the two images line
+/// up.
+pub fn render(
+    url_template: &str,
+    viewport: &WebMercatorViewport,
+    width: u32,
+    height: u32,
+    zoom_range: RangeInclusive<u32>,
+) -> Result<RgbaImage> {
+    let bounds = TileBounds::from_viewport(viewport, width, height, zoom_range.clone());
+
+    let rt = Runtime::new()?;
+    let mut tiles = rt.block_on(async {
+        let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+        let mut requests = JoinSet::new();
+
+        for x in bounds.xmin..=bounds.xmax {
+            for y in bounds.ymin..=bounds.ymax {
+                let tile = Tile::new(x, y, bounds.z);
+                let url = tile_url(url_template, &tile);
+                let client = client.clone();
+
+                requests.spawn(async move {
+                    let image = fetch_tile(&client, &url)
+                        .await
+                        .map_err(|err| anyhow!("failed to fetch {}: {}", url, err))?;
+                    Ok::<_, anyhow::Error>((tile, image))
+                });
+            }
+        }
+
+        let mut tiles = HashMap::new();
+        while let Some(res) = requests.join_next().await {
+            let (tile, image) = res??;
+            tiles.insert(tile, image);
+        }
+
+        Ok::<_, anyhow::Error>(tiles)
+    })?;
+
+    raster::render_mosaic(viewport, width, height, zoom_range, |tile| {
+        Ok(tiles.remove(&tile).flatten())
+    })
+}
+
+/// Draw `heatmap` over `basemap`, with its alpha scaled by `opacity`.
+pub fn composite(basemap: &mut RgbaImage, heatmap: &RgbaImage, opacity: f32) {
+    let mut heatmap = heatmap.clone();
+    for pixel in heatmap.pixels_mut() {
+        pixel[3] = (pixel[3] as f32 * opacity.clamp(0.0, 1.0)).round() as u8;
+    }
+
+    imageops::overlay(basemap, &heatmap, 0, 0);
+}
+
+/// Stamp `text` in the bottom right corner of `image`, on a light background
+/// so it stays legible over any map.
+pub fn draw_attribution(image: &mut RgbaImage, text: &str) {
+    let scale = (image.width() / 640).max(1);
+    let padding = 2 * scale;
+
+    let (text_w, text_h) = text::measure(text, scale);
+    let box_w = (text_w + 2 * padding).min(image.width());
+    let box_h = (text_h + 2 * padding).min(image.height());
+    let (box_x, box_y) = (image.width() - box_w, image.height() - box_h);
+
+    let background = RgbaImage::from_pixel(box_w, box_h, Rgba([255, 255, 255, 192]));
+    imageops::overlay(image, &background, box_x as i64, box_y as i64);
+
+    text::draw(
+        image,
+        box_x + padding,
+        box_y + padding,
+        text,
+        scale,
+        Rgba([0x33, 0x33, 0x33, 0xff]),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tile_url() {
+        let tile = Tile::new(1, 2, 3);
+        assert_eq!(
+            tile_url("https://{s}.tile.example.com/{z}/{x}/{y}.png", &tile),
+            "https://a.tile.example.com/3/1/2.png"
+        );
+    }
+}
@@ -1,9 +1,74 @@
 use std::fmt::Formatter;
 use std::marker::PhantomData;
 
+use anyhow::{anyhow, Result};
 use serde::de::Error;
 use serde::Deserializer;
-use time::Date;
+use time::format_description::well_known::Iso8601;
+use time::{Date, Duration, Month, OffsetDateTime};
+
+/// Parse a date given as `YYYY-MM-DD`, or relative to today (in UTC) as a
+/// number of days, weeks, months or years ago, e.g. `-90d` or `-1y`.
+pub fn parse_date(s: &str) -> Result<Date> {
+    parse_date_from(s, OffsetDateTime::now_utc().date())
+}
+
+fn parse_date_from(s: &str, today: Date) -> Result<Date> {
+    let s = s.trim();
+    let Some(relative) = s.strip_prefix('-') else {
+        return Date::parse(s, &Iso8601::DATE)
+            .map_err(|_| anyhow!("expected a date as YYYY-MM-DD, or relative like -90d"));
+    };
+
+    let unit_start = relative.char_indices().last().map_or(0, |(i, _)| i);
+    let (n, unit) = relative.split_at(unit_start);
+    let n: i32 = n
+        .parse()
+        .ok()
+        .filter(|n| *n >= 0)
+        .ok_or_else(|| anyhow!("expected a relative date like -90d, got {:?}", s))?;
+
+    let date = match unit {
+        "d" => today.checked_sub(Duration::days(n.into())),
+        "w" => today.checked_sub(Duration::weeks(n.into())),
+        "m" => months_before(today, n),
+        "y" => months_before(today, n.saturating_mul(12)),
+        _ => return Err(anyhow!("unknown unit {:?}, expected d, w, m or y", unit)),
+    };
+
+    date.ok_or_else(|| anyhow!("date out of range: {}", s))
+}
+
+/// Whether `s` contains anything that looks like a relative date (e.g.
+/// `-90d`), meaning its meaning changes from one day to the next.
+pub fn contains_relative_date(s: &str) -> bool {
+    s.match_indices('-').any(|(i, _)| {
+        let rest = &s[i + 1..];
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let mut after = rest[digits..].chars();
+
+        !s[..i]
+            .chars()
+            .next_back()
+            .is_some_and(char::is_alphanumeric)
+            && digits > 0
+            && matches!(after.next(), Some('d' | 'w' | 'm' | 'y'))
+            && !after.next().is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// Same day of the month `n` months earlier, or the last day of that month
+/// if it's shorter.
+fn months_before(date: Date, n: i32) -> Option<Date> {
+    let months = (date.year() * 12 + date.month() as i32 - 1).checked_sub(n)?;
+    let year = months.div_euclid(12);
+    let month = Month::try_from((months.rem_euclid(12) + 1) as u8).ok()?;
+    let day = date.day();
+
+    (day.min(28)..=day)
+        .rev()
+        .find_map(|day| Date::from_calendar_date(year, month, day).ok())
+}
 
 struct Visitor<T>(PhantomData<T>);
 
@@ -22,19 +87,14 @@ impl<'de> serde::de::Visitor<'de> for Visitor<Option<Date>> {
     type Value = Option<Date>;
 
     fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
-        formatter.write_str("a date formatted in YYYY-MM-DD")
+        formatter.write_str("a date formatted in YYYY-MM-DD, or relative like -90d")
     }
 
     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
     where
         E: Error,
     {
-        Date::parse(
-            v,
-            &time::format_description::well_known::iso8601::Iso8601::DATE,
-        )
-        .map_err(Error::custom)
-        .map(Some)
+        parse_date(v).map_err(Error::custom).map(Some)
     }
 
     fn visit_none<E>(self) -> Result<Self::Value, E>
@@ -58,3 +118,45 @@ impl<'de> serde::de::Visitor<'de> for Visitor<Option<Date>> {
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u8, day: u8) -> Date {
+        Date::from_calendar_date(year, Month::try_from(month).unwrap(), day).unwrap()
+    }
+
+    #[test]
+    fn test_parse_date() {
+        let today = date(2024, 3, 31);
+        assert_eq!(
+            parse_date_from("2023-06-01", today).unwrap(),
+            date(2023, 6, 1)
+        );
+        assert_eq!(parse_date_from("-90d", today).unwrap(), date(2024, 1, 1));
+        assert_eq!(parse_date_from("-2w", today).unwrap(), date(2024, 3, 17));
+        assert_eq!(parse_date_from("-1m", today).unwrap(), date(2024, 2, 29));
+        assert_eq!(parse_date_from("-15m", today).unwrap(), date(2022, 12, 31));
+        assert_eq!(parse_date_from("-1y", today).unwrap(), date(2023, 3, 31));
+
+        assert!(parse_date_from("-90", today).is_err());
+        assert!(parse_date_from("-d", today).is_err());
+        assert!(parse_date_from("--1d", today).is_err());
+        assert!(parse_date_from("-3x", today).is_err());
+        assert!(parse_date_from("yesterday", today).is_err());
+    }
+
+    #[test]
+    fn test_contains_relative_date() {
+        assert!(contains_relative_date("-7d"));
+        assert!(contains_relative_date(r#"{"start_time": {">": "-90d"}}"#));
+        assert!(contains_relative_date("start_time >= -1y"));
+
+        assert!(!contains_relative_date("2024-03-01"));
+        assert!(!contains_relative_date("-90"));
+        assert!(!contains_relative_date("elevation > -10"));
+        assert!(!contains_relative_date("title=half-2day"));
+        assert!(!contains_relative_date("-3days"));
+    }
+}
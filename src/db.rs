@@ -1,19 +1,29 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Cursor;
-use std::path::Path;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Result};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use geo::{CoordNum, LineString};
+use byteorder::{LittleEndian, ReadBytesExt};
+use geo::{CoordNum, LineString, MultiLineString};
 use geo_types::Coord;
 use num_traits::AsPrimitive;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params, ToSql};
+use rusqlite::types::ToSqlOutput;
+use rusqlite::{params, OpenFlags, ToSql};
 use serde::{Deserialize, Deserializer};
 use time::{Date, OffsetDateTime};
 
+use crate::date;
+use crate::hooks::Hooks;
+use crate::mask;
+use crate::mask::PrivacyMask;
+use crate::spatial_index;
+use crate::tile::{TileBounds, WebMercatorViewport};
+
 const SCHEMA: &str = "\
 CREATE TABLE IF NOT EXISTS config (
       key   TEXT NOT NULL PRIMARY KEY
@@ -42,16 +52,46 @@ CREATE TABLE IF NOT EXISTS activity_tiles (
 CREATE INDEX IF NOT EXISTS activity_tiles_activity_id ON activity_tiles (activity_id);
 CREATE INDEX IF NOT EXISTS activity_tiles_zxy ON activity_tiles (z, x, y);
 
+CREATE TABLE IF NOT EXISTS activity_tracks (
+      activity_id INTEGER PRIMARY KEY
+    -- JSON array of encoded polylines (lng/lat, precision 5), one per segment
+    , polylines   TEXT    NOT NULL
+);
+
 CREATE TABLE IF NOT EXISTS strava_tokens (
       athlete_id    INTEGER PRIMARY KEY
     , access_token  TEXT    NOT NULL
     , refresh_token TEXT    NOT NULL
     , expires_at    INTEGER NOT NULL
 );
+
+CREATE TABLE IF NOT EXISTS garmin_tokens (
+      user_id       TEXT    PRIMARY KEY
+    , access_token  TEXT    NOT NULL
+    , token_secret  TEXT    NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS privacy_masks (
+      id            INTEGER PRIMARY KEY
+    , name          TEXT    NOT NULL UNIQUE
+    -- JSON encoded `mask::MaskGeometry`
+    , geometry      TEXT    NOT NULL
+);
+
+-- Temporary OAuth 1.0a credentials, only needed during the auth flow.
+CREATE TABLE IF NOT EXISTS garmin_request_tokens (
+      token         TEXT    PRIMARY KEY
+    , secret        TEXT    NOT NULL
+);
 ";
 
+/// How long connections to a read-only database are kept open. Reopening
+/// them picks up a database file which has been replaced by a newer copy.
+const READ_ONLY_MAX_LIFETIME: Duration = Duration::from_secs(30);
+
 pub struct Database {
     pool: r2d2::Pool<SqliteConnectionManager>,
+    path: PathBuf,
     pub config: Config,
 }
 
@@ -76,7 +116,55 @@ impl Database {
         let config = Config::load(&mut conn)?;
         config.save(&mut conn)?;
 
-        Ok(Database { pool, config })
+        Ok(Database {
+            pool,
+            path: path.to_path_buf(),
+            config,
+        })
+    }
+
+    /// Open an existing database without ever writing to it, e.g. a replica
+    /// kept up to date by Litestream or Syncthing.
+    ///
+    /// SQLite treats the file as immutable, so it can be on a read-only file
+    /// system, but changes must replace it as a whole rather than be written
+    /// in place. Connections are reopened periodically to pick them up.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            anyhow::bail!("database does not exist: {}", path.display());
+        }
+
+        let manager = SqliteConnectionManager::file(immutable_uri(path))
+            .with_flags(
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )
+            .with_init(|conn| conn.pragma_update(None, "query_only", true));
+
+        let pool = r2d2::Pool::builder()
+            .max_lifetime(Some(READ_ONLY_MAX_LIFETIME))
+            .build(manager)?;
+        let mut conn = pool.get()?;
+
+        // Migrations can't be applied, and queries assume the latest schema.
+        let version = schema_version(&conn)?;
+        if version != MIGRATIONS.len() {
+            anyhow::bail!(
+                "database schema version {} doesn't match this version of hotpot ({}), \
+                open the database with the same version elsewhere first",
+                version,
+                MIGRATIONS.len()
+            );
+        }
+
+        let config = Config::load(&mut conn)?;
+
+        Ok(Database {
+            pool,
+            path: path.to_path_buf(),
+            config,
+        })
     }
 
     /// Open an existing database, fail if it doesn't exist
@@ -88,18 +176,105 @@ impl Database {
         Self::new(path)
     }
 
+    /// Persist changes to `config`.
+    pub fn save_config(&self) -> Result<()> {
+        let mut conn = self.connection()?;
+        self.config.save(&mut conn)
+    }
+
+    /// Read the settings again, e.g. after they were changed by another
+    /// process. The returned database shares connections with this one.
+    pub fn reload(&self) -> Result<Self> {
+        let mut conn = self.connection()?;
+        let config = Config::load(&mut conn)?;
+
+        Ok(Database {
+            pool: self.pool.clone(),
+            path: self.path.clone(),
+            config,
+        })
+    }
+
     pub fn reset_activities(&self) -> Result<()> {
         let conn = self.connection()?;
 
         let num_activities = conn.execute("DELETE FROM activities", [])?;
         let num_tiles = conn.execute("DELETE FROM activity_tiles", [])?;
-        conn.execute_batch("VACUUM")?;
+        conn.execute("DELETE FROM activity_tracks", [])?;
+        conn.execute("DELETE FROM linked_files", [])?;
+        self.vacuum()?;
 
         tracing::info!(num_activities, num_tiles, "Reset database");
 
         Ok(())
     }
 
+    /// Run SQLite's integrity check, returning any problems found.
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let conn = self.connection()?;
+        let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+        let results = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(results.into_iter().filter(|r| r != "ok").collect())
+    }
+
+    /// Refresh the statistics used by the query planner.
+    pub fn analyze(&self) -> Result<()> {
+        self.connection()?.execute_batch("ANALYZE")?;
+        Ok(())
+    }
+
+    /// Rewrite tiles stored in the older, larger line format (see
+    /// [`encode_line`]), returning how many were changed.
+    pub fn reencode_tiles(&self) -> Result<usize> {
+        const BATCH_SIZE: usize = 10_000;
+
+        let mut conn = self.connection()?;
+        let mut num_tiles = 0;
+        let mut last_id = 0;
+        loop {
+            let tx = conn.transaction()?;
+            let rows = tx
+                .prepare(
+                    "\
+                    SELECT id, coords FROM activity_tiles \
+                    WHERE id > ? AND length(coords) % 4 = 0 \
+                    ORDER BY id \
+                    LIMIT ?",
+                )?
+                .query_map(params![last_id, BATCH_SIZE], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let Some(&(id, _)) = rows.last() else {
+                break;
+            };
+            last_id = id;
+
+            let mut update = tx.prepare("UPDATE activity_tiles SET coords = ? WHERE id = ?")?;
+            for (id, bytes) in &rows {
+                let line = LineString::from(decode_line(bytes)?);
+                update.execute(params![encode_line(&line)?, id])?;
+            }
+            drop(update);
+
+            tx.commit()?;
+            num_tiles += rows.len();
+        }
+
+        Ok(num_tiles)
+    }
+
+    /// Rebuild the database file, reclaiming space left by deleted data.
+    pub fn vacuum(&self) -> Result<()> {
+        tracing::info!("vacuuming database");
+        self.connection()?.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
     pub fn connection(&self) -> Result<r2d2::PooledConnection<SqliteConnectionManager>> {
         let conn = self.pool.get()?;
         Ok(conn)
@@ -108,21 +283,427 @@ impl Database {
     pub fn shared_pool(&self) -> r2d2::Pool<SqliteConnectionManager> {
         self.pool.clone()
     }
+
+    /// Location of the database file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// When the database file (or its write-ahead log) was last written to.
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        let mut wal_path = self.path.clone().into_os_string();
+        wal_path.push("-wal");
+
+        [self.path.as_os_str(), &wal_path]
+            .into_iter()
+            .filter_map(|path| {
+                std::fs::metadata(path)
+                    .and_then(|meta| meta.modified())
+                    .ok()
+            })
+            .max()
+    }
+}
+
+/// SQLite URI opening `path` as an immutable database.
+fn immutable_uri(path: &Path) -> String {
+    let mut uri = String::from("file:");
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '%' | '?' | '#' => uri.push_str(&format!("%{:02X}", c as u32)),
+            c => uri.push(c),
+        }
+    }
+    uri.push_str("?immutable=1");
+    uri
+}
+
+/// A change to the schema of existing databases.
+struct Migration {
+    description: &'static str,
+    apply: fn(&rusqlite::Transaction) -> Result<()>,
+}
+
+/// Schema version of databases with all migrations applied.
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Changes made since the initial `SCHEMA`, in order. The `user_version` of a
+/// database is the number of migrations which have been applied to it.
+///
+/// Never remove or reorder these, only append.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "add activities.content_hash, for detecting duplicate imports",
+        apply: |tx| {
+            // SHA-1 of the (uncompressed) source file, if imported from one.
+            // Databases created before migrations existed may already have it.
+            add_column(tx, "activities", "content_hash", "TEXT")?;
+            tx.execute_batch(
+                "CREATE INDEX IF NOT EXISTS activities_content_hash ON activities (content_hash);",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add jobs table, for work deferred to the background",
+        apply: |tx| {
+            tx.execute_batch(
+                "\
+                CREATE TABLE IF NOT EXISTS jobs ( \
+                    id         INTEGER PRIMARY KEY, \
+                    kind       TEXT NOT NULL, \
+                    payload    TEXT NOT NULL, \
+                    run_after  INTEGER NOT NULL, \
+                    created_at INTEGER NOT NULL \
+                ); \
+                CREATE INDEX IF NOT EXISTS jobs_run_after ON jobs (kind, run_after);",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add jobs.status, attempts and last_error, for retrying failed jobs",
+        apply: |tx| {
+            // `pending`, or `failed` after running out of attempts.
+            add_column(tx, "jobs", "status", "TEXT NOT NULL DEFAULT 'pending'")?;
+            add_column(tx, "jobs", "attempts", "INTEGER NOT NULL DEFAULT 0")?;
+            add_column(tx, "jobs", "last_error", "TEXT")?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add activities.athlete_id, for sharing an instance between athletes",
+        apply: |tx| {
+            add_column(tx, "activities", "athlete_id", "INTEGER")?;
+            tx.execute_batch(
+                "\
+                CREATE INDEX IF NOT EXISTS activities_athlete_id ON activities (athlete_id); \
+                UPDATE activities \
+                SET athlete_id = properties ->> '$.athlete.id' \
+                WHERE file LIKE 'strava:%';",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add users table and activities.user_id, for hosting multiple users",
+        apply: |tx| {
+            tx.execute_batch(
+                "\
+                CREATE TABLE IF NOT EXISTS users ( \
+                    id                INTEGER PRIMARY KEY, \
+                    name              TEXT NOT NULL UNIQUE, \
+                    token_hash        TEXT NOT NULL UNIQUE, \
+                    strava_athlete_id INTEGER, \
+                    created_at        INTEGER NOT NULL \
+                );",
+            )?;
+            add_column(tx, "activities", "user_id", "INTEGER")?;
+            tx.execute_batch(
+                "CREATE INDEX IF NOT EXISTS activities_user_id ON activities (user_id);",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add tokens table, for scoped API tokens",
+        apply: |tx| {
+            tx.execute_batch(
+                "\
+                CREATE TABLE IF NOT EXISTS tokens ( \
+                    id         INTEGER PRIMARY KEY, \
+                    name       TEXT NOT NULL UNIQUE, \
+                    scope      TEXT NOT NULL, \
+                    token_hash TEXT NOT NULL UNIQUE, \
+                    created_at INTEGER NOT NULL \
+                );",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add komoot_tokens table, for syncing tours from Komoot",
+        apply: |tx| {
+            tx.execute_batch(
+                "\
+                CREATE TABLE IF NOT EXISTS komoot_tokens ( \
+                    user_id TEXT PRIMARY KEY, \
+                    token   TEXT NOT NULL \
+                );",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add live_points table, for collecting live location updates",
+        apply: |tx| {
+            tx.execute_batch(
+                "\
+                CREATE TABLE IF NOT EXISTS live_points ( \
+                    id      INTEGER PRIMARY KEY, \
+                    device  TEXT NOT NULL, \
+                    user_id INTEGER, \
+                    lng     REAL NOT NULL, \
+                    lat     REAL NOT NULL, \
+                    time    INTEGER NOT NULL \
+                ); \
+                CREATE INDEX IF NOT EXISTS live_points_device ON live_points (device, time);",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add remote_files table, for revalidating activities imported from URLs",
+        apply: |tx| {
+            tx.execute_batch(
+                "\
+                CREATE TABLE IF NOT EXISTS remote_files ( \
+                    url           TEXT PRIMARY KEY, \
+                    etag          TEXT, \
+                    last_modified TEXT \
+                );",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add geocode_cache table, for reverse geocoding activity locations",
+        apply: |tx| {
+            // Coordinates are rounded, see `geocode::cache_key`.
+            tx.execute_batch(
+                "\
+                CREATE TABLE IF NOT EXISTS geocode_cache ( \
+                    lng     REAL NOT NULL, \
+                    lat     REAL NOT NULL, \
+                    city    TEXT, \
+                    country TEXT, \
+                    PRIMARY KEY (lng, lat) \
+                );",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add views table, for saved tile parameters",
+        apply: |tx| {
+            tx.execute_batch(
+                "\
+                CREATE TABLE IF NOT EXISTS views ( \
+                    name     TEXT PRIMARY KEY, \
+                    filter   TEXT, \
+                    color    TEXT, \
+                    gradient TEXT, \
+                    before   TEXT, \
+                    after    TEXT \
+                );",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add activity_search table, for full-text search of titles and properties",
+        apply: |tx| {
+            // Kept up to date by triggers, so every way of adding or editing
+            // activities is covered. Only text properties are indexed.
+            tx.execute_batch(
+                "\
+                CREATE VIRTUAL TABLE IF NOT EXISTS activity_search USING fts5( \
+                    title, \
+                    properties, \
+                    tokenize = 'unicode61 remove_diacritics 2' \
+                ); \
+                \
+                CREATE TRIGGER IF NOT EXISTS activity_search_insert \
+                AFTER INSERT ON activities BEGIN \
+                    INSERT INTO activity_search (rowid, title, properties) \
+                    SELECT NEW.id, NEW.title, group_concat(value, ' ') \
+                    FROM json_each(NEW.properties) WHERE type = 'text'; \
+                END; \
+                \
+                CREATE TRIGGER IF NOT EXISTS activity_search_update \
+                AFTER UPDATE OF title, properties ON activities BEGIN \
+                    DELETE FROM activity_search WHERE rowid = OLD.id; \
+                    INSERT INTO activity_search (rowid, title, properties) \
+                    SELECT NEW.id, NEW.title, group_concat(value, ' ') \
+                    FROM json_each(NEW.properties) WHERE type = 'text'; \
+                END; \
+                \
+                CREATE TRIGGER IF NOT EXISTS activity_search_delete \
+                AFTER DELETE ON activities BEGIN \
+                    DELETE FROM activity_search WHERE rowid = OLD.id; \
+                END; \
+                \
+                INSERT INTO activity_search (rowid, title, properties) \
+                SELECT id, title, ( \
+                    SELECT group_concat(value, ' ') \
+                    FROM json_each(activities.properties) WHERE type = 'text' \
+                ) \
+                FROM activities;",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add aggregate_tiles table, for precomputed low zoom tiles",
+        apply: |tx| {
+            // Tiles covering a changed stored tile are marked as stale (with
+            // NULL counts) by triggers, and redrawn by `aggregate::update`.
+            // Only stored tiles which low zoom tiles are drawn from are
+            // checked, i.e. those up to the first zoom level >= 6
+            // (`aggregate::MAX_ZOOM`). Counts are kept for each tile size in
+            // `aggregate::WIDTHS`.
+            tx.execute_batch(
+                "\
+                CREATE TABLE IF NOT EXISTS aggregate_tiles ( \
+                    z       INTEGER NOT NULL, \
+                    x       INTEGER NOT NULL, \
+                    y       INTEGER NOT NULL, \
+                    width   INTEGER NOT NULL, \
+                    version INTEGER NOT NULL DEFAULT 0, \
+                    counts  BLOB, \
+                    PRIMARY KEY (z, x, y, width) \
+                ); \
+                \
+                CREATE TRIGGER IF NOT EXISTS aggregate_tiles_insert \
+                AFTER INSERT ON activity_tiles \
+                WHEN NEW.z <= ( \
+                    SELECT coalesce(min(zoom.value) FILTER (WHERE zoom.value >= 6), max(zoom.value)) \
+                    FROM config, json_each(config.value) AS zoom \
+                    WHERE config.key = 'zoom_levels' \
+                ) BEGIN \
+                    INSERT INTO aggregate_tiles (z, x, y, width) \
+                    SELECT zoom.value, NEW.x >> (NEW.z - zoom.value), \
+                        NEW.y >> (NEW.z - zoom.value), width.value \
+                    FROM json_each('[0, 1, 2, 3, 4, 5, 6]') AS zoom, \
+                        json_each('[256, 512]') AS width \
+                    WHERE zoom.value <= NEW.z \
+                    ON CONFLICT DO UPDATE SET version = version + 1, counts = NULL; \
+                END; \
+                \
+                CREATE TRIGGER IF NOT EXISTS aggregate_tiles_delete \
+                AFTER DELETE ON activity_tiles \
+                WHEN OLD.z <= ( \
+                    SELECT coalesce(min(zoom.value) FILTER (WHERE zoom.value >= 6), max(zoom.value)) \
+                    FROM config, json_each(config.value) AS zoom \
+                    WHERE config.key = 'zoom_levels' \
+                ) BEGIN \
+                    UPDATE aggregate_tiles SET version = version + 1, counts = NULL \
+                    WHERE (z, x, y) IN ( \
+                        SELECT value, OLD.x >> (OLD.z - value), OLD.y >> (OLD.z - value) \
+                        FROM json_each('[0, 1, 2, 3, 4, 5, 6]') WHERE value <= OLD.z \
+                    ); \
+                END;",
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        description: "add linked_files table, for duplicates merged into another activity",
+        apply: |tx| {
+            // Files which weren't stored as an activity of their own, since
+            // they duplicate the one stored as `activity_file`. `properties`
+            // are those last merged in from the file.
+            tx.execute_batch(
+                "\
+                CREATE TABLE IF NOT EXISTS linked_files ( \
+                    file          TEXT NOT NULL PRIMARY KEY, \
+                    activity_file TEXT NOT NULL, \
+                    properties    TEXT NOT NULL DEFAULT '{}' \
+                ); \
+                CREATE INDEX IF NOT EXISTS linked_files_activity_file \
+                    ON linked_files (activity_file);",
+            )?;
+            Ok(())
+        },
+    },
+];
+
+pub fn schema_version(conn: &rusqlite::Connection) -> Result<usize> {
+    Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
 }
 
-// NOTE: we can use PRAGMA.user_version to track schema versions
-// https://www.sqlite.org/pragma.html#pragma_user_version
 fn apply_schema(conn: &mut rusqlite::Connection) -> Result<()> {
     let tx = conn.transaction()?;
     tx.execute_batch(SCHEMA)?;
+
+    let version = schema_version(&tx)?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "database schema version {} is newer than supported ({}), please upgrade hotpot",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        tracing::info!(
+            version = i + 1,
+            description = migration.description,
+            "applying migration"
+        );
+        (migration.apply)(&tx)?;
+    }
+
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
     tx.commit()?;
 
     Ok(())
 }
 
+/// Descriptions of the migrations which would be applied when opening the
+/// database at `path`, without changing it.
+pub fn pending_migrations(path: &Path) -> Result<Vec<&'static str>> {
+    if !path.exists() {
+        anyhow::bail!("database does not exist: {}", path.display());
+    }
+
+    let conn = rusqlite::Connection::open(path)?;
+    let version = schema_version(&conn)?;
+
+    Ok(MIGRATIONS
+        .iter()
+        .skip(version)
+        .map(|m| m.description)
+        .collect())
+}
+
+fn add_column(conn: &rusqlite::Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info(?) WHERE name = ?")?
+        .exists([table, column])?;
+
+    if !exists {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, decl
+        ))?;
+    }
+
+    Ok(())
+}
+
 const DEFAULT_TILE_EXTENT: u32 = 2048;
 const DEFAULT_ZOOM_LEVELS: [u8; 5] = [2, 6, 10, 14, 16];
 const DEFAULT_TRIM_DIST: f64 = 200.0;
+const GRADIENT_KEY_PREFIX: &str = "gradient:";
+/// Prefix of keys for Strava API credentials, which are read directly by
+/// `strava::StravaAuth` rather than being part of [`Config`].
+pub const STRAVA_KEY_PREFIX: &str = "strava:";
+/// Key of the secret for signing render links, read directly by
+/// `render_link::RenderLinks`.
+pub const RENDER_SECRET_KEY: &str = "render_secret";
+
+/// Settings which can be changed with `hotpot config set`, and whether
+/// changing them affects how activities are stored (so already imported ones
+/// need to be imported again).
+pub const SETTINGS: &[(&str, bool)] = &[
+    ("zoom_levels", true),
+    ("tile_extent", true),
+    ("trim_dist", true),
+    ("default_gradient", false),
+    ("include_virtual", true),
+    ("hooks_file", true),
+];
 
 pub struct Config {
     /// Zoom levels that we store activity tiles for.
@@ -131,6 +712,25 @@ pub struct Config {
     pub tile_extent: u32,
     /// Distance to trim start/end of activities, in meters.
     pub trim_dist: f64,
+    /// Areas to hide from activities (stored separately from the other config).
+    pub masks: Vec<PrivacyMask>,
+    /// Gradient used by the server for tiles without a `color` or `gradient`
+    /// parameter, in any format accepted by `raster::Gradient`.
+    pub default_gradient: Option<String>,
+    /// User defined gradients, selectable by name like the built in ones.
+    /// Stored as `gradient:{name}` keys.
+    pub gradients: BTreeMap<String, String>,
+    /// Store virtual activities (e.g. Zwift rides), tagged as `virtual`.
+    /// They're skipped by default, since their tracks aren't real places.
+    pub include_virtual: bool,
+    /// TOML file listing commands to run over each activity before it's
+    /// stored, see [`hooks`].
+    pub hooks_file: Option<PathBuf>,
+    /// Hooks loaded from `hooks_file`.
+    pub hooks: Hooks,
+    /// Whether `activity_tiles` has an R*Tree index, see [`spatial_index`].
+    /// Not a setting, it's detected when opening the database.
+    pub spatial_index: bool,
 }
 
 impl Config {
@@ -145,13 +745,21 @@ impl Config {
             let value: String = row.get_unwrap(1);
 
             match key.as_str() {
-                "zoom_levels" => cfg.zoom_levels = serde_json::from_str(&value)?,
-                "tile_extent" => cfg.tile_extent = value.parse()?,
-                "trim_dist" => cfg.trim_dist = value.parse()?,
-                key => tracing::warn!("Ignoring unknown config key: {}", key),
+                key if SETTINGS.iter().any(|(k, _)| *k == key) => cfg.set(key, &value)?,
+                key if key.starts_with(STRAVA_KEY_PREFIX) => {}
+                RENDER_SECRET_KEY => {}
+                key => match key.strip_prefix(GRADIENT_KEY_PREFIX) {
+                    Some(name) => {
+                        cfg.gradients.insert(name.to_string(), value);
+                    }
+                    None => tracing::warn!("Ignoring unknown config key: {}", key),
+                },
             }
         }
 
+        cfg.masks = mask::load(conn)?;
+        cfg.spatial_index = spatial_index::exists(conn)?;
+
         Ok(cfg)
     }
 
@@ -166,6 +774,99 @@ impl Config {
         stmt.execute(params!["zoom_levels", &zoom_levels])?;
         stmt.execute(params!["tile_extent", &self.tile_extent])?;
         stmt.execute(params!["trim_dist", &self.trim_dist])?;
+        stmt.execute(params![
+            "include_virtual",
+            &self.include_virtual.to_string()
+        ])?;
+        match &self.hooks_file {
+            Some(path) => stmt.execute(params!["hooks_file", &path.to_string_lossy()])?,
+            None => conn.execute("DELETE FROM config WHERE key = 'hooks_file'", [])?,
+        };
+        if let Some(gradient) = &self.default_gradient {
+            stmt.execute(params!["default_gradient", gradient])?;
+        }
+
+        // Replace all named gradients, so removed ones don't linger.
+        conn.execute(
+            "DELETE FROM config WHERE key LIKE ?",
+            [format!("{}%", GRADIENT_KEY_PREFIX)],
+        )?;
+        for (name, gradient) in &self.gradients {
+            stmt.execute(params![
+                format!("{}{}", GRADIENT_KEY_PREFIX, name),
+                gradient
+            ])?;
+        }
+
+        Ok(())
+    }
+
+    /// Current value of a setting, in the same format accepted by
+    /// [`Config::set`]. Returns `None` for unset optional settings.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(match key {
+            "zoom_levels" => Some(serde_json::to_string(&self.zoom_levels)?),
+            "tile_extent" => Some(self.tile_extent.to_string()),
+            "trim_dist" => Some(self.trim_dist.to_string()),
+            "default_gradient" => self.default_gradient.clone(),
+            "include_virtual" => Some(self.include_virtual.to_string()),
+            "hooks_file" => self
+                .hooks_file
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+            key => return Err(anyhow!("unknown config key: {}", key)),
+        })
+    }
+
+    /// Parse and update a setting. Doesn't persist the change.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "zoom_levels" => {
+                // Accept both `[2,6,10]` (as stored) and `2,6,10`
+                let mut zooms = value
+                    .trim_matches(|c| c == '[' || c == ']')
+                    .split(',')
+                    .map(|z| z.trim().parse::<u8>())
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                zooms.sort();
+                zooms.dedup();
+                if zooms.is_empty() {
+                    return Err(anyhow!("zoom_levels must not be empty"));
+                }
+
+                self.zoom_levels = zooms;
+            }
+            "tile_extent" => {
+                let extent: u32 = value.parse()?;
+                if extent == 0 || extent > u16::MAX as u32 {
+                    return Err(anyhow!("tile_extent must be in [1, {}]", u16::MAX));
+                }
+
+                self.tile_extent = extent;
+            }
+            "trim_dist" => {
+                let dist: f64 = value.parse()?;
+                if dist.is_nan() || dist < 0.0 {
+                    return Err(anyhow!("trim_dist must be >= 0"));
+                }
+
+                self.trim_dist = dist;
+            }
+            "default_gradient" => self.default_gradient = Some(value.to_string()),
+            "include_virtual" => self.include_virtual = value.parse()?,
+            // An empty path turns hooks off again.
+            "hooks_file" if value.is_empty() => {
+                self.hooks_file = None;
+                self.hooks = Hooks::default();
+            }
+            "hooks_file" => {
+                self.hooks = Hooks::load(Path::new(value))?;
+                // Commands may be run from a different directory later.
+                self.hooks_file = Some(std::fs::canonicalize(value)?);
+            }
+            key => return Err(anyhow!("unknown config key: {}", key)),
+        }
 
         Ok(())
     }
@@ -178,6 +879,13 @@ impl Config {
         }
         None
     }
+
+    /// Lowest to highest zoom level that we store activity tiles for.
+    pub fn zoom_range(&self) -> RangeInclusive<u32> {
+        let min = self.zoom_levels.iter().min().copied().unwrap_or(0);
+        let max = self.zoom_levels.iter().max().copied().unwrap_or(0);
+        min as u32..=max as u32
+    }
 }
 
 impl Default for Config {
@@ -186,23 +894,83 @@ impl Default for Config {
             zoom_levels: DEFAULT_ZOOM_LEVELS.to_vec(),
             tile_extent: DEFAULT_TILE_EXTENT,
             trim_dist: DEFAULT_TRIM_DIST,
+            masks: vec![],
+            default_gradient: None,
+            gradients: BTreeMap::new(),
+            include_virtual: false,
+            hooks_file: None,
+            hooks: Hooks::default(),
+            spatial_index: false,
         }
     }
 }
 
+/// Version byte of tile lines stored as deltas, see [`encode_line`].
+const LINE_FORMAT_DELTA: u8 = 1;
+
+/// Encode the coordinates of a line within a tile, as the difference to the
+/// previous point (zigzag encoded, as varints). Nearby points usually take a
+/// single byte per axis.
+///
+/// Lines used to be stored as plain little endian `u16` pairs, which always
+/// take a multiple of 4 bytes. To tell them apart, lines start with a version
+/// byte, and are padded with a zero byte if they'd be a multiple of 4 bytes
+/// long otherwise.
 pub fn encode_line<T>(line: &LineString<T>) -> Result<Vec<u8>>
 where
     T: CoordNum + AsPrimitive<u16>,
 {
-    let mut w = Vec::with_capacity(line.0.len() * 2);
+    let mut w = Vec::with_capacity(2 + line.0.len() * 2);
+    w.push(LINE_FORMAT_DELTA);
+
+    let mut prev = (0, 0);
     for pt in line.coords() {
-        w.write_u16::<LittleEndian>(pt.x.as_())?;
-        w.write_u16::<LittleEndian>(pt.y.as_())?;
+        let (x, y) = (pt.x.as_() as i32, pt.y.as_() as i32);
+        write_varint(&mut w, zigzag(x - prev.0));
+        write_varint(&mut w, zigzag(y - prev.1));
+        prev = (x, y);
+    }
+
+    if w.len().is_multiple_of(4) {
+        w.push(0);
     }
+
     Ok(w)
 }
 
+/// Inverse of [`encode_line`], which also reads lines in the older format.
 pub fn decode_line(bytes: &[u8]) -> Result<Vec<Coord<u32>>> {
+    if bytes.len().is_multiple_of(4) {
+        return decode_line_u16(bytes);
+    }
+
+    let (version, mut rest) = bytes.split_first().expect("not a multiple of 4");
+    if *version != LINE_FORMAT_DELTA {
+        return Err(anyhow!("unknown tile line format: {}", version));
+    }
+
+    let mut coords = Vec::with_capacity(rest.len() / 2);
+    let mut prev = (0, 0);
+    // Each point takes at least 2 bytes, so a single zero byte is padding.
+    while !rest.is_empty() && rest != [0] {
+        let x = prev.0 + unzigzag(read_varint(&mut rest)?);
+        let y = prev.1 + unzigzag(read_varint(&mut rest)?);
+        if !(0..=u16::MAX as i32).contains(&x) || !(0..=u16::MAX as i32).contains(&y) {
+            return Err(anyhow!("tile line coordinates out of range"));
+        }
+
+        coords.push(Coord {
+            x: x as u32,
+            y: y as u32,
+        });
+        prev = (x, y);
+    }
+
+    Ok(coords)
+}
+
+/// Lines stored before [`LINE_FORMAT_DELTA`], as `u16` pairs.
+fn decode_line_u16(bytes: &[u8]) -> Result<Vec<Coord<u32>>> {
     let mut coords = Vec::with_capacity(bytes.len() / (2 * 2));
     let mut reader = Cursor::new(bytes);
     while reader.position() < bytes.len() as u64 {
@@ -213,32 +981,169 @@ pub fn decode_line(bytes: &[u8]) -> Result<Vec<Coord<u32>>> {
     Ok(coords)
 }
 
+fn zigzag(n: i32) -> u32 {
+    ((n << 1) ^ (n >> 31)) as u32
+}
+
+fn unzigzag(n: u32) -> i32 {
+    (n >> 1) as i32 ^ -((n & 1) as i32)
+}
+
+/// Write `n` in 7 bit groups, least significant first, with the high bit set
+/// on all but the last byte.
+fn write_varint(w: &mut Vec<u8>, mut n: u32) {
+    while n >= 0x80 {
+        w.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    w.push(n as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u32> {
+    let mut n: u32 = 0;
+    for shift in (0..32).step_by(7) {
+        let (&byte, rest) = bytes
+            .split_first()
+            .ok_or_else(|| anyhow!("truncated tile line"))?;
+        *bytes = rest;
+
+        n |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+
+    Err(anyhow!("invalid varint in tile line"))
+}
+
+/// Precision used when storing tracks as polylines (~1m)
+const TRACK_PRECISION: u32 = 5;
+
+/// Encode a set of lng/lat tracks as a JSON array of polylines.
+pub fn encode_tracks(tracks: &MultiLineString) -> Result<String> {
+    let polylines = tracks
+        .iter()
+        .filter(|line| line.0.len() >= 2)
+        .map(|line| {
+            polyline::encode_coordinates(line.coords().copied(), TRACK_PRECISION)
+                .map_err(|err| anyhow!("failed to encode track: {}", err))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(serde_json::to_string(&polylines)?)
+}
+
+/// Inverse of [`encode_tracks`].
+pub fn decode_tracks(s: &str) -> Result<MultiLineString> {
+    let polylines: Vec<String> = serde_json::from_str(s)?;
+
+    polylines
+        .iter()
+        .map(|p| {
+            polyline::decode_polyline(p, TRACK_PRECISION)
+                .map_err(|err| anyhow!("failed to decode track: {}", err))
+        })
+        .collect()
+}
+
+/// Filter key which matches the athlete an activity was imported for (via
+/// the Strava webhook), rather than a property.
+pub const ATHLETE_FILTER_KEY: &str = "athlete";
+
+/// Filter key which matches when an activity started. Compared as text, so
+/// dates (`YYYY-MM-DD`) or relative dates (`-90d`) can be used.
+pub const START_TIME_FILTER_KEY: &str = "start_time";
+
+/// Names matched by the `weekday` filter key, indexed by `strftime('%w')`.
+const WEEKDAY_SQL: &str = "(CASE strftime('%w', start_time) \
+    WHEN '0' THEN 'sunday' WHEN '1' THEN 'monday' WHEN '2' THEN 'tuesday' \
+    WHEN '3' THEN 'wednesday' WHEN '4' THEN 'thursday' WHEN '5' THEN 'friday' \
+    WHEN '6' THEN 'saturday' END)";
+
+/// Filter keys which match a column (or something derived from it) rather
+/// than a property. Start times are stored in UTC, so that's what `year`,
+/// `month` and `weekday` go by.
+fn filter_column(key: &str) -> Option<&'static str> {
+    match key {
+        ATHLETE_FILTER_KEY => Some("athlete_id"),
+        START_TIME_FILTER_KEY => Some("start_time"),
+        // Usually its own column, but may also be a property (e.g. joined
+        // from a CSV).
+        "title" => Some("coalesce(title, properties ->> '$.title')"),
+        "year" => Some("CAST(strftime('%Y', start_time) AS INTEGER)"),
+        "month" => Some("CAST(strftime('%m', start_time) AS INTEGER)"),
+        "weekday" => Some(WEEKDAY_SQL),
+        _ => None,
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct PropertyFilter(HashMap<String, PropExpr>);
 
 impl PropertyFilter {
     fn to_query<'a>(&'a self, clauses: &mut Vec<Cow<'a, str>>, params: &mut Vec<&'a dyn ToSql>) {
         for (key, expr) in self.0.iter() {
-            expr.as_sql(key, clauses, params);
+            expr.as_sql(filter_column(key), key, clauses, params);
+        }
+    }
+
+    /// Resolve relative dates (e.g. `-90d`) compared against `start_time`,
+    /// which would otherwise be compared as text. Weekday names are
+    /// lowercased to match [`WEEKDAY_SQL`].
+    fn resolve_dates(mut self) -> Result<Self> {
+        if let Some(expr) = self.0.get_mut("weekday") {
+            let names = [&mut expr.eq, &mut expr.neq].into_iter().flatten();
+            let lists = [&mut expr.any_of, &mut expr.none_of].into_iter().flatten();
+            for name in names.chain(lists.flatten()) {
+                *name = name.to_lowercase();
+            }
+        }
+
+        let Some(expr) = self.0.get_mut(START_TIME_FILTER_KEY) else {
+            return Ok(self);
+        };
+
+        let bounds = [&mut expr.gt, &mut expr.gte, &mut expr.lt, &mut expr.lte];
+        for bound in bounds.into_iter().flatten() {
+            if let FilterValue::Text(s) = bound {
+                if s.starts_with('-') {
+                    *s = date::parse_date(s)?.to_string();
+                }
+            }
         }
+
+        Ok(self)
     }
 }
 
 impl PropExpr {
+    /// Add the clauses for this expression on a property `key`, or on
+    /// `column` instead if the key matches one.
     fn as_sql<'a>(
         &'a self,
-        key: &'a String,
+        column: Option<&str>,
+        key: &'a dyn ToSql,
         clauses: &mut Vec<Cow<'_, str>>,
         params: &mut Vec<&'a dyn ToSql>,
     ) {
+        // Some keys match columns rather than properties. The athlete, year
+        // and month are integers, so string values compare as numbers.
+        let lhs = column.unwrap_or("properties ->> ?");
+
+        let push_key = |params: &mut Vec<&'a dyn ToSql>| {
+            if column.is_none() {
+                params.push(key);
+            }
+        };
+
         macro_rules! filter_list {
             ($e:ident, $cmp:expr) => {
                 if let Some(ref values) = self.$e {
-                    params.push(key);
+                    push_key(params);
                     params.extend(values.iter().map(|v| v as &dyn ToSql));
 
                     let placeholders = vec!["?"; values.len()].join(",");
-                    clauses.push(format!("({} ({}))", $cmp, placeholders).into());
+                    clauses.push(format!("({} {} ({}))", lhs, $cmp, placeholders).into());
                 }
             };
         }
@@ -246,41 +1151,61 @@ impl PropExpr {
         macro_rules! filter {
             ($field:ident, $expected:expr, $sql:expr) => {
                 if let Some($expected) = self.$field {
-                    params.push(key);
-                    clauses.push($sql.into());
+                    push_key(params);
+                    clauses.push(format!($sql, lhs).into());
                 }
             };
             ($field:ident, $sql:expr) => {
                 if let Some(ref val) = self.$field {
-                    params.push(key);
+                    push_key(params);
+                    params.push(val);
+                    clauses.push(format!($sql, lhs).into());
+                }
+            };
+        }
+
+        // Properties can be stored as strings (e.g. joined from a CSV), which
+        // SQLite would always consider greater than any number. Cast them, so
+        // `"150"` compares like `150`.
+        macro_rules! compare {
+            ($field:ident, $op:expr) => {
+                if let Some(ref val) = self.$field {
+                    push_key(params);
                     params.push(val);
-                    clauses.push($sql.into());
+                    let clause = match val {
+                        FilterValue::Number(_) => format!("(CAST({} AS REAL) {} ?)", lhs, $op),
+                        FilterValue::Text(_) => format!("({} {} ?)", lhs, $op),
+                    };
+                    clauses.push(clause.into());
                 }
             };
         }
 
-        filter_list!(any_of, "properties ->> ? IN");
-        filter_list!(none_of, "properties ->> ? NOT IN");
+        filter_list!(any_of, "IN");
+        filter_list!(none_of, "NOT IN");
 
-        filter!(eq, "(properties ->> ? = ?)");
-        filter!(neq, "(properties ->> ? != ?)");
-        filter!(gt, "(properties ->> ? > ?)");
-        filter!(gte, "(properties ->> ? >= ?)");
-        filter!(lt, "(properties ->> ? < ?)");
-        filter!(lte, "(properties ->> ? <= ?)");
-        filter!(matches, "(instr(properties ->> ?, ?) > 0)");
+        filter!(eq, "({} = ?)");
+        filter!(neq, "({} != ?)");
+        compare!(gt, ">");
+        compare!(gte, ">=");
+        compare!(lt, "<");
+        compare!(lte, "<=");
+        filter!(matches, "(instr({}, ?) > 0)");
+        filter!(like, "({} LIKE ?)");
 
-        filter!(exists, true, "(properties ->> ? IS NOT NULL)");
-        filter!(exists, false, "(properties ->> ? IS NULL)");
+        filter!(exists, true, "({} IS NOT NULL)");
+        filter!(exists, false, "({} IS NULL)");
     }
 }
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Default, Deserialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct PropExpr {
     any_of: Option<Vec<String>>,
     none_of: Option<Vec<String>>,
     matches: Option<String>,
+    /// SQL `LIKE` pattern, case insensitive (for ASCII).
+    like: Option<String>,
     exists: Option<bool>,
 
     // TODO: support non-string type here as well
@@ -291,24 +1216,108 @@ pub struct PropExpr {
     neq: Option<String>,
 
     #[serde(rename = ">")]
-    gt: Option<f64>,
+    gt: Option<FilterValue>,
 
     #[serde(rename = ">=")]
-    gte: Option<f64>,
+    gte: Option<FilterValue>,
 
     #[serde(rename = "<")]
-    lt: Option<f64>,
+    lt: Option<FilterValue>,
 
     #[serde(rename = "<=")]
-    lte: Option<f64>,
+    lte: Option<FilterValue>,
+}
+
+/// Value compared against with `<`, `>` etc. Usually a number, but dates are
+/// compared as strings.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum FilterValue {
+    Number(f64),
+    Text(String),
+}
+
+impl FilterValue {
+    fn parse(s: &str) -> Self {
+        s.parse()
+            .map(FilterValue::Number)
+            .unwrap_or_else(|_| FilterValue::Text(s.to_string()))
+    }
+}
+
+impl ToSql for FilterValue {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match self {
+            FilterValue::Number(n) => n.to_sql(),
+            FilterValue::Text(s) => s.to_sql(),
+        }
+    }
+}
+
+/// Parse a single predicate, e.g. `activity_type=Ride`, `distance > 100`,
+/// `month in [6, 7, 8]` or `title like %gravel%`. Values may be quoted.
+fn parse_predicate(s: &str) -> Option<(String, PropExpr)> {
+    let unquote = |v: &str| {
+        let v = v.trim();
+        ['"', '\'']
+            .iter()
+            .find_map(|q| v.strip_prefix(*q)?.strip_suffix(*q))
+            .unwrap_or(v)
+            .to_string()
+    };
+
+    // Whichever operator comes first separates the key from the value.
+    let pos = [
+        s.find(['=', '!', '<', '>']),
+        s.find(" in "),
+        s.find(" like "),
+    ]
+    .into_iter()
+    .flatten()
+    .min()?;
+    let (key, rest) = (s[..pos].trim(), &s[pos..]);
+    if key.is_empty() {
+        return None;
+    }
+
+    let mut expr = PropExpr::default();
+    if let Some(values) = rest.strip_prefix(" in ") {
+        let values = values.trim().strip_prefix('[')?.strip_suffix(']')?;
+        expr.any_of = Some(values.split(',').map(unquote).collect());
+    } else if let Some(pattern) = rest.strip_prefix(" like ") {
+        expr.like = Some(unquote(pattern));
+    } else {
+        let (op, value) = ["!=", ">=", "<=", "=", ">", "<"]
+            .iter()
+            .find_map(|op| Some((*op, unquote(rest.strip_prefix(op)?))))?;
+
+        match op {
+            "=" => expr.eq = Some(value),
+            "!=" => expr.neq = Some(value),
+            ">" => expr.gt = Some(FilterValue::parse(&value)),
+            ">=" => expr.gte = Some(FilterValue::parse(&value)),
+            "<" => expr.lt = Some(FilterValue::parse(&value)),
+            _ => expr.lte = Some(FilterValue::parse(&value)),
+        }
+    }
+
+    Some((key.to_string(), expr))
 }
 
 impl FromStr for PropertyFilter {
     type Err = anyhow::Error;
 
+    /// Parse a JSON filter expression, or a single predicate like
+    /// `key=value` as a shorthand for `{"key": {"=": "value"}}`.
     fn from_str(s: &str) -> Result<Self> {
+        if !s.trim_start().starts_with('{') {
+            if let Some((key, expr)) = parse_predicate(s) {
+                return PropertyFilter(HashMap::from([(key, expr)])).resolve_dates();
+            }
+        }
+
         let obj = serde_json::from_str(s)?;
-        Ok(PropertyFilter(obj))
+        PropertyFilter(obj).resolve_dates()
     }
 }
 
@@ -317,18 +1326,49 @@ impl<'de> Deserialize<'de> for PropertyFilter {
     where
         D: Deserializer<'de>,
     {
-        let s = String::deserialize(deserializer)?;
-        PropertyFilter::from_str(&s).map_err(|err| {
-            serde::de::Error::custom(format!("invalid filter expression: {:?}", err))
-        })
+        // Query parameters give us a string, but the filter may also be
+        // nested as an object within a larger JSON document.
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            String(String),
+            Object(HashMap<String, PropExpr>),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Object(obj) => PropertyFilter(obj)
+                .resolve_dates()
+                .map_err(serde::de::Error::custom),
+            Repr::String(s) => PropertyFilter::from_str(&s).map_err(|err| {
+                serde::de::Error::custom(format!("invalid filter expression: {:?}", err))
+            }),
+        }
     }
 }
 
+/// Turn free text into an FTS5 query, quoting each word so that punctuation
+/// and keywords (`AND`, `NEAR`, ...) don't have any special meaning.
+fn search_query(text: &str) -> Option<String> {
+    let words: Vec<_> = text
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect();
+
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
 #[derive(Default)]
 pub struct ActivityFilter {
     before: Option<OffsetDateTime>,
     after: Option<OffsetDateTime>,
     props: Option<PropertyFilter>,
+    user_id: Option<i64>,
+    /// Tiles (at a stored zoom level) which activities need to pass through.
+    bounds: Option<TileBounds>,
+    /// Condition on `activity_tiles` used to look up `bounds`.
+    bounds_clause: &'static str,
+    /// FTS5 query matched against titles and text properties.
+    search: Option<String>,
 }
 
 impl ActivityFilter {
@@ -337,9 +1377,52 @@ impl ActivityFilter {
             props,
             before: before.map(|date| date.midnight().assume_utc()),
             after: after.map(|date| date.midnight().assume_utc()),
+            user_id: None,
+            bounds: None,
+            bounds_clause: "",
+            search: None,
+        }
+    }
+
+    /// Only match activities passing through the given area, going by the
+    /// tiles they cover at the highest stored zoom level. Since whole tiles
+    /// are compared, activities passing just outside may also match.
+    pub fn within(self, bbox: &WebMercatorViewport, config: &Config) -> Self {
+        let zoom = *config.zoom_range().end() as u8;
+        Self {
+            bounds: Some(bbox.tile_bounds(zoom)),
+            bounds_clause: spatial_index::range_clause(config),
+            ..self
+        }
+    }
+
+    /// Only match activities whose title or text properties contain all the
+    /// words of `query` (or words starting with them).
+    pub fn search(self, query: &str) -> Self {
+        Self {
+            search: search_query(query),
+            ..self
         }
     }
 
+    /// Only match activities belonging to the given user.
+    pub fn for_user(self, user_id: i64) -> Self {
+        Self {
+            user_id: Some(user_id),
+            ..self
+        }
+    }
+
+    /// Whether every activity matches.
+    pub fn is_empty(&self) -> bool {
+        self.before.is_none()
+            && self.after.is_none()
+            && self.props.is_none()
+            && self.user_id.is_none()
+            && self.bounds.is_none()
+            && self.search.is_none()
+    }
+
     pub fn to_query<'a>(&'a self, params: &mut Vec<&'a dyn ToSql>) -> String {
         let mut clauses: Vec<Cow<'a, str>> = vec!["true".into()];
 
@@ -353,10 +1436,41 @@ impl ActivityFilter {
             params.push(after);
         }
 
+        if let Some(ref user_id) = self.user_id {
+            clauses.push("user_id = ?".into());
+            params.push(user_id);
+        }
+
         if let Some(ref props) = self.props {
             props.to_query(&mut clauses, params);
         }
 
+        if let Some(ref search) = self.search {
+            clauses.push(
+                "activities.id IN ( \
+                    SELECT rowid FROM activity_search WHERE activity_search MATCH ?)"
+                    .into(),
+            );
+            params.push(search);
+        }
+
+        if let Some(ref bounds) = self.bounds {
+            clauses.push(
+                format!(
+                    "activities.id IN (SELECT activity_id FROM activity_tiles WHERE {})",
+                    self.bounds_clause
+                )
+                .into(),
+            );
+            params.extend(params![
+                bounds.z,
+                bounds.xmin,
+                bounds.xmax,
+                bounds.ymin,
+                bounds.ymax
+            ]);
+        }
+
         clauses.join(" AND ")
     }
 
@@ -379,3 +1493,244 @@ impl ActivityFilter {
         Ok(count.get_unwrap(0))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> (String, PropExpr) {
+        let filter = PropertyFilter::from_str(s).unwrap();
+        filter.0.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn test_parse_predicates() {
+        let (key, expr) = parse("activity_type=Ride");
+        assert_eq!(key, "activity_type");
+        assert_eq!(expr.eq.as_deref(), Some("Ride"));
+
+        let (key, expr) = parse("distance > 100");
+        assert_eq!(key, "distance");
+        assert_eq!(expr.gt, Some(FilterValue::Number(100.0)));
+
+        let (_, expr) = parse("title != 'Morning Ride'");
+        assert_eq!(expr.neq.as_deref(), Some("Morning Ride"));
+
+        let (key, expr) = parse("start_time >= \"2024-01-01\"");
+        assert_eq!(key, "start_time");
+        assert_eq!(expr.gte, Some(FilterValue::Text("2024-01-01".to_string())));
+
+        let (key, expr) = parse("month in [6, 7, 8]");
+        assert_eq!(key, "month");
+        assert_eq!(expr.any_of, Some(vec!["6".into(), "7".into(), "8".into()]));
+
+        // Only the first operator counts.
+        let (key, expr) = parse("title=Ride in [the park]");
+        assert_eq!(key, "title");
+        assert_eq!(expr.eq.as_deref(), Some("Ride in [the park]"));
+
+        let (key, expr) = parse("title like '%gravel%'");
+        assert_eq!(key, "title");
+        assert_eq!(expr.like.as_deref(), Some("%gravel%"));
+
+        assert!(PropertyFilter::from_str("= 5").is_err());
+        assert!(PropertyFilter::from_str("month in 6").is_err());
+    }
+
+    /// IDs of the activities with the given properties which match `filter`.
+    fn matching(properties: &[&str], filter: &str) -> Vec<i64> {
+        let rows: Vec<_> = properties.iter().map(|props| (None, *props)).collect();
+        matching_rows(&rows, filter)
+    }
+
+    /// Same as [`matching`], for activities with a start time as well.
+    fn matching_rows(rows: &[(Option<&str>, &str)], filter: &str) -> Vec<i64> {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE activities (id INTEGER PRIMARY KEY, start_time TEXT, properties TEXT)",
+        )
+        .unwrap();
+        for (id, (start_time, props)) in rows.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO activities (id, start_time, properties) VALUES (?, ?, ?)",
+                params![id as i64 + 1, start_time, props],
+            )
+            .unwrap();
+        }
+
+        let filter = ActivityFilter::new(None, None, Some(filter.parse().unwrap()));
+        let mut params = vec![];
+        let query = format!(
+            "SELECT id FROM activities WHERE {} ORDER BY id",
+            filter.to_query(&mut params)
+        );
+        let mut stmt = conn.prepare(&query).unwrap();
+        let ids = stmt
+            .query_map(params.as_slice(), |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        ids
+    }
+
+    #[test]
+    fn test_numeric_comparisons() {
+        let properties = [
+            r#"{"distance": 150}"#,
+            r#"{"distance": 50.5}"#,
+            r#"{"distance": "150"}"#,
+            r#"{"distance": "50"}"#,
+            r#"{"distance": null}"#,
+            r#"{}"#,
+        ];
+
+        assert_eq!(matching(&properties, "distance > 100"), vec![1, 3]);
+        assert_eq!(matching(&properties, "distance <= 50.5"), vec![2, 4]);
+        assert_eq!(
+            matching(&properties, r#"{"distance": {">=": 50, "<": 150}}"#),
+            vec![2, 4]
+        );
+    }
+
+    #[test]
+    fn test_date_parts() {
+        // A Monday in June, a Saturday in August and a Sunday in January.
+        let rows = [
+            (Some("2024-06-03T07:00:00Z"), "{}"),
+            (Some("2023-08-12T18:30:00Z"), "{}"),
+            (Some("2022-01-02T09:15:00Z"), "{}"),
+            (None, "{}"),
+        ];
+
+        assert_eq!(matching_rows(&rows, "year=2024"), vec![1]);
+        assert_eq!(matching_rows(&rows, "year >= 2023"), vec![1, 2]);
+        assert_eq!(matching_rows(&rows, "month in [6, 7, 8]"), vec![1, 2]);
+        assert_eq!(matching_rows(&rows, "month=1"), vec![3]);
+        assert_eq!(matching_rows(&rows, "weekday=monday"), vec![1]);
+        assert_eq!(matching_rows(&rows, "weekday=Monday"), vec![1]);
+        assert_eq!(
+            matching_rows(&rows, "weekday in [Saturday, SUNDAY]"),
+            vec![2, 3]
+        );
+        assert_eq!(matching_rows(&rows, "weekday != monday"), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_search() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        apply_schema(&mut conn).unwrap();
+        conn.execute_batch(
+            r#"
+            INSERT INTO activities (id, file, title, properties) VALUES
+                (1, 'a.gpx', 'Gravel ride by the lake', '{"gear": "Crux"}'),
+                (2, 'b.gpx', 'Morning run', '{"start_city": "Zürich", "distance": 10}'),
+                (3, 'c.gpx', NULL, '{}');
+            UPDATE activities SET title = 'Evening run' WHERE id = 2;
+            DELETE FROM activities WHERE id = 3;
+            "#,
+        )
+        .unwrap();
+
+        let search = |text: &str| -> Vec<i64> {
+            let filter = ActivityFilter::default().search(text);
+            let mut params = vec![];
+            let query = format!(
+                "SELECT id FROM activities WHERE {} ORDER BY id",
+                filter.to_query(&mut params)
+            );
+            let mut stmt = conn.prepare(&query).unwrap();
+            let ids = stmt
+                .query_map(params.as_slice(), |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            ids
+        };
+
+        assert_eq!(search("grav LAKE"), vec![1]);
+        assert_eq!(search("crux"), vec![1]);
+        assert_eq!(search("zurich run"), vec![2]);
+        assert_eq!(search("morning"), Vec::<i64>::new());
+        assert_eq!(search("lake NEAR \"ride"), Vec::<i64>::new());
+        assert_eq!(search("  "), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_resolve_relative_dates() {
+        let (_, expr) = parse(r#"{"start_time": {">": "-1d", "<": "2030-01-01"}}"#);
+        let yesterday = OffsetDateTime::now_utc().date().previous_day().unwrap();
+        assert_eq!(expr.gt, Some(FilterValue::Text(yesterday.to_string())));
+        assert_eq!(expr.lt, Some(FilterValue::Text("2030-01-01".to_string())));
+
+        // Only `start_time` has dates.
+        let (_, expr) = parse(r#"{"elevation": {">": "-1d"}}"#);
+        assert_eq!(expr.gt, Some(FilterValue::Text("-1d".to_string())));
+    }
+
+    #[test]
+    fn test_encode_line() {
+        let coords = |points: &[(u32, u32)]| -> Vec<Coord<u32>> {
+            points.iter().map(|&(x, y)| Coord { x, y }).collect()
+        };
+
+        let line = coords(&[(100, 2000), (101, 1990), (40, 2047), (65535, 0)]);
+        let encoded = encode_line(&LineString::from(line.clone())).unwrap();
+        // 4 bytes for the first point, 2 for the next ones close by.
+        assert_eq!(
+            encoded[..8],
+            [LINE_FORMAT_DELTA, 200, 1, 160, 31, 2, 19, 121]
+        );
+        assert_eq!(decode_line(&encoded).unwrap(), line);
+
+        // Padded to not be a multiple of 4 bytes.
+        let line = coords(&[(64, 1)]);
+        let encoded = encode_line(&LineString::from(line.clone())).unwrap();
+        assert_eq!(encoded, vec![LINE_FORMAT_DELTA, 128, 1, 2, 0]);
+        assert_eq!(decode_line(&encoded).unwrap(), line);
+        assert_eq!(decode_line(&[LINE_FORMAT_DELTA]).unwrap(), vec![]);
+
+        // Lines stored before the delta encoding.
+        assert_eq!(
+            decode_line(&[1, 0, 2, 0, 0, 1, 3, 0]).unwrap(),
+            coords(&[(1, 2), (256, 3)])
+        );
+        assert_eq!(decode_line(&[]).unwrap(), vec![]);
+
+        assert!(decode_line(&[2, 0, 0]).is_err());
+        assert!(decode_line(&[LINE_FORMAT_DELTA, 2, 0x80]).is_err());
+        assert!(decode_line(&[LINE_FORMAT_DELTA, 1, 1]).is_err());
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db #1?.sqlite3");
+        assert!(Database::open_read_only(&path).is_err());
+
+        let db = Database::new(&path).unwrap();
+        db.connection()
+            .unwrap()
+            .execute("INSERT INTO activities (id, file) VALUES (1, 'a.gpx')", [])
+            .unwrap();
+        drop(db);
+
+        let db = Database::open_read_only(&path).unwrap();
+        assert_eq!(db.config.zoom_levels, Config::default().zoom_levels);
+        let conn = db.connection().unwrap();
+        let num_activities: u32 = conn
+            .query_row("SELECT count(*) FROM activities", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(num_activities, 1);
+        assert!(conn.execute("DELETE FROM activities", []).is_err());
+        assert!(db.save_config().is_err());
+        assert!(db.last_modified().is_some());
+        drop((conn, db));
+
+        // Outdated databases need migrating first.
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", 1)
+            .unwrap();
+        assert!(Database::open_read_only(&path).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+//! Breakdown of what takes up space in the database, for `hotpot db stats`.
+//!
+//! Stored tiles make up most of a typical database, so they're broken down by
+//! zoom level, to show what re-tiling with fewer levels would save.
+
+use anyhow::Result;
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::db::{decode_line, Database};
+
+/// Rough number of bytes taken up by a stored tile besides its coordinates:
+/// the other columns, row header, and entries in the `(z, x, y)` and
+/// `activity_id` indexes.
+const TILE_OVERHEAD: u64 = 40;
+
+/// Number of tiles per zoom level to decode, to estimate how many points
+/// they hold. The size of encoded lines varies, see `db::encode_line`.
+const SAMPLE_SIZE: usize = 1000;
+
+#[derive(Debug, Serialize)]
+pub struct DbStats {
+    /// Size of the database file, in bytes.
+    pub size: u64,
+    /// Unused space within the file, which is reclaimed by `VACUUM`.
+    pub free: u64,
+    pub num_activities: u64,
+    pub num_tiles: u64,
+    pub zoom_levels: Vec<ZoomStats>,
+    pub largest_activities: Vec<ActivitySize>,
+    /// Space taken up by each table and index, largest first. Empty if
+    /// SQLite was built without the `dbstat` table.
+    pub tables: Vec<TableSize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ZoomStats {
+    pub z: u8,
+    pub num_tiles: u64,
+    /// Estimated from a sample of the tiles.
+    pub avg_coords: f64,
+    /// Estimated space taken up by tiles at this zoom level, which is about
+    /// what dropping it from `zoom_levels` would save.
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ActivitySize {
+    pub id: i64,
+    pub title: Option<String>,
+    pub file: String,
+    pub num_tiles: u64,
+    /// Estimated space taken up by the activity's tiles.
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TableSize {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Collect stats for the database, including the `top` activities taking
+/// up the most space.
+pub fn collect(db: &Database, top: usize) -> Result<DbStats> {
+    let conn = db.connection()?;
+
+    let page_size: u64 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+    let page_count: u64 = conn.pragma_query_value(None, "page_count", |row| row.get(0))?;
+    let freelist_count: u64 = conn.pragma_query_value(None, "freelist_count", |row| row.get(0))?;
+
+    let num_activities = conn.query_row("SELECT count(*) FROM activities", [], |row| row.get(0))?;
+
+    let tables = table_sizes(&conn).unwrap_or_else(|err| {
+        tracing::debug!(?err, "table sizes not available");
+        vec![]
+    });
+
+    let levels = conn
+        .prepare(
+            "\
+            SELECT z, count(*), coalesce(sum(length(coords)), 0) \
+            FROM activity_tiles \
+            GROUP BY z \
+            ORDER BY z",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<Vec<(u8, u64, u64)>, _>>()?;
+
+    // Split up the space actually used by the tiles (if known) between zoom
+    // levels, going by how many bytes each would take up on its own.
+    let estimate = |num_tiles: u64, coord_bytes: u64| num_tiles * TILE_OVERHEAD + coord_bytes;
+    let estimated_total: u64 = levels.iter().map(|&(_, n, bytes)| estimate(n, bytes)).sum();
+    let actual_total: u64 = tables
+        .iter()
+        .filter(|table| table.name.starts_with("activity_tiles"))
+        .map(|table| table.size)
+        .sum();
+    let scale = match (actual_total, estimated_total) {
+        (0, _) | (_, 0) => 1.0,
+        (actual, estimated) => actual as f64 / estimated as f64,
+    };
+    let size = |num_tiles: u64, coord_bytes: u64| {
+        (estimate(num_tiles, coord_bytes) as f64 * scale).round() as u64
+    };
+
+    let mut sample = conn.prepare("SELECT coords FROM activity_tiles WHERE z = ? LIMIT ?")?;
+    let mut zoom_levels = vec![];
+    for &(z, num_tiles, coord_bytes) in &levels {
+        let (mut sample_coords, mut sample_bytes) = (0, 0);
+        let mut rows = sample.query(params![z, SAMPLE_SIZE])?;
+        while let Some(row) = rows.next()? {
+            let bytes: Vec<u8> = row.get_unwrap(0);
+            sample_coords += decode_line(&bytes)?.len();
+            sample_bytes += bytes.len();
+        }
+
+        let coords_per_byte = sample_coords as f64 / sample_bytes.max(1) as f64;
+        zoom_levels.push(ZoomStats {
+            z,
+            num_tiles,
+            avg_coords: coords_per_byte * coord_bytes as f64 / num_tiles.max(1) as f64,
+            size: size(num_tiles, coord_bytes),
+        });
+    }
+
+    let largest_activities = conn
+        .prepare(
+            "\
+            SELECT activities.id, title, file, num_tiles, coord_bytes \
+            FROM ( \
+                SELECT activity_id, count(*) AS num_tiles, sum(length(coords)) AS coord_bytes \
+                FROM activity_tiles \
+                GROUP BY activity_id \
+                ORDER BY coord_bytes DESC \
+                LIMIT ? \
+            ) \
+            JOIN activities ON activities.id = activity_id \
+            ORDER BY coord_bytes DESC",
+        )?
+        .query_map(params![top], |row| {
+            Ok(ActivitySize {
+                id: row.get(0)?,
+                title: row.get(1)?,
+                file: row.get(2)?,
+                num_tiles: row.get(3)?,
+                size: size(row.get(3)?, row.get(4)?),
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(DbStats {
+        size: page_count * page_size,
+        free: freelist_count * page_size,
+        num_activities,
+        num_tiles: levels.iter().map(|&(_, n, _)| n).sum(),
+        zoom_levels,
+        largest_activities,
+        tables,
+    })
+}
+
+/// Space taken up by each table and index, from the `dbstat` virtual table.
+fn table_sizes(conn: &rusqlite::Connection) -> Result<Vec<TableSize>> {
+    let mut stmt = conn.prepare(
+        "\
+        SELECT name, pgsize FROM dbstat \
+        WHERE aggregate = TRUE \
+        ORDER BY pgsize DESC",
+    )?;
+
+    let tables = stmt
+        .query_map([], |row| {
+            Ok(TableSize {
+                name: row.get(0)?,
+                size: row.get(1)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_db_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("db.sqlite3")).unwrap();
+
+        let conn = db.connection().unwrap();
+        conn.execute_batch(
+            "\
+            INSERT INTO activities (id, file, title) VALUES (1, 'a.gpx', 'Short'), (2, 'b.gpx', 'Long'); \
+            INSERT INTO activity_tiles (activity_id, z, x, y, coords) VALUES \
+                (1, 2, 0, 0, zeroblob(8)), \
+                (2, 2, 0, 0, zeroblob(16)), \
+                (2, 6, 0, 0, zeroblob(40)), \
+                (2, 6, 1, 0, zeroblob(40));",
+        )
+        .unwrap();
+
+        let stats = collect(&db, 1).unwrap();
+        assert_eq!((stats.num_activities, stats.num_tiles), (2, 4));
+        assert!(stats.size > 0);
+
+        let zooms: Vec<_> = stats
+            .zoom_levels
+            .iter()
+            .map(|level| (level.z, level.num_tiles, level.avg_coords))
+            .collect();
+        assert_eq!(zooms, vec![(2, 2, 3.0), (6, 2, 10.0)]);
+        assert!(stats.zoom_levels[1].size > stats.zoom_levels[0].size);
+
+        assert_eq!(stats.largest_activities.len(), 1);
+        assert_eq!(stats.largest_activities[0].id, 2);
+        assert_eq!(stats.largest_activities[0].num_tiles, 3);
+    }
+}
@@ -0,0 +1,343 @@
+//! Detect the same activity arriving from multiple sources, e.g. once via the
+//! Strava webhook and again from a Garmin file export.
+//!
+//! File names and content hashes differ between sources, so activities are
+//! considered duplicates when they start at about the same time and their
+//! tracks mostly overlap.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use geo::EuclideanDistance;
+use geo_types::{Coord, LineString, MultiLineString, Point};
+use rusqlite::params;
+use time::{Duration, UtcOffset};
+
+use crate::activity::{self, ActivitySummary, RawActivity};
+use crate::db::{decode_tracks, ActivityFilter, Database};
+
+/// Largest difference in start time between two copies of an activity.
+const MAX_START_OFFSET: Duration = Duration::minutes(5);
+
+/// Distance (in meters) within which a point counts as being on the other
+/// track. Generous, since Strava's polylines are heavily simplified.
+const MAX_POINT_DISTANCE: f64 = 50.0;
+
+/// Fraction of points which need to be on the other track (checked in both
+/// directions).
+const MIN_OVERLAP: f64 = 0.9;
+
+/// Number of points of each track to compare.
+const NUM_SAMPLES: usize = 100;
+
+/// Project lng/lat coordinates to (approximate) meters around `origin`, which
+/// is accurate enough to compare nearby tracks.
+pub fn to_meters(tracks: &MultiLineString, origin: Coord) -> MultiLineString {
+    const METERS_PER_DEGREE: f64 = 111_320.0;
+    let scale_x = METERS_PER_DEGREE * origin.y.to_radians().cos();
+
+    tracks
+        .iter()
+        .map(|line| {
+            line.coords()
+                .map(|c| Coord {
+                    x: (c.x - origin.x) * scale_x,
+                    y: (c.y - origin.y) * METERS_PER_DEGREE,
+                })
+                .collect::<LineString>()
+        })
+        .collect()
+}
+
+/// Fraction of (sampled) points of `a` which are close to `b`.
+fn overlap(a: &MultiLineString, b: &MultiLineString) -> f64 {
+    let points: Vec<Point> = a.iter().flat_map(|line| line.points()).collect();
+    if points.is_empty() || b.0.is_empty() {
+        return 0.0;
+    }
+
+    let step = points.len().div_ceil(NUM_SAMPLES);
+    let samples: Vec<_> = points.iter().step_by(step).collect();
+    let num_close = samples
+        .iter()
+        .filter(|pt| {
+            b.iter()
+                .any(|line| pt.euclidean_distance(line) <= MAX_POINT_DISTANCE)
+        })
+        .count();
+
+    num_close as f64 / samples.len() as f64
+}
+
+/// Whether two (lng/lat) tracks follow the same path.
+pub fn is_same_track(a: &MultiLineString, b: &MultiLineString) -> bool {
+    let Some(origin) = a.iter().flat_map(|line| line.coords()).next() else {
+        return false;
+    };
+
+    let (a, b) = (to_meters(a, *origin), to_meters(b, *origin));
+    overlap(&a, &b) >= MIN_OVERLAP && overlap(&b, &a) >= MIN_OVERLAP
+}
+
+/// Whether two activities could belong to the same athlete. Activities
+/// imported from files don't have an athlete, so could belong to anyone.
+fn same_athlete(a: Option<u64>, b: Option<u64>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    }
+}
+
+/// Find an existing activity (stored under a different name than `name`)
+/// which is the same as the given one. Activities of different athletes are
+/// never considered the same, even if they were recorded together.
+pub fn find_duplicate(
+    conn: &rusqlite::Connection,
+    name: &str,
+    activity: &RawActivity,
+) -> Result<Option<i64>> {
+    let start_time = activity.start_time.map(|ts| ts.to_offset(UtcOffset::UTC));
+    let Some(start_time) = start_time else {
+        return Ok(None);
+    };
+
+    let mut stmt = conn.prepare_cached(
+        "\
+        SELECT a.id, t.polylines \
+        FROM activities a \
+        JOIN activity_tracks t ON t.activity_id = a.id \
+        WHERE a.file != ? \
+            AND a.start_time BETWEEN ? AND ? \
+            AND (?4 IS NULL OR a.athlete_id IS NULL OR a.athlete_id = ?4)",
+    )?;
+
+    let mut rows = stmt.query(params![
+        name,
+        start_time - MAX_START_OFFSET,
+        start_time + MAX_START_OFFSET,
+        activity.athlete_id,
+    ])?;
+
+    while let Some(row) = rows.next()? {
+        let polylines: String = row.get_unwrap(1);
+        if is_same_track(&activity.tracks, &decode_tracks(&polylines)?) {
+            return Ok(Some(row.get_unwrap(0)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Merge the `properties` of `name` into the existing activity `id` it
+/// duplicates, and remember the file so it isn't imported again.
+///
+/// Values the activity already has are kept, unless they were merged in from
+/// `name` before, so that updates from the same source still apply.
+pub fn link(
+    conn: &rusqlite::Connection,
+    name: &str,
+    id: i64,
+    properties: &serde_json::Map<String, serde_json::Value>,
+) -> Result<()> {
+    let (file, current, previous): (String, String, Option<String>) = conn.query_row(
+        "\
+        SELECT a.file, a.properties, l.properties \
+        FROM activities a \
+        LEFT JOIN linked_files l ON l.file = ?1 AND l.activity_file = a.file \
+        WHERE a.id = ?2",
+        params![name, id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+
+    let mut merged: serde_json::Map<_, _> = serde_json::from_str(&current)?;
+    let previous: serde_json::Map<_, _> = match previous {
+        Some(previous) => serde_json::from_str(&previous)?,
+        None => Default::default(),
+    };
+    for (key, value) in properties {
+        if !merged.contains_key(key) || merged.get(key) == previous.get(key) {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+
+    conn.execute(
+        "UPDATE activities SET properties = ? WHERE id = ?",
+        params![serde_json::to_string(&merged)?, id],
+    )?;
+    conn.execute(
+        "\
+        INSERT OR REPLACE INTO linked_files (file, activity_file, properties) \
+        VALUES (?, ?, ?)",
+        params![name, file, serde_json::to_string(properties)?],
+    )?;
+
+    Ok(())
+}
+
+pub struct Duplicate {
+    /// The activity which was imported first.
+    pub original: ActivitySummary,
+    pub duplicate: ActivitySummary,
+}
+
+/// Find pairs of duplicate activities which are already in the database.
+pub fn find_existing(db: &Database) -> Result<Vec<Duplicate>> {
+    let activities = activity::list(db, &ActivityFilter::default(), None)?;
+    let mut tracks = HashMap::new();
+    let mut duplicate_ids = HashSet::new();
+    let mut pairs = vec![];
+
+    // Activities are sorted by start time, so only the following few can be
+    // duplicates of each one.
+    for (i, a) in activities.iter().enumerate() {
+        let Some(start_time) = a.start_time else {
+            continue;
+        };
+
+        if duplicate_ids.contains(&a.id) {
+            continue;
+        }
+
+        for b in activities[i + 1..].iter() {
+            let Some(other_start) = b.start_time else {
+                continue;
+            };
+
+            if other_start - start_time > MAX_START_OFFSET {
+                break;
+            }
+
+            if duplicate_ids.contains(&b.id) || !same_athlete(a.athlete_id, b.athlete_id) {
+                continue;
+            }
+
+            for id in [a.id, b.id] {
+                if let Entry::Vacant(entry) = tracks.entry(id) {
+                    let track = activity::load_tracks(db, id)?;
+                    entry.insert(track.unwrap_or_else(|| MultiLineString::new(vec![])));
+                }
+            }
+
+            if is_same_track(&tracks[&a.id], &tracks[&b.id]) {
+                let (original, duplicate) = if a.id < b.id { (a, b) } else { (b, a) };
+                duplicate_ids.insert(duplicate.id);
+                pairs.push((original.id, duplicate.id));
+            }
+        }
+    }
+
+    let mut by_id: HashMap<i64, ActivitySummary> =
+        activities.into_iter().map(|a| (a.id, a)).collect();
+
+    Ok(pairs
+        .into_iter()
+        .filter_map(|(original, duplicate)| {
+            // An original can have several duplicates, so only clone it.
+            let original = by_id.get(&original)?.clone();
+            let duplicate = by_id.remove(&duplicate)?;
+            Some(Duplicate {
+                original,
+                duplicate,
+            })
+        })
+        .collect())
+}
+
+/// Delete the duplicate activity, linking it to the original instead.
+pub fn remove(db: &Database, dup: &Duplicate) -> Result<()> {
+    let mut conn = db.connection()?;
+
+    activity::delete_by_id(&mut conn, dup.duplicate.id)?;
+    link(
+        &conn,
+        &dup.duplicate.file,
+        dup.original.id,
+        &dup.duplicate.properties,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::Upserted;
+    use geo_types::line_string;
+
+    #[test]
+    fn test_is_same_track() {
+        let track = MultiLineString::new(vec![line_string![
+            (x: -122.400, y: 37.700),
+            (x: -122.410, y: 37.700),
+            (x: -122.410, y: 37.710),
+        ]]);
+
+        // Same path with fewer points, offset by a few meters.
+        let simplified = MultiLineString::new(vec![line_string![
+            (x: -122.4001, y: 37.7001),
+            (x: -122.4101, y: 37.7001),
+            (x: -122.4101, y: 37.7101),
+        ]]);
+        assert!(is_same_track(&track, &simplified));
+
+        // Only the first leg.
+        let partial = MultiLineString::new(vec![line_string![
+            (x: -122.400, y: 37.700),
+            (x: -122.410, y: 37.700),
+        ]]);
+        assert!(!is_same_track(&track, &partial));
+        assert!(!is_same_track(&track, &MultiLineString::new(vec![])));
+    }
+
+    #[test]
+    fn test_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("db.sqlite3")).unwrap();
+        let mut conn = db.connection().unwrap();
+
+        let activity = |properties: serde_json::Value| RawActivity {
+            title: None,
+            start_time: Some(time::OffsetDateTime::from_unix_timestamp(1_714_550_400).unwrap()),
+            tracks: MultiLineString::new(vec![line_string![
+                (x: -122.400, y: 37.700),
+                (x: -122.410, y: 37.700),
+            ]]),
+            properties: serde_json::from_value(properties).unwrap(),
+            content_hash: None,
+            athlete_id: None,
+            user_id: None,
+        };
+        let properties = |conn: &rusqlite::Connection| -> serde_json::Value {
+            let props: String = conn
+                .query_row("SELECT properties FROM activities", [], |row| row.get(0))
+                .unwrap();
+            serde_json::from_str(&props).unwrap()
+        };
+
+        let original = activity(serde_json::json!({"name": "morning ride"}));
+        let Upserted::Inserted(id) =
+            activity::upsert(&mut conn, "a.gpx", &original, &db.config).unwrap()
+        else {
+            panic!("not inserted");
+        };
+
+        // The original's values are kept, but the duplicate's own values are
+        // updated by later versions of it.
+        for kudos in [1, 2] {
+            let duplicate = activity(serde_json::json!({"name": "Ride", "kudos": kudos}));
+            assert_eq!(
+                activity::upsert(&mut conn, "strava:1", &duplicate, &db.config).unwrap(),
+                Upserted::Linked(id)
+            );
+            assert_eq!(
+                properties(&conn),
+                serde_json::json!({"name": "morning ride", "kudos": kudos})
+            );
+        }
+        assert!(activity::is_known(&conn, "strava:1").unwrap());
+
+        activity::delete(&mut conn, "a.gpx").unwrap();
+        assert!(!activity::is_known(&conn, "strava:1").unwrap());
+    }
+}
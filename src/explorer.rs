@@ -0,0 +1,240 @@
+//! "Explorer tiles": every zoom 14 tile with at least one activity passing
+//! through it is shaded, and the largest square of visited tiles is
+//! highlighted.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use image::{Rgba, RgbaImage};
+use rusqlite::params;
+
+use crate::db::{ActivityFilter, Database};
+use crate::heat;
+use crate::tile::{Tile, WebMercatorViewport};
+
+/// Zoom level of the tiles being explored.
+pub const EXPLORER_ZOOM: u8 = 14;
+
+const VISITED_COLOR: Rgba<u8> = Rgba([0xfc, 0x4a, 0x1a, 0x50]);
+const MAX_SQUARE_COLOR: Rgba<u8> = Rgba([0x2a, 0x6f, 0xf0, 0x60]);
+/// Outlines are only drawn once tiles are at least this many pixels wide.
+const MIN_OUTLINE_SIZE: f64 = 8.0;
+
+pub struct ExplorerTiles {
+    visited: HashSet<(u32, u32)>,
+    /// Top left corner and size of the largest square of visited tiles.
+    max_square: Option<(u32, u32, u32)>,
+}
+
+impl ExplorerTiles {
+    pub fn new(visited: HashSet<(u32, u32)>) -> Self {
+        let max_square = find_max_square(&visited);
+        ExplorerTiles {
+            visited,
+            max_square,
+        }
+    }
+
+    /// Find the visited tiles of activities matching `filter`.
+    pub fn load(db: &Database, filter: &ActivityFilter) -> Result<Self> {
+        let Some(source_zoom) = db.config.source_level(EXPLORER_ZOOM) else {
+            // Nothing stored at this level of detail, so trace the lines from
+            // a lower one instead.
+            let heat_map =
+                heat::count_visits(db, filter, &WebMercatorViewport::world(), EXPLORER_ZOOM)?;
+            let visited = heat_map.cells().iter().map(|c| (c.x, c.y)).collect();
+            return Ok(Self::new(visited));
+        };
+
+        // Every stored tile lies within a single explorer tile.
+        let shift = source_zoom - EXPLORER_ZOOM;
+        let mut params = params![shift, shift, source_zoom].to_vec();
+        let filter_clause = filter.to_query(&mut params);
+
+        let conn = db.connection()?;
+        let mut stmt = conn.prepare(&format!(
+            "\
+            SELECT DISTINCT x >> ?, y >> ? \
+            FROM activity_tiles \
+            JOIN activities ON activities.id = activity_tiles.activity_id \
+            WHERE z = ? AND {};",
+            filter_clause
+        ))?;
+
+        let visited = stmt
+            .query_map(params.as_slice(), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self::new(visited))
+    }
+
+    pub fn num_visited(&self) -> usize {
+        self.visited.len()
+    }
+
+    /// Top left tile and size of the largest square of visited tiles.
+    pub fn max_square(&self) -> Option<(Tile, u32)> {
+        self.max_square
+            .map(|(x, y, size)| (Tile::new(x, y, EXPLORER_ZOOM), size))
+    }
+
+    fn in_max_square(&self, x: u32, y: u32) -> bool {
+        matches!(self.max_square, Some((sx, sy, size))
+            if x >= sx && x < sx + size && y >= sy && y < sy + size)
+    }
+
+    /// Shade the visited tiles falling within `tile`.
+    pub fn render_tile(&self, tile: Tile, width: u32) -> Option<RgbaImage> {
+        // Size of an explorer tile in pixels, at the requested zoom level.
+        let cell_size = width as f64 * 2f64.powi(tile.z as i32 - EXPLORER_ZOOM as i32);
+        let origin = (tile.x as f64 * width as f64, tile.y as f64 * width as f64);
+
+        let (min, max) = if tile.z >= EXPLORER_ZOOM {
+            let shift = tile.z - EXPLORER_ZOOM;
+            let min = (tile.x >> shift, tile.y >> shift);
+            (min, (min.0 + 1, min.1 + 1))
+        } else {
+            let shift = EXPLORER_ZOOM - tile.z;
+            (
+                (tile.x << shift, tile.y << shift),
+                ((tile.x + 1) << shift, (tile.y + 1) << shift),
+            )
+        };
+
+        let mut image: Option<RgbaImage> = None;
+        for &(x, y) in &self.visited {
+            if x < min.0 || x >= max.0 || y < min.1 || y >= max.1 {
+                continue;
+            }
+
+            let color = if self.in_max_square(x, y) {
+                MAX_SQUARE_COLOR
+            } else {
+                VISITED_COLOR
+            };
+
+            // Pixel range covered by the explorer tile, at least one pixel
+            // wide so that distant tiles don't disappear.
+            let to_px = |v: f64, origin: f64| (v - origin).clamp(0.0, width as f64);
+            let x0 = to_px(x as f64 * cell_size, origin.0).floor() as u32;
+            let y0 = to_px(y as f64 * cell_size, origin.1).floor() as u32;
+            let x1 = (to_px((x + 1) as f64 * cell_size, origin.0).ceil() as u32).max(x0 + 1);
+            let y1 = (to_px((y + 1) as f64 * cell_size, origin.1).ceil() as u32).max(y0 + 1);
+
+            let image = image.get_or_insert_with(|| RgbaImage::new(width, width));
+            for py in y0..y1.min(width) {
+                for px in x0..x1.min(width) {
+                    image.put_pixel(px, py, color);
+                }
+            }
+
+            if cell_size >= MIN_OUTLINE_SIZE {
+                draw_outline(image, (x, y), cell_size, origin, opaque(color));
+            }
+        }
+
+        image
+    }
+}
+
+fn opaque(color: Rgba<u8>) -> Rgba<u8> {
+    let Rgba([r, g, b, a]) = color;
+    Rgba([r, g, b, a.saturating_mul(2)])
+}
+
+/// Draw the edges of explorer tile `(x, y)` which fall within the image.
+fn draw_outline(
+    image: &mut RgbaImage,
+    (x, y): (u32, u32),
+    cell_size: f64,
+    origin: (f64, f64),
+    color: Rgba<u8>,
+) {
+    let width = image.width() as i64;
+    let left = (x as f64 * cell_size - origin.0).round() as i64;
+    let top = (y as f64 * cell_size - origin.1).round() as i64;
+    let right = ((x + 1) as f64 * cell_size - origin.0).round() as i64 - 1;
+    let bottom = ((y + 1) as f64 * cell_size - origin.1).round() as i64 - 1;
+
+    let mut put = |px: i64, py: i64| {
+        if px >= 0 && py >= 0 && px < width && py < width {
+            image.put_pixel(px as u32, py as u32, color);
+        }
+    };
+
+    for px in left.max(0)..=right.min(width - 1) {
+        put(px, top);
+        put(px, bottom);
+    }
+    for py in top.max(0)..=bottom.min(width - 1) {
+        put(left, py);
+        put(right, py);
+    }
+}
+
+/// Largest square of visited tiles, as its top left corner and size.
+///
+/// Each tile records the size of the largest square which has it as the
+/// bottom right corner, building on its neighbors above and to the left.
+fn find_max_square(visited: &HashSet<(u32, u32)>) -> Option<(u32, u32, u32)> {
+    let mut tiles: Vec<_> = visited.iter().copied().collect();
+    tiles.sort_by_key(|&(x, y)| (y, x));
+
+    let mut sizes: HashMap<(u32, u32), u32> = HashMap::with_capacity(tiles.len());
+    let mut best: Option<(u32, u32, u32)> = None;
+
+    for (x, y) in tiles {
+        let size_at = |dx: u32, dy: u32| match (x.checked_sub(dx), y.checked_sub(dy)) {
+            (Some(nx), Some(ny)) => sizes.get(&(nx, ny)).copied().unwrap_or(0),
+            _ => 0,
+        };
+
+        let size = 1 + size_at(1, 0).min(size_at(0, 1)).min(size_at(1, 1));
+        sizes.insert((x, y), size);
+
+        if !matches!(best, Some((_, _, best_size)) if best_size >= size) {
+            best = Some((x + 1 - size, y + 1 - size, size));
+        }
+    }
+
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_square() {
+        // A 3x3 block, with a longer row along the top.
+        let mut visited: HashSet<(u32, u32)> = (0..3)
+            .flat_map(|x| (0..3).map(move |y| (10 + x, 20 + y)))
+            .collect();
+        visited.extend([(13, 20), (14, 20), (0, 0)]);
+
+        let explorer = ExplorerTiles::new(visited);
+        assert_eq!(explorer.max_square, Some((10, 20, 3)));
+        assert!(explorer.in_max_square(12, 22));
+        assert!(!explorer.in_max_square(13, 20));
+
+        assert_eq!(find_max_square(&HashSet::new()), None);
+    }
+
+    #[test]
+    fn test_render_explorer_tile() {
+        let explorer = ExplorerTiles::new(HashSet::from([(4, 6), (5, 6)]));
+
+        // Zoom 12 tile (1, 1) covers explorer tiles (4..8, 4..8), each 64px.
+        let image = explorer.render_tile(Tile::new(1, 1, 12), 256).unwrap();
+        assert_eq!(image.get_pixel(32, 160), &MAX_SQUARE_COLOR);
+        assert_eq!(image.get_pixel(96, 160), &VISITED_COLOR);
+        assert_eq!(image.get_pixel(160, 160), &Rgba([0, 0, 0, 0]));
+        assert_eq!(image.get_pixel(0, 128), &opaque(MAX_SQUARE_COLOR));
+
+        // Zoomed in past the explorer level, tiles are filled entirely.
+        let image = explorer.render_tile(Tile::new(20, 25, 16), 256).unwrap();
+        assert_eq!(image.get_pixel(128, 128), &VISITED_COLOR);
+
+        assert!(explorer.render_tile(Tile::new(0, 0, 12), 256).is_none());
+    }
+}
@@ -0,0 +1,222 @@
+use std::io::Write;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use geo::Simplify;
+use geo_types::MultiLineString;
+use serde::Deserialize;
+use serde_json::json;
+use time::format_description::well_known::Rfc3339;
+
+use crate::activity::{self, ActivitySummary};
+use crate::db::{ActivityFilter, Database};
+use crate::table::{self, ActivityRow, TableFormat};
+
+/// Close enough for converting simplification tolerances to degrees.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Gpx,
+    #[value(name = "geojson")]
+    GeoJson,
+    /// One row per activity, with properties and stats but no tracks.
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Gpx => "gpx",
+            ExportFormat::GeoJson => "geojson",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Gpx => "application/gpx+xml",
+            ExportFormat::GeoJson => "application/geo+json",
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    /// Tabular formats hold all activities in a single file, rather than
+    /// one file per activity.
+    pub fn table_format(&self) -> Option<TableFormat> {
+        match self {
+            ExportFormat::Gpx | ExportFormat::GeoJson => None,
+            ExportFormat::Csv => Some(TableFormat::Csv),
+            ExportFormat::Parquet => Some(TableFormat::Parquet),
+        }
+    }
+}
+
+/// Write the (stored) tracks of an activity in the given format.
+pub fn write<W: Write + Send>(
+    w: W,
+    format: ExportFormat,
+    activity: &ActivitySummary,
+    tracks: &MultiLineString,
+) -> Result<()> {
+    match format {
+        ExportFormat::Gpx => write_gpx(w, activity, tracks),
+        ExportFormat::GeoJson => write_geojson(w, activity, tracks),
+        ExportFormat::Csv | ExportFormat::Parquet => {
+            // A table with a single row.
+            let row = ActivityRow::new(activity.clone(), Some(tracks));
+            table::write(w, format.table_format().unwrap(), &[row])
+        }
+    }
+}
+
+fn write_gpx<W: Write>(w: W, activity: &ActivitySummary, tracks: &MultiLineString) -> Result<()> {
+    let track = gpx::Track {
+        name: activity.title.clone(),
+        segments: tracks
+            .iter()
+            .map(|line| gpx::TrackSegment {
+                points: line.points().map(gpx::Waypoint::new).collect(),
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    let gpx = gpx::Gpx {
+        version: gpx::GpxVersion::Gpx11,
+        creator: Some("hotpot".into()),
+        metadata: Some(gpx::Metadata {
+            name: activity.title.clone(),
+            time: activity.start_time.map(Into::into),
+            ..Default::default()
+        }),
+        tracks: vec![track],
+        ..Default::default()
+    };
+
+    gpx::write(&gpx, w)?;
+    Ok(())
+}
+
+fn write_geojson<W: Write>(
+    w: W,
+    activity: &ActivitySummary,
+    tracks: &MultiLineString,
+) -> Result<()> {
+    serde_json::to_writer(w, &geojson_feature(activity, tracks))?;
+    Ok(())
+}
+
+/// Simplify tracks to within `tolerance` meters.
+pub fn simplify_tracks(tracks: &MultiLineString, tolerance: f64) -> MultiLineString {
+    tracks.simplify(&(tolerance / METERS_PER_DEGREE))
+}
+
+/// Tracks as a GeoJSON MultiLineString geometry.
+pub fn geojson_geometry(tracks: &MultiLineString) -> serde_json::Value {
+    let coordinates: Vec<Vec<[f64; 2]>> = tracks
+        .iter()
+        .map(|line| line.coords().map(|c| [c.x, c.y]).collect())
+        .collect();
+
+    json!({
+        "type": "MultiLineString",
+        "coordinates": coordinates,
+    })
+}
+
+fn geojson_feature(activity: &ActivitySummary, tracks: &MultiLineString) -> serde_json::Value {
+    let mut properties = activity.properties.clone();
+    properties.insert("title".into(), json!(activity.title));
+    properties.insert(
+        "start_time".into(),
+        json!(activity.start_time.and_then(|ts| ts.format(&Rfc3339).ok())),
+    );
+
+    json!({
+        "type": "Feature",
+        "id": activity.id,
+        "geometry": geojson_geometry(tracks),
+        "properties": properties,
+    })
+}
+
+/// Write the activities matching `filter` as a GeoJSON FeatureCollection,
+/// one at a time, so it can be streamed. Tracks have trimming and privacy
+/// masks applied, and are simplified to within `tolerance` meters.
+pub fn write_feature_collection<W: Write>(
+    mut w: W,
+    db: &Database,
+    filter: &ActivityFilter,
+    tolerance: f64,
+) -> Result<()> {
+    w.write_all(br#"{"type":"FeatureCollection","features":["#)?;
+
+    let mut first = true;
+    for activity in activity::list(db, filter, None)? {
+        let Some(tracks) = activity::load_tracks(db, activity.id)? else {
+            continue;
+        };
+
+        let tracks = simplify_tracks(
+            &activity::visible_tracks(&tracks, &activity, &db.config),
+            tolerance,
+        );
+        if tracks.0.is_empty() {
+            continue;
+        }
+
+        if !first {
+            w.write_all(b",")?;
+        }
+        first = false;
+        serde_json::to_writer(&mut w, &geojson_feature(&activity, &tracks))?;
+    }
+
+    w.write_all(b"]}")?;
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo_types::line_string;
+
+    #[test]
+    fn test_geojson() {
+        let activity = ActivitySummary {
+            id: 1,
+            file: "a.gpx".into(),
+            title: Some("Morning Run".into()),
+            start_time: None,
+            properties: serde_json::Map::new(),
+            athlete_id: None,
+            user_id: None,
+        };
+        let tracks = MultiLineString::new(vec![line_string![(x: 1.0, y: 2.0), (x: 3.0, y: 4.0)]]);
+
+        let mut buf = vec![];
+        write(&mut buf, ExportFormat::GeoJson, &activity, &tracks).unwrap();
+
+        let value: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(value["id"], 1);
+        assert_eq!(value["properties"]["title"], "Morning Run");
+        assert_eq!(
+            value["geometry"]["coordinates"],
+            json!([[[1.0, 2.0], [3.0, 4.0]]])
+        );
+
+        // The middle point is less than a meter off the line.
+        let wiggly = MultiLineString::new(vec![
+            line_string![(x: 0.0, y: 0.0), (x: 0.001, y: 0.000001), (x: 0.002, y: 0.0)],
+        ]);
+        assert_eq!(simplify_tracks(&wiggly, 5.0).0[0].0.len(), 2);
+        assert_eq!(simplify_tracks(&wiggly, 0.0).0[0].0.len(), 3);
+    }
+}
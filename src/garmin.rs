@@ -0,0 +1,551 @@
+use std::io::Cursor;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Redirect};
+use axum::routing::{get, post};
+use axum::{headers, Json, Router, TypedHeader};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, Response, Url};
+use rusqlite::params;
+use serde::Deserialize;
+use sha1::Sha1;
+
+use crate::activity;
+use crate::activity::{Compression, MediaType};
+use crate::db::Database;
+use crate::web::AppState;
+
+const REQUEST_TOKEN_URL: &str = "https://connectapi.garmin.com/oauth-service/oauth/request_token";
+const AUTHORIZE_URL: &str = "https://connect.garmin.com/oauthConfirm";
+const ACCESS_TOKEN_URL: &str = "https://connectapi.garmin.com/oauth-service/oauth/access_token";
+const USER_ID_URL: &str = "https://apis.garmin.com/wellness-api/rest/user/id";
+/// Activity files are only ever downloaded from here.
+const API_HOST: &str = "apis.garmin.com";
+
+#[derive(Deserialize)]
+struct OAuthToken {
+    oauth_token: String,
+    oauth_token_secret: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UserId {
+    user_id: String,
+}
+
+#[derive(Clone)]
+pub struct GarminAuth {
+    consumer_key: String,
+    consumer_secret: String,
+}
+
+impl GarminAuth {
+    pub fn from_env() -> Result<GarminAuth> {
+        let get_env =
+            |k| std::env::var(k).map_err(|_| anyhow!("environment variable not set: {}", k));
+
+        let consumer_key = get_env("GARMIN_CONSUMER_KEY")?;
+        let consumer_secret = get_env("GARMIN_CONSUMER_SECRET")?;
+
+        Ok(Self {
+            consumer_key,
+            consumer_secret,
+        })
+    }
+}
+
+/// Percent encode a string as described in RFC 5849, section 3.6
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Garmin's API only supports OAuth 1.0a, so we need to sign each request.
+///
+/// https://oauth.net/core/1.0a/#signing_process
+fn authorization_header(
+    auth: &GarminAuth,
+    method: &Method,
+    url: &Url,
+    token: Option<&OAuthToken>,
+    extra_params: &[(&str, &str)],
+) -> Result<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let timestamp = now.as_secs().to_string();
+    let nonce = format!("{:x}", now.as_nanos());
+
+    let mut oauth_params = vec![
+        ("oauth_consumer_key", auth.consumer_key.as_str()),
+        ("oauth_nonce", nonce.as_str()),
+        ("oauth_signature_method", "HMAC-SHA1"),
+        ("oauth_timestamp", timestamp.as_str()),
+        ("oauth_version", "1.0"),
+    ];
+    if let Some(token) = token {
+        oauth_params.push(("oauth_token", token.oauth_token.as_str()));
+    }
+    oauth_params.extend_from_slice(extra_params);
+
+    // Query parameters are also included in the signature
+    let mut sig_params: Vec<(String, String)> = oauth_params
+        .iter()
+        .map(|(k, v)| (percent_encode(k), percent_encode(v)))
+        .chain(
+            url.query_pairs()
+                .map(|(k, v)| (percent_encode(&k), percent_encode(&v))),
+        )
+        .collect();
+    sig_params.sort();
+
+    let param_str = sig_params
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let mut base_url = url.clone();
+    base_url.set_query(None);
+
+    let base_string = format!(
+        "{}&{}&{}",
+        method.as_str(),
+        percent_encode(base_url.as_str()),
+        percent_encode(&param_str)
+    );
+
+    let signing_key = format!(
+        "{}&{}",
+        percent_encode(&auth.consumer_secret),
+        token
+            .map(|t| percent_encode(&t.oauth_token_secret))
+            .unwrap_or_default()
+    );
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(signing_key.as_bytes())?;
+    mac.update(base_string.as_bytes());
+    let signature = base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+
+    oauth_params.push(("oauth_signature", signature.as_str()));
+
+    let header = oauth_params
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", percent_encode(k), percent_encode(v)))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Ok(format!("OAuth {}", header))
+}
+
+struct GarminClient<'a> {
+    auth: &'a GarminAuth,
+    db: &'a Database,
+}
+
+impl<'a> GarminClient<'a> {
+    async fn signed_request(
+        &self,
+        method: Method,
+        url: &str,
+        token: Option<&OAuthToken>,
+        extra_params: &[(&str, &str)],
+    ) -> Result<Response> {
+        let url = Url::parse(url)?;
+        let header = authorization_header(self.auth, &method, &url, token, extra_params)?;
+
+        let res = reqwest::Client::new()
+            .request(method, url)
+            .header(reqwest::header::AUTHORIZATION, header)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await?;
+            return Err(anyhow!("HTTP request failed with status {status}: {body}"));
+        }
+
+        Ok(res)
+    }
+
+    async fn request_token(&self) -> Result<OAuthToken> {
+        let res = self
+            .signed_request(Method::POST, REQUEST_TOKEN_URL, None, &[])
+            .await?;
+
+        let token: OAuthToken = serde_urlencoded::from_str(&res.text().await?)?;
+
+        let conn = self.db.connection()?;
+        conn.execute(
+            "\
+            INSERT OR REPLACE \
+            INTO garmin_request_tokens (token, secret) \
+            VALUES (?, ?)",
+            params![token.oauth_token, token.oauth_token_secret],
+        )?;
+
+        Ok(token)
+    }
+
+    async fn exchange_token(&self, request_token: &str, verifier: &str) -> Result<String> {
+        let request_token = {
+            let conn = self.db.connection()?;
+            let secret: String = conn
+                .query_row(
+                    "SELECT secret FROM garmin_request_tokens WHERE token = ?",
+                    [request_token],
+                    |row| row.get(0),
+                )
+                .map_err(|_| anyhow!("unknown request token: {request_token}"))?;
+
+            conn.execute(
+                "DELETE FROM garmin_request_tokens WHERE token = ?",
+                [request_token],
+            )?;
+
+            OAuthToken {
+                oauth_token: request_token.to_string(),
+                oauth_token_secret: secret,
+            }
+        };
+
+        let res = self
+            .signed_request(
+                Method::POST,
+                ACCESS_TOKEN_URL,
+                Some(&request_token),
+                &[("oauth_verifier", verifier)],
+            )
+            .await?;
+
+        let token: OAuthToken = serde_urlencoded::from_str(&res.text().await?)?;
+
+        let res = self
+            .signed_request(Method::GET, USER_ID_URL, Some(&token), &[])
+            .await?;
+        let user: UserId = res.json().await?;
+
+        self.store_token(&user.user_id, &token)?;
+        Ok(user.user_id)
+    }
+
+    fn store_token(&self, user_id: &str, token: &OAuthToken) -> Result<()> {
+        let conn = self.db.connection()?;
+        conn.execute(
+            "\
+            INSERT OR REPLACE \
+            INTO garmin_tokens (user_id, access_token, token_secret) \
+            VALUES (?, ?, ?)",
+            params![user_id, token.oauth_token, token.oauth_token_secret],
+        )?;
+
+        Ok(())
+    }
+
+    fn get_token(&self, user_id: &str) -> Result<OAuthToken> {
+        let conn = self.db.connection()?;
+        let mut stmt = conn.prepare(
+            "\
+            SELECT access_token, token_secret \
+            FROM garmin_tokens \
+            WHERE user_id = ?",
+        )?;
+
+        stmt.query_row([user_id], |row| {
+            Ok(OAuthToken {
+                oauth_token: row.get_unwrap(0),
+                oauth_token_secret: row.get_unwrap(1),
+            })
+        })
+        .map_err(|_| anyhow!("no credentials available for: {user_id}"))
+    }
+
+    /// Check that a pushed activity file belongs to a user we have a token
+    /// for, and that its callback points at Garmin's API. The webhook isn't
+    /// authenticated, so otherwise anyone could have us send signed requests
+    /// to an arbitrary URL.
+    fn verify_activity_file(&self, file: &ActivityFile) -> Result<OAuthToken> {
+        let url = Url::parse(&file.callback_url)?;
+        if url.scheme() != "https" || url.host_str() != Some(API_HOST) || url.port().is_some() {
+            bail!("unexpected callback URL: {}", file.callback_url);
+        }
+
+        let token = self.get_token(&file.user_id)?;
+        if token.oauth_token != file.user_access_token {
+            bail!("access token doesn't match for: {}", file.user_id);
+        }
+
+        Ok(token)
+    }
+
+    async fn download_activity_file(
+        &self,
+        file: &ActivityFile,
+        token: &OAuthToken,
+    ) -> Result<Vec<u8>> {
+        let res = self
+            .signed_request(Method::GET, &file.callback_url, Some(token), &[])
+            .await?;
+
+        Ok(res.bytes().await?.to_vec())
+    }
+}
+
+pub fn webhook_routes() -> Router<AppState> {
+    Router::new().route("/webhook", post(receive_webhook))
+}
+
+pub fn auth_routes() -> Router<AppState> {
+    Router::new()
+        .route("/auth", get(auth_redirect))
+        .route("/auth/exchange_token", get(exchange_token))
+}
+
+async fn auth_redirect(
+    TypedHeader(host): TypedHeader<headers::Host>,
+    State(AppState { db, garmin, .. }): State<AppState>,
+) -> impl IntoResponse {
+    let garmin = garmin.expect("garmin auth creds missing");
+
+    let client = GarminClient {
+        auth: &garmin,
+        db: &db,
+    };
+
+    let token = match client.request_token().await {
+        Ok(token) => token,
+        Err(e) => {
+            tracing::error!("failed to get request token: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "error getting request token",
+            )
+                .into_response();
+        }
+    };
+
+    let callback = format!("http://{}/garmin/auth/exchange_token", host);
+    let url = format!(
+        "{}?oauth_token={}&oauth_callback={}",
+        AUTHORIZE_URL,
+        percent_encode(&token.oauth_token),
+        percent_encode(&callback),
+    );
+
+    Redirect::to(&url).into_response()
+}
+
+#[derive(Deserialize)]
+struct ExchangeTokenQuery {
+    oauth_token: String,
+    oauth_verifier: String,
+}
+
+async fn exchange_token(
+    State(AppState { db, garmin, .. }): State<AppState>,
+    Query(params): Query<ExchangeTokenQuery>,
+) -> impl IntoResponse {
+    let garmin = garmin.expect("garmin auth creds missing");
+
+    let client = GarminClient {
+        auth: &garmin,
+        db: &db,
+    };
+
+    let user_id = match client
+        .exchange_token(&params.oauth_token, &params.oauth_verifier)
+        .await
+    {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            tracing::error!("failed to exchange token: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "error exchanging token").into_response();
+        }
+    };
+
+    (
+        StatusCode::OK,
+        format!(
+            "Successfully authenticated with Garmin Connect (user ID: {user_id}).
+
+Next, make sure push notifications are enabled for activity files in the
+Garmin developer portal, using the following endpoint:
+
+    https://[example.com]/garmin/webhook
+
+More information: https://developer.garmin.com/gc-developer-program/activity-api/
+",
+        ),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ActivityFile {
+    user_id: String,
+    user_access_token: String,
+    summary_id: String,
+    file_type: String,
+    #[serde(rename = "callbackURL")]
+    callback_url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WebhookBody {
+    #[serde(default)]
+    activity_files: Vec<ActivityFile>,
+}
+
+async fn receive_webhook(
+    State(AppState {
+        db,
+        garmin,
+        tile_cache,
+        ..
+    }): State<AppState>,
+    Json(body): Json<WebhookBody>,
+) -> impl IntoResponse {
+    let garmin = garmin.expect("garmin auth creds missing");
+
+    let client = GarminClient {
+        auth: &garmin,
+        db: &db,
+    };
+
+    for file in &body.activity_files {
+        let media_type = match file.file_type.as_str() {
+            "FIT" => MediaType::Fit,
+            "TCX" => MediaType::Tcx,
+            "GPX" => MediaType::Gpx,
+            kind => {
+                tracing::warn!("skipping unsupported activity file type: {}", kind);
+                continue;
+            }
+        };
+
+        let token = match client.verify_activity_file(file) {
+            Ok(token) => token,
+            Err(e) => {
+                tracing::warn!("rejecting activity file: {}", e);
+                return (StatusCode::BAD_REQUEST, "invalid activity file");
+            }
+        };
+
+        let bytes = match client.download_activity_file(file, &token).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::error!("error getting activity file: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "error getting activity");
+            }
+        };
+
+        let activities = match activity::read(Cursor::new(bytes), media_type, Compression::None) {
+            Ok(activities) => activities,
+            Err(e) => {
+                tracing::error!("error reading activity file: {}", e);
+                continue;
+            }
+        };
+
+        let mut conn = match db.connection() {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!("error getting database connection: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "error writing activity");
+            }
+        };
+
+        let name = format!("garmin:{}", file.summary_id);
+        for (i, activity) in activities.iter().enumerate() {
+            if let Err(e) = activity::upsert(
+                &mut conn,
+                &activity::session_name(&name, i),
+                activity,
+                &db.config,
+            ) {
+                tracing::error!("error writing activity: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "error writing activity");
+            }
+        }
+
+        tile_cache.clear();
+    }
+
+    (StatusCode::OK, "added!")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("abc-._~123"), "abc-._~123");
+        assert_eq!(percent_encode("a b&c=d"), "a%20b%26c%3Dd");
+        assert_eq!(percent_encode("http://x/y"), "http%3A%2F%2Fx%2Fy");
+    }
+
+    #[test]
+    fn test_verify_activity_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("db.sqlite3")).unwrap();
+        let auth = GarminAuth {
+            consumer_key: "key".into(),
+            consumer_secret: "secret".into(),
+        };
+        let client = GarminClient {
+            auth: &auth,
+            db: &db,
+        };
+        let token = OAuthToken {
+            oauth_token: "token".into(),
+            oauth_token_secret: "token-secret".into(),
+        };
+        client.store_token("user", &token).unwrap();
+
+        let file = |user_id: &str, access_token: &str, url: &str| ActivityFile {
+            user_id: user_id.into(),
+            user_access_token: access_token.into(),
+            summary_id: "1".into(),
+            file_type: "FIT".into(),
+            callback_url: url.into(),
+        };
+        let url = "https://apis.garmin.com/wellness-api/rest/activityFile?id=1";
+        assert!(client
+            .verify_activity_file(&file("user", "token", url))
+            .is_ok());
+
+        assert!(client
+            .verify_activity_file(&file("user", "other", url))
+            .is_err());
+        assert!(client
+            .verify_activity_file(&file("unknown", "token", url))
+            .is_err());
+
+        for url in [
+            "http://apis.garmin.com/wellness-api/rest/activityFile?id=1",
+            "https://apis.garmin.com:8443/activityFile",
+            "https://apis.garmin.com.example.com/activityFile",
+            "https://example.com/?apis.garmin.com",
+            "file:///etc/passwd",
+        ] {
+            assert!(client
+                .verify_activity_file(&file("user", "token", url))
+                .is_err());
+        }
+    }
+}
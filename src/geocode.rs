@@ -0,0 +1,262 @@
+//! Reverse geocode where activities start and end, using a (self-hosted)
+//! Nominatim or Photon server, so they can be filtered by place, e.g.
+//! `{"start_country": {"=": "FR"}}`.
+//!
+//! Results are cached in the database by rounded coordinates, since most
+//! activities start and end in the same few places.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use geo_types::Coord;
+use reqwest::Url;
+use rusqlite::{params, OptionalExtension};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::activity;
+use crate::db::Database;
+
+/// Decimal places kept for cache keys, about 100m.
+const CACHE_PRECISION: f64 = 1000.0;
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum Provider {
+    #[default]
+    Nominatim,
+    Photon,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Place {
+    city: Option<String>,
+    /// ISO 3166-1 alpha-2 code, upper case.
+    country: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NominatimResponse {
+    #[serde(default)]
+    address: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct PhotonResponse {
+    features: Vec<PhotonFeature>,
+}
+
+#[derive(Deserialize)]
+struct PhotonFeature {
+    properties: HashMap<String, Value>,
+}
+
+impl Place {
+    fn from_nominatim(res: NominatimResponse) -> Self {
+        // Smaller places don't have a `city`.
+        let city = ["city", "town", "village", "municipality", "hamlet"]
+            .iter()
+            .find_map(|key| res.address.get(*key).cloned());
+
+        Place {
+            city,
+            country: res.address.get("country_code").map(|c| c.to_uppercase()),
+        }
+    }
+
+    fn from_photon(res: PhotonResponse) -> Self {
+        let Some(feature) = res.features.into_iter().next() else {
+            return Place::default();
+        };
+
+        let prop = |key| feature.properties.get(key).and_then(Value::as_str);
+        // Points within a city's boundary can resolve to the city itself.
+        let city = prop("city").or_else(|| match prop("type") {
+            Some("city") => prop("name"),
+            _ => None,
+        });
+
+        Place {
+            city: city.map(str::to_string),
+            country: prop("countrycode").map(str::to_uppercase),
+        }
+    }
+}
+
+fn cache_key(coord: Coord) -> (f64, f64) {
+    (
+        (coord.x * CACHE_PRECISION).round() / CACHE_PRECISION,
+        (coord.y * CACHE_PRECISION).round() / CACHE_PRECISION,
+    )
+}
+
+pub struct Geocoder {
+    client: reqwest::Client,
+    base_url: Url,
+    provider: Provider,
+}
+
+impl Geocoder {
+    pub fn new(base_url: &str, provider: Provider) -> Result<Self> {
+        // Make sure relative URLs are resolved under the base path.
+        let base_url = Url::parse(&format!("{}/", base_url.trim_end_matches('/')))?;
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("hotpot/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+
+        Ok(Geocoder {
+            client,
+            base_url,
+            provider,
+        })
+    }
+
+    async fn reverse(&self, coord: Coord) -> Result<Place> {
+        let mut url = self.base_url.join("reverse")?;
+        let (lng, lat) = (coord.x.to_string(), coord.y.to_string());
+        match self.provider {
+            Provider::Nominatim => url
+                .query_pairs_mut()
+                .extend_pairs([("format", "jsonv2"), ("zoom", "10")])
+                .extend_pairs([("lat", lat), ("lon", lng)]),
+            Provider::Photon => url
+                .query_pairs_mut()
+                .extend_pairs([("lat", lat), ("lon", lng)]),
+        };
+
+        let res = self.client.get(url).send().await?;
+        if !res.status().is_success() {
+            return Err(anyhow!("geocoding failed with status {}", res.status()));
+        }
+
+        Ok(match self.provider {
+            Provider::Nominatim => Place::from_nominatim(res.json().await?),
+            Provider::Photon => Place::from_photon(res.json().await?),
+        })
+    }
+
+    async fn lookup(&self, db: &Database, coord: Coord) -> Result<Place> {
+        let (lng, lat) = cache_key(coord);
+
+        let cached = db
+            .connection()?
+            .query_row(
+                "SELECT city, country FROM geocode_cache WHERE lng = ? AND lat = ?",
+                params![lng, lat],
+                |row| {
+                    Ok(Place {
+                        city: row.get(0)?,
+                        country: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        if let Some(place) = cached {
+            return Ok(place);
+        }
+
+        let place = self.reverse(Coord { x: lng, y: lat }).await?;
+        db.connection()?.execute(
+            "INSERT OR REPLACE INTO geocode_cache (lng, lat, city, country) VALUES (?, ?, ?, ?)",
+            params![lng, lat, place.city, place.country],
+        )?;
+
+        Ok(place)
+    }
+}
+
+fn place_properties(prefix: &str, place: Place) -> impl Iterator<Item = (String, Value)> + '_ {
+    [("city", place.city), ("country", place.country)]
+        .into_iter()
+        .filter_map(move |(key, value)| Some((format!("{}_{}", prefix, key), value?.into())))
+}
+
+/// Add `start_city`, `start_country`, `end_city` and `end_country`
+/// properties to activities which don't have them yet (or all, with
+/// `overwrite`). Returns the number of activities updated.
+pub async fn geocode_activities(
+    db: &Database,
+    geocoder: &Geocoder,
+    overwrite: bool,
+) -> Result<usize> {
+    let ids = db
+        .connection()?
+        .prepare("SELECT id FROM activities WHERE ? OR properties ->> '$.start_country' IS NULL")?
+        .query_map(params![overwrite], |row| row.get::<_, i64>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    tracing::info!(num_activities = ids.len(), "reverse geocoding activities");
+
+    let mut num_updated = 0;
+    for id in ids {
+        let Some(tracks) = activity::load_tracks(db, id)? else {
+            continue;
+        };
+        let mut coords = tracks.iter().flat_map(|line| line.coords());
+        let (Some(start), Some(end)) = (coords.next().copied(), coords.last().copied()) else {
+            continue;
+        };
+
+        let mut properties: HashMap<String, Value> = HashMap::new();
+        properties.extend(place_properties("start", geocoder.lookup(db, start).await?));
+        properties.extend(place_properties("end", geocoder.lookup(db, end).await?));
+        if properties.is_empty() {
+            continue;
+        }
+
+        db.connection()?.execute(
+            "UPDATE activities SET properties = json_patch(properties, ?) WHERE id = ?",
+            params![serde_json::to_string(&properties)?, id],
+        )?;
+        num_updated += 1;
+    }
+
+    Ok(num_updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_places() {
+        let res = serde_json::from_str(
+            r#"{"place_id": 1, "address": {"town": "Annecy", "state": "Auvergne-Rhône-Alpes", "country": "France", "country_code": "fr"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Place::from_nominatim(res),
+            Place {
+                city: Some("Annecy".to_string()),
+                country: Some("FR".to_string()),
+            }
+        );
+
+        // Nothing nearby, e.g. in the middle of the ocean.
+        let res = serde_json::from_str(r#"{"error": "Unable to geocode"}"#).unwrap();
+        assert_eq!(Place::from_nominatim(res), Place::default());
+
+        let res = serde_json::from_str(
+            r#"{"type": "FeatureCollection", "features": [{"type": "Feature", "geometry": {"type": "Point", "coordinates": [13.4, 52.5]}, "properties": {"name": "Berlin", "type": "city", "country": "Germany", "countrycode": "DE"}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Place::from_photon(res),
+            Place {
+                city: Some("Berlin".to_string()),
+                country: Some("DE".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_cache_key() {
+        assert_eq!(
+            cache_key(Coord {
+                x: 13.40494,
+                y: 52.52001
+            }),
+            (13.405, 52.52)
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Raw visit counts behind the heatmap, for analysis outside of the rendered
+//! tiles (e.g. finding the most ridden stretches of road).
+//!
+//! The map is divided into cells, which are the tiles at a chosen zoom level,
+//! and each cell counts the number of distinct activities passing through it.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+use geo_types::{Coord, Point};
+use image::{ImageBuffer, Luma};
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::db::{decode_line, ActivityFilter, Database};
+use crate::raster::MAX_ZOOM;
+use crate::spatial_index;
+use crate::tile::{Tile, TileBounds, WebMercator, WebMercatorViewport};
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct HeatCell {
+    /// Tile coordinates of the cell at the requested zoom level.
+    pub x: u32,
+    pub y: u32,
+    /// Center of the cell.
+    pub lng: f64,
+    pub lat: f64,
+    pub count: u32,
+}
+
+pub struct HeatMap {
+    /// Range of cells covered by the requested area.
+    pub bounds: TileBounds,
+    counts: HashMap<(u32, u32), u32>,
+}
+
+impl HeatMap {
+    /// Cells with at least one visit, most visited first.
+    pub fn cells(&self) -> Vec<HeatCell> {
+        let mut cells: Vec<HeatCell> = self
+            .counts
+            .iter()
+            .map(|(&(x, y), &count)| {
+                let center = cell_center(&Tile::new(x, y, self.bounds.z)).lnglat().0;
+                HeatCell {
+                    x,
+                    y,
+                    lng: center.x(),
+                    lat: center.y(),
+                    count,
+                }
+            })
+            .collect();
+
+        cells.sort_by(|a, b| b.count.cmp(&a.count).then((a.y, a.x).cmp(&(b.y, b.x))));
+        cells
+    }
+
+    /// One pixel per cell, holding the raw visit count.
+    pub fn to_image(&self) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        let bounds = &self.bounds;
+        ImageBuffer::from_fn(
+            bounds.xmax - bounds.xmin,
+            bounds.ymax - bounds.ymin,
+            |x, y| {
+                let count = self
+                    .counts
+                    .get(&(bounds.xmin + x, bounds.ymin + y))
+                    .copied()
+                    .unwrap_or_default();
+                Luma([count.min(u16::MAX as u32) as u16])
+            },
+        )
+    }
+}
+
+fn cell_center(cell: &Tile) -> WebMercator {
+    let bbox = cell.xy_bounds();
+    WebMercator(Point::new(
+        (bbox.left + bbox.right) / 2.0,
+        (bbox.top + bbox.bot) / 2.0,
+    ))
+}
+
+/// Count the activities matching `filter` which pass through each cell (tile
+/// at zoom level `z`) within `viewport`. Cells inside privacy masks are left
+/// out.
+pub fn count_visits(
+    db: &Database,
+    filter: &ActivityFilter,
+    viewport: &WebMercatorViewport,
+    z: u8,
+) -> Result<HeatMap> {
+    if z > MAX_ZOOM {
+        return Err(anyhow!("zoom level must be <= {}", MAX_ZOOM));
+    }
+
+    let source_zoom = db
+        .config
+        .source_level(z)
+        .unwrap_or(*db.config.zoom_range().end() as u8);
+    let source = viewport.tile_bounds(source_zoom);
+    let bounds = viewport.tile_bounds(z);
+
+    let conn = db.connection()?;
+    let mut params = params![source.z, source.xmin, source.xmax, source.ymin, source.ymax].to_vec();
+    let filter_clause = filter.to_query(&mut params);
+
+    let mut stmt = conn.prepare(&format!(
+        "\
+        SELECT activity_id, x, y, coords \
+        FROM activity_tiles \
+        JOIN activities ON activities.id = activity_tiles.activity_id \
+        WHERE {} AND {} \
+        ORDER BY activity_id;",
+        spatial_index::range_clause(&db.config),
+        filter_clause,
+    ))?;
+
+    let mut counts = HashMap::new();
+    let mut add_visits = |cells: &mut HashSet<(u32, u32)>| {
+        for (x, y) in cells.drain() {
+            if x >= bounds.xmin && x < bounds.xmax && y >= bounds.ymin && y < bounds.ymax {
+                *counts.entry((x, y)).or_insert(0) += 1;
+            }
+        }
+    };
+
+    // Activities are spread over several tiles, but should only count once
+    // per cell.
+    let mut cells = HashSet::new();
+    let mut current = None;
+    let mut rows = stmt.query(params.as_slice())?;
+    while let Some(row) = rows.next()? {
+        let activity_id: i64 = row.get_unwrap(0);
+        if current != Some(activity_id) {
+            add_visits(&mut cells);
+            current = Some(activity_id);
+        }
+
+        let tile = Tile::new(row.get_unwrap(1), row.get_unwrap(2), source.z);
+        let bytes: Vec<u8> = row.get_unwrap(3);
+        cells_along(
+            &decode_line(&bytes)?,
+            &tile,
+            db.config.tile_extent,
+            z,
+            &mut cells,
+        );
+    }
+    add_visits(&mut cells);
+
+    let masks = &db.config.masks;
+    counts.retain(|&(x, y), _| {
+        let center = cell_center(&Tile::new(x, y, z));
+        !masks.iter().any(|mask| mask.contains(&center))
+    });
+
+    Ok(HeatMap { bounds, counts })
+}
+
+/// Add the cells at zoom level `z` which the line (stored in `tile`) passes
+/// through.
+fn cells_along(
+    coords: &[Coord<u32>],
+    tile: &Tile,
+    tile_extent: u32,
+    z: u8,
+    cells: &mut HashSet<(u32, u32)>,
+) {
+    let extent = tile_extent as i64;
+    let shift = tile.z as i32 + tile_extent.ilog2() as i32 - z as i32;
+    let to_cell = |Coord { x, y }: &Coord<u32>| {
+        // Position in the global pixel grid of the source zoom level.
+        let x = tile.x as i64 * extent + *x as i64;
+        let y = tile.y as i64 * extent + (extent - *y as i64);
+
+        if shift >= 0 {
+            (x >> shift, y >> shift)
+        } else {
+            (x << -shift, y << -shift)
+        }
+    };
+
+    let max_idx = (1i64 << z) - 1;
+    let mut add = |(x, y): (i64, i64)| {
+        cells.insert((x.clamp(0, max_idx) as u32, y.clamp(0, max_idx) as u32));
+    };
+
+    let mut prev = None;
+    for coord in coords {
+        let cell = to_cell(coord);
+        match prev {
+            None => add(cell),
+            Some(prev) if prev == cell => continue,
+            Some(prev) => line_drawing::Bresenham::new(prev, cell).for_each(&mut add),
+        }
+        prev = Some(cell);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cells_along() {
+        let tile = Tile::new(2, 1, 2);
+        // Horizontal line across the middle of the tile.
+        let line = [Coord { x: 0, y: 2048 }, Coord { x: 4095, y: 2048 }];
+
+        let mut cells = HashSet::new();
+        cells_along(&line, &tile, 4096, 2, &mut cells);
+        assert_eq!(cells, HashSet::from([(2, 1)]));
+
+        // Two zoom levels deeper, the tile is split into 4x4 cells.
+        cells.clear();
+        cells_along(&line, &tile, 4096, 4, &mut cells);
+        assert_eq!(cells, HashSet::from([(8, 6), (9, 6), (10, 6), (11, 6)]));
+
+        // And at a lower zoom, it's part of a larger cell.
+        cells.clear();
+        cells_along(&line, &tile, 4096, 1, &mut cells);
+        assert_eq!(cells, HashSet::from([(1, 0)]));
+    }
+}
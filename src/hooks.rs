@@ -0,0 +1,232 @@
+//! User defined hooks which compute or rewrite activity properties before
+//! they're stored, e.g. tagging the country an activity was in, or
+//! classifying commutes by where they start and end.
+//!
+//! Hooks are external commands, listed in a TOML file:
+//!
+//! ```toml
+//! [[hook]]
+//! command = ["python3", "/etc/hotpot/commute.py"]
+//! timeout = 10
+//! ```
+//!
+//! Each command is run once per activity, with the activity as JSON on stdin.
+//! It prints a JSON object of properties to set (or remove, with `null`) on
+//! stdout. Hooks run in order, each seeing the changes of the previous ones.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::activity::RawActivity;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    #[serde(default, rename = "hook")]
+    hooks: Vec<Hook>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Hook {
+    /// Program and arguments, not run through a shell.
+    command: Vec<String>,
+    /// Seconds to wait for the command before failing the import.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT_SECS
+}
+
+/// What a hook gets to see of an activity.
+#[derive(Serialize)]
+struct HookInput<'a> {
+    name: &'a str,
+    title: Option<&'a str>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    start_time: Option<OffsetDateTime>,
+    /// First and last point, as `[lng, lat]`.
+    start: Option<[f64; 2]>,
+    end: Option<[f64; 2]>,
+    properties: &'a HashMap<String, Value>,
+}
+
+impl Hooks {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read hooks from {}", path.display()))?;
+        Self::parse(&text)
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let hooks: Hooks = toml::from_str(text)?;
+        if hooks.hooks.iter().any(|hook| hook.command.is_empty()) {
+            return Err(anyhow!("hook command must not be empty"));
+        }
+        Ok(hooks)
+    }
+
+    /// Run each hook over the activity stored as `name`, returning it with
+    /// the updated properties.
+    pub fn apply<'a>(&self, name: &str, activity: &'a RawActivity) -> Result<Cow<'a, RawActivity>> {
+        if self.hooks.is_empty() {
+            return Ok(Cow::Borrowed(activity));
+        }
+
+        let coords = || activity.tracks.iter().flat_map(|line| line.coords());
+        let start = coords().next().map(|c| [c.x, c.y]);
+        let end = coords().last().map(|c| [c.x, c.y]);
+
+        let mut properties = activity.properties.clone();
+        for hook in &self.hooks {
+            let input = serde_json::to_vec(&HookInput {
+                name,
+                title: activity.title.as_deref(),
+                start_time: activity.start_time,
+                start,
+                end,
+                properties: &properties,
+            })?;
+
+            let output = hook
+                .run(&input)
+                .with_context(|| format!("hook {:?} failed", hook.command))?;
+            merge_properties(&mut properties, output);
+        }
+
+        Ok(Cow::Owned(RawActivity {
+            properties,
+            ..activity.clone()
+        }))
+    }
+}
+
+impl Hook {
+    fn run(&self, input: &[u8]) -> Result<HashMap<String, Value>> {
+        let mut child = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        // Read on another thread, so a chatty command can't fill up the pipe
+        // and block while we wait for it to exit.
+        let mut stdout = child.stdout.take().expect("piped");
+        let reader = std::thread::spawn(move || {
+            let mut buf = vec![];
+            stdout.read_to_end(&mut buf).map(|_| buf)
+        });
+
+        // Dropping stdin closes it, so the command knows the input is done.
+        // It may exit without reading any of it, which is fine.
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(input);
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(self.timeout);
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() > deadline {
+                child.kill()?;
+                child.wait()?;
+                return Err(anyhow!("timed out after {}s", self.timeout));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        if !status.success() {
+            return Err(anyhow!("exited with {}", status));
+        }
+
+        let output = reader.join().expect("reader panicked")?;
+        if output.iter().all(u8::is_ascii_whitespace) {
+            return Ok(HashMap::new());
+        }
+
+        serde_json::from_slice(&output).context("expected a JSON object of properties")
+    }
+}
+
+/// Set the properties returned by a hook, removing those set to `null`.
+fn merge_properties(properties: &mut HashMap<String, Value>, output: HashMap<String, Value>) {
+    for (key, value) in output {
+        if value.is_null() {
+            properties.remove(&key);
+        } else {
+            properties.insert(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo_types::{LineString, MultiLineString};
+
+    #[test]
+    fn test_parse_hooks() {
+        let hooks = Hooks::parse(
+            r#"
+            [[hook]]
+            command = ["python3", "commute.py"]
+
+            [[hook]]
+            command = ["./country"]
+            timeout = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(hooks.hooks.len(), 2);
+        assert_eq!(hooks.hooks[0].timeout, DEFAULT_TIMEOUT_SECS);
+        assert_eq!(hooks.hooks[1].timeout, 5);
+
+        assert!(Hooks::parse("").unwrap().hooks.is_empty());
+        assert!(Hooks::parse("[[hook]]\ncommand = []").is_err());
+        assert!(Hooks::parse("[[hook]]\ncmd = [\"x\"]").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_hooks() {
+        let hooks = Hooks::parse(
+            r#"
+            [[hook]]
+            command = ["sh", "-c", "cat > /dev/null; echo '{\"commute\": true, \"device\": null}'"]
+            "#,
+        )
+        .unwrap();
+
+        let activity = RawActivity {
+            title: None,
+            start_time: None,
+            tracks: MultiLineString::new(vec![LineString::from(vec![(13.4, 52.5), (13.5, 52.6)])]),
+            properties: HashMap::from([
+                ("device".to_string(), "Watch".into()),
+                ("distance".to_string(), 1000.into()),
+            ]),
+            content_hash: None,
+            athlete_id: None,
+            user_id: None,
+        };
+
+        let updated = hooks.apply("ride.gpx", &activity).unwrap();
+        assert_eq!(updated.properties["commute"], true);
+        assert_eq!(updated.properties["distance"], 1000);
+        assert!(!updated.properties.contains_key("device"));
+    }
+}
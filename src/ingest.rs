@@ -0,0 +1,152 @@
+//! Activities pushed as JSON, for sources which don't produce activity files
+//! (home automation, OwnTracks, custom scripts, ...).
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use geo_types::{Coord, LineString, MultiLineString};
+use serde::Deserialize;
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::activity::RawActivity;
+
+/// Body of `POST /api/ingest`, with exactly one of `geometry` or `polyline`.
+#[derive(Deserialize)]
+pub struct IngestBody {
+    /// Identifies the activity, pushing the same ID again replaces it.
+    pub id: String,
+    pub title: Option<String>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub start_time: Option<OffsetDateTime>,
+    /// GeoJSON `LineString` or `MultiLineString`.
+    pub geometry: Option<Geometry>,
+    /// Encoded polyline (lat/lng, as used by Google and Strava).
+    pub polyline: Option<String>,
+    /// Number of decimal places the polyline was encoded with.
+    #[serde(default = "default_precision")]
+    pub precision: u32,
+    #[serde(default)]
+    pub properties: HashMap<String, Value>,
+}
+
+fn default_precision() -> u32 {
+    5
+}
+
+/// GeoJSON positions may include elevation, which we ignore.
+type Position = Vec<f64>;
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+pub enum Geometry {
+    LineString { coordinates: Vec<Position> },
+    MultiLineString { coordinates: Vec<Vec<Position>> },
+}
+
+fn to_line(positions: Vec<Position>) -> Result<LineString> {
+    positions
+        .into_iter()
+        .map(|pos| match pos[..] {
+            [lng, lat, ..] if (-180.0..=180.0).contains(&lng) && (-90.0..=90.0).contains(&lat) => {
+                Ok(Coord { x: lng, y: lat })
+            }
+            _ => Err(anyhow!("invalid position: {:?}", pos)),
+        })
+        .collect()
+}
+
+impl IngestBody {
+    pub fn into_activity(self) -> Result<RawActivity> {
+        let lines = match (self.geometry, self.polyline) {
+            (Some(Geometry::LineString { coordinates }), None) => vec![to_line(coordinates)?],
+            (Some(Geometry::MultiLineString { coordinates }), None) => coordinates
+                .into_iter()
+                .map(to_line)
+                .collect::<Result<_>>()?,
+            (None, Some(polyline)) => vec![polyline::decode_polyline(&polyline, self.precision)
+                .map_err(|e| anyhow!("invalid polyline: {}", e))?],
+            _ => return Err(anyhow!("expected one of `geometry` or `polyline`")),
+        };
+
+        let tracks: MultiLineString = lines.into_iter().filter(|line| line.0.len() > 1).collect();
+
+        if tracks.0.is_empty() {
+            return Err(anyhow!("no line with at least two points"));
+        }
+
+        Ok(RawActivity {
+            title: self.title,
+            start_time: self.start_time,
+            tracks,
+            properties: self.properties,
+            content_hash: None,
+            athlete_id: None,
+            user_id: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: Value) -> Result<RawActivity> {
+        serde_json::from_value::<IngestBody>(body)?.into_activity()
+    }
+
+    #[test]
+    fn test_geojson() {
+        let activity = parse(serde_json::json!({
+            "id": "walk",
+            "start_time": "2024-05-01T18:30:00Z",
+            "geometry": {
+                "type": "MultiLineString",
+                "coordinates": [
+                    [[13.40, 52.52, 34.0], [13.41, 52.53, 35.0]],
+                    [[13.42, 52.54]]
+                ]
+            },
+            "properties": {"activity_type": "walk"}
+        }))
+        .unwrap();
+
+        // Single point lines are dropped.
+        assert_eq!(activity.tracks.0.len(), 1);
+        assert_eq!(activity.tracks.0[0].0[1], Coord { x: 13.41, y: 52.53 });
+        assert_eq!(activity.properties["activity_type"], "walk");
+        assert!(activity.start_time.is_some());
+    }
+
+    #[test]
+    fn test_polyline() {
+        let activity = parse(serde_json::json!({
+            "id": "ride",
+            "polyline": "_p~iF~ps|U_ulLnnqC_mqNvxq`@"
+        }))
+        .unwrap();
+
+        assert_eq!(activity.tracks.0[0].0.len(), 3);
+        assert_eq!(activity.tracks.0[0].0[0], Coord { x: -120.2, y: 38.5 });
+    }
+
+    #[test]
+    fn test_invalid() {
+        let line = serde_json::json!({"type": "LineString", "coordinates": [[0, 0], [1, 1]]});
+
+        assert!(parse(serde_json::json!({"id": "none"})).is_err());
+        assert!(
+            parse(serde_json::json!({"id": "both", "geometry": line, "polyline": "??"})).is_err()
+        );
+        assert!(parse(serde_json::json!({
+            "id": "out of range",
+            "geometry": {"type": "LineString", "coordinates": [[0, 0], [0, 100]]}
+        }))
+        .is_err());
+        assert!(parse(serde_json::json!({
+            "id": "point",
+            "geometry": {"type": "Point", "coordinates": [0, 0]}
+        }))
+        .is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! Persistent queue of work to be done in the background, such as fetching
+//! activities from Strava after receiving a webhook event.
+//!
+//! Jobs are stored in the database so they survive restarts. Each has a
+//! `kind` identifying who processes it, and a JSON payload. Failed jobs are
+//! retried with exponential backoff, and kept around (as `failed`) after
+//! running out of attempts.
+
+use anyhow::Result;
+use rusqlite::params;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use time::{Duration, OffsetDateTime};
+
+/// Number of times to try a job before giving up on it.
+pub const MAX_ATTEMPTS: u32 = 8;
+
+/// Delay before the first retry, doubled after each further failure.
+const INITIAL_BACKOFF: Duration = Duration::minutes(1);
+const MAX_BACKOFF: Duration = Duration::hours(6);
+
+const STATUS_PENDING: &str = "pending";
+const STATUS_FAILED: &str = "failed";
+
+pub struct Job<T> {
+    pub id: i64,
+    pub attempts: u32,
+    pub payload: T,
+}
+
+/// A job as shown by `GET /api/jobs`.
+#[derive(Debug, Serialize)]
+pub struct JobInfo {
+    pub id: i64,
+    pub kind: String,
+    pub status: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub payload: serde_json::Value,
+    #[serde(with = "time::serde::rfc3339")]
+    pub run_after: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+/// Add a job which should run no earlier than `run_after`.
+pub fn enqueue<T: Serialize>(
+    conn: &rusqlite::Connection,
+    kind: &str,
+    payload: &T,
+    run_after: OffsetDateTime,
+) -> Result<i64> {
+    conn.execute(
+        "\
+        INSERT INTO jobs (kind, payload, run_after, created_at) \
+        VALUES (?, ?, ?, ?)",
+        params![
+            kind,
+            serde_json::to_string(payload)?,
+            run_after.unix_timestamp(),
+            OffsetDateTime::now_utc().unix_timestamp(),
+        ],
+    )?;
+
+    Ok(conn.last_insert_rowid())
+}
+
+/// Pending jobs of the given kind which are ready to run, oldest first.
+pub fn due<T: DeserializeOwned>(
+    conn: &rusqlite::Connection,
+    kind: &str,
+    now: OffsetDateTime,
+) -> Result<Vec<Job<T>>> {
+    let mut stmt = conn.prepare(
+        "\
+        SELECT id, attempts, payload FROM jobs \
+        WHERE kind = ? AND status = ? AND run_after <= ? \
+        ORDER BY run_after, id",
+    )?;
+
+    let mut rows = stmt.query(params![kind, STATUS_PENDING, now.unix_timestamp()])?;
+    let mut jobs = vec![];
+    while let Some(row) = rows.next()? {
+        let payload: String = row.get_unwrap(2);
+        jobs.push(Job {
+            id: row.get_unwrap(0),
+            attempts: row.get_unwrap(1),
+            payload: serde_json::from_str(&payload)?,
+        });
+    }
+
+    Ok(jobs)
+}
+
+/// When the next pending job of the given kind is ready to run.
+pub fn next_run(conn: &rusqlite::Connection, kind: &str) -> Result<Option<OffsetDateTime>> {
+    let ts: Option<i64> = conn.query_row(
+        "SELECT MIN(run_after) FROM jobs WHERE kind = ? AND status = ?",
+        params![kind, STATUS_PENDING],
+        |row| row.get(0),
+    )?;
+
+    Ok(ts.map(OffsetDateTime::from_unix_timestamp).transpose()?)
+}
+
+/// Postpone a job until `run_after`, without counting it as an attempt.
+pub fn reschedule(conn: &rusqlite::Connection, id: i64, run_after: OffsetDateTime) -> Result<()> {
+    conn.execute(
+        "UPDATE jobs SET run_after = ? WHERE id = ?",
+        params![run_after.unix_timestamp(), id],
+    )?;
+
+    Ok(())
+}
+
+/// Record a failed attempt, scheduling a retry unless the job is out of
+/// attempts. Returns whether it will be retried.
+pub fn fail<T>(
+    conn: &rusqlite::Connection,
+    job: &Job<T>,
+    error: &str,
+    now: OffsetDateTime,
+) -> Result<bool> {
+    let attempts = job.attempts + 1;
+    let retry = attempts < MAX_ATTEMPTS;
+    let status = if retry { STATUS_PENDING } else { STATUS_FAILED };
+
+    conn.execute(
+        "\
+        UPDATE jobs \
+        SET status = ?, attempts = ?, last_error = ?, run_after = ? \
+        WHERE id = ?",
+        params![
+            status,
+            attempts,
+            error,
+            (now + backoff(attempts)).unix_timestamp(),
+            job.id
+        ],
+    )?;
+
+    Ok(retry)
+}
+
+pub fn remove(conn: &rusqlite::Connection, id: i64) -> Result<()> {
+    conn.execute("DELETE FROM jobs WHERE id = ?", params![id])?;
+    Ok(())
+}
+
+/// All queued and failed jobs, oldest first.
+pub fn list(conn: &rusqlite::Connection) -> Result<Vec<JobInfo>> {
+    let mut stmt = conn.prepare(
+        "\
+        SELECT id, kind, status, attempts, last_error, payload, run_after, created_at \
+        FROM jobs \
+        ORDER BY created_at, id",
+    )?;
+
+    let mut rows = stmt.query([])?;
+    let mut jobs = vec![];
+    while let Some(row) = rows.next()? {
+        let payload: String = row.get_unwrap(5);
+        jobs.push(JobInfo {
+            id: row.get_unwrap(0),
+            kind: row.get_unwrap(1),
+            status: row.get_unwrap(2),
+            attempts: row.get_unwrap(3),
+            last_error: row.get_unwrap(4),
+            payload: serde_json::from_str(&payload)?,
+            run_after: OffsetDateTime::from_unix_timestamp(row.get_unwrap(6))?,
+            created_at: OffsetDateTime::from_unix_timestamp(row.get_unwrap(7))?,
+        });
+    }
+
+    Ok(jobs)
+}
+
+/// How long to wait before retrying a job which failed `attempts` times.
+fn backoff(attempts: u32) -> Duration {
+    let exp = attempts.saturating_sub(1).min(16);
+    (INITIAL_BACKOFF * 2_i32.pow(exp)).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::minutes(1));
+        assert_eq!(backoff(2), Duration::minutes(2));
+        assert_eq!(backoff(5), Duration::minutes(16));
+        assert_eq!(backoff(MAX_ATTEMPTS * 4), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,323 @@
+//! Import recorded tours from Komoot.
+//!
+//! Komoot doesn't offer a public API, so this uses the same endpoints as
+//! their apps. Logging in with email and password gives us a token, which is
+//! stored so that tours can be synced later on without the password.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::{Response, StatusCode, Url};
+use rusqlite::params;
+use serde::Deserialize;
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::activity::{self, Compression, MediaType};
+use crate::db::Database;
+
+const API_URL: &str = "https://api.komoot.de";
+
+/// Number of tours to request at once.
+const PAGE_SIZE: usize = 50;
+
+/// Response of the login endpoint. Confusingly, the token is returned as
+/// the "password" to use for further requests.
+#[derive(Deserialize)]
+struct Account {
+    username: String,
+    password: String,
+}
+
+/// https://static.komoot.de/doc/external-api/v007/index.html
+#[derive(Deserialize)]
+struct Tour {
+    id: u64,
+    name: String,
+    sport: String,
+    #[serde(with = "time::serde::iso8601")]
+    date: OffsetDateTime,
+    /// Meters
+    distance: f64,
+    /// Seconds
+    duration: f64,
+    time_in_motion: Option<f64>,
+    elevation_up: Option<f64>,
+}
+
+impl Tour {
+    /// Same property names as activities imported from Strava, so that
+    /// filters work the same for both.
+    fn properties(&self) -> HashMap<String, Value> {
+        let mut properties = HashMap::from([
+            ("komoot_id".to_string(), self.id.into()),
+            ("activity_type".to_string(), self.sport.clone().into()),
+            ("distance".to_string(), self.distance.into()),
+            ("elapsed_time".to_string(), self.duration.into()),
+        ]);
+
+        if let Some(moving_time) = self.time_in_motion {
+            properties.insert("moving_time".to_string(), moving_time.into());
+        }
+
+        if let Some(elevation_gain) = self.elevation_up {
+            properties.insert("elevation_gain".to_string(), elevation_gain.into());
+        }
+
+        properties
+    }
+}
+
+#[derive(Deserialize)]
+struct ToursPage {
+    #[serde(rename = "_embedded", default)]
+    embedded: Option<EmbeddedTours>,
+    page: PageInfo,
+}
+
+#[derive(Deserialize)]
+struct EmbeddedTours {
+    tours: Vec<Tour>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageInfo {
+    total_pages: usize,
+}
+
+struct KomootClient {
+    user_id: String,
+    token: String,
+    http: reqwest::Client,
+}
+
+impl KomootClient {
+    async fn get(&self, url: &str) -> Result<Response> {
+        let res = self
+            .http
+            .get(url)
+            .basic_auth(&self.user_id, Some(&self.token))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await?;
+            return Err(anyhow!("HTTP request failed with status {status}: {body}"));
+        }
+
+        Ok(res)
+    }
+
+    /// All tours the user recorded (as opposed to planned).
+    async fn recorded_tours(&self) -> Result<Vec<Tour>> {
+        let mut tours = vec![];
+        let mut page = 0;
+
+        loop {
+            let url = format!(
+                "{}/v007/users/{}/tours/?type=tour_recorded&page={}&limit={}",
+                API_URL, self.user_id, page, PAGE_SIZE
+            );
+            let res: ToursPage = self.get(&url).await?.json().await?;
+
+            tours.extend(res.embedded.map(|it| it.tours).unwrap_or_default());
+
+            page += 1;
+            if page >= res.page.total_pages {
+                break;
+            }
+        }
+
+        Ok(tours)
+    }
+
+    async fn download_gpx(&self, tour_id: u64) -> Result<Vec<u8>> {
+        let url = format!("{}/v007/tours/{}.gpx", API_URL, tour_id);
+        let res = self.get(&url).await?;
+
+        Ok(res.bytes().await?.to_vec())
+    }
+}
+
+/// Log in to Komoot and store the token, returning the Komoot user ID.
+pub async fn login(db: &Database, email: &str, password: &str) -> Result<String> {
+    let mut url = Url::parse(API_URL)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid API URL"))?
+        .extend(["v006", "account", "email", email, ""]);
+
+    let res = reqwest::Client::new()
+        .get(url)
+        .basic_auth(email, Some(password))
+        .send()
+        .await?;
+
+    if res.status() == StatusCode::FORBIDDEN || res.status() == StatusCode::UNAUTHORIZED {
+        return Err(anyhow!("invalid Komoot email or password"));
+    } else if !res.status().is_success() {
+        let status = res.status();
+        let body = res.text().await?;
+        return Err(anyhow!("HTTP request failed with status {status}: {body}"));
+    }
+
+    let account: Account = res.json().await?;
+
+    let conn = db.connection()?;
+    conn.execute(
+        "\
+        INSERT OR REPLACE \
+        INTO komoot_tokens (user_id, token) \
+        VALUES (?, ?)",
+        params![account.username, account.password],
+    )?;
+
+    Ok(account.username)
+}
+
+fn clients(db: &Database) -> Result<Vec<KomootClient>> {
+    let conn = db.connection()?;
+    let mut stmt = conn.prepare("SELECT user_id, token FROM komoot_tokens")?;
+    let mut rows = stmt.query([])?;
+
+    let http = reqwest::Client::new();
+    let mut clients = vec![];
+    while let Some(row) = rows.next()? {
+        clients.push(KomootClient {
+            user_id: row.get_unwrap(0),
+            token: row.get_unwrap(1),
+            http: http.clone(),
+        });
+    }
+
+    Ok(clients)
+}
+
+fn is_imported(db: &Database, name: &str) -> Result<bool> {
+    let conn = db.connection()?;
+    activity::is_known(&conn, name)
+}
+
+async fn import_tour(db: &Database, client: &KomootClient, tour: &Tour) -> Result<()> {
+    let name = format!("komoot:{}", tour.id);
+    let bytes = client.download_gpx(tour.id).await?;
+
+    let mut activity = activity::read(Cursor::new(bytes), MediaType::Gpx, Compression::None)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("tour has no track"))?;
+
+    activity.title = Some(tour.name.clone());
+    activity.start_time = activity.start_time.or(Some(tour.date));
+    activity.properties.extend(tour.properties());
+
+    let mut conn = db.connection()?;
+    activity::upsert(&mut conn, &name, &activity, &db.config)?;
+
+    Ok(())
+}
+
+/// Import any recorded tours which haven't been imported yet, for each
+/// Komoot account that logged in. Returns the number of tours imported.
+///
+/// Failing to import a single tour is logged rather than stopping the sync.
+pub async fn sync(db: &Database) -> Result<usize> {
+    let clients = clients(db)?;
+    if clients.is_empty() {
+        return Err(anyhow!(
+            "no Komoot accounts found, run `hotpot komoot-auth` first"
+        ));
+    }
+
+    let mut num_imported = 0;
+    for client in clients {
+        let tours = client
+            .recorded_tours()
+            .await
+            .with_context(|| format!("failed to list tours for Komoot user {}", client.user_id))?;
+
+        for tour in tours {
+            if is_imported(db, &format!("komoot:{}", tour.id))? {
+                continue;
+            }
+
+            match import_tour(db, &client, &tour).await {
+                Ok(()) => {
+                    tracing::info!(tour_id = tour.id, name = tour.name, "imported Komoot tour");
+                    num_imported += 1;
+                }
+                Err(err) => tracing::error!(tour_id = tour.id, ?err, "failed to import tour"),
+            }
+        }
+    }
+
+    Ok(num_imported)
+}
+
+/// Sync tours every `interval`. Runs forever.
+pub async fn sync_periodically(
+    db: impl Fn() -> Arc<Database>,
+    interval: Duration,
+    on_import: impl Fn(),
+) {
+    loop {
+        match sync(&db()).await {
+            Ok(0) => {}
+            Ok(_) => on_import(),
+            Err(err) => tracing::error!(?err, "failed to sync Komoot tours"),
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tour_properties() {
+        let page: ToursPage = serde_json::from_value(serde_json::json!({
+            "_embedded": {
+                "tours": [{
+                    "id": 123,
+                    "type": "tour_recorded",
+                    "name": "Evening ride",
+                    "sport": "touringbicycle",
+                    "date": "2024-05-01T18:30:00.000+02:00",
+                    "distance": 25123.4,
+                    "duration": 4200,
+                    "time_in_motion": 3900,
+                    "elevation_up": 210.5,
+                    "elevation_down": 208.0
+                }]
+            },
+            "page": {"size": 50, "totalElements": 1, "totalPages": 1, "number": 0}
+        }))
+        .unwrap();
+
+        let tours = page.embedded.unwrap().tours;
+        let props = tours[0].properties();
+
+        assert_eq!(props["activity_type"], "touringbicycle");
+        assert_eq!(props["distance"], 25123.4);
+        assert_eq!(props["moving_time"], 3900.0);
+        assert_eq!(props["elevation_gain"], 210.5);
+        assert!(!props.contains_key("name"));
+    }
+
+    #[test]
+    fn test_empty_tours_page() {
+        let page: ToursPage = serde_json::from_value(serde_json::json!({
+            "page": {"size": 50, "totalElements": 0, "totalPages": 0, "number": 0}
+        }))
+        .unwrap();
+
+        assert!(page.embedded.is_none());
+        assert_eq!(page.page.total_pages, 0);
+    }
+}
@@ -0,0 +1,235 @@
+//! Live location tracking, where a phone pushes its location one point at a
+//! time (e.g. using OwnTracks), rather than uploading a finished activity.
+//!
+//! Points are collected per device in the `live_points` table, and turned
+//! into a regular activity once the device hasn't reported for a while.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use geo_types::{Coord, LineString, MultiLineString};
+use rusqlite::{params, OptionalExtension};
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use crate::activity::{self, RawActivity};
+use crate::db::{self, Database};
+
+/// Points less accurate than this (in meters) are ignored, they're likely
+/// from cell towers or wifi rather than GPS.
+const MAX_ACCURACY: f64 = 100.0;
+
+/// How often to check for devices which stopped reporting.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Message posted by OwnTracks in HTTP mode. We only care about locations.
+///
+/// https://owntracks.org/booklet/tech/json/
+#[derive(Deserialize)]
+#[serde(tag = "_type", rename_all = "lowercase")]
+pub enum OwnTracksMessage {
+    Location(OwnTracksLocation),
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+pub struct OwnTracksLocation {
+    lat: f64,
+    lon: f64,
+    /// Unix timestamp of the fix.
+    tst: i64,
+    /// Accuracy in meters.
+    acc: Option<f64>,
+    /// Tracker ID, used to tell devices apart if there's no `X-Limit-D`.
+    pub tid: Option<String>,
+}
+
+impl OwnTracksLocation {
+    pub fn is_accurate(&self) -> bool {
+        self.acc.unwrap_or(0.0) <= MAX_ACCURACY
+    }
+}
+
+/// Append a point to the device's live activity.
+///
+/// If the device has been quiet for longer than `timeout`, the points
+/// collected so far are saved as an activity first. Returns whether an
+/// activity was saved.
+pub fn add_point(
+    conn: &mut rusqlite::Connection,
+    device: &str,
+    user_id: Option<i64>,
+    location: &OwnTracksLocation,
+    timeout: Duration,
+    config: &db::Config,
+) -> Result<bool> {
+    let last_time: Option<i64> = conn
+        .query_row(
+            "SELECT MAX(time) FROM live_points WHERE device = ?",
+            params![device],
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+
+    let flushed = match last_time {
+        Some(last) if location.tst - last > timeout.as_secs() as i64 => {
+            flush(conn, device, config)?
+        }
+        _ => false,
+    };
+
+    conn.execute(
+        "\
+        INSERT INTO live_points (device, user_id, lng, lat, time) \
+        VALUES (?, ?, ?, ?, ?)",
+        params![device, user_id, location.lon, location.lat, location.tst],
+    )?;
+
+    Ok(flushed)
+}
+
+/// Save the device's collected points as an activity, and start over.
+fn flush(conn: &mut rusqlite::Connection, device: &str, config: &db::Config) -> Result<bool> {
+    let (points, user_id) = {
+        let mut stmt = conn.prepare(
+            "\
+            SELECT lng, lat, time, user_id \
+            FROM live_points \
+            WHERE device = ? \
+            ORDER BY time",
+        )?;
+        let mut rows = stmt.query(params![device])?;
+
+        let mut points = vec![];
+        let mut user_id = None;
+        while let Some(row) = rows.next()? {
+            points.push((Coord::from((row.get(0)?, row.get(1)?)), row.get(2)?));
+            user_id = user_id.or(row.get(3)?);
+        }
+        (points, user_id)
+    };
+
+    conn.execute("DELETE FROM live_points WHERE device = ?", params![device])?;
+
+    let Some(activity) = to_activity(device, user_id, &points)? else {
+        return Ok(false);
+    };
+
+    let name = format!(
+        "live:{}/{}",
+        device,
+        activity.start_time.map_or(0, |t| t.unix_timestamp())
+    );
+    tracing::info!(name, num_points = points.len(), "saving live activity");
+    activity::upsert(conn, &name, &activity, config)?;
+
+    Ok(true)
+}
+
+fn to_activity(
+    device: &str,
+    user_id: Option<i64>,
+    points: &[(Coord, i64)],
+) -> Result<Option<RawActivity>> {
+    // Nothing to draw
+    if points.len() < 2 {
+        return Ok(None);
+    }
+
+    let line: LineString = points.iter().map(|(coord, _)| *coord).collect();
+
+    Ok(Some(RawActivity {
+        title: None,
+        start_time: Some(OffsetDateTime::from_unix_timestamp(points[0].1)?),
+        tracks: MultiLineString::new(vec![line]),
+        properties: HashMap::from([("device".to_string(), device.into())]),
+        content_hash: None,
+        athlete_id: None,
+        user_id,
+    }))
+}
+
+/// Save the live activities of devices which haven't reported for longer
+/// than `timeout`. Returns the number of activities saved.
+pub fn flush_idle(db: &Database, timeout: Duration) -> Result<usize> {
+    let mut conn = db.connection()?;
+    let cutoff = OffsetDateTime::now_utc().unix_timestamp() - timeout.as_secs() as i64;
+
+    let devices = conn
+        .prepare("SELECT device FROM live_points GROUP BY device HAVING MAX(time) < ?")?
+        .query_map(params![cutoff], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut num_flushed = 0;
+    for device in devices {
+        if flush(&mut conn, &device, &db.config)? {
+            num_flushed += 1;
+        }
+    }
+
+    Ok(num_flushed)
+}
+
+/// Periodically save live activities of idle devices. Runs forever.
+pub async fn flush_periodically(
+    db: impl Fn() -> Arc<Database>,
+    timeout: Duration,
+    on_flush: impl Fn(),
+) {
+    loop {
+        let db = db();
+        let result = tokio::task::spawn_blocking(move || flush_idle(&db, timeout))
+            .await
+            .expect("flush task panicked");
+
+        match result {
+            Ok(0) => {}
+            Ok(_) => on_flush(),
+            Err(err) => tracing::error!(?err, "failed to save live activities"),
+        }
+
+        tokio::time::sleep(FLUSH_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owntracks_message() {
+        let msg: OwnTracksMessage = serde_json::from_str(
+            r#"{"_type": "location", "lat": 52.52, "lon": 13.40, "tst": 1714580000, "acc": 12, "tid": "ph", "batt": 80}"#,
+        )
+        .unwrap();
+
+        let OwnTracksMessage::Location(location) = msg else {
+            panic!("expected location");
+        };
+        assert!(location.is_accurate());
+        assert_eq!(location.tid.as_deref(), Some("ph"));
+
+        let msg: OwnTracksMessage =
+            serde_json::from_str(r#"{"_type": "transition", "event": "enter"}"#).unwrap();
+        assert!(matches!(msg, OwnTracksMessage::Other));
+    }
+
+    #[test]
+    fn test_to_activity() {
+        let points = [
+            (Coord { x: 13.40, y: 52.52 }, 1714580000),
+            (Coord { x: 13.41, y: 52.53 }, 1714580060),
+        ];
+
+        let activity = to_activity("phone", Some(1), &points).unwrap().unwrap();
+        assert_eq!(activity.tracks.0[0].0.len(), 2);
+        assert_eq!(activity.start_time.unwrap().unix_timestamp(), 1714580000);
+        assert_eq!(activity.user_id, Some(1));
+
+        assert!(to_activity("phone", None, &points[..1]).unwrap().is_none());
+    }
+}
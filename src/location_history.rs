@@ -0,0 +1,494 @@
+//! Import Google location history, from a Google Takeout export.
+//!
+//! Two formats are supported:
+//!
+//! - `Records.json`, the raw location fixes recorded by the phone. These are
+//!   split into one activity per (UTC) day.
+//! - `Semantic Location History/YYYY/YYYY_MONTH.json`, where Google already
+//!   grouped the fixes into trips ("activity segments") by mode of transport.
+//!   Segments are combined into one activity per day and activity type.
+//!
+//! Phone location data is much noisier than a GPS watch, so inaccurate fixes
+//! and implausible jumps are dropped.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use geo::{HaversineDistance, Point};
+use geo_types::{Coord, LineString, MultiLineString};
+use serde::Deserialize;
+use time::{Date, OffsetDateTime};
+use walkdir::WalkDir;
+
+use crate::activity::{self, ImportFailure, ImportSummary, RawActivity, Upserted};
+use crate::db::Database;
+
+/// Start a new line if there's no fix for this long (seconds), rather than
+/// drawing a straight line between the two.
+const MAX_GAP: i64 = 10 * 60;
+
+/// Which location fixes to keep.
+#[derive(Copy, Clone, Debug)]
+pub struct Filter {
+    /// Meters, fixes with a larger accuracy radius are ignored.
+    pub max_accuracy: f64,
+    /// Meters per second, fixes which would require moving faster than this
+    /// from the previous one are ignored (along with flights).
+    pub max_speed: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LocationHistory {
+    Records {
+        locations: Vec<Record>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Semantic {
+        timeline_objects: Vec<TimelineObject>,
+    },
+}
+
+/// Older exports have `timestampMs` (as a string), newer ones `timestamp`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Timestamp {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    timestamp: Option<OffsetDateTime>,
+    timestamp_ms: Option<String>,
+}
+
+impl Timestamp {
+    fn unix_timestamp(&self) -> Option<i64> {
+        match (self.timestamp, &self.timestamp_ms) {
+            (Some(ts), _) => Some(ts.unix_timestamp()),
+            (None, Some(ms)) => ms.parse::<i64>().ok().map(|ms| ms / 1000),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    latitude_e7: i64,
+    longitude_e7: i64,
+    accuracy: Option<f64>,
+    #[serde(flatten)]
+    time: Timestamp,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TimelineObject {
+    activity_segment: Option<ActivitySegment>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ActivitySegment {
+    activity_type: Option<String>,
+    /// Meters
+    distance: Option<f64>,
+    duration: SegmentDuration,
+    simplified_raw_path: Option<RawPath>,
+    waypoint_path: Option<WaypointPath>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SegmentDuration {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    start_timestamp: Option<OffsetDateTime>,
+    start_timestamp_ms: Option<String>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    end_timestamp: Option<OffsetDateTime>,
+    end_timestamp_ms: Option<String>,
+}
+
+impl SegmentDuration {
+    fn start(&self) -> Option<i64> {
+        Timestamp {
+            timestamp: self.start_timestamp,
+            timestamp_ms: self.start_timestamp_ms.clone(),
+        }
+        .unix_timestamp()
+    }
+
+    fn end(&self) -> Option<i64> {
+        Timestamp {
+            timestamp: self.end_timestamp,
+            timestamp_ms: self.end_timestamp_ms.clone(),
+        }
+        .unix_timestamp()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawPath {
+    points: Vec<PathPoint>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PathPoint {
+    lat_e7: i64,
+    lng_e7: i64,
+    accuracy_meters: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct WaypointPath {
+    waypoints: Vec<Waypoint>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Waypoint {
+    lat_e7: i64,
+    lng_e7: i64,
+}
+
+fn to_coord(lat_e7: i64, lng_e7: i64) -> Coord {
+    Coord {
+        x: lng_e7 as f64 / 1e7,
+        y: lat_e7 as f64 / 1e7,
+    }
+}
+
+/// A day's worth of lines, and the properties of the activity.
+#[derive(Default)]
+struct Day {
+    start_time: Option<i64>,
+    lines: Vec<LineString>,
+    distance: f64,
+}
+
+impl Day {
+    fn into_activity(self, activity_type: Option<&str>) -> Result<Option<RawActivity>> {
+        let lines: Vec<_> = self
+            .lines
+            .into_iter()
+            .filter(|line| line.0.len() > 1)
+            .collect();
+
+        if lines.is_empty() {
+            return Ok(None);
+        }
+
+        let mut properties = HashMap::from([("source".to_string(), "google".into())]);
+        if let Some(activity_type) = activity_type {
+            properties.insert("activity_type".to_string(), activity_type.into());
+        }
+        if self.distance > 0.0 {
+            properties.insert("distance".to_string(), self.distance.into());
+        }
+
+        Ok(Some(RawActivity {
+            title: None,
+            start_time: self
+                .start_time
+                .map(OffsetDateTime::from_unix_timestamp)
+                .transpose()?,
+            tracks: MultiLineString::new(lines),
+            properties,
+            content_hash: None,
+            athlete_id: None,
+            user_id: None,
+        }))
+    }
+}
+
+fn date_of(ts: i64) -> Result<Date> {
+    Ok(OffsetDateTime::from_unix_timestamp(ts)?.date())
+}
+
+/// Split raw fixes into days, dropping inaccurate ones and implausible jumps.
+fn records_by_day(mut records: Vec<Record>, filter: &Filter) -> Result<BTreeMap<Date, Day>> {
+    records.retain(|r| r.accuracy.unwrap_or(0.0) <= filter.max_accuracy);
+
+    let mut points: Vec<(i64, Coord)> = records
+        .iter()
+        .filter_map(|r| {
+            let ts = r.time.unix_timestamp()?;
+            Some((ts, to_coord(r.latitude_e7, r.longitude_e7)))
+        })
+        .collect();
+    points.sort_by_key(|(ts, _)| *ts);
+
+    let mut days: BTreeMap<Date, Day> = BTreeMap::new();
+    let mut prev: Option<(i64, Coord)> = None;
+
+    for (ts, coord) in points {
+        let date = date_of(ts)?;
+        let day = days.entry(date).or_default();
+
+        let continues_line = match prev {
+            Some((prev_ts, prev_coord)) if date_of(prev_ts)? == date && ts - prev_ts <= MAX_GAP => {
+                let dist = Point::from(prev_coord).haversine_distance(&Point::from(coord));
+                // Same timestamp is likely a duplicate, not infinitely fast.
+                if dist / ((ts - prev_ts).max(1) as f64) > filter.max_speed {
+                    continue;
+                }
+
+                day.distance += dist;
+                true
+            }
+            _ => false,
+        };
+
+        if !continues_line {
+            day.lines.push(LineString::new(vec![]));
+        }
+
+        day.start_time.get_or_insert(ts);
+        day.lines.last_mut().expect("line was added").0.push(coord);
+        prev = Some((ts, coord));
+    }
+
+    Ok(days)
+}
+
+/// Group trips by day and activity type, skipping any too fast to be real
+/// (or flights).
+fn segments_by_day(
+    segments: Vec<ActivitySegment>,
+    filter: &Filter,
+) -> Result<BTreeMap<(Date, String), Day>> {
+    let mut days: BTreeMap<(Date, String), Day> = BTreeMap::new();
+
+    for segment in segments {
+        let (Some(start), Some(end)) = (segment.duration.start(), segment.duration.end()) else {
+            continue;
+        };
+
+        let distance = segment.distance.unwrap_or_default();
+        if distance / ((end - start).max(1) as f64) > filter.max_speed {
+            continue;
+        }
+
+        // The raw path is more detailed, but not always available.
+        let line: LineString = match (segment.simplified_raw_path, segment.waypoint_path) {
+            (Some(path), _) if path.points.len() > 1 => path
+                .points
+                .iter()
+                .filter(|p| p.accuracy_meters.unwrap_or(0.0) <= filter.max_accuracy)
+                .map(|p| to_coord(p.lat_e7, p.lng_e7))
+                .collect(),
+            (_, Some(path)) => path
+                .waypoints
+                .iter()
+                .map(|p| to_coord(p.lat_e7, p.lng_e7))
+                .collect(),
+            _ => continue,
+        };
+
+        let activity_type = segment
+            .activity_type
+            .unwrap_or_else(|| "UNKNOWN_ACTIVITY_TYPE".to_string());
+        let day = days.entry((date_of(start)?, activity_type)).or_default();
+
+        day.start_time = Some(day.start_time.map_or(start, |ts| ts.min(start)));
+        day.distance += distance;
+        day.lines.push(line);
+    }
+
+    Ok(days)
+}
+
+/// Read a location history file, returning activities keyed by the name they
+/// should be stored as.
+fn read_file(path: &Path, filter: &Filter) -> Result<Vec<(String, RawActivity)>> {
+    let rdr = BufReader::new(File::open(path)?);
+    let history: LocationHistory = serde_json::from_reader(rdr)
+        .map_err(|err| anyhow!("not a location history file: {}", err))?;
+
+    let mut activities = vec![];
+    match history {
+        LocationHistory::Records { locations } => {
+            for (date, day) in records_by_day(locations, filter)? {
+                if let Some(activity) = day.into_activity(None)? {
+                    activities.push((format!("google:{}", date), activity));
+                }
+            }
+        }
+        LocationHistory::Semantic { timeline_objects } => {
+            let segments = timeline_objects
+                .into_iter()
+                .filter_map(|it| it.activity_segment)
+                .collect();
+
+            for ((date, activity_type), day) in segments_by_day(segments, filter)? {
+                if let Some(activity) = day.into_activity(Some(&activity_type))? {
+                    activities.push((format!("google:{}/{}", date, activity_type), activity));
+                }
+            }
+        }
+    }
+
+    Ok(activities)
+}
+
+/// Import all location history (JSON) files below the given path.
+///
+/// Activities are named by day (`google:2022-01-31`, or with the activity
+/// type for semantic location history), and skipped if already imported.
+pub fn import_path(path: &Path, db: &Database, filter: &Filter) -> Result<ImportSummary> {
+    let mut conn = db.connection()?;
+    let mut summary = ImportSummary::default();
+
+    let files = WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path());
+
+    for file in files {
+        if file.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            summary
+                .unsupported
+                .push(file.to_string_lossy().into_owned());
+            continue;
+        }
+
+        let activities = match read_file(&file, filter) {
+            Ok(activities) => activities,
+            Err(err) => {
+                summary.failed.push(ImportFailure {
+                    file: file.to_string_lossy().into_owned(),
+                    error: format!("{:#}", err),
+                });
+                continue;
+            }
+        };
+
+        tracing::info!(
+            ?file,
+            num_activities = activities.len(),
+            "importing location history"
+        );
+
+        for (name, activity) in activities {
+            if activity::is_known(&conn, &name)? {
+                summary.skipped.push(name);
+                continue;
+            }
+
+            match activity::upsert(&mut conn, &name, &activity, &db.config)? {
+                Upserted::Inserted(_) => summary.imported.push(name),
+                Upserted::Linked(_) | Upserted::Excluded => summary.skipped.push(name),
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTER: Filter = Filter {
+        max_accuracy: 100.0,
+        max_speed: 50.0,
+    };
+
+    fn record(lat: f64, lng: f64, accuracy: f64, timestamp: &str) -> serde_json::Value {
+        serde_json::json!({
+            "latitudeE7": (lat * 1e7) as i64,
+            "longitudeE7": (lng * 1e7) as i64,
+            "accuracy": accuracy,
+            "timestamp": timestamp,
+        })
+    }
+
+    #[test]
+    fn test_records_by_day() {
+        let history: LocationHistory = serde_json::from_value(serde_json::json!({
+            "locations": [
+                record(52.520, 13.400, 10.0, "2022-01-01T10:00:00Z"),
+                record(52.521, 13.401, 10.0, "2022-01-01T10:01:00Z"),
+                // Inaccurate
+                record(52.600, 13.500, 2000.0, "2022-01-01T10:01:30Z"),
+                // Jump of ~10km in a minute
+                record(52.610, 13.500, 10.0, "2022-01-01T10:02:00Z"),
+                record(52.522, 13.402, 10.0, "2022-01-01T10:03:00Z"),
+                // After a gap, so a new line
+                record(52.530, 13.410, 10.0, "2022-01-01T12:00:00Z"),
+                record(52.531, 13.411, 10.0, "2022-01-01T12:01:00Z"),
+                {"latitudeE7": 525200000, "longitudeE7": 134000000, "timestampMs": "1641081600000"},
+                {"latitudeE7": 525210000, "longitudeE7": 134010000, "timestampMs": "1641081660000"},
+            ]
+        }))
+        .unwrap();
+
+        let LocationHistory::Records { locations } = history else {
+            panic!("expected records");
+        };
+
+        let days = records_by_day(locations, &FILTER).unwrap();
+        assert_eq!(days.len(), 2);
+
+        let day = &days[&Date::from_calendar_date(2022, time::Month::January, 1).unwrap()];
+        assert_eq!(day.lines.len(), 2);
+        assert_eq!(day.lines[0].0.len(), 3);
+        assert_eq!(day.lines[1].0.len(), 2);
+        assert!(day.distance > 0.0);
+    }
+
+    #[test]
+    fn test_segments_by_day() {
+        let history: LocationHistory = serde_json::from_value(serde_json::json!({
+            "timelineObjects": [
+                {"placeVisit": {}},
+                {"activitySegment": {
+                    "activityType": "WALKING",
+                    "distance": 500,
+                    "duration": {
+                        "startTimestamp": "2022-01-01T10:00:00Z",
+                        "endTimestamp": "2022-01-01T10:10:00Z"
+                    },
+                    "waypointPath": {"waypoints": [
+                        {"latE7": 525200000, "lngE7": 134000000},
+                        {"latE7": 525240000, "lngE7": 134000000}
+                    ]}
+                }},
+                {"activitySegment": {
+                    "activityType": "FLYING",
+                    "distance": 500000,
+                    "duration": {
+                        "startTimestampMs": "1641031200000",
+                        "endTimestampMs": "1641034800000"
+                    },
+                    "waypointPath": {"waypoints": [
+                        {"latE7": 525200000, "lngE7": 134000000},
+                        {"latE7": 485200000, "lngE7": 104000000}
+                    ]}
+                }}
+            ]
+        }))
+        .unwrap();
+
+        let LocationHistory::Semantic { timeline_objects } = history else {
+            panic!("expected semantic location history");
+        };
+
+        let segments = timeline_objects
+            .into_iter()
+            .filter_map(|it| it.activity_segment)
+            .collect();
+        let days = segments_by_day(segments, &FILTER).unwrap();
+
+        assert_eq!(days.len(), 1);
+        let ((date, activity_type), day) = days.into_iter().next().unwrap();
+        assert_eq!(date.to_string(), "2022-01-01");
+        assert_eq!(activity_type, "WALKING");
+
+        let activity = day.into_activity(Some(&activity_type)).unwrap().unwrap();
+        assert_eq!(activity.properties["distance"], 500.0);
+    }
+}
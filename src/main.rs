@@ -1,49 +1,183 @@
+use std::ffi::OsString;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
-use clap::{Args, Parser, Subcommand};
-use image::RgbaImage;
+use anyhow::{anyhow, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
+use clap::{
+    ArgMatches, Args, Command, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
+use image::{Rgba, RgbaImage};
 use tile::WebMercatorViewport;
+use time::format_description::well_known::Rfc3339;
 use time::Date;
 
-use activity::PropertySource;
+use activity::{DedupeBy, PropertySource, SortOrder};
 
 use crate::db::{ActivityFilter, Database, PropertyFilter};
-use crate::raster::{LinearGradient, PINKISH};
+use crate::explorer::ExplorerTiles;
+use crate::export::ExportFormat;
+use crate::mask::MaskGeometry;
+use crate::raster::{ColorBy, Count, Gradient, Intensity, RenderMode, Stroke, PINKISH};
+use crate::server_config::ServerConfigFile;
+use crate::table::ActivityRow;
 use crate::tile::Tile;
+use crate::timelapse::{FrameStep, Timelapse};
+use crate::track_stats::GroupBy;
+use crate::vector::RenderFormat;
 
 mod activity;
+mod aggregate;
+mod analyze;
+mod apple_health;
+mod backup;
+mod basemap;
 mod date;
 mod db;
+mod db_stats;
+mod dedupe;
+mod explorer;
+mod export;
+mod garmin;
+mod geocode;
+mod heat;
+mod hooks;
+mod ingest;
+mod jobs;
+mod komoot;
+mod live;
+mod location_history;
+mod mask;
+mod mbtiles;
+mod mvt;
+mod pregenerate;
 mod raster;
+mod rate_limit;
+mod remote;
+mod render_link;
+mod render_pool;
+mod server_config;
+mod spatial_index;
 mod strava;
+mod table;
+mod text;
 mod tile;
+mod timelapse;
+mod tokens;
+mod track_stats;
+mod users;
+mod vector;
+mod views;
+mod watch;
 mod web;
 
-// TODO: move to `date` module, use a `FromStr` impl
-fn try_parse_date(value: &str) -> Result<Date, &'static str> {
-    Date::parse(value, &time::format_description::well_known::Iso8601::DATE)
-        .map_err(|_| "invalid date")
+fn try_parse_color(value: &str) -> Result<Rgba<u8>, &'static str> {
+    raster::parse_color(value).ok_or("invalid color")
+}
+
+/// Validate a gradient, but keep it as a string so it can be stored.
+fn try_parse_gradient(value: &str) -> Result<String, &'static str> {
+    match value.parse::<Gradient>() {
+        Ok(_) => Ok(value.to_string()),
+        Err(_) => Err("invalid gradient"),
+    }
+}
+
+fn try_parse_origin(value: &str) -> Result<HeaderValue, &'static str> {
+    let valid =
+        (value.starts_with("http://") || value.starts_with("https://")) && !value.ends_with('/');
+    match HeaderValue::from_str(value) {
+        Ok(origin) if valid => Ok(origin),
+        _ => Err("expected an origin, e.g. https://example.com"),
+    }
+}
+
+fn try_parse_method(value: &str) -> Result<Method, &'static str> {
+    Method::from_bytes(value.to_ascii_uppercase().as_bytes()).map_err(|_| "invalid method")
+}
+
+fn try_parse_header_name(value: &str) -> Result<HeaderName, &'static str> {
+    HeaderName::from_bytes(value.as_bytes()).map_err(|_| "invalid header name")
+}
+
+/// Parse a duration like `90s`, `15m`, or `6h`. Plain numbers are seconds.
+/// Parse an IP address range, or a single address.
+fn try_parse_ip_net(value: &str) -> Result<ipnet::IpNet, &'static str> {
+    match value.parse::<ipnet::IpNet>() {
+        Ok(net) => Ok(net),
+        Err(_) => value
+            .parse::<std::net::IpAddr>()
+            .map(ipnet::IpNet::from)
+            .map_err(|_| "expected an IP address or range, e.g. 10.0.0.0/8"),
+    }
+}
+
+fn try_parse_duration(value: &str) -> Result<Duration, &'static str> {
+    let (num, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => value.split_at(idx),
+        None => (value, "s"),
+    };
+
+    let num: u64 = num.parse().map_err(|_| "invalid duration")?;
+    let secs = match unit {
+        "s" => num,
+        "m" => num * 60,
+        "h" => num * 60 * 60,
+        _ => return Err("expected duration unit of 's', 'm', or 'h'"),
+    };
+
+    if secs == 0 {
+        return Err("duration must be positive");
+    }
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// Like [`try_parse_duration`], but allowing `0`.
+fn try_parse_timeout(value: &str) -> Result<Duration, &'static str> {
+    match value.trim_end_matches(['s', 'm', 'h']) {
+        "0" => Ok(Duration::ZERO),
+        _ => try_parse_duration(value),
+    }
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Import activities from GPX, TCX, and FIT files.
+    /// Import activities from GPX, TCX, FIT, and KML/KMZ files.
     ///
     /// Imports will be deduplicated (based on file name), so it's safe to run
     /// this twice on the same directory.
     Import {
         /// Path to activity data.
         ///
-        /// Can also pass a path to a single file.
-        path: PathBuf,
+        /// Can also pass a path to a single file, or an HTTP(S) URL.
+        #[arg(required_unless_present_any = ["strava_export", "url_list"])]
+        path: Option<PathBuf>,
+
+        /// Download and import the URLs listed in this file (one per line).
+        ///
+        /// Files which haven't changed since they were last imported are
+        /// skipped, using the server's `ETag` or `Last-Modified` headers.
+        #[arg(long, conflicts_with_all = ["path", "strava_export"])]
+        url_list: Option<PathBuf>,
+
+        /// Import a Strava bulk export (`export_12345.zip`) without unzipping
+        /// it, including the metadata from its `activities.csv`.
+        #[arg(long, conflicts_with_all = ["path", "join"])]
+        strava_export: Option<PathBuf>,
 
         /// Remove all existing activity data before importing.
         #[arg(long, default_value = "false")]
         reset: bool,
 
         /// Hide points within given distance (meters) of start/end of activity.
+        ///
+        /// Saved as the `trim_dist` setting for future imports. Activities
+        /// with a `trim` property use that distance instead.
         #[arg(short, long)]
         trim: Option<f64>,
 
@@ -53,6 +187,221 @@ enum Commands {
         /// which will assign properties to each parsed activity.
         #[arg(long)]
         join: Option<PathBuf>,
+
+        /// How to detect files which were already imported.
+        #[arg(long, value_enum, default_value_t)]
+        dedupe_by: DedupeBy,
+
+        /// Also import virtual activities (e.g. Zwift rides), tagged with
+        /// `virtual=true`.
+        ///
+        /// Only applies to this import, see the `include_virtual` setting to
+        /// always include them.
+        #[arg(long, default_value = "false")]
+        include_virtual: bool,
+
+        /// Exit with an error if any file failed to import.
+        #[arg(long, default_value = "false")]
+        strict: bool,
+
+        /// Reclaim unused space after importing, see `hotpot db maintain`.
+        #[arg(long, default_value = "false")]
+        vacuum: bool,
+
+        /// Don't show a progress bar.
+        #[arg(short, long, default_value = "false")]
+        quiet: bool,
+    },
+
+    /// Import Google location history from a Takeout export, either
+    /// `Records.json` or the `Semantic Location History` directory.
+    ImportLocationHistory {
+        /// Path to a location history file, or a directory of them.
+        path: PathBuf,
+
+        /// Ignore location fixes less accurate than this (meters).
+        #[arg(long, default_value = "100")]
+        max_accuracy: f64,
+
+        /// Ignore location fixes implying travel faster than this (km/h),
+        /// e.g. glitches and flights.
+        #[arg(long, default_value = "200")]
+        max_speed: f64,
+    },
+
+    /// List imported activities matching the given filters.
+    Activities {
+        /// Select activities before this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
+        before: Option<Date>,
+
+        /// Select activities after this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
+        after: Option<Date>,
+
+        /// Filter activities by arbitrary metadata properties
+        #[arg(short, long)]
+        filter: Option<PropertyFilter>,
+
+        /// Only select activities passing through this area, given as
+        /// `west,south,east,north` coordinates.
+        #[arg(long, allow_hyphen_values = true)]
+        bbox: Option<WebMercatorViewport>,
+
+        /// Only select activities whose title or text properties contain
+        /// these words, e.g. "gravel lake".
+        #[arg(short, long)]
+        search: Option<String>,
+
+        /// Only print the number of matching activities.
+        #[arg(short, long, default_value = "false")]
+        count: bool,
+
+        /// Maximum number of activities to list.
+        #[arg(short, long)]
+        limit: Option<u32>,
+
+        /// Order to list activities in.
+        #[arg(long, value_enum, default_value_t)]
+        sort: SortOrder,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+
+    /// Print total distance, time, and elevation gain of matching activities.
+    ///
+    /// Times and elevation gain are only known for activities which have the
+    /// `elapsed_time`, `moving_time` and `elevation_gain` properties set.
+    Stats {
+        /// Select activities before this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
+        before: Option<Date>,
+
+        /// Select activities after this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
+        after: Option<Date>,
+
+        /// Filter activities by arbitrary metadata properties
+        #[arg(short, long)]
+        filter: Option<PropertyFilter>,
+
+        /// Only select activities passing through this area, given as
+        /// `west,south,east,north` coordinates.
+        #[arg(long, allow_hyphen_values = true)]
+        bbox: Option<WebMercatorViewport>,
+
+        /// Split totals into groups, rather than a single total.
+        #[arg(short, long, value_enum)]
+        group_by: Option<GroupBy>,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+
+    /// Reports on where activities go, e.g. the most traveled roads.
+    Analyze {
+        #[command(subcommand)]
+        cmd: AnalyzeCommands,
+    },
+
+    /// Find activities which were imported more than once, e.g. from both
+    /// Strava and a file export, and remove the extra copies.
+    ///
+    /// The first imported copy is kept, with the properties of the removed
+    /// ones merged into it.
+    Dedupe {
+        /// Only list the duplicates, without removing anything.
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+    },
+
+    /// Export stored activity tracks as GPX or GeoJSON files, or a table of
+    /// activities as CSV or Parquet.
+    ///
+    /// Exported tracks are simplified, and have start/end trimming and
+    /// privacy masks applied unless `--unmasked` is given. Tables are written
+    /// to a single `activities.csv` (or `.parquet`) file, with one row per
+    /// activity holding its properties and distance.
+    Export {
+        /// Directory to write exported files to.
+        #[arg(short, long, default_value = "export")]
+        output: PathBuf,
+
+        /// Output file format.
+        #[arg(long, value_enum, default_value = "gpx")]
+        format: ExportFormat,
+
+        /// Select activities before this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
+        before: Option<Date>,
+
+        /// Select activities after this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
+        after: Option<Date>,
+
+        /// Filter activities by arbitrary metadata properties
+        #[arg(short, long)]
+        filter: Option<PropertyFilter>,
+
+        /// Only select activities passing through this area, given as
+        /// `west,south,east,north` coordinates.
+        #[arg(long, allow_hyphen_values = true)]
+        bbox: Option<WebMercatorViewport>,
+
+        /// Export tracks without trimming or privacy masks applied.
+        #[arg(long, default_value = "false")]
+        unmasked: bool,
+    },
+
+    /// Remove a single activity from the database.
+    Remove {
+        /// Activity ID or file name, as shown by the `activities` subcommand.
+        activity: String,
+    },
+
+    /// Manage privacy masks, which hide areas (e.g. home) from activities.
+    Mask {
+        #[command(subcommand)]
+        cmd: MaskCommands,
+    },
+
+    /// Manage user accounts, for `serve --users`.
+    User {
+        #[command(subcommand)]
+        cmd: UserCommands,
+    },
+
+    /// Manage scoped API tokens for the web server.
+    Token {
+        #[command(subcommand)]
+        cmd: TokenCommands,
+    },
+
+    /// Manage named gradients, which can be selected with `?color=<name>`.
+    Gradient {
+        #[command(subcommand)]
+        cmd: GradientCommands,
+    },
+
+    /// Manage saved views, which are served under `/view/<name>/`.
+    View {
+        #[command(subcommand)]
+        cmd: ViewCommands,
+    },
+
+    /// View or change stored settings.
+    Config {
+        #[command(subcommand)]
+        cmd: ConfigCommands,
+    },
+
+    /// Database maintenance.
+    Db {
+        #[command(subcommand)]
+        cmd: DbCommands,
     },
 
     /// Render a single XYZ tile as a PNG.
@@ -60,12 +409,12 @@ enum Commands {
         /// Tile to render, in "z/x/y" format.
         zxy: Tile,
 
-        /// Select activities before this date (YYYY-MM-DD).
-        #[arg(short, long, value_parser = try_parse_date)]
+        /// Select activities before this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
         before: Option<Date>,
 
-        /// Select activities after this date (YYYY-MM-DD).
-        #[arg(short, long, value_parser = try_parse_date)]
+        /// Select activities after this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
         after: Option<Date>,
 
         /// Filter activities by arbitrary metadata properties
@@ -78,8 +427,45 @@ enum Commands {
         /// by `;`. Colors may be written as `RGB`, `RRGGBB`, or `RRGGBBAA`
         ///
         /// For example: `0:001122;25:789;50:334455;75:ffffff33`
+        ///
+        /// Also accepts a JSON object with different thresholds per zoom
+        /// level, see the README for details.
         #[arg(short, long)]
-        gradient: Option<LinearGradient>,
+        gradient: Option<Gradient>,
+
+        /// How activity counts are mapped onto the gradient.
+        #[arg(long, value_enum, default_value_t)]
+        intensity: Intensity,
+
+        /// Width of activity lines, in pixels.
+        #[arg(long, default_value_t = 1)]
+        line_width: u32,
+
+        /// Blur activity lines, with this standard deviation in pixels, for a
+        /// softer look.
+        #[arg(long, default_value_t = 0.0)]
+        blur: f32,
+
+        /// Draw anti-aliased activity lines.
+        #[arg(long)]
+        antialias: bool,
+
+        /// What pixel counts measure: every pass of a line, or distinct
+        /// activities.
+        #[arg(long, value_enum, default_value_t)]
+        count: Count,
+
+        /// Fade out older activities, which count half as much after this
+        /// many days.
+        #[arg(long, value_name = "DAYS")]
+        half_life: Option<f32>,
+
+        /// Color activities by a property instead of how often they overlap.
+        ///
+        /// One of `year`, a property name (e.g. `activity_type`), or
+        /// `{property}:{step}` to group numeric values into buckets.
+        #[arg(long)]
+        color_by: Option<ColorBy>,
 
         /// Width of output image in pixels.
         #[arg(short, long, default_value = "1024")]
@@ -106,12 +492,12 @@ enum Commands {
         #[arg(short = 'H', long, default_value = "1024")]
         height: u32,
 
-        /// Select activities before this date (YYYY-MM-DD).
-        #[arg(short, long, value_parser = try_parse_date)]
+        /// Select activities before this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
         before: Option<Date>,
 
-        /// Select activities after this date (YYYY-MM-DD).
-        #[arg(short, long, value_parser = try_parse_date)]
+        /// Select activities after this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
         after: Option<Date>,
 
         /// Filter activities by arbitrary metadata properties
@@ -126,214 +512,2171 @@ enum Commands {
         /// by `;`. Colors may be written as `RGB`, `RRGGBB`, or `RRGGBBAA`
         ///
         /// For example: `0:001122;25:789;50:334455;75:ffffff33`
+        ///
+        /// Also accepts a JSON object with different thresholds per zoom
+        /// level, see the README for details.
         #[arg(short, long)]
-        gradient: Option<LinearGradient>,
+        gradient: Option<Gradient>,
+
+        /// How activity counts are mapped onto the gradient.
+        #[arg(long, value_enum, default_value_t)]
+        intensity: Intensity,
+
+        /// Width of activity lines, in pixels.
+        #[arg(long, default_value_t = 1)]
+        line_width: u32,
+
+        /// Blur activity lines, with this standard deviation in pixels, for a
+        /// softer look.
+        #[arg(long, default_value_t = 0.0)]
+        blur: f32,
+
+        /// Draw anti-aliased activity lines.
+        #[arg(long)]
+        antialias: bool,
+
+        /// What pixel counts measure: every pass of a line, or distinct
+        /// activities.
+        #[arg(long, value_enum, default_value_t)]
+        count: Count,
+
+        /// Fade out older activities, which count half as much after this
+        /// many days.
+        #[arg(long, value_name = "DAYS")]
+        half_life: Option<f32>,
+
+        /// Color activities by a property instead of how often they overlap.
+        ///
+        /// One of `year`, a property name (e.g. `activity_type`), or
+        /// `{property}:{step}` to group numeric values into buckets.
+        #[arg(long)]
+        color_by: Option<ColorBy>,
+
+        /// Draw the heatmap, or shade the zoom 14 tiles which contain at
+        /// least one activity ("explorer tiles").
+        #[arg(long, value_enum, default_value_t)]
+        mode: RenderMode,
+
+        /// Output format.
+        ///
+        /// `svg` and `pdf` draw each activity's track as a vector path in the
+        /// gradient's lowest color, rather than rasterizing the heatmap.
+        #[arg(long, value_enum, default_value_t)]
+        format: RenderFormat,
+
+        /// Draw the heatmap over tiles from this XYZ tile server.
+        ///
+        /// For example: `https://tile.openstreetmap.org/{z}/{x}/{y}.png`
+        #[arg(long)]
+        basemap: Option<String>,
+
+        /// Opacity of the heatmap when drawn over a basemap, from 0 to 1.
+        #[arg(long, default_value = "1.0", requires = "basemap")]
+        opacity: f32,
+
+        /// Attribution text for the basemap, drawn in the bottom right corner.
+        #[arg(long, default_value = basemap::DEFAULT_ATTRIBUTION, requires = "basemap")]
+        attribution: String,
 
         /// Path to output image.
         #[arg(short, long, default_value = "tile.png")]
         output: PathBuf,
     },
 
-    /// Start an XYZ raster tile server.
-    Serve {
-        /// Host to listen on.
-        #[arg(short = 'H', long, default_value = "127.0.0.1")]
-        host: String,
+    /// Render a single activity's track, e.g. as a thumbnail.
+    RenderActivity {
+        /// Activity ID, as shown by the `activities` subcommand.
+        id: i64,
 
-        /// Port to listen on.
-        #[arg(short, long, default_value = "8080")]
-        port: u16,
+        /// Width of output image in pixels.
+        #[arg(short, long, default_value = "256")]
+        width: u32,
 
-        /// Allow uploading new activities via `/upload` endpoint.
-        ///
-        /// Remember to set `HOTPOT_UPLOAD_TOKEN` environment variable.
-        #[arg(long, default_value = "false")]
-        upload: bool,
+        /// Height of output image in pixels.
+        #[arg(short = 'H', long, default_value = "256")]
+        height: u32,
 
-        /// Allow exporting arbitrary viewports as images via `/render`
-        /// endpoint.
-        #[arg(long, default_value = "false")]
-        render: bool,
+        /// Line color, written as `RGB`, `RRGGBB`, or `RRGGBBAA`.
+        #[arg(short, long, value_parser = try_parse_color, default_value = raster::DEFAULT_ACTIVITY_COLOR)]
+        color: Rgba<u8>,
 
-        /// Enable Strava activity webhook
-        ///
-        /// Use `strava-auth` subcommand to grab OAuth tokens.
+        /// Render the track without trimming or privacy masks applied.
         #[arg(long, default_value = "false")]
-        strava_webhook: bool,
+        unmasked: bool,
 
-        /// Allow cross origin requests (use CORS headers)
-        #[arg(long, default_value = "false")]
-        cors: bool,
+        /// Path to output image.
+        #[arg(short, long, default_value = "activity.png")]
+        output: PathBuf,
     },
 
-    /// Authenticate with Strava to fetch OAuth tokens for webhook.
-    StravaAuth {
-        /// Host to listen on
-        #[arg(short = 'H', long, default_value = "127.0.0.1")]
-        host: String,
+    /// Render a time-lapse of a region as a sequence of frames, e.g. the
+    /// heatmap growing month by month.
+    Timelapse {
+        /// Coordinates in order of "west,south,east,north"
+        #[arg(long = "bounds")]
+        viewport: WebMercatorViewport,
 
-        /// Port to listen on
-        #[arg(short, long, default_value = "8080")]
-        port: u16,
-    },
-}
+        /// Width of output frames in pixels.
+        #[arg(short, long, default_value = "1024")]
+        width: u32,
 
-#[derive(Args)]
-struct GlobalOpts {
-    /// Path to database
-    #[arg(short = 'D', long = "db", default_value = "./hotpot.sqlite3")]
-    db_path: PathBuf,
-    /// Enable verbose logging
-    #[arg(short, long)]
-    verbose: bool,
-}
+        /// Height of output frames in pixels.
+        #[arg(short = 'H', long, default_value = "1024")]
+        height: u32,
 
-#[derive(Parser)]
-#[command(author, version, about)]
-struct Opts {
-    #[clap(flatten)]
-    global: GlobalOpts,
+        /// Time span covered by each frame.
+        #[arg(long, value_enum, default_value = "month")]
+        step: FrameStep,
 
-    /// Subcommand
-    #[command(subcommand)]
-    cmd: Commands,
-}
+        /// Only show activities within each frame's time span, instead of
+        /// all activities up to that point.
+        #[arg(long, default_value = "false")]
+        no_cumulative: bool,
 
-fn main() {
-    if let Err(e) = run() {
-        eprintln!("error: {}", e);
-        std::process::exit(1);
-    }
-}
+        /// Date of the first frame (YYYY-MM-DD), defaults to the first activity.
+        #[arg(long, value_parser = date::parse_date, allow_hyphen_values = true)]
+        from: Option<Date>,
 
-fn run() -> Result<()> {
-    let opts = Opts::parse();
+        /// Date of the last frame (YYYY-MM-DD), defaults to the last activity.
+        #[arg(long, value_parser = date::parse_date, allow_hyphen_values = true)]
+        to: Option<Date>,
 
-    tracing_subscriber::fmt()
-        .compact()
-        .with_max_level(if opts.global.verbose {
-            tracing::Level::DEBUG
-        } else {
-            tracing::Level::INFO
-        })
-        .init();
+        /// Filter activities by arbitrary metadata properties
+        #[arg(short, long)]
+        filter: Option<PropertyFilter>,
 
-    // TODO: pull out into separate function
-    match opts.cmd {
-        Commands::Import {
-            path,
-            reset,
-            join,
-            trim,
-        } => {
-            let mut db = Database::new(&opts.global.db_path)?;
+        /// Custom color gradient to use for heatmap.
+        #[arg(short, long)]
+        gradient: Option<Gradient>,
 
-            // TODO: should be persisted to DB
-            if let Some(trim) = trim {
-                db.config.trim_dist = trim;
-            }
+        /// How activity counts are mapped onto the gradient.
+        #[arg(long, value_enum, default_value_t)]
+        intensity: Intensity,
 
-            let prop_source = join
-                .map(|csv| PropertySource::from_csv(&csv))
-                .transpose()?
-                .unwrap_or_default();
+        /// Width of activity lines, in pixels.
+        #[arg(long, default_value_t = 1)]
+        line_width: u32,
 
-            if reset {
-                db.reset_activities()?;
-            }
+        /// Blur activity lines, with this standard deviation in pixels, for a
+        /// softer look.
+        #[arg(long, default_value_t = 0.0)]
+        blur: f32,
 
-            activity::import_path(&path, &db, &prop_source)?;
-        }
+        /// Draw anti-aliased activity lines.
+        #[arg(long)]
+        antialias: bool,
 
-        Commands::Tile {
-            zxy,
-            width,
-            output,
-            filter,
-            before,
-            after,
-            gradient,
-        } => {
-            let db = Database::open(&opts.global.db_path)?;
-            let mut file = File::create(output)?;
+        /// What pixel counts measure: every pass of a line, or distinct
+        /// activities.
+        #[arg(long, value_enum, default_value_t)]
+        count: Count,
 
-            let filter = ActivityFilter::new(before, after, filter);
-            let gradient = gradient.unwrap_or_else(|| PINKISH.clone());
-            let image =
-                raster::render_tile(zxy, &gradient, width, &filter, &db)?.unwrap_or_else(|| {
-                    // note: could also just use RgbaImage::default() here if we don't care about size.
-                    RgbaImage::new(width, width)
-                });
+        /// Fill frames with a background color (`RGB`, `RRGGBB`, or
+        /// `RRGGBBAA`) instead of leaving them transparent.
+        #[arg(long, value_parser = try_parse_color)]
+        background: Option<Rgba<u8>>,
 
-            image.write_to(&mut file, image::ImageOutputFormat::Png)?;
-        }
+        /// How long each frame is shown in animated output, in milliseconds.
+        #[arg(long, default_value = "500")]
+        frame_delay: u32,
 
-        Commands::Render {
-            viewport,
-            width,
-            height,
-            before,
-            after,
-            filter,
-            gradient,
-            output,
-        } => {
+        /// Path ending in `.gif` for an animated GIF, otherwise a directory to
+        /// write numbered PNG frames to.
+        #[arg(short, long, default_value = "timelapse.gif")]
+        output: PathBuf,
+    },
+
+    /// Render all tiles within a bounding box to a static `{z}/{x}/{y}.png`
+    /// directory structure (or MBTiles tileset), for hosting without a tile
+    /// server.
+    Pregenerate {
+        /// Coordinates in order of "west,south,east,north"
+        ///
+        /// Use a tool like https://boundingbox.klokantech.com/ to generate.
+        #[arg(long = "bounds")]
+        viewport: WebMercatorViewport,
+
+        /// Lowest zoom level to render.
+        #[arg(long, default_value = "2")]
+        min_zoom: u8,
+
+        /// Highest zoom level to render.
+        #[arg(long, default_value = "14")]
+        max_zoom: u8,
+
+        /// Width of each tile in pixels.
+        #[arg(short, long, default_value = "256")]
+        width: u32,
+
+        /// Select activities before this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
+        before: Option<Date>,
+
+        /// Select activities after this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
+        after: Option<Date>,
+
+        /// Filter activities by arbitrary metadata properties
+        #[arg(short, long)]
+        filter: Option<PropertyFilter>,
+
+        /// Custom color gradient to use for heatmap.
+        ///
+        /// Represented as a string of threshold values and colors, separated
+        /// by `;`. Colors may be written as `RGB`, `RRGGBB`, or `RRGGBBAA`
+        ///
+        /// For example: `0:001122;25:789;50:334455;75:ffffff33`
+        ///
+        /// Also accepts a JSON object with different thresholds per zoom
+        /// level, see the README for details.
+        #[arg(short, long)]
+        gradient: Option<Gradient>,
+
+        /// How activity counts are mapped onto the gradient.
+        #[arg(long, value_enum, default_value_t)]
+        intensity: Intensity,
+
+        /// Width of activity lines, in pixels.
+        #[arg(long, default_value_t = 1)]
+        line_width: u32,
+
+        /// Blur activity lines, with this standard deviation in pixels, for a
+        /// softer look.
+        #[arg(long, default_value_t = 0.0)]
+        blur: f32,
+
+        /// Draw anti-aliased activity lines.
+        #[arg(long)]
+        antialias: bool,
+
+        /// What pixel counts measure: every pass of a line, or distinct
+        /// activities.
+        #[arg(long, value_enum, default_value_t)]
+        count: Count,
+
+        /// Fade out older activities, which count half as much after this
+        /// many days.
+        #[arg(long, value_name = "DAYS")]
+        half_life: Option<f32>,
+
+        /// Output directory, or a path ending in `.mbtiles` to write a
+        /// single MBTiles tileset instead.
+        #[arg(short, long, default_value = "tiles")]
+        output: PathBuf,
+    },
+
+    /// Start an XYZ raster tile server.
+    Serve(Box<ServeArgs>),
+
+    /// Save Strava API credentials to the database, instead of passing them
+    /// as environment variables to `strava-auth` and `serve`.
+    StravaSetup {
+        /// Client ID of your Strava API application.
+        #[arg(long)]
+        client_id: u64,
+
+        /// Client secret of your Strava API application.
+        #[arg(long)]
+        client_secret: String,
+
+        /// Token for verifying the webhook subscription, generated if not
+        /// given.
+        #[arg(long)]
+        webhook_secret: Option<String>,
+    },
+
+    /// Subscribe to Strava webhook events, and save the subscription so that
+    /// events for other subscriptions are rejected.
+    ///
+    /// The server needs to be running with `--strava-webhook` (and reachable
+    /// at the callback URL), since Strava checks it before subscribing.
+    StravaSubscribe {
+        /// Public URL of the webhook, e.g. `https://example.com/strava/webhook`
+        callback_url: String,
+    },
+
+    /// Authenticate with Strava to fetch OAuth tokens for webhook.
+    StravaAuth {
+        /// Host to listen on
+        #[arg(short = 'H', long, default_value = "127.0.0.1")]
+        host: String,
+
+        /// Port to listen on
+        #[arg(short, long, default_value = "8080")]
+        port: u16,
+    },
+
+    /// Log in to Komoot, storing a token for `komoot-sync`.
+    ///
+    /// The password is read from the `KOMOOT_PASSWORD` environment variable,
+    /// or prompted for if not set. Only the token is stored.
+    KomootAuth {
+        /// Email address of your Komoot account.
+        email: String,
+    },
+
+    /// Import recorded tours from Komoot which haven't been imported yet.
+    KomootSync,
+
+    /// Add the city and country where activities start and end as
+    /// `start_city`, `start_country`, `end_city` and `end_country`.
+    ///
+    /// Uses a Nominatim or Photon server, ideally self-hosted. Lookups are
+    /// cached, so running this again only geocodes new places.
+    Geocode {
+        /// Base URL of the geocoding server, e.g. `http://localhost:8080`
+        #[arg(long)]
+        url: String,
+
+        #[arg(long, value_enum, default_value_t)]
+        provider: geocode::Provider,
+
+        /// Also geocode activities which already have a location.
+        #[arg(long, default_value = "false")]
+        all: bool,
+    },
+
+    /// Authenticate with Garmin Connect to fetch OAuth tokens for webhook.
+    GarminAuth {
+        /// Host to listen on
+        #[arg(short = 'H', long, default_value = "127.0.0.1")]
+        host: String,
+
+        /// Port to listen on
+        #[arg(short, long, default_value = "8080")]
+        port: u16,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    /// Tab separated columns, one row per line
+    Table,
+    /// One JSON object per line
+    Json,
+}
+
+/// Format seconds as `H:MM`.
+fn format_duration(secs: f64) -> String {
+    let minutes = (secs / 60.0).round() as u64;
+    format!("{}:{:02}", minutes / 60, minutes % 60)
+}
+
+#[derive(Subcommand)]
+enum MaskCommands {
+    /// Add (or replace) a privacy mask.
+    ///
+    /// New activities will have any points inside the mask removed. Use
+    /// `mask apply` to also update existing activities.
+    Add {
+        /// Name of the mask
+        name: String,
+
+        /// Circular mask, given as "lng,lat,radius" (radius in meters)
+        #[arg(long, value_parser = MaskGeometry::parse_circle, conflicts_with = "polygon", required_unless_present = "polygon")]
+        circle: Option<MaskGeometry>,
+
+        /// Polygonal mask, given as "lng,lat;lng,lat;..."
+        #[arg(long, value_parser = MaskGeometry::parse_polygon)]
+        polygon: Option<MaskGeometry>,
+    },
+
+    /// List all privacy masks.
+    List,
+
+    /// Remove a privacy mask.
+    ///
+    /// Note that points already removed from activities will not be restored.
+    Remove {
+        /// Name of the mask
+        name: String,
+    },
+
+    /// Apply all privacy masks to previously imported activities.
+    Apply,
+}
+
+#[derive(Subcommand)]
+enum TokenCommands {
+    /// Issue a new token, and print it.
+    Create {
+        /// Name of the token, e.g. the device it's for
+        name: String,
+
+        /// What the token may be used for.
+        #[arg(long, value_enum)]
+        scope: tokens::Scope,
+    },
+
+    /// Revoke a token, so it can no longer be used.
+    Revoke {
+        /// Name of the token
+        name: String,
+    },
+
+    /// List all tokens (without their secret values).
+    List,
+}
+
+#[derive(Subcommand)]
+enum UserCommands {
+    /// Create a user, and print their API token.
+    ///
+    /// The token can be used to upload activities (see `/upload`), which
+    /// are then shown on the user's own map at `/u/<name>/`.
+    Add {
+        /// Name of the user, as used in URLs
+        name: String,
+
+        /// Strava athlete ID, to assign activities received through the
+        /// Strava webhook to this user.
+        #[arg(long)]
+        strava_athlete: Option<u64>,
+    },
+
+    /// List all users.
+    List,
+
+    /// Remove a user. Their activities are kept.
+    Remove {
+        /// Name of the user
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum GradientCommands {
+    /// Add (or replace) a named gradient.
+    Add {
+        /// Name of the gradient, as used in `?color=<name>`
+        name: String,
+
+        /// Gradient, in the same format as `--gradient`
+        #[arg(value_parser = try_parse_gradient)]
+        gradient: String,
+    },
+
+    /// List all named gradients.
+    List,
+
+    /// Remove a named gradient.
+    Remove {
+        /// Name of the gradient
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum AnalyzeCommands {
+    /// Rank the most traveled stretches of road or trail.
+    ///
+    /// Tracks are binned into a grid of cells (the tiles at `--zoom`), and
+    /// neighboring cells passed by the same number of distinct activities are
+    /// grouped into segments.
+    TopSegments {
+        /// Zoom level of the grid. Cells are ~150m across at 18, ~40m at 20.
+        #[arg(short, long, default_value = "18")]
+        zoom: u8,
+
+        /// Number of segments to list.
+        #[arg(short = 'n', long, default_value = "20")]
+        limit: usize,
+
+        /// Select activities before this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
+        before: Option<Date>,
+
+        /// Select activities after this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, value_parser = date::parse_date, allow_hyphen_values = true)]
+        after: Option<Date>,
+
+        /// Filter activities by arbitrary metadata properties
+        #[arg(short, long)]
+        filter: Option<PropertyFilter>,
+
+        /// Only consider this area, given as `west,south,east,north`
+        /// coordinates.
+        #[arg(long, allow_hyphen_values = true)]
+        bbox: Option<WebMercatorViewport>,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+}
+
+/// Format a number of bytes with binary prefixes, e.g. `1.5 MiB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+#[derive(Subcommand)]
+enum ViewCommands {
+    /// Add (or replace) a saved view.
+    ///
+    /// Tiles under `/view/<name>/tile/...` use the view's settings, unless
+    /// overridden by the request's query parameters.
+    Add {
+        /// Name of the view, as used in URLs
+        name: String,
+
+        /// Filter expression, in the same format as `--filter`
+        #[arg(short, long)]
+        filter: Option<String>,
+
+        /// Named gradient, as used in `?color=<name>`
+        #[arg(long, conflicts_with = "gradient")]
+        color: Option<String>,
+
+        /// Gradient, in the same format as `--gradient`
+        #[arg(long, value_parser = try_parse_gradient)]
+        gradient: Option<String>,
+
+        /// Select activities before this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, allow_hyphen_values = true)]
+        before: Option<String>,
+
+        /// Select activities after this date (YYYY-MM-DD, or relative like -90d).
+        #[arg(short, long, allow_hyphen_values = true)]
+        after: Option<String>,
+    },
+
+    /// List all saved views.
+    List,
+
+    /// Remove a saved view.
+    Remove {
+        /// Name of the view
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print all settings.
+    List,
+
+    /// Print the value of a single setting.
+    Get {
+        /// One of `zoom_levels`, `tile_extent`, `trim_dist`, `default_gradient`,
+        /// `include_virtual`, `hooks_file`
+        key: String,
+    },
+
+    /// Change a setting.
+    ///
+    /// Changing `zoom_levels`, `tile_extent`, `trim_dist`, `include_virtual`
+    /// or `hooks_file` only applies to activities imported afterwards.
+    Set {
+        /// One of `zoom_levels`, `tile_extent`, `trim_dist`, `default_gradient`,
+        /// `include_virtual`, `hooks_file`
+        key: String,
+
+        /// New value, e.g. `2,6,10,14,16` for `zoom_levels`
+        #[arg(allow_hyphen_values = true)]
+        value: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DbCommands {
+    /// Upgrade the database schema to the current version.
+    ///
+    /// This also happens automatically whenever the database is opened.
+    Migrate {
+        /// Only list the migrations which would be applied.
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+    },
+
+    /// Check the database for corruption, shrink tiles stored in an older
+    /// format, refresh query planner statistics, and reclaim unused space.
+    ///
+    /// Vacuuming rewrites the whole database, which can take a few minutes
+    /// for large ones.
+    Maintain {
+        /// Skip the `VACUUM` step.
+        #[arg(long, default_value = "false")]
+        no_vacuum: bool,
+    },
+
+    /// Copy the database to a file. Safe to run while the server is running
+    /// or activities are being imported.
+    Backup {
+        /// Where to write the backup, replacing the file if it exists.
+        path: PathBuf,
+    },
+
+    /// Replace the database with a backup made by `db backup`.
+    ///
+    /// Restart the server afterwards, if one is running.
+    Restore {
+        /// Backup to restore.
+        path: PathBuf,
+    },
+
+    /// Show what takes up space in the database: tiles per zoom level, the
+    /// largest activities, and the size of each table and index.
+    ///
+    /// Sizes of zoom levels are estimates of what removing them from the
+    /// `zoom_levels` setting (and re-importing) would save.
+    Stats {
+        /// Number of largest activities to list.
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+
+    /// Build an R*Tree index over the stored tiles, which speeds up looking
+    /// them up over wide areas (e.g. low zoom tiles) in large databases.
+    ///
+    /// The index makes the database larger and imports slower.
+    SpatialIndex {
+        /// Remove the index again.
+        #[arg(long, default_value = "false")]
+        drop: bool,
+    },
+
+    /// Draw the precomputed low zoom tiles which are missing or out of date.
+    ///
+    /// The server keeps these up to date in the background, this is useful
+    /// to have them ready before copying a database over to it.
+    Aggregate,
+
+    /// Time tile lookups at each zoom level, with and without the spatial
+    /// index.
+    Bench {
+        /// Number of tiles with activities to sample.
+        #[arg(short = 'n', long, default_value_t = 100)]
+        samples: usize,
+    },
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Read options from a TOML file, using the same names as the flags
+    /// (e.g. `tile_cache_size = 5000`). Flags given on the command line take
+    /// precedence.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Host to listen on.
+    #[arg(short = 'H', long, default_value = "127.0.0.1")]
+    host: String,
+
+    /// Port to listen on.
+    #[arg(short, long, default_value = "8080")]
+    port: u16,
+
+    /// Allow uploading new activities via `/upload` endpoint, and
+    /// deleting them via `DELETE /api/activities/:id`.
+    ///
+    /// Remember to set `HOTPOT_UPLOAD_TOKEN` environment variable, which
+    /// works as an admin token (see `hotpot token` for scoped ones).
+    #[arg(long, default_value = "false")]
+    upload: bool,
+
+    /// Allow exporting arbitrary viewports as images via `/render`
+    /// endpoint.
+    #[arg(long, default_value = "false")]
+    render: bool,
+
+    /// Serve signed links to `/render` images at `/render/signed`, which
+    /// work without `--render` or a token until they expire. Links are made
+    /// with `POST /api/render-links`, using a render token.
+    ///
+    /// Signed with `HOTPOT_RENDER_SECRET`, or a secret generated once and
+    /// kept in the database.
+    #[arg(long, default_value = "false")]
+    render_links: bool,
+
+    /// Host multiple users (see `user add`), each with their own map
+    /// at `/u/<name>/`.
+    ///
+    /// Uploads are assigned to the user whose token was given.
+    #[arg(long, default_value = "false")]
+    users: bool,
+
+    /// Enable Strava activity webhook
+    ///
+    /// Use `strava-auth` subcommand to grab OAuth tokens.
+    #[arg(long, default_value = "false")]
+    strava_webhook: bool,
+
+    /// Don't import private ("Only You") activities from the Strava
+    /// webhook, and remove activities which are made private later.
+    #[arg(long, default_value = "false", requires = "strava_webhook")]
+    strava_skip_private: bool,
+
+    /// Hide points within given distance (meters) of start/end of
+    /// private Strava activities, instead of the usual trim distance.
+    #[arg(
+        long,
+        requires = "strava_webhook",
+        conflicts_with = "strava_skip_private"
+    )]
+    strava_private_trim: Option<f64>,
+
+    /// Only accept Strava webhook events from this IP address or range
+    /// (e.g. `10.0.0.0/8`). Can be repeated.
+    ///
+    /// Behind a reverse proxy, the address is taken from
+    /// `--client-ip-header`.
+    #[arg(long, value_parser = try_parse_ip_net, requires = "strava_webhook")]
+    strava_webhook_allow: Vec<ipnet::IpNet>,
+
+    /// Enable Garmin Connect activity file push notifications
+    ///
+    /// Use `garmin-auth` subcommand to grab OAuth tokens.
+    #[arg(long, default_value = "false")]
+    garmin_webhook: bool,
+
+    /// Allow cross origin requests (use CORS headers)
+    ///
+    /// By default, `GET` requests are allowed from any origin.
+    #[arg(long, default_value = "false")]
+    cors: bool,
+
+    /// Only allow cross origin requests from this origin, e.g.
+    /// `https://example.com`. Can be repeated.
+    #[arg(long, value_parser = try_parse_origin, requires = "cors")]
+    cors_origin: Vec<HeaderValue>,
+
+    /// Allow cross origin requests with this method, e.g. `POST` for
+    /// uploads. Can be repeated.
+    #[arg(long, value_parser = try_parse_method, requires = "cors")]
+    cors_method: Vec<Method>,
+
+    /// Allow cross origin requests with this header, e.g. `Authorization`
+    /// for uploads with a token. Can be repeated.
+    #[arg(long, value_parser = try_parse_header_name, requires = "cors")]
+    cors_header: Vec<HeaderName>,
+
+    /// Limit how many tiles each client can request, e.g. `600/m`. Requests
+    /// over the limit get `429 Too Many Requests`.
+    #[arg(long)]
+    rate_limit_tiles: Option<rate_limit::Limit>,
+
+    /// Limit how many images each client can request from `/render`, e.g.
+    /// `10/m`.
+    #[arg(long)]
+    rate_limit_render: Option<rate_limit::Limit>,
+
+    /// Limit how many uploads (including OwnTracks locations) each client
+    /// can make, e.g. `100/h`.
+    #[arg(long)]
+    rate_limit_upload: Option<rate_limit::Limit>,
+
+    /// Rate limit clients by the address in this header, e.g.
+    /// `X-Forwarded-For` or `Fly-Client-IP`, when behind a reverse proxy.
+    /// Requests without it are limited by the connecting address instead.
+    ///
+    /// Only use this if the proxy always sets the header, since clients
+    /// could otherwise make up their own.
+    #[arg(long, value_parser = try_parse_header_name)]
+    client_ip_header: Option<HeaderName>,
+
+    /// Serve HTTPS using this certificate chain (PEM file), e.g. as
+    /// required for the Strava webhook's callback URL.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM file) for `--tls-cert`.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS for this domain, with a certificate provisioned (and
+    /// renewed) automatically from Let's Encrypt. Can be repeated.
+    ///
+    /// Uses the TLS-ALPN-01 challenge, so the server needs to be reachable
+    /// on port 443. Certificates are stored in an `acme` directory next to
+    /// the database.
+    #[arg(long, conflicts_with = "tls_cert")]
+    acme_domain: Vec<String>,
+
+    /// Email address for Let's Encrypt to send expiry notices to.
+    #[arg(long, requires = "acme_domain")]
+    acme_email: Option<String>,
+
+    /// Use Let's Encrypt's staging environment, for testing.
+    #[arg(long, default_value = "false", requires = "acme_domain")]
+    acme_staging: bool,
+
+    /// Require a token (see `token create --scope render`) to view the
+    /// map, tiles and activities, rather than showing them to anyone.
+    #[arg(long, default_value = "false")]
+    private: bool,
+
+    /// Gradient for tiles requested without a `color` or `gradient`
+    /// parameter, in the same format as `--gradient` for other commands.
+    ///
+    /// Saved to the database, so later runs keep using it.
+    #[arg(long, value_parser = try_parse_gradient)]
+    default_gradient: Option<String>,
+
+    /// Number of rendered tiles to cache in memory (0 to disable).
+    ///
+    /// The cache is cleared whenever activities are changed through the
+    /// server, but not when importing from the command line.
+    #[arg(long, default_value = "1000")]
+    tile_cache_size: usize,
+
+    /// Number of threads to render tiles and images on, separate from
+    /// the ones serving requests. Defaults to one per CPU.
+    #[arg(long, default_value_t = 0)]
+    render_threads: usize,
+
+    /// Number of tiles and images to render at once. Defaults to one per
+    /// render thread; lower it to leave CPU and memory for other requests
+    /// on a small server.
+    #[arg(long, default_value_t = 0)]
+    max_concurrent_renders: usize,
+
+    /// How long a render waits for its turn before giving up with `503
+    /// Service Unavailable`, e.g. `5s`. With `0`, renders which can't start
+    /// right away fail.
+    #[arg(long, value_parser = try_parse_timeout, default_value = "10s")]
+    render_queue_timeout: Duration,
+
+    /// Watch a directory, and automatically import activity files as
+    /// they're added (e.g. a folder synced by Syncthing or Dropbox).
+    #[arg(long)]
+    watch: Option<PathBuf>,
+
+    /// Periodically rescan this path for new activity files.
+    ///
+    /// Like `import`, files which were already imported are skipped.
+    #[arg(long, requires = "reimport_interval")]
+    import_path: Option<PathBuf>,
+
+    /// How often to rescan `--import-path`, e.g. `30m` or `6h`.
+    #[arg(long, value_parser = try_parse_duration, requires = "import_path")]
+    reimport_interval: Option<Duration>,
+
+    /// With `--upload`, how long a device can go without sending its
+    /// location to `/api/owntracks` before its track so far is saved as an
+    /// activity.
+    #[arg(long, value_parser = try_parse_duration, default_value = "30m")]
+    live_timeout: Duration,
+
+    /// Periodically import new tours from Komoot, e.g. every `1h`.
+    ///
+    /// Use `komoot-auth` subcommand to log in first.
+    #[arg(long, value_parser = try_parse_duration)]
+    komoot_sync_interval: Option<Duration>,
+
+    /// Only read from the database, e.g. to serve a copy replicated by
+    /// Litestream or Syncthing from another host.
+    ///
+    /// Routes and options which would change the database are disabled,
+    /// and the file should be replaced as a whole rather than written to
+    /// in place. Newer copies are picked up within a minute or two.
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with_all = [
+            "upload",
+            "strava_webhook",
+            "garmin_webhook",
+            "default_gradient",
+            "watch",
+            "import_path",
+            "komoot_sync_interval",
+        ],
+    )]
+    read_only: bool,
+
+    /// Periodically back up the database into this directory, as
+    /// `hotpot-<timestamp>.sqlite3` files.
+    #[arg(long)]
+    backup_dir: Option<PathBuf>,
+
+    /// How often to back up the database to `--backup-dir`.
+    #[arg(long, value_parser = try_parse_duration, default_value = "24h", requires = "backup_dir")]
+    backup_interval: Duration,
+
+    /// Number of backups to keep in `--backup-dir`, removing the oldest.
+    #[arg(long, default_value = "7", requires = "backup_dir")]
+    backup_keep: usize,
+}
+
+#[derive(Args)]
+struct GlobalOpts {
+    /// Path to database
+    #[arg(short = 'D', long = "db", default_value = "./hotpot.sqlite3")]
+    db_path: PathBuf,
+    /// Enable verbose logging
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[derive(Parser)]
+#[command(author, version, about)]
+struct Opts {
+    #[clap(flatten)]
+    global: GlobalOpts,
+
+    /// Subcommand
+    #[command(subcommand)]
+    cmd: Commands,
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Parse the command line again for `serve --config`, adding the options
+/// from the config file which weren't given as flags.
+fn serve_args() -> Result<(ServeArgs, ServerConfigFile)> {
+    let parse = |args: &[OsString]| -> Result<(Command, ArgMatches)> {
+        let mut command = Opts::command();
+        let matches = command.try_get_matches_from_mut(args).map_err(|err| {
+            let message = err.to_string();
+            let first_line = message.lines().next().unwrap_or_default();
+            anyhow!("{}", first_line.trim_start_matches("error: "))
+        })?;
+        Ok((command, matches))
+    };
+    let serve_matches = |matches: &ArgMatches| -> Result<ArgMatches> {
+        matches
+            .subcommand_matches("serve")
+            .cloned()
+            .ok_or_else(|| anyhow!("expected serve command"))
+    };
+
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let (mut command, matches) = parse(&args)?;
+    let matches = serve_matches(&matches)?;
+    let serve_args = ServeArgs::from_arg_matches(&matches)?;
+    let Some(path) = &serve_args.config else {
+        return Ok((serve_args, ServerConfigFile::default()));
+    };
+
+    let file = ServerConfigFile::load(path)?;
+    let serve_command = command
+        .find_subcommand_mut("serve")
+        .expect("serve command exists");
+    args.extend(
+        file.args(serve_command, &matches)?
+            .into_iter()
+            .map(OsString::from),
+    );
+
+    let (_, matches) = parse(&args)?;
+    let serve_args = ServeArgs::from_arg_matches(&serve_matches(&matches)?)?;
+    Ok((serve_args, file))
+}
+
+/// Address to listen on and server configuration for `serve`.
+fn serve_config(
+    args: ServeArgs,
+    file: ServerConfigFile,
+    db_path: &Path,
+) -> Result<(SocketAddr, web::Config)> {
+    let tls = if !args.acme_domain.is_empty() {
+        Some(web::TlsConfig::Acme(web::AcmeOptions {
+            domains: args.acme_domain,
+            contact: args.acme_email,
+            cache_dir: db_path.with_file_name("acme"),
+            staging: args.acme_staging,
+        }))
+    } else {
+        args.tls_cert
+            .zip(args.tls_key)
+            .map(|(cert, key)| web::TlsConfig::Files { cert, key })
+    };
+
+    let addr = format!("{}:{}", args.host, args.port).parse()?;
+    let routes = web::RouteConfig {
+        strava_webhook: args.strava_webhook,
+        upload: args.upload,
+        render: args.render,
+        render_links: args.render_links,
+        users: args.users,
+        tiles: true,
+        strava_auth: false,
+        garmin_webhook: args.garmin_webhook,
+        garmin_auth: false,
+    };
+
+    let config = web::Config {
+        cors: args.cors.then(|| web::CorsConfig {
+            origins: args.cors_origin,
+            methods: args.cors_method,
+            headers: args.cors_header,
+        }),
+        private: args.private,
+        routes,
+        upload_token: std::env::var("HOTPOT_UPLOAD_TOKEN")
+            .ok()
+            .or(file.upload_token),
+        render_secret: std::env::var("HOTPOT_RENDER_SECRET")
+            .ok()
+            .or(file.render_secret),
+        tile_cache_size: args.tile_cache_size,
+        render_threads: args.render_threads,
+        max_concurrent_renders: args.max_concurrent_renders,
+        render_queue_timeout: args.render_queue_timeout,
+        watch_dir: args.watch,
+        reimport: args.import_path.zip(args.reimport_interval),
+        live_timeout: args.live_timeout,
+        komoot_sync: args.komoot_sync_interval,
+        strava_privacy: strava::PrivacyOptions {
+            skip_private: args.strava_skip_private,
+            private_trim: args.strava_private_trim,
+        },
+        strava_webhook_ips: args.strava_webhook_allow,
+        rate_limits: rate_limit::RateLimits {
+            tiles: args.rate_limit_tiles,
+            render: args.rate_limit_render,
+            upload: args.rate_limit_upload,
+            client_ip_header: args.client_ip_header,
+        },
+        tls,
+        read_only: args.read_only,
+        backup: args.backup_dir.map(|dir| backup::Schedule {
+            dir,
+            interval: args.backup_interval,
+            keep: args.backup_keep,
+        }),
+        gradients: file.gradients,
+        strava_credentials: file.strava,
+        reload_config: None,
+    };
+
+    Ok((addr, config))
+}
+
+fn run() -> Result<()> {
+    let opts = Opts::parse();
+
+    tracing_subscriber::fmt()
+        .compact()
+        .with_max_level(if opts.global.verbose {
+            tracing::Level::DEBUG
+        } else {
+            tracing::Level::INFO
+        })
+        .init();
+
+    // TODO: pull out into separate function
+    match opts.cmd {
+        Commands::Import {
+            path,
+            strava_export,
+            url_list,
+            reset,
+            join,
+            trim,
+            dedupe_by,
+            include_virtual,
+            strict,
+            vacuum,
+            quiet,
+        } => {
+            let mut db = Database::new(&opts.global.db_path)?;
+
+            if let Some(trim) = trim {
+                db.config.set("trim_dist", &trim.to_string())?;
+                db.save_config()?;
+            }
+
+            // Not saved, unlike `--trim`.
+            db.config.include_virtual |= include_virtual;
+
+            // Apple Health exports come with their own metadata.
+            let prop_source = match (join, &path) {
+                (Some(csv), _) => PropertySource::from_csv(&csv)?,
+                (None, Some(path)) if path.join(apple_health::EXPORT_FILE).is_file() => {
+                    let export = path.join(apple_health::EXPORT_FILE);
+                    tracing::info!("reading workouts from {:?}", export);
+                    let reader = BufReader::new(File::open(&export)?);
+                    PropertySource::from_apple_health(reader, path.clone(), Path::new(""))?
+                }
+                _ => PropertySource::default(),
+            };
+
+            if reset {
+                db.reset_activities()?;
+            }
+
+            let urls = match (url_list, &path) {
+                (Some(list), _) => Some(remote::parse_url_list(&std::fs::read_to_string(list)?)),
+                (None, Some(path)) if remote::is_url(&path.to_string_lossy()) => {
+                    Some(vec![path.to_string_lossy().into_owned()])
+                }
+                _ => None,
+            };
+
+            let summary = match (urls, path, strava_export) {
+                (Some(urls), _, _) => {
+                    let rt = tokio::runtime::Runtime::new()?;
+                    rt.block_on(remote::import_urls(urls, &db, !quiet))?
+                }
+                (None, _, Some(zip)) => {
+                    // Activities are named by their path within the archive.
+                    let prefix = format!("{}/", zip.display());
+                    activity::import_zip(File::open(&zip)?, &prefix, None, &db)?
+                }
+                (None, Some(path), None) => {
+                    activity::import_path(&path, &db, &prop_source, dedupe_by, !quiet)?
+                }
+                (None, None, None) => unreachable!("path is required without --strava-export"),
+            };
+
+            if vacuum && !summary.imported.is_empty() {
+                db.vacuum()?;
+            }
+
+            println!("imported\t{}", summary.imported.len());
+            println!("skipped (duplicate)\t{}", summary.skipped.len());
+            println!("skipped (unsupported)\t{}", summary.unsupported.len());
+            println!("failed\t{}", summary.failed.len());
+
+            for failure in &summary.failed {
+                println!("  {}: {}", failure.file, failure.error);
+            }
+
+            if strict && !summary.failed.is_empty() {
+                return Err(anyhow!("{} file(s) failed to import", summary.failed.len()));
+            }
+        }
+
+        Commands::ImportLocationHistory {
+            path,
+            max_accuracy,
+            max_speed,
+        } => {
+            let db = Database::new(&opts.global.db_path)?;
+            let filter = location_history::Filter {
+                max_accuracy,
+                max_speed: max_speed / 3.6,
+            };
+
+            let summary = location_history::import_path(&path, &db, &filter)?;
+
+            println!("imported\t{}", summary.imported.len());
+            println!("skipped (duplicate)\t{}", summary.skipped.len());
+            println!("skipped (unsupported)\t{}", summary.unsupported.len());
+            println!("failed\t{}", summary.failed.len());
+
+            for failure in &summary.failed {
+                println!("  {}: {}", failure.file, failure.error);
+            }
+        }
+
+        Commands::Activities {
+            before,
+            after,
+            filter,
+            bbox,
+            search,
+            count,
+            limit,
+            sort,
+            format,
+        } => {
+            let db = Database::open(&opts.global.db_path)?;
+            let filter = ActivityFilter::new(before, after, filter);
+            let filter = match bbox {
+                Some(bbox) => filter.within(&bbox, &db.config),
+                None => filter,
+            };
+            let filter = match search {
+                Some(text) => filter.search(&text),
+                None => filter,
+            };
+
+            if count {
+                println!("{}", filter.count(&db)?);
+                return Ok(());
+            }
+
+            for activity in activity::list_page(&db, &filter, sort, limit, 0)? {
+                match format {
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&activity)?),
+                    OutputFormat::Table => {
+                        let start_time = activity
+                            .start_time
+                            .and_then(|ts| ts.format(&Rfc3339).ok())
+                            .unwrap_or_default();
+
+                        println!(
+                            "{}\t{}\t{}\t{}\t{}",
+                            activity.id,
+                            start_time,
+                            activity.title.unwrap_or_default(),
+                            activity.file,
+                            serde_json::to_string(&activity.properties)?,
+                        );
+                    }
+                }
+            }
+        }
+
+        Commands::Stats {
+            before,
+            after,
+            filter,
+            bbox,
+            group_by,
+            format,
+        } => {
             let db = Database::open(&opts.global.db_path)?;
             let filter = ActivityFilter::new(before, after, filter);
-            let gradient = gradient.unwrap_or_else(|| PINKISH.clone());
+            let filter = match bbox {
+                Some(bbox) => filter.within(&bbox, &db.config),
+                None => filter,
+            };
+
+            for totals in track_stats::summarize(&db, &filter, group_by)? {
+                match format {
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&totals)?),
+                    OutputFormat::Table => println!(
+                        "{}\t{}\t{:.1} km\t{}\t{}\t{:.0} m",
+                        totals.key,
+                        totals.activities,
+                        totals.distance / 1000.0,
+                        format_duration(totals.elapsed_time),
+                        format_duration(totals.moving_time),
+                        totals.elevation_gain,
+                    ),
+                }
+            }
+        }
+
+        Commands::Analyze { cmd } => {
+            let db = Database::open(&opts.global.db_path)?;
+
+            match cmd {
+                AnalyzeCommands::TopSegments {
+                    zoom,
+                    limit,
+                    before,
+                    after,
+                    filter,
+                    bbox,
+                    format,
+                } => {
+                    let filter = ActivityFilter::new(before, after, filter);
+                    let bbox = bbox.unwrap_or_else(WebMercatorViewport::world);
+
+                    let segments = analyze::top_segments(&db, &filter, &bbox, zoom, limit)?;
+                    for (rank, segment) in segments.iter().enumerate() {
+                        match format {
+                            OutputFormat::Json => println!("{}", serde_json::to_string(segment)?),
+                            OutputFormat::Table => println!(
+                                "{}\t{}\t{:.5},{:.5}\t~{:.0} m",
+                                rank + 1,
+                                segment.count,
+                                segment.lat,
+                                segment.lng,
+                                segment.length,
+                            ),
+                        }
+                    }
+                }
+            }
+        }
+
+        Commands::Dedupe { dry_run } => {
+            let db = Database::open(&opts.global.db_path)?;
+
+            let duplicates = dedupe::find_existing(&db)?;
+            for dup in &duplicates {
+                println!(
+                    "{}\t{}\tduplicate of\t{}\t{}",
+                    dup.duplicate.id, dup.duplicate.file, dup.original.id, dup.original.file
+                );
+
+                if !dry_run {
+                    dedupe::remove(&db, dup)?;
+                }
+            }
+
+            tracing::info!(
+                num_duplicates = duplicates.len(),
+                dry_run,
+                "finished dedupe"
+            );
+        }
+
+        Commands::Export {
+            output,
+            format,
+            before,
+            after,
+            filter,
+            bbox,
+            unmasked,
+        } => {
+            let db = Database::open(&opts.global.db_path)?;
+            let filter = ActivityFilter::new(before, after, filter);
+            let filter = match bbox {
+                Some(bbox) => filter.within(&bbox, &db.config),
+                None => filter,
+            };
+            std::fs::create_dir_all(&output)?;
+
+            let mut num_exported = 0;
+            let mut rows = vec![];
+            for activity in activity::list(&db, &filter, None)? {
+                let tracks = activity::load_tracks(&db, activity.id)?.map(|tracks| {
+                    if unmasked {
+                        tracks
+                    } else {
+                        activity::visible_tracks(&tracks, &activity, &db.config)
+                    }
+                });
+
+                if format.table_format().is_some() {
+                    rows.push(ActivityRow::new(activity, tracks.as_ref()));
+                    num_exported += 1;
+                    continue;
+                }
+
+                let Some(tracks) = tracks else {
+                    tracing::warn!(
+                        id = activity.id,
+                        file = activity.file,
+                        "no stored track, skipping"
+                    );
+                    continue;
+                };
+
+                let path = output.join(format!("{}.{}", activity.id, format.extension()));
+                export::write(File::create(&path)?, format, &activity, &tracks)?;
+                num_exported += 1;
+            }
+
+            if let Some(table_format) = format.table_format() {
+                let path = output.join(format!("activities.{}", format.extension()));
+                table::write(File::create(&path)?, table_format, &rows)?;
+            }
+
+            println!(
+                "Exported {} activities to {}",
+                num_exported,
+                output.display()
+            );
+        }
+
+        Commands::Remove { activity } => {
+            let db = Database::open(&opts.global.db_path)?;
+            let mut conn = db.connection()?;
+
+            let removed = match activity.parse::<i64>() {
+                Ok(id) => activity::delete_by_id(&mut conn, id)?,
+                Err(_) => activity::delete(&mut conn, &activity)?,
+            };
+
+            if !removed {
+                anyhow::bail!("no such activity: {}", activity);
+            }
+            println!("Removed activity: {}", activity);
+        }
+
+        Commands::Mask { cmd } => {
+            let db = Database::new(&opts.global.db_path)?;
+
+            match cmd {
+                MaskCommands::Add {
+                    name,
+                    circle,
+                    polygon,
+                } => {
+                    let geometry = circle.or(polygon).expect("clap enforces one of these");
+                    mask::insert(&db, &name, &geometry)?;
+                    println!("Saved mask: {}", name);
+                }
+
+                MaskCommands::List => {
+                    for mask in &db.config.masks {
+                        println!("{}\t{}", mask.name, serde_json::to_string(&mask.geometry)?);
+                    }
+                }
+
+                MaskCommands::Remove { name } => {
+                    if !mask::remove(&db, &name)? {
+                        anyhow::bail!("no mask named: {}", name);
+                    }
+                    println!("Removed mask: {}", name);
+                }
+
+                MaskCommands::Apply => {
+                    let num_tiles = mask::apply(&db)?;
+                    println!("Updated {} activity tiles", num_tiles);
+                }
+            }
+        }
+
+        Commands::Token { cmd } => {
+            let db = Database::new(&opts.global.db_path)?;
+
+            match cmd {
+                TokenCommands::Create { name, scope } => {
+                    let (api_token, token) = tokens::create(&db, &name, scope)?;
+                    println!("Created token: {}", api_token.name);
+                    println!("API token (only shown once): {}", token);
+                }
+
+                TokenCommands::Revoke { name } => {
+                    if !tokens::revoke(&db, &name)? {
+                        anyhow::bail!("no token named: {}", name);
+                    }
+                    println!("Revoked token: {}", name);
+                }
+
+                TokenCommands::List => {
+                    for api_token in tokens::list(&db)? {
+                        println!(
+                            "{}\t{}\t{}",
+                            api_token.name,
+                            api_token.scope.as_str(),
+                            api_token.created_at.format(&Rfc3339)?,
+                        );
+                    }
+                }
+            }
+        }
+
+        Commands::User { cmd } => {
+            let db = Database::new(&opts.global.db_path)?;
+
+            match cmd {
+                UserCommands::Add {
+                    name,
+                    strava_athlete,
+                } => {
+                    let (user, token) = users::create(&db, &name, strava_athlete)?;
+                    println!("Created user: {}", user.name);
+                    println!("API token (only shown once): {}", token);
+                }
+
+                UserCommands::List => {
+                    for user in users::list(&db)? {
+                        let athlete = user.strava_athlete_id.map(|id| id.to_string());
+                        println!(
+                            "{}\t{}\t{}",
+                            user.name,
+                            user.created_at.format(&Rfc3339)?,
+                            athlete.unwrap_or_default()
+                        );
+                    }
+                }
+
+                UserCommands::Remove { name } => {
+                    if !users::remove(&db, &name)? {
+                        anyhow::bail!("no user named: {}", name);
+                    }
+                    println!("Removed user: {}", name);
+                }
+            }
+        }
+
+        Commands::View { cmd } => {
+            let db = Database::new(&opts.global.db_path)?;
+
+            match cmd {
+                ViewCommands::Add {
+                    name,
+                    filter,
+                    color,
+                    gradient,
+                    before,
+                    after,
+                } => {
+                    let view = views::View {
+                        name,
+                        filter,
+                        color,
+                        gradient,
+                        before,
+                        after,
+                    };
+                    views::save(&db, &view)?;
+                    println!("Saved view: {}", view.name);
+                }
+
+                ViewCommands::List => {
+                    for view in views::list(&db)? {
+                        println!("{}\t{}", view.name, serde_json::to_string(&view)?);
+                    }
+                }
+
+                ViewCommands::Remove { name } => {
+                    if !views::remove(&db, &name)? {
+                        anyhow::bail!("no view named: {}", name);
+                    }
+                    println!("Removed view: {}", name);
+                }
+            }
+        }
+
+        Commands::Config { cmd } => {
+            let mut db = Database::new(&opts.global.db_path)?;
+
+            match cmd {
+                ConfigCommands::List => {
+                    for (key, _) in db::SETTINGS {
+                        let value = db.config.get(key)?.unwrap_or_default();
+                        println!("{}\t{}", key, value);
+                    }
+                }
+
+                ConfigCommands::Get { key } => {
+                    if let Some(value) = db.config.get(&key)? {
+                        println!("{}", value);
+                    }
+                }
+
+                ConfigCommands::Set { key, value } => {
+                    if key == "default_gradient" {
+                        try_parse_gradient(&value).map_err(|e| anyhow!(e))?;
+                    }
+
+                    let before = db.config.get(&key)?;
+                    db.config.set(&key, &value)?;
+                    db.save_config()?;
+
+                    let after = db.config.get(&key)?;
+                    println!("{} = {}", key, after.clone().unwrap_or_default());
+
+                    let affects_tiles = db::SETTINGS.iter().any(|(k, retile)| *k == key && *retile);
+                    let num_activities = ActivityFilter::default().count(&db)?;
+                    if affects_tiles && before != after && num_activities > 0 {
+                        tracing::warn!(
+                            num_activities,
+                            "existing activities are not affected by this change, \
+                             re-import them with `hotpot import --reset` to apply it"
+                        );
+                    }
+                }
+            }
+        }
+
+        Commands::Gradient { cmd } => {
+            let mut db = Database::new(&opts.global.db_path)?;
+
+            match cmd {
+                GradientCommands::Add { name, gradient } => {
+                    db.config.gradients.insert(name.clone(), gradient);
+                    db.save_config()?;
+                    println!("Saved gradient: {}", name);
+                }
+
+                GradientCommands::List => {
+                    for (name, gradient) in &db.config.gradients {
+                        println!("{}\t{}", name, gradient);
+                    }
+                }
+
+                GradientCommands::Remove { name } => {
+                    if db.config.gradients.remove(&name).is_none() {
+                        anyhow::bail!("no gradient named: {}", name);
+                    }
+                    db.save_config()?;
+                    println!("Removed gradient: {}", name);
+                }
+            }
+        }
+
+        Commands::Db { cmd } => match cmd {
+            DbCommands::Migrate { dry_run } => {
+                let pending = db::pending_migrations(&opts.global.db_path)?;
+                for description in &pending {
+                    println!("{}", description);
+                }
+
+                if pending.is_empty() {
+                    println!("database is up to date");
+                } else if !dry_run {
+                    Database::open(&opts.global.db_path)?;
+                    println!("applied {} migration(s)", pending.len());
+                }
+            }
+
+            DbCommands::Maintain { no_vacuum } => {
+                let db = Database::open(&opts.global.db_path)?;
+
+                let problems = db.integrity_check()?;
+                if !problems.is_empty() {
+                    for problem in &problems {
+                        eprintln!("{}", problem);
+                    }
+                    anyhow::bail!("integrity check failed, skipping maintenance");
+                }
+                println!("integrity check: ok");
+
+                let num_tiles = db.reencode_tiles()?;
+                println!("re-encode tiles: {} updated", num_tiles);
+
+                db.analyze()?;
+                println!("analyze: done");
+
+                if !no_vacuum {
+                    db.vacuum()?;
+                    println!("vacuum: done");
+                }
+            }
+
+            DbCommands::Backup { path } => {
+                let db = Database::open(&opts.global.db_path)?;
+                backup::backup(&db, &path)?;
+                println!("backed up database to {}", path.display());
+            }
+
+            DbCommands::Restore { path } => {
+                backup::restore(&path, &opts.global.db_path)?;
+
+                // The backup may be from an older version.
+                let db = Database::open(&opts.global.db_path)?;
+                let num_activities: usize =
+                    db.connection()?
+                        .query_row("SELECT count(*) FROM activities", [], |row| row.get(0))?;
+                println!("restored {} activities", num_activities);
+            }
+
+            DbCommands::Stats { top, format } => {
+                let db = Database::open(&opts.global.db_path)?;
+                let stats = db_stats::collect(&db, top)?;
+
+                if let OutputFormat::Json = format {
+                    println!("{}", serde_json::to_string(&stats)?);
+                    return Ok(());
+                }
+
+                println!(
+                    "size:\t{} ({} free)",
+                    format_size(stats.size),
+                    format_size(stats.free)
+                );
+                println!("activities:\t{}", stats.num_activities);
+                println!("tiles:\t{}", stats.num_tiles);
+
+                println!("\nzoom\ttiles\tcoords/tile\tsize");
+                for level in &stats.zoom_levels {
+                    println!(
+                        "{}\t{}\t{:.1}\t~{}",
+                        level.z,
+                        level.num_tiles,
+                        level.avg_coords,
+                        format_size(level.size)
+                    );
+                }
+
+                println!("\nlargest activities:");
+                for activity in &stats.largest_activities {
+                    println!(
+                        "{}\t{}\t~{}\t{}",
+                        activity.id,
+                        activity.num_tiles,
+                        format_size(activity.size),
+                        activity.title.as_deref().unwrap_or(&activity.file)
+                    );
+                }
+
+                if !stats.tables.is_empty() {
+                    // Skip the many tiny ones.
+                    println!("\ntables and indexes (over 0.1%):");
+                    for table in stats.tables.iter().filter(|t| t.size * 1000 >= stats.size) {
+                        println!("{}\t{}", table.name, format_size(table.size));
+                    }
+                }
+            }
+
+            DbCommands::SpatialIndex { drop } => {
+                let db = Database::open(&opts.global.db_path)?;
+
+                if drop {
+                    spatial_index::remove(&db)?;
+                    println!("removed spatial index");
+                } else {
+                    let num_tiles = spatial_index::create(&db)?;
+                    println!("indexed {} tiles", num_tiles);
+                }
+            }
+
+            DbCommands::Aggregate => {
+                let db = Database::open(&opts.global.db_path)?;
+                let num_tiles = aggregate::update(&db)?;
+                println!("updated {} tiles", num_tiles);
+            }
+
+            DbCommands::Bench { samples } => {
+                let db = Database::open(&opts.global.db_path)?;
+
+                let results = spatial_index::bench(&db, samples)?;
+
+                println!("zoom\ttiles\trows\tb-tree\tr*tree");
+                for result in results {
+                    println!(
+                        "{}\t{}\t{}\t{:.2?}\t{:.2?}",
+                        result.zoom, result.num_tiles, result.num_rows, result.btree, result.rtree,
+                    );
+                }
+            }
+        },
+
+        Commands::Tile {
+            zxy,
+            width,
+            output,
+            filter,
+            before,
+            after,
+            gradient,
+            intensity,
+            line_width,
+            blur,
+            antialias,
+            count,
+            half_life,
+            color_by,
+        } => {
+            let db = Database::open(&opts.global.db_path)?;
             let mut file = File::create(output)?;
 
-            let image = raster::render_view(viewport, &gradient, width, height, &filter, &db)?;
+            let filter = ActivityFilter::new(before, after, filter);
+            let gradient = gradient.unwrap_or_else(|| PINKISH.clone());
+            let stroke = Stroke::new(line_width, blur, antialias)?
+                .counting(count)
+                .decaying(half_life)?;
+            let image = raster::render_tile(
+                zxy,
+                &gradient,
+                intensity,
+                stroke,
+                color_by.as_ref(),
+                width,
+                &filter,
+                &db,
+            )?
+            .unwrap_or_else(|| {
+                // note: could also just use RgbaImage::default() here if we don't care about size.
+                RgbaImage::new(width, width)
+            });
+
+            image.write_to(&mut file, image::ImageOutputFormat::Png)?;
+        }
+
+        Commands::Render {
+            viewport,
+            width,
+            height,
+            before,
+            after,
+            filter,
+            gradient,
+            intensity,
+            line_width,
+            blur,
+            antialias,
+            count,
+            half_life,
+            color_by,
+            mode,
+            format,
+            basemap,
+            opacity,
+            attribution,
+            output,
+        } => {
+            let db = Database::open(&opts.global.db_path)?;
+            let filter = ActivityFilter::new(before, after, filter);
+            let gradient = gradient.unwrap_or_else(|| PINKISH.clone());
+            let stroke = Stroke::new(line_width, blur, antialias)?
+                .counting(count)
+                .decaying(half_life)?;
+
+            if format != RenderFormat::Png {
+                if basemap.is_some() {
+                    return Err(anyhow!("--basemap is only supported for PNG output"));
+                }
+                if stroke.blur > 0.0 {
+                    return Err(anyhow!("--blur is only supported for PNG output"));
+                }
+                if stroke.half_life.is_some() {
+                    return Err(anyhow!("--half-life is only supported for PNG output"));
+                }
+                if mode == RenderMode::Explorer {
+                    return Err(anyhow!("--mode explorer is only supported for PNG output"));
+                }
+
+                let file = File::create(output)?;
+                // Posters show the most detail, so use the highest zoom level's colors.
+                let color = gradient.for_zoom(u8::MAX).sample(1);
+                vector::render(
+                    &db,
+                    &viewport,
+                    width,
+                    height,
+                    stroke.width,
+                    &filter,
+                    color,
+                    format,
+                    file,
+                )?;
+                return Ok(());
+            }
+
+            // Fetch the basemap first so a bad URL doesn't waste a render.
+            let background = basemap
+                .map(|url| basemap::render(&url, &viewport, width, height, db.config.zoom_range()))
+                .transpose()?;
+
+            let heatmap = match mode {
+                RenderMode::Heatmap => raster::render_view(
+                    viewport,
+                    &gradient,
+                    intensity,
+                    stroke,
+                    color_by.as_ref(),
+                    width,
+                    height,
+                    &filter,
+                    &db,
+                )?,
+                RenderMode::Explorer => {
+                    let explorer = ExplorerTiles::load(&db, &filter)?;
+                    println!("Visited {} explorer tiles", explorer.num_visited());
+                    if let Some((tile, size)) = explorer.max_square() {
+                        println!(
+                            "Max square: {}x{}, from {}/{}/{}",
+                            size, size, tile.z, tile.x, tile.y
+                        );
+                    }
+
+                    raster::render_mosaic(
+                        &viewport,
+                        width,
+                        height,
+                        db.config.zoom_range(),
+                        |tile| Ok(explorer.render_tile(tile, 256)),
+                    )?
+                }
+            };
+
+            let image = match background {
+                Some(mut image) => {
+                    basemap::composite(&mut image, &heatmap, opacity);
+                    if !attribution.is_empty() {
+                        basemap::draw_attribution(&mut image, &attribution);
+                    }
+                    image
+                }
+                None => heatmap,
+            };
+
+            let mut file = File::create(output)?;
             image.write_to(&mut file, image::ImageOutputFormat::Png)?;
         }
 
-        Commands::Serve {
-            host,
-            port,
-            upload,
-            render,
-            strava_webhook,
-            cors,
+        Commands::RenderActivity {
+            id,
+            width,
+            height,
+            color,
+            unmasked,
+            output,
+        } => {
+            let db = Database::open(&opts.global.db_path)?;
+            let Some(summary) = activity::get(&db, id)? else {
+                return Err(anyhow!("no activity with id {}", id));
+            };
+            let Some(tracks) = activity::load_tracks(&db, id)? else {
+                return Err(anyhow!("no track stored for activity {}", id));
+            };
+
+            let tracks = if unmasked {
+                tracks
+            } else {
+                activity::visible_tracks(&tracks, &summary, &db.config)
+            };
+
+            let image = raster::render_activity(&tracks, color, width, height);
+            image.write_to(&mut File::create(output)?, image::ImageOutputFormat::Png)?;
+        }
+
+        Commands::Timelapse {
+            viewport,
+            width,
+            height,
+            step,
+            no_cumulative,
+            from,
+            to,
+            filter,
+            gradient,
+            intensity,
+            line_width,
+            blur,
+            antialias,
+            count,
+            background,
+            frame_delay,
+            output,
+        } => {
+            let db = Database::open(&opts.global.db_path)?;
+            let gradient = gradient.unwrap_or_else(|| PINKISH.clone());
+            let stroke = Stroke::new(line_width, blur, antialias)?.counting(count);
+
+            let timelapse = Timelapse {
+                viewport,
+                width,
+                height,
+                step,
+                cumulative: !no_cumulative,
+                intensity,
+                stroke,
+                background,
+                frame_delay_ms: frame_delay,
+            };
+
+            let num_frames = timelapse.render(&db, &gradient, from, to, filter, &output)?;
+            println!("Wrote {} frames to {}", num_frames, output.display());
+        }
+
+        Commands::Pregenerate {
+            viewport,
+            min_zoom,
+            max_zoom,
+            width,
+            before,
+            after,
+            filter,
+            gradient,
+            intensity,
+            line_width,
+            blur,
+            antialias,
+            count,
+            half_life,
+            output,
+        } => {
+            let db = Database::open(&opts.global.db_path)?;
+            let filter = ActivityFilter::new(before, after, filter);
+            let gradient = gradient.unwrap_or_else(|| PINKISH.clone());
+            let stroke = Stroke::new(line_width, blur, antialias)?
+                .counting(count)
+                .decaying(half_life)?;
+
+            let num_tiles = pregenerate::pregenerate(
+                &db,
+                &viewport,
+                min_zoom..=max_zoom,
+                width,
+                &gradient,
+                intensity,
+                stroke,
+                &filter,
+                &output,
+            )?;
+
+            println!("Wrote {} tiles to {}", num_tiles, output.display());
+        }
+
+        Commands::Serve(args) => {
+            let (args, file) = match args.config {
+                Some(_) => serve_args()?,
+                None => (*args, ServerConfigFile::default()),
+            };
+            let from_file = args.config.is_some();
+
+            let db = if args.read_only {
+                Database::open_read_only(&opts.global.db_path)?
+            } else {
+                let mut db = Database::new(&opts.global.db_path)?;
+                if args.default_gradient.is_some() {
+                    db.config.default_gradient = args.default_gradient.clone();
+                    db.save_config()?;
+                }
+                db
+            };
+
+            let (addr, mut config) = serve_config(args, file, &opts.global.db_path)?;
+            if from_file {
+                let db_path = opts.global.db_path.clone();
+                config.reload_config = Some(Arc::new(move || {
+                    let (args, file) = serve_args()?;
+                    Ok(serve_config(args, file, &db_path)?.1)
+                }));
+            }
+
+            web::run_blocking(addr, db, config)?;
+        }
+
+        Commands::StravaSetup {
+            client_id,
+            client_secret,
+            webhook_secret,
         } => {
+            let db = Database::new(&opts.global.db_path)?;
+            let webhook_secret = match webhook_secret {
+                Some(secret) => secret,
+                None => tokens::generate_token()?,
+            };
+
+            strava::StravaAuth::save(&db, client_id, &client_secret, &webhook_secret)?;
+            println!("Saved Strava credentials.");
+            println!("Webhook verify token: {}", webhook_secret);
+            println!("Next, run `hotpot strava-auth` to authorize your account.");
+        }
+
+        Commands::StravaSubscribe { callback_url } => {
+            let db = Database::new(&opts.global.db_path)?;
+            let auth = strava::StravaAuth::load(&db)?;
+
+            let rt = tokio::runtime::Runtime::new()?;
+            let id = rt.block_on(auth.subscribe(&db, &callback_url))?;
+            println!("Subscribed to webhook events (subscription {})", id);
+        }
+
+        Commands::KomootAuth { email } => {
+            let db = Database::new(&opts.global.db_path)?;
+            let password = match std::env::var("KOMOOT_PASSWORD") {
+                Ok(password) => password,
+                Err(_) => {
+                    eprint!("Komoot password: ");
+                    let mut password = String::new();
+                    std::io::stdin().read_line(&mut password)?;
+                    password.trim_end_matches(['\r', '\n']).to_string()
+                }
+            };
+
+            let rt = tokio::runtime::Runtime::new()?;
+            let user_id = rt.block_on(komoot::login(&db, &email, &password))?;
+            println!("Logged in to Komoot (user ID: {})", user_id);
+            println!("Next, run `hotpot komoot-sync` to import your tours.");
+        }
+
+        Commands::KomootSync => {
+            let db = Database::new(&opts.global.db_path)?;
+
+            let rt = tokio::runtime::Runtime::new()?;
+            let num_imported = rt.block_on(komoot::sync(&db))?;
+            println!("Imported {} tours from Komoot", num_imported);
+        }
+
+        Commands::Geocode { url, provider, all } => {
+            let db = Database::new(&opts.global.db_path)?;
+            let geocoder = geocode::Geocoder::new(&url, provider)?;
+
+            let rt = tokio::runtime::Runtime::new()?;
+            let num_updated = rt.block_on(geocode::geocode_activities(&db, &geocoder, all))?;
+            println!("Geocoded {} activities", num_updated);
+        }
+
+        Commands::StravaAuth { host, port } => {
             let db = Database::new(&opts.global.db_path)?;
             let addr = format!("{}:{}", host, port).parse()?;
             let routes = web::RouteConfig {
-                strava_webhook,
-                upload,
-                render,
-                tiles: true,
-                strava_auth: false,
+                strava_auth: true,
+                tiles: false,
+                strava_webhook: false,
+                garmin_webhook: false,
+                garmin_auth: false,
+                upload: false,
+                render: false,
+                render_links: false,
+                users: false,
             };
 
             let config = web::Config {
-                cors,
                 routes,
-                upload_token: std::env::var("HOTPOT_UPLOAD_TOKEN").ok(),
+                cors: None,
+                private: false,
+                upload_token: None,
+                render_secret: None,
+                tile_cache_size: 0,
+                render_threads: 1,
+                max_concurrent_renders: 0,
+                render_queue_timeout: Duration::from_secs(10),
+                watch_dir: None,
+                reimport: None,
+                live_timeout: Duration::from_secs(30 * 60),
+                komoot_sync: None,
+                strava_privacy: Default::default(),
+                strava_webhook_ips: vec![],
+                rate_limits: Default::default(),
+                tls: None,
+                read_only: false,
+                backup: None,
+                gradients: Default::default(),
+                strava_credentials: Default::default(),
+                reload_config: None,
             };
 
+            println!(
+                "==============================\
+                \nOpen http://{}/strava/auth in your browser.\
+                \n==============================",
+                addr
+            );
             web::run_blocking(addr, db, config)?;
         }
 
-        Commands::StravaAuth { host, port } => {
+        Commands::GarminAuth { host, port } => {
             let db = Database::new(&opts.global.db_path)?;
             let addr = format!("{}:{}", host, port).parse()?;
             let routes = web::RouteConfig {
-                strava_auth: true,
+                garmin_auth: true,
                 tiles: false,
                 strava_webhook: false,
+                strava_auth: false,
+                garmin_webhook: false,
                 upload: false,
                 render: false,
+                render_links: false,
+                users: false,
             };
 
             let config = web::Config {
                 routes,
-                cors: false,
+                cors: None,
+                private: false,
                 upload_token: None,
+                render_secret: None,
+                tile_cache_size: 0,
+                render_threads: 1,
+                max_concurrent_renders: 0,
+                render_queue_timeout: Duration::from_secs(10),
+                watch_dir: None,
+                reimport: None,
+                live_timeout: Duration::from_secs(30 * 60),
+                komoot_sync: None,
+                strava_privacy: Default::default(),
+                strava_webhook_ips: vec![],
+                rate_limits: Default::default(),
+                tls: None,
+                read_only: false,
+                backup: None,
+                gradients: Default::default(),
+                strava_credentials: Default::default(),
+                reload_config: None,
             };
 
             println!(
                 "==============================\
-                \nOpen http://{}/strava/auth in your browser.\
+                \nOpen http://{}/garmin/auth in your browser.\
                 \n==============================",
                 addr
             );
@@ -0,0 +1,427 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use geo::{Contains, EuclideanDistance, Intersects};
+use geo_types::{Line, LineString, Point, Polygon};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::db::{decode_line, encode_line, Database};
+use crate::tile::{BBox, LngLat, Tile, WebMercator};
+
+/// Shape of a privacy mask, in WGS84 coordinates.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskGeometry {
+    /// Circle around `[lng, lat]`, radius given in meters.
+    Circle { center: [f64; 2], radius: f64 },
+    /// Closed polygon of `[lng, lat]` points.
+    Polygon { points: Vec<[f64; 2]> },
+}
+
+impl MaskGeometry {
+    /// Parse a circle in the form of `lng,lat,radius`
+    pub fn parse_circle(s: &str) -> Result<Self, &'static str> {
+        let parts = s
+            .split(',')
+            .map(|it| it.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| "invalid number")?;
+
+        match parts[..] {
+            [lng, lat, radius] if radius > 0.0 => Ok(MaskGeometry::Circle {
+                center: [lng, lat],
+                radius,
+            }),
+            [_, _, _] => Err("radius must be positive"),
+            _ => Err("expected circle as 'lng,lat,radius'"),
+        }
+    }
+
+    /// Parse a polygon in the form of `lng,lat;lng,lat;...`
+    pub fn parse_polygon(s: &str) -> Result<Self, &'static str> {
+        let points = s
+            .split(';')
+            .map(|pair| {
+                let (lng, lat) = pair.split_once(',').ok_or("expected 'lng,lat' pairs")?;
+                let lng = lng.trim().parse::<f64>().map_err(|_| "invalid number")?;
+                let lat = lat.trim().parse::<f64>().map_err(|_| "invalid number")?;
+                Ok([lng, lat])
+            })
+            .collect::<Result<Vec<_>, &'static str>>()?;
+
+        if points.len() < 3 {
+            return Err("polygon needs at least 3 points");
+        }
+
+        Ok(MaskGeometry::Polygon { points })
+    }
+}
+
+impl FromStr for MaskGeometry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(serde_json::from_str(s)?)
+    }
+}
+
+/// Mask geometry projected into WebMercator, ready for point lookups.
+#[derive(Clone, Debug)]
+enum Projected {
+    Circle { center: Point<f64>, radius: f64 },
+    Polygon(Polygon<f64>),
+}
+
+#[derive(Clone, Debug)]
+pub struct PrivacyMask {
+    pub name: String,
+    pub geometry: MaskGeometry,
+    projected: Projected,
+    bbox: BBox,
+}
+
+impl PrivacyMask {
+    pub fn new(name: &str, geometry: MaskGeometry) -> Result<Self> {
+        let project = |[lng, lat]: [f64; 2]| {
+            LngLat::new(lng, lat)
+                .xy()
+                .map(|pt| pt.0)
+                .ok_or_else(|| anyhow!("mask coordinate out of WebMercator bounds"))
+        };
+
+        let (projected, bbox) = match geometry {
+            MaskGeometry::Circle { center, radius } => {
+                let xy = project(center)?;
+                // WebMercator inflates distances away from the equator.
+                let radius = radius / center[1].to_radians().cos();
+                let bbox = BBox {
+                    left: xy.x() - radius,
+                    right: xy.x() + radius,
+                    bot: xy.y() - radius,
+                    top: xy.y() + radius,
+                };
+                (Projected::Circle { center: xy, radius }, bbox)
+            }
+
+            MaskGeometry::Polygon { ref points } => {
+                let points = points
+                    .iter()
+                    .map(|pt| project(*pt))
+                    .collect::<Result<LineString<f64>>>()?;
+
+                let bbox = points.coords().fold(
+                    BBox {
+                        left: f64::MAX,
+                        right: f64::MIN,
+                        bot: f64::MAX,
+                        top: f64::MIN,
+                    },
+                    |b, c| BBox {
+                        left: b.left.min(c.x),
+                        right: b.right.max(c.x),
+                        bot: b.bot.min(c.y),
+                        top: b.top.max(c.y),
+                    },
+                );
+
+                (Projected::Polygon(Polygon::new(points, vec![])), bbox)
+            }
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            geometry,
+            projected,
+            bbox,
+        })
+    }
+
+    pub fn contains(&self, pt: &WebMercator) -> bool {
+        if !self.bbox.contains(pt) {
+            return false;
+        }
+
+        match self.projected {
+            Projected::Circle { center, radius } => {
+                let (dx, dy) = (pt.0.x() - center.x(), pt.0.y() - center.y());
+                dx * dx + dy * dy <= radius * radius
+            }
+            Projected::Polygon(ref poly) => poly.contains(&pt.0),
+        }
+    }
+
+    /// Whether any part of the line segment between `start` and `end` is
+    /// inside the mask. This catches tracks passing straight through a mask
+    /// without having a recorded point inside of it.
+    pub fn intersects(&self, start: &WebMercator, end: &WebMercator) -> bool {
+        if self.bbox.clip_line(start, end).is_none() {
+            return false;
+        }
+
+        let line = Line::new(start.0, end.0);
+        match self.projected {
+            Projected::Circle { center, radius } => center.euclidean_distance(&line) <= radius,
+            Projected::Polygon(ref poly) => poly.intersects(&line),
+        }
+    }
+
+    pub fn bbox(&self) -> &BBox {
+        &self.bbox
+    }
+}
+
+pub fn any_contains(masks: &[PrivacyMask], pt: &WebMercator) -> bool {
+    masks.iter().any(|m| m.contains(pt))
+}
+
+pub fn any_intersects(masks: &[PrivacyMask], start: &WebMercator, end: &WebMercator) -> bool {
+    masks.iter().any(|m| m.intersects(start, end))
+}
+
+pub fn load(conn: &rusqlite::Connection) -> Result<Vec<PrivacyMask>> {
+    let mut stmt = conn.prepare("SELECT name, geometry FROM privacy_masks ORDER BY name")?;
+    let mut rows = stmt.query([])?;
+
+    let mut masks = vec![];
+    while let Some(row) = rows.next()? {
+        let name: String = row.get_unwrap(0);
+        let geometry: String = row.get_unwrap(1);
+        masks.push(PrivacyMask::new(&name, geometry.parse()?)?);
+    }
+
+    Ok(masks)
+}
+
+pub fn insert(db: &Database, name: &str, geometry: &MaskGeometry) -> Result<()> {
+    // Validate before storing.
+    PrivacyMask::new(name, geometry.clone())?;
+
+    let conn = db.connection()?;
+    conn.execute(
+        "\
+        INSERT OR REPLACE INTO privacy_masks (name, geometry) \
+        VALUES (?, ?)",
+        params![name, serde_json::to_string(geometry)?],
+    )?;
+
+    Ok(())
+}
+
+pub fn remove(db: &Database, name: &str) -> Result<bool> {
+    let conn = db.connection()?;
+    let num_rows = conn.execute("DELETE FROM privacy_masks WHERE name = ?", params![name])?;
+    Ok(num_rows > 0)
+}
+
+/// Split a line into the runs of points which fall outside all masks. Runs
+/// are also broken where the line passes through a mask between two points.
+pub fn split_line<T: Copy>(
+    points: &[T],
+    masks: &[PrivacyMask],
+    to_xy: impl Fn(&T) -> WebMercator,
+) -> Vec<Vec<T>> {
+    let mut runs = vec![];
+    let mut current: Vec<T> = vec![];
+
+    for pt in points {
+        let xy = to_xy(pt);
+        if any_contains(masks, &xy) {
+            if !current.is_empty() {
+                runs.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+
+        if let Some(prev) = current.last() {
+            if any_intersects(masks, &to_xy(prev), &xy) {
+                runs.push(std::mem::take(&mut current));
+            }
+        }
+
+        current.push(*pt);
+    }
+
+    if !current.is_empty() {
+        runs.push(current);
+    }
+
+    runs
+}
+
+/// Retroactively apply privacy masks to already imported activity tiles.
+///
+/// Returns the number of modified tiles.
+pub fn apply(db: &Database) -> Result<usize> {
+    let masks = &db.config.masks;
+    let extent = db.config.tile_extent;
+
+    let mut conn = db.connection()?;
+    let tx = conn.transaction()?;
+    let mut num_modified = 0;
+
+    {
+        let mut select = tx.prepare(
+            "\
+            SELECT id, activity_id, x, y, coords \
+            FROM activity_tiles \
+            WHERE z = ? \
+                AND (x >= ? AND x <= ?) \
+                AND (y >= ? AND y <= ?)",
+        )?;
+        let mut delete = tx.prepare("DELETE FROM activity_tiles WHERE id = ?")?;
+        let mut insert = tx.prepare(
+            "\
+            INSERT INTO activity_tiles (activity_id, z, x, y, coords) \
+            VALUES (?, ?, ?, ?, ?)",
+        )?;
+
+        for mask in masks {
+            let bbox = mask.bbox();
+            let nw = WebMercator(Point::new(bbox.left, bbox.top));
+            let se = WebMercator(Point::new(bbox.right, bbox.bot));
+
+            for z in &db.config.zoom_levels {
+                let (min, max) = (nw.tile(*z), se.tile(*z));
+
+                // Collect up front, since we'll be modifying the table as we go.
+                let rows = select
+                    .query_map(params![z, min.x, max.x, min.y, max.y], |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, i64>(1)?,
+                            Tile::new(row.get(2)?, row.get(3)?, *z),
+                            row.get::<_, Vec<u8>>(4)?,
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                for (id, activity_id, tile, bytes) in rows {
+                    let tile_bbox = tile.xy_bounds();
+                    let coords = decode_line(&bytes)?;
+                    let runs = split_line(&coords, masks, |c| {
+                        WebMercator::from_tile_pixel(*c, &tile_bbox, extent)
+                    });
+
+                    if runs.len() == 1 && runs[0].len() == coords.len() {
+                        continue;
+                    }
+
+                    delete.execute(params![id])?;
+                    for run in runs.into_iter().filter(|r| r.len() >= 2) {
+                        let line = LineString::<u32>::from(run);
+                        insert.execute(params![
+                            activity_id,
+                            tile.z,
+                            tile.x,
+                            tile.y,
+                            encode_line(&line)?
+                        ])?;
+                    }
+
+                    num_modified += 1;
+                }
+            }
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(num_modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_geometry() {
+        assert_eq!(
+            MaskGeometry::parse_circle("-122.4, 37.7, 500"),
+            Ok(MaskGeometry::Circle {
+                center: [-122.4, 37.7],
+                radius: 500.0
+            })
+        );
+        assert!(MaskGeometry::parse_circle("-122.4,37.7").is_err());
+        assert!(MaskGeometry::parse_circle("-122.4,37.7,-1").is_err());
+
+        assert_eq!(
+            MaskGeometry::parse_polygon("0,0;1,0;1,1"),
+            Ok(MaskGeometry::Polygon {
+                points: vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0]]
+            })
+        );
+        assert!(MaskGeometry::parse_polygon("0,0;1,0").is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let circle = PrivacyMask::new(
+            "circle",
+            MaskGeometry::Circle {
+                center: [10.0, 50.0],
+                radius: 100.0,
+            },
+        )
+        .unwrap();
+
+        let inside = LngLat::new(10.0005, 50.0).xy().unwrap();
+        let outside = LngLat::new(10.002, 50.0).xy().unwrap();
+        assert!(circle.contains(&inside));
+        assert!(!circle.contains(&outside));
+
+        let polygon = PrivacyMask::new(
+            "polygon",
+            MaskGeometry::parse_polygon("0,0;1,0;1,1;0,1").unwrap(),
+        )
+        .unwrap();
+
+        assert!(polygon.contains(&LngLat::new(0.5, 0.5).xy().unwrap()));
+        assert!(!polygon.contains(&LngLat::new(1.5, 0.5).xy().unwrap()));
+    }
+
+    #[test]
+    fn test_split_line() {
+        let mask = PrivacyMask::new(
+            "polygon",
+            MaskGeometry::parse_polygon("0.5,-1;1.5,-1;1.5,1;0.5,1").unwrap(),
+        )
+        .unwrap();
+
+        let points = [0.0, 1.0, 2.0, 3.0];
+        let runs = split_line(&points, &[mask], |x| LngLat::new(*x, 0.0).xy().unwrap());
+
+        assert_eq!(runs, vec![vec![0.0], vec![2.0, 3.0]]);
+    }
+
+    #[test]
+    fn test_intersects() {
+        let circle = PrivacyMask::new(
+            "circle",
+            MaskGeometry::Circle {
+                center: [10.0, 50.0],
+                radius: 100.0,
+            },
+        )
+        .unwrap();
+
+        // Neither point is inside, but the segment passes through the center.
+        let west = LngLat::new(9.99, 50.0).xy().unwrap();
+        let east = LngLat::new(10.01, 50.0).xy().unwrap();
+        let north = LngLat::new(10.01, 50.01).xy().unwrap();
+        assert!(circle.intersects(&west, &east));
+        assert!(!circle.intersects(&east, &north));
+
+        let polygon = PrivacyMask::new(
+            "polygon",
+            MaskGeometry::parse_polygon("0.5,-1;1.5,-1;1.5,1;0.5,1").unwrap(),
+        )
+        .unwrap();
+
+        // Points on either side of the mask, without any inside.
+        let points = [0.0, 2.0, 3.0];
+        let runs = split_line(&points, &[polygon], |x| LngLat::new(*x, 0.0).xy().unwrap());
+        assert_eq!(runs, vec![vec![0.0], vec![2.0, 3.0]]);
+    }
+}
@@ -0,0 +1,135 @@
+//! Writer for [MBTiles] tilesets, a single SQLite file holding a whole tile
+//! pyramid, which can be consumed offline by MapLibre / Leaflet plugins.
+//!
+//! [MBTiles]: https://github.com/mapbox/mbtiles-spec/blob/master/1.3/spec.md
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use rusqlite::{params, Connection};
+
+use crate::tile::{Tile, WebMercatorViewport};
+
+const SCHEMA: &str = "\
+CREATE TABLE metadata (name TEXT, value TEXT);
+CREATE UNIQUE INDEX name ON metadata (name);
+
+CREATE TABLE tiles (
+    zoom_level INTEGER,
+    tile_column INTEGER,
+    tile_row INTEGER,
+    tile_data BLOB
+);
+CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);
+";
+
+pub struct MBTiles {
+    conn: Mutex<Connection>,
+}
+
+impl MBTiles {
+    /// Create a new tileset of PNG tiles covering `viewport` at the given
+    /// zoom levels. Refuses to overwrite an existing file.
+    pub fn create(
+        path: &Path,
+        viewport: &WebMercatorViewport,
+        min_zoom: u8,
+        max_zoom: u8,
+    ) -> Result<Self> {
+        if path.exists() {
+            return Err(anyhow!("{} already exists", path.display()));
+        }
+
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+
+        let [west, south, east, north] = viewport.lnglat_bounds();
+        let center = [(west + east) / 2.0, (south + north) / 2.0];
+
+        let metadata = [
+            ("name", "hotpot".to_string()),
+            ("format", "png".to_string()),
+            ("type", "overlay".to_string()),
+            ("bounds", format!("{},{},{},{}", west, south, east, north)),
+            (
+                "center",
+                format!("{},{},{}", center[0], center[1], min_zoom),
+            ),
+            ("minzoom", min_zoom.to_string()),
+            ("maxzoom", max_zoom.to_string()),
+        ];
+
+        for (name, value) in metadata {
+            conn.execute(
+                "INSERT INTO metadata (name, value) VALUES (?, ?)",
+                params![name, value],
+            )?;
+        }
+
+        // Committed in `finish`, individual inserts are far too slow.
+        conn.execute_batch("BEGIN")?;
+
+        Ok(MBTiles {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn insert(&self, tile: &Tile, data: &[u8]) -> Result<()> {
+        // MBTiles uses TMS tile numbering, so y is flipped.
+        let row = (1u32 << tile.z) - 1 - tile.y;
+
+        self.conn.lock().unwrap().execute(
+            "\
+            INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) \
+            VALUES (?, ?, ?, ?)",
+            params![tile.z, tile.x, row, data],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        let conn = self.conn.into_inner().unwrap();
+        conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_create() {
+        let path = std::env::temp_dir().join(format!("hotpot-{}.mbtiles", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let viewport = WebMercatorViewport::from_str("-10,-10,10,10").unwrap();
+        let mbtiles = MBTiles::create(&path, &viewport, 2, 4).unwrap();
+        mbtiles.insert(&Tile::new(1, 0, 2), b"png").unwrap();
+        mbtiles.finish().unwrap();
+
+        assert!(MBTiles::create(&path, &viewport, 2, 4).is_err());
+
+        let conn = Connection::open(&path).unwrap();
+        let maxzoom: String = conn
+            .query_row(
+                "SELECT value FROM metadata WHERE name = 'maxzoom'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let (column, row): (u32, u32) = conn
+            .query_row("SELECT tile_column, tile_row FROM tiles", [], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })
+            .unwrap();
+
+        assert_eq!(maxzoom, "4");
+        assert_eq!((column, row), (1, 3));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
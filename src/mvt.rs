@@ -0,0 +1,413 @@
+//! Minimal encoder for Mapbox Vector Tiles.
+//!
+//! Only supports what we need to expose activity tracks: a single layer of
+//! `LINESTRING` features, with properties attached to each feature.
+//!
+//! https://github.com/mapbox/vector-tile-spec/tree/master/2.1
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, Result};
+use geo_types::Coord;
+use rusqlite::{params, ToSql};
+
+use crate::db::{decode_line, ActivityFilter, Config, Database};
+use crate::mask::split_line;
+use crate::spatial_index;
+use crate::tile::{Tile, TileBounds, WebMercator};
+
+/// Default extent used by most MVT consumers.
+pub const MVT_EXTENT: u32 = 4096;
+
+/// Name of the layer containing activity tracks.
+pub const ACTIVITY_LAYER: &str = "activities";
+
+// Protobuf wire types
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LEN: u8 = 2;
+
+// Geometry commands
+const CMD_MOVE_TO: u32 = 1;
+const CMD_LINE_TO: u32 = 2;
+
+const GEOM_LINESTRING: u64 = 2;
+
+fn write_varint(buf: &mut Vec<u8>, mut val: u64) {
+    while val >= 0x80 {
+        buf.push((val as u8) | 0x80);
+        val >>= 7;
+    }
+    buf.push(val as u8);
+}
+
+fn write_key(buf: &mut Vec<u8>, field: u32, wire_type: u8) {
+    write_varint(buf, ((field << 3) | wire_type as u32) as u64);
+}
+
+fn write_len_delimited(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_key(buf, field, WIRE_LEN);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_packed(buf: &mut Vec<u8>, field: u32, values: &[u32]) {
+    let mut packed = Vec::with_capacity(values.len() * 2);
+    for v in values {
+        write_varint(&mut packed, *v as u64);
+    }
+    write_len_delimited(buf, field, &packed);
+}
+
+fn zigzag(val: i32) -> u32 {
+    ((val << 1) ^ (val >> 31)) as u32
+}
+
+fn command(id: u32, count: u32) -> u32 {
+    (id & 0x7) | (count << 3)
+}
+
+/// Encode a single property value as a `Tile.Value` message.
+///
+/// Returns `None` for values which can't be represented (e.g. `null`).
+fn encode_value(value: &serde_json::Value) -> Option<Vec<u8>> {
+    let mut buf = vec![];
+
+    match value {
+        serde_json::Value::Null => return None,
+        serde_json::Value::String(s) => write_len_delimited(&mut buf, 1, s.as_bytes()),
+        serde_json::Value::Bool(b) => {
+            write_key(&mut buf, 7, WIRE_VARINT);
+            write_varint(&mut buf, *b as u64);
+        }
+        serde_json::Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                // sint64
+                write_key(&mut buf, 6, WIRE_VARINT);
+                write_varint(&mut buf, ((i << 1) ^ (i >> 63)) as u64);
+            } else if let Some(u) = n.as_u64() {
+                write_key(&mut buf, 5, WIRE_VARINT);
+                write_varint(&mut buf, u);
+            } else {
+                let f = n.as_f64()?;
+                write_key(&mut buf, 3, WIRE_FIXED64);
+                buf.extend_from_slice(&f.to_le_bytes());
+            }
+        }
+        // Nested values aren't supported by MVT, flatten them to a string.
+        serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+            write_len_delimited(&mut buf, 1, value.to_string().as_bytes())
+        }
+    }
+
+    Some(buf)
+}
+
+struct Feature {
+    id: u64,
+    tags: Vec<u32>,
+    geometry: Vec<u32>,
+}
+
+pub struct Layer {
+    name: String,
+    extent: u32,
+    keys: Vec<String>,
+    key_index: HashMap<String, u32>,
+    values: Vec<Vec<u8>>,
+    value_index: HashMap<Vec<u8>, u32>,
+    features: Vec<Feature>,
+}
+
+impl Layer {
+    pub fn new(name: &str, extent: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            extent,
+            keys: vec![],
+            key_index: HashMap::new(),
+            values: vec![],
+            value_index: HashMap::new(),
+            features: vec![],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    fn key_id(&mut self, key: &str) -> u32 {
+        if let Some(id) = self.key_index.get(key) {
+            return *id;
+        }
+
+        let id = self.keys.len() as u32;
+        self.keys.push(key.to_string());
+        self.key_index.insert(key.to_string(), id);
+        id
+    }
+
+    fn value_id(&mut self, encoded: Vec<u8>) -> u32 {
+        if let Some(id) = self.value_index.get(&encoded) {
+            return *id;
+        }
+
+        let id = self.values.len() as u32;
+        self.values.push(encoded.clone());
+        self.value_index.insert(encoded, id);
+        id
+    }
+
+    /// Add a (multi-)linestring feature to the layer.
+    ///
+    /// Coordinates are expected to be in tile space, `[0, extent]` with the
+    /// origin at the top left.
+    pub fn add_linestring(
+        &mut self,
+        id: u64,
+        lines: &[Vec<Coord<i32>>],
+        properties: &serde_json::Map<String, serde_json::Value>,
+    ) {
+        let mut geometry = vec![];
+        let mut cursor = Coord { x: 0, y: 0 };
+
+        for line in lines {
+            let mut points = line.to_vec();
+            points.dedup();
+
+            if points.len() < 2 {
+                continue;
+            }
+
+            let delta = |cursor: &mut Coord<i32>, pt: Coord<i32>| {
+                let (dx, dy) = (pt.x - cursor.x, pt.y - cursor.y);
+                *cursor = pt;
+                [zigzag(dx), zigzag(dy)]
+            };
+
+            geometry.push(command(CMD_MOVE_TO, 1));
+            geometry.extend(delta(&mut cursor, points[0]));
+
+            geometry.push(command(CMD_LINE_TO, points.len() as u32 - 1));
+            for pt in &points[1..] {
+                geometry.extend(delta(&mut cursor, *pt));
+            }
+        }
+
+        if geometry.is_empty() {
+            return;
+        }
+
+        let mut tags = vec![];
+        for (key, value) in properties {
+            let Some(encoded) = encode_value(value) else {
+                continue;
+            };
+
+            tags.push(self.key_id(key));
+            tags.push(self.value_id(encoded));
+        }
+
+        self.features.push(Feature { id, tags, geometry });
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+
+        write_key(&mut buf, 15, WIRE_VARINT);
+        write_varint(&mut buf, 2);
+        write_len_delimited(&mut buf, 1, self.name.as_bytes());
+
+        for feature in &self.features {
+            let mut feat = vec![];
+            write_key(&mut feat, 1, WIRE_VARINT);
+            write_varint(&mut feat, feature.id);
+            if !feature.tags.is_empty() {
+                write_packed(&mut feat, 2, &feature.tags);
+            }
+            write_key(&mut feat, 3, WIRE_VARINT);
+            write_varint(&mut feat, GEOM_LINESTRING);
+            write_packed(&mut feat, 4, &feature.geometry);
+
+            write_len_delimited(&mut buf, 2, &feat);
+        }
+
+        for key in &self.keys {
+            write_len_delimited(&mut buf, 3, key.as_bytes());
+        }
+
+        for value in &self.values {
+            write_len_delimited(&mut buf, 4, value);
+        }
+
+        write_key(&mut buf, 5, WIRE_VARINT);
+        write_varint(&mut buf, self.extent as u64);
+
+        buf
+    }
+}
+
+/// Encode the given layers as a `Tile` message.
+pub fn encode_tile(layers: &[Layer]) -> Vec<u8> {
+    let mut buf = vec![];
+    for layer in layers.iter().filter(|layer| !layer.is_empty()) {
+        write_len_delimited(&mut buf, 3, &layer.encode());
+    }
+    buf
+}
+
+struct ActivityFeature {
+    properties: serde_json::Map<String, serde_json::Value>,
+    lines: Vec<Vec<Coord<i32>>>,
+}
+
+/// Build a vector tile containing all the activity tracks which intersect
+/// with the given tile.
+///
+/// Returns `None` if there are no matching activities.
+pub fn render_tile(tile: Tile, filter: &ActivityFilter, db: &Database) -> Result<Option<Vec<u8>>> {
+    let zoom_level = db
+        .config
+        .source_level(tile.z)
+        .ok_or_else(|| anyhow!("no source level for tile: {:?}", tile))?;
+
+    let bounds = TileBounds::from(zoom_level, &tile);
+    let tile_extent = db.config.tile_extent as i64;
+
+    // Size of the target tile in source tile pixel units.
+    let source_width = tile_extent << (zoom_level - tile.z);
+    let scale = |v: i64| (v * MVT_EXTENT as i64 / source_width) as i32;
+
+    let mut activities: BTreeMap<i64, ActivityFeature> = BTreeMap::new();
+
+    let conn = db.connection()?;
+    let (mut stmt, params) = prepare_activities_query(&conn, filter, &bounds, &db.config)?;
+    let mut rows = stmt.query(params.as_slice())?;
+    while let Some(row) = rows.next()? {
+        let activity_id: i64 = row.get_unwrap(0);
+        let source_tile = Tile::new(row.get_unwrap(1), row.get_unwrap(2), row.get_unwrap(3));
+        let bytes: Vec<u8> = row.get_unwrap(4);
+
+        let feature = match activities.get_mut(&activity_id) {
+            Some(feature) => feature,
+            None => {
+                let title: Option<String> = row.get_unwrap(5);
+                let start_time: Option<String> = row.get_unwrap(6);
+                let props: String = row.get_unwrap(7);
+
+                let mut properties: serde_json::Map<_, _> = serde_json::from_str(&props)?;
+                if let Some(title) = title {
+                    properties.insert("title".into(), title.into());
+                }
+                if let Some(start_time) = start_time {
+                    properties.insert("start_time".into(), start_time.into());
+                }
+
+                activities.entry(activity_id).or_insert(ActivityFeature {
+                    properties,
+                    lines: vec![],
+                })
+            }
+        };
+
+        // Origin of source tile within target tile
+        let x_offset = tile_extent * (source_tile.x - bounds.xmin) as i64;
+        let y_offset = tile_extent * (source_tile.y - bounds.ymin) as i64;
+
+        // Privacy masks may have been added since the activity was imported.
+        let coords = decode_line(&bytes)?;
+        let source_bbox = source_tile.xy_bounds();
+        let runs = split_line(&coords, &db.config.masks, |c| {
+            WebMercator::from_tile_pixel(*c, &source_bbox, db.config.tile_extent)
+        });
+
+        for run in runs.into_iter().filter(|r| r.len() >= 2) {
+            let line = run
+                .into_iter()
+                .map(|Coord { x, y }| Coord {
+                    x: scale(x as i64 + x_offset),
+                    // Stored tiles have their origin at the bottom left.
+                    y: scale((tile_extent - y as i64) + y_offset),
+                })
+                .collect();
+
+            feature.lines.push(line);
+        }
+    }
+
+    let mut layer = Layer::new(ACTIVITY_LAYER, MVT_EXTENT);
+    for (id, feature) in activities {
+        layer.add_linestring(id as u64, &feature.lines, &feature.properties);
+    }
+
+    if layer.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(encode_tile(&[layer])))
+}
+
+fn prepare_activities_query<'a>(
+    conn: &'a rusqlite::Connection,
+    filter: &'a ActivityFilter,
+    bounds: &'a TileBounds,
+    config: &Config,
+) -> Result<(rusqlite::Statement<'a>, Vec<&'a dyn ToSql>)> {
+    let mut params = params![bounds.z, bounds.xmin, bounds.xmax, bounds.ymin, bounds.ymax].to_vec();
+    let filter_clause = filter.to_query(&mut params);
+
+    let stmt = conn.prepare(&format!(
+        "\
+        SELECT activity_id, x, y, z, coords, title, start_time, properties \
+        FROM activity_tiles \
+        JOIN activities ON activities.id = activity_tiles.activity_id \
+        WHERE {} AND {};",
+        spatial_index::range_clause(config),
+        filter_clause,
+    ))?;
+
+    Ok((stmt, params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zigzag() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(-2), 3);
+    }
+
+    #[test]
+    fn test_linestring_geometry() {
+        let mut layer = Layer::new("test", MVT_EXTENT);
+        let line = vec![
+            Coord { x: 2, y: 2 },
+            Coord { x: 2, y: 2 },
+            Coord { x: 2, y: 10 },
+            Coord { x: 10, y: 10 },
+        ];
+        layer.add_linestring(1, &[line], &serde_json::Map::new());
+
+        // Example taken from the vector tile spec (duplicate point removed).
+        assert_eq!(layer.features[0].geometry, vec![9, 4, 4, 18, 0, 16, 16, 0]);
+    }
+
+    #[test]
+    fn test_dedup_properties() {
+        let mut layer = Layer::new("test", MVT_EXTENT);
+        let line = vec![Coord { x: 0, y: 0 }, Coord { x: 1, y: 1 }];
+
+        let props = serde_json::json!({"kind": "ride", "distance": 10.5});
+        let props = props.as_object().unwrap();
+
+        layer.add_linestring(1, std::slice::from_ref(&line), props);
+        layer.add_linestring(2, &[line], props);
+
+        assert_eq!(layer.keys.len(), 2);
+        assert_eq!(layer.values.len(), 2);
+        assert_eq!(layer.features[0].tags, layer.features[1].tags);
+    }
+}
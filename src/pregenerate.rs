@@ -0,0 +1,121 @@
+//! Render a static pyramid of heatmap tiles, which can be served by any
+//! plain file server (nginx, S3, ...) without running `hotpot serve`.
+
+use std::io::Cursor;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{anyhow, Result};
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::iter::{ParallelBridge, ParallelIterator};
+
+use crate::db::{ActivityFilter, Database};
+use crate::mbtiles::MBTiles;
+use crate::raster::{self, Gradient, Intensity, Stroke};
+use crate::tile::{Tile, WebMercatorViewport};
+
+enum TileWriter {
+    /// `{z}/{x}/{y}.png` files below the given directory.
+    Directory(PathBuf),
+    MBTiles(MBTiles),
+}
+
+impl TileWriter {
+    fn write(&self, tile: &Tile, png: &[u8]) -> Result<()> {
+        match self {
+            TileWriter::Directory(root) => {
+                let dir = root.join(tile.z.to_string()).join(tile.x.to_string());
+                std::fs::create_dir_all(&dir)?;
+                std::fs::write(dir.join(format!("{}.png", tile.y)), png)?;
+            }
+            TileWriter::MBTiles(mbtiles) => mbtiles.insert(tile, png)?,
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            TileWriter::Directory(_) => Ok(()),
+            TileWriter::MBTiles(mbtiles) => mbtiles.finish(),
+        }
+    }
+}
+
+/// Render all non-empty tiles intersecting `viewport` for every zoom level in
+/// `zooms`, writing them to `output`.
+///
+/// If `output` ends with `.mbtiles`, tiles are written to an MBTiles
+/// tileset, otherwise to a `{z}/{x}/{y}.png` directory structure.
+///
+/// Returns the number of tiles written.
+#[allow(clippy::too_many_arguments)]
+pub fn pregenerate(
+    db: &Database,
+    viewport: &WebMercatorViewport,
+    zooms: RangeInclusive<u8>,
+    width: u32,
+    gradient: &Gradient,
+    intensity: Intensity,
+    stroke: Stroke,
+    filter: &ActivityFilter,
+    output: &Path,
+) -> Result<u64> {
+    if *zooms.end() > raster::MAX_ZOOM {
+        return Err(anyhow!("max zoom must be <= {}", raster::MAX_ZOOM));
+    }
+
+    let writer = if output.extension().is_some_and(|ext| ext == "mbtiles") {
+        TileWriter::MBTiles(MBTiles::create(
+            output,
+            viewport,
+            *zooms.start(),
+            *zooms.end(),
+        )?)
+    } else {
+        std::fs::create_dir_all(output)?;
+        TileWriter::Directory(output.to_owned())
+    };
+
+    let num_tiles: usize = zooms.clone().map(|z| viewport.tiles(z).count()).sum();
+    let progress = ProgressBar::new(num_tiles as u64).with_style(
+        ProgressStyle::with_template("{wide_bar} {pos}/{len} tiles ({eta} remaining)")
+            .expect("valid template"),
+    );
+
+    tracing::info!(num_tiles, ?zooms, "rendering tiles");
+
+    let num_written = AtomicU64::new(0);
+    zooms
+        .flat_map(|z| viewport.tiles(z))
+        .par_bridge()
+        .try_for_each(|tile| -> Result<()> {
+            let image =
+                raster::render_tile(tile, gradient, intensity, stroke, None, width, filter, db)?;
+            progress.inc(1);
+
+            // Empty tiles are left out, file servers will simply 404.
+            let Some(image) = image else {
+                return Ok(());
+            };
+
+            let mut png = Vec::new();
+            image.write_with_encoder(PngEncoder::new_with_quality(
+                Cursor::new(&mut png),
+                CompressionType::Best,
+                FilterType::Adaptive,
+            ))?;
+
+            writer.write(&tile, &png)?;
+            num_written.fetch_add(1, Ordering::Relaxed);
+
+            Ok(())
+        })?;
+
+    progress.finish();
+    writer.finish()?;
+
+    Ok(num_written.into_inner())
+}
@@ -1,124 +1,949 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fmt::Display;
 use std::ops::RangeInclusive;
 use std::str::FromStr;
 
 use anyhow::{anyhow, Result};
-use geo_types::Coord;
-use image::{Rgba, RgbaImage};
+use clap::ValueEnum;
+use geo_types::{Coord, MultiLineString, Point};
+use image::{imageops, Rgba, RgbaImage};
 use once_cell::sync::Lazy;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rusqlite::types::Value;
 use rusqlite::{params, ToSql};
 use serde::{Deserialize, Deserializer};
+use time::OffsetDateTime;
 
-use crate::db::{decode_line, ActivityFilter, Database};
-use crate::tile::{Tile, TileBounds};
+use crate::aggregate;
+use crate::db::{decode_line, ActivityFilter, Config, Database};
+use crate::mask::PrivacyMask;
+use crate::spatial_index;
+use crate::tile::{BBox, LngLat, Tile, TileBounds, WebMercator};
 use crate::WebMercatorViewport;
 
-pub static PINKISH: Lazy<LinearGradient> = Lazy::new(|| {
-    LinearGradient::from_stops(&[
+pub static PINKISH: Lazy<Gradient> = Lazy::new(|| {
+    Gradient::from(LinearGradient::from_stops(&[
         (1, [0xff, 0xb1, 0xff, 0x7f]),
         (10, [0xff, 0xb1, 0xff, 0xff]),
         (50, [0xff, 0xff, 0xff, 0xff]),
-    ])
+    ]))
 });
 
-pub static BLUE_RED: Lazy<LinearGradient> = Lazy::new(|| {
-    LinearGradient::from_stops(&[
+pub static BLUE_RED: Lazy<Gradient> = Lazy::new(|| {
+    Gradient::from(LinearGradient::from_stops(&[
         (1, [0x3f, 0x5e, 0xfb, 0xff]),
         (10, [0xfc, 0x46, 0x6b, 0xff]),
         (50, [0xff, 0xff, 0xff, 0xff]),
-    ])
+    ]))
 });
 
-pub static RED: Lazy<LinearGradient> = Lazy::new(|| {
-    LinearGradient::from_stops(&[
+pub static RED: Lazy<Gradient> = Lazy::new(|| {
+    Gradient::from(LinearGradient::from_stops(&[
         (1, [0xb2, 0x0a, 0x2c, 0xff]),
         (10, [0xff, 0xfb, 0xd5, 0xff]),
         (50, [0xff, 0xff, 0xff, 0xff]),
-    ])
+    ]))
 });
 
-pub static ORANGE: Lazy<LinearGradient> = Lazy::new(|| {
-    LinearGradient::from_stops(&[
+pub static ORANGE: Lazy<Gradient> = Lazy::new(|| {
+    Gradient::from(LinearGradient::from_stops(&[
         (1, [0xfc, 0x4a, 0x1a, 0xff]),
         (10, [0xf7, 0xb7, 0x33, 0xff]),
-    ])
+    ]))
 });
 
+/// Gradients selectable by name: the built in ones, plus any user defined
+/// ones stored in the database (which may replace them).
+pub fn named_gradients(stored: &BTreeMap<String, String>) -> Result<BTreeMap<String, Gradient>> {
+    let mut gradients = BTreeMap::from([
+        ("orange".to_string(), ORANGE.clone()),
+        ("pinkish".to_string(), PINKISH.clone()),
+        ("blue-red".to_string(), BLUE_RED.clone()),
+        ("red".to_string(), RED.clone()),
+    ]);
+
+    for (name, spec) in stored {
+        let gradient = Gradient::from_str(spec)
+            .map_err(|_| anyhow!("invalid gradient stored for {}: {}", name, spec))?;
+        gradients.insert(name.clone(), gradient);
+    }
+
+    Ok(gradients)
+}
+
+/// Line color used when rendering individual activities.
+pub const DEFAULT_ACTIVITY_COLOR: &str = "fc4a1a";
+
+/// Highest zoom level tiles can be rendered at. Above the highest stored zoom
+/// level, tiles are "overzoomed" by scaling up the stored data.
+pub const MAX_ZOOM: u8 = 24;
+
+/// How activity lines are drawn when rasterizing tiles.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Stroke {
+    /// Line width, in pixels.
+    pub width: u32,
+    /// Standard deviation of the Gaussian blur applied to lines, in pixels.
+    /// Zero disables blurring.
+    pub blur: f32,
+    /// Draw anti-aliased lines, with partially covered pixels contributing a
+    /// fraction of a visit.
+    pub antialias: bool,
+    pub count: Count,
+    /// Number of days after which an activity only counts half as much, so
+    /// that recent activities stand out. `None` counts all of them equally.
+    pub half_life: Option<f32>,
+}
+
+/// What the pixel counts of a heatmap measure.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Count {
+    /// Every time a line passes over a pixel.
+    #[default]
+    Passes,
+    /// Distinct activities passing over a pixel, so that GPS jitter or laps
+    /// of the same loop don't make a single activity look busy.
+    Activities,
+}
+
+impl Stroke {
+    pub const MAX_WIDTH: u32 = 16;
+    pub const MAX_BLUR: f32 = 16.0;
+
+    pub fn new(width: u32, blur: f32, antialias: bool) -> Result<Self> {
+        if !(1..=Self::MAX_WIDTH).contains(&width) {
+            return Err(anyhow!("line width must be in [1, {}]", Self::MAX_WIDTH));
+        }
+
+        if !(0.0..=Self::MAX_BLUR).contains(&blur) {
+            return Err(anyhow!("blur must be in [0, {}]", Self::MAX_BLUR));
+        }
+
+        Ok(Stroke {
+            width,
+            blur,
+            antialias,
+            count: Count::default(),
+            half_life: None,
+        })
+    }
+
+    pub fn counting(self, count: Count) -> Self {
+        Stroke { count, ..self }
+    }
+
+    pub fn decaying(self, half_life: Option<f32>) -> Result<Self> {
+        if matches!(half_life, Some(days) if !days.is_finite() || days <= 0.0) {
+            return Err(anyhow!("half life must be a positive number of days"));
+        }
+
+        Ok(Stroke { half_life, ..self })
+    }
+
+    /// Whether pixels count fractional visits, rather than whole ones.
+    fn fractional(&self) -> bool {
+        self.antialias || self.half_life.is_some()
+    }
+
+    /// How much an activity starting at `start_time` counts for, relative to
+    /// one starting at `now`. Activities without a start time count fully.
+    fn weight(&self, start_time: Option<OffsetDateTime>, now: OffsetDateTime) -> f32 {
+        match (self.half_life, start_time) {
+            (Some(half_life), Some(start_time)) => {
+                let age = (now - start_time).as_seconds_f32().max(0.0) / 86_400.0;
+                0.5f32.powf(age / half_life)
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Distance in pixels outside of a tile from which lines can still
+    /// affect pixels inside of it.
+    fn margin(&self) -> u32 {
+        self.width / 2 + (3.0 * self.blur).ceil() as u32 + self.antialias as u32
+    }
+}
+
+impl Default for Stroke {
+    fn default() -> Self {
+        Stroke {
+            width: 1,
+            blur: 0.0,
+            antialias: false,
+            count: Count::default(),
+            half_life: None,
+        }
+    }
+}
+
 struct TileRaster {
     bounds: TileBounds,
-    scale: u32,
+    /// Number of bits to shift stored coordinates right by to get pixel
+    /// coordinates. Negative when upscaling.
+    scale: i32,
+    /// Origin of the target tile, in pixels at the target zoom level.
+    origin: Coord<i64>,
     width: u32,
     tile_extent: u32,
-    pixels: Vec<u8>,
+    stroke: Stroke,
+    /// Extra pixels drawn around each edge of the tile, so that wide and
+    /// blurred lines match up with neighboring tiles. Removed by `finish`.
+    margin: u32,
+    /// Visit counts. These are only turned into gradient stops (and
+    /// clamped to them) once the tile is colored, so that busy areas can
+    /// still be told apart.
+    pixels: Vec<u16>,
+    /// Fractional visit counts, only used for anti-aliased lines or when
+    /// weighting activities by age. `pixels` holds these rounded up once the
+    /// raster is finished.
+    coverage: Vec<f32>,
+    /// How much of each pixel is covered by the activity currently being
+    /// drawn, so that wide lines only count each activity once per pixel.
+    covered: Vec<f32>,
+    /// Pixels with non-zero `covered`, along with the color of the activity
+    /// covering them. Flushed into the counts by `end_activity`.
+    touched: Vec<usize>,
+    touched_color: Option<Rgba<u8>>,
+    /// Weight of the activity currently being drawn, see `Stroke::weight`.
+    weight: f32,
+    /// Weighted sums of activity colors (RGB, then total weight), only used
+    /// when coloring by property.
+    colors: Vec<[f32; 4]>,
 }
 
 impl TileRaster {
-    fn new(tile: Tile, source: TileBounds, width: u32, tile_extent: u32) -> Self {
-        // TODO: support upscaling
-        assert!(width <= tile_extent, "Upscaling not supported");
+    fn new(tile: Tile, source: TileBounds, width: u32, tile_extent: u32, stroke: Stroke) -> Self {
         assert!(width.is_power_of_two(), "width must be power of two");
-        assert!(source.z >= tile.z, "source zoom must be >= target zoom");
 
-        let zoom_steps = (source.z - tile.z) as u32;
-        let width_steps = tile_extent.ilog2() - width.ilog2();
+        let zoom_steps = source.z as i32 - tile.z as i32;
+        let width_steps = tile_extent.ilog2() as i32 - width.ilog2() as i32;
+
+        let margin = stroke.margin();
+        let size = ((width + 2 * margin) as usize).pow(2);
 
         Self {
             width,
             tile_extent,
-            pixels: vec![0; (width * width) as usize],
+            stroke,
+            margin,
+            pixels: vec![0; size],
+            coverage: if stroke.fractional() {
+                vec![0.0; size]
+            } else {
+                vec![]
+            },
+            covered: if stroke.width > 1 || stroke.antialias || stroke.count == Count::Activities {
+                vec![0.0; size]
+            } else {
+                vec![]
+            },
+            touched: vec![],
+            touched_color: None,
+            weight: 1.0,
+            colors: vec![],
             bounds: source,
             scale: zoom_steps + width_steps,
+            origin: Coord {
+                x: tile.x as i64 * width as i64 - margin as i64,
+                y: tile.y as i64 * width as i64 - margin as i64,
+            },
+        }
+    }
+
+    /// A finished raster holding precomputed counts, drawn with the default
+    /// stroke.
+    fn from_counts(tile: Tile, width: u32, counts: Vec<u16>, config: &Config) -> Self {
+        debug_assert_eq!(counts.len(), (width * width) as usize);
+
+        let source = TileBounds::from(config.source_level(tile.z).unwrap_or(tile.z), &tile);
+        Self {
+            pixels: counts,
+            ..Self::new(tile, source, width, config.tile_extent, Stroke::default())
         }
     }
 
-    fn add_activity(&mut self, source_tile: &Tile, coords: &[Coord<u32>]) {
+    /// Width of the pixel buffer, including margins.
+    fn stride(&self) -> u32 {
+        self.width + 2 * self.margin
+    }
+
+    /// Number of source tiles around `bounds` which can draw into the
+    /// margins.
+    fn margin_tiles(&self) -> u32 {
+        if self.margin == 0 {
+            return 0;
+        }
+
+        let margin = if self.scale >= 0 {
+            (self.margin as u64) << self.scale
+        } else {
+            (self.margin as u64) >> -self.scale
+        };
+
+        (margin.div_ceil(self.tile_extent as u64) as u32).max(1)
+    }
+
+    /// Draw the lines of a single activity within `source_tile`, optionally
+    /// tagged with a color to blend by when coloring by property.
+    ///
+    /// When counting distinct activities, an activity's lines from every
+    /// source tile are collected until `end_activity` is called.
+    fn add_activity(&mut self, source_tile: &Tile, coords: &[Coord<u32>], color: Option<Rgba<u8>>) {
         debug_assert_eq!(source_tile.z, self.bounds.z);
 
-        // Origin of source tile within target tile
-        let x_offset = self.tile_extent * (source_tile.x - self.bounds.xmin);
-        let y_offset = self.tile_extent * (source_tile.y - self.bounds.ymin);
+        let extent = self.tile_extent as i64;
+        let stride = self.stride() as i64;
+        let pixel_size = 2f64.powi(self.scale);
+
+        // Square brush, centered on the line.
+        let brush_min = -((self.stroke.width as i64 - 1) / 2);
+        let brush_max = self.stroke.width as i64 / 2;
+        let (lo, hi) = (-brush_max - 1, stride - brush_min + 1);
+
+        // Origin of source tile, in pixels at the source zoom level.
+        let x_offset = extent * source_tile.x as i64;
+        let y_offset = extent * source_tile.y as i64;
 
+        let mut touched = std::mem::take(&mut self.touched);
         let mut prev = None;
         for Coord { x, y } in coords {
-            // Translate (x,y) to location in target tile.
-            // [0..(width * STORED_TILE_WIDTH)]
-            let x = x + x_offset;
-            let y = (self.tile_extent - y) + y_offset;
+            let x = x_offset + *x as i64;
+            let y = y_offset + (extent - *y as i64);
 
-            // Scale the coordinates back down to [0..width]
-            let x = x >> self.scale;
-            let y = y >> self.scale;
+            // Position of the pixel center relative to the target tile, for
+            // anti-aliased lines.
+            let exact = (
+                x as f64 / pixel_size - self.origin.x as f64 - 0.5,
+                y as f64 / pixel_size - self.origin.y as f64 - 0.5,
+            );
+
+            // Scale the coordinates to the target zoom level, and translate
+            // them to [0..stride] within the target tile.
+            let (x, y) = if self.scale >= 0 {
+                (x >> self.scale, y >> self.scale)
+            } else {
+                (x << -self.scale, y << -self.scale)
+            };
+            let (x, y) = (x - self.origin.x, y - self.origin.y);
 
-            if let Some(Coord { x: px, y: py }) = prev {
+            if let Some((Coord { x: px, y: py }, prev_exact)) = prev {
                 if x == px && y == py {
                     continue;
                 }
 
-                let line_iter = line_drawing::Bresenham::<i32>::new(
-                    (px as i32, py as i32),
-                    (x as i32, y as i32),
-                );
+                // When overzooming, most segments fall entirely outside the
+                // target tile, so skip them before walking every pixel.
+                let outside = (x < lo && px < lo)
+                    || (y < lo && py < lo)
+                    || (x >= hi && px >= hi)
+                    || (y >= hi && py >= hi);
 
-                for (ix, iy) in line_iter {
-                    if ix < 0 || iy < 0 || ix >= self.width as i32 || iy >= self.width as i32 {
-                        continue;
+                if outside {
+                    // Nothing to draw.
+                } else if self.stroke.antialias {
+                    draw_line_aa(prev_exact, exact, |ix, iy, alpha| {
+                        self.cover(ix, iy, alpha, (brush_min, brush_max), &mut touched);
+                    });
+                } else if self.stroke.width > 1 || self.stroke.count == Count::Activities {
+                    for (ix, iy) in line_drawing::Bresenham::<i64>::new((px, py), (x, y)) {
+                        self.cover(ix, iy, 1.0, (brush_min, brush_max), &mut touched);
                     }
+                } else {
+                    for (ix, iy) in line_drawing::Bresenham::<i64>::new((px, py), (x, y)) {
+                        if ix < 0 || iy < 0 || ix >= stride || iy >= stride {
+                            continue;
+                        }
+
+                        self.visit((iy * stride + ix) as usize, 1.0, color);
+                    }
+                }
+            }
+            prev = Some((Coord { x, y }, exact));
+        }
+
+        self.touched = touched;
+        self.touched_color = color;
+        if self.stroke.count == Count::Passes {
+            self.end_activity();
+        }
+    }
+
+    /// Count the pixels covered by the activity drawn since the last call.
+    fn end_activity(&mut self) {
+        let color = self.touched_color.take();
+        for idx in std::mem::take(&mut self.touched) {
+            let alpha = std::mem::take(&mut self.covered[idx]);
+            self.visit(idx, alpha, color);
+        }
+    }
+
+    /// Draw each of `lines`, which are grouped by activity when counting
+    /// distinct activities.
+    fn draw(&mut self, lines: &[SourceLine]) -> Result<()> {
+        let mut current = None;
+        for line in lines {
+            if current != Some(line.activity_id) {
+                self.end_activity();
+                current = Some(line.activity_id);
+            }
+
+            self.weight = line.weight;
+            self.add_activity(&line.tile, &decode_line(&line.coords)?, line.color);
+        }
+
+        Ok(())
+    }
+
+    /// Add the counts of another raster of the same tile, which has no
+    /// activity left to end.
+    fn merge(&mut self, other: TileRaster) {
+        debug_assert_eq!(self.pixels.len(), other.pixels.len());
+        debug_assert!(other.touched.is_empty());
+
+        for (px, count) in self.pixels.iter_mut().zip(other.pixels) {
+            *px = px.saturating_add(count);
+        }
+        for (value, other) in self.coverage.iter_mut().zip(other.coverage) {
+            *value += other;
+        }
+
+        if self.colors.is_empty() {
+            self.colors = other.colors;
+        } else {
+            for (sum, other) in self.colors.iter_mut().zip(other.colors) {
+                for channel in 0..4 {
+                    sum[channel] += other[channel];
+                }
+            }
+        }
+    }
+
+    /// Count a visit to the pixel at `idx`, of which `alpha` is covered by
+    /// the line.
+    fn visit(&mut self, idx: usize, alpha: f32, color: Option<Rgba<u8>>) {
+        let weight = alpha * self.weight;
+        if self.stroke.fractional() {
+            self.coverage[idx] += weight;
+        } else {
+            self.pixels[idx] = self.pixels[idx].saturating_add(1);
+        }
+
+        if let Some(Rgba([r, g, b, _])) = color {
+            if self.colors.is_empty() {
+                self.colors = vec![[0.0; 4]; self.pixels.len()];
+            }
+
+            let sum = &mut self.colors[idx];
+            sum[0] += r as f32 * weight;
+            sum[1] += g as f32 * weight;
+            sum[2] += b as f32 * weight;
+            sum[3] += weight;
+        }
+    }
+
+    /// Mark the pixels under the brush centered on `(x, y)` as covered by
+    /// the current activity, keeping the highest coverage seen.
+    fn cover(&mut self, x: i64, y: i64, alpha: f32, brush: (i64, i64), touched: &mut Vec<usize>) {
+        if alpha <= 0.0 {
+            return;
+        }
+
+        let stride = self.stride() as i64;
+        for bx in (x + brush.0)..=(x + brush.1) {
+            for by in (y + brush.0)..=(y + brush.1) {
+                if bx < 0 || by < 0 || bx >= stride || by >= stride {
+                    continue;
+                }
+
+                let idx = (by * stride + bx) as usize;
+                if self.covered[idx] == 0.0 {
+                    touched.push(idx);
+                }
+                self.covered[idx] = self.covered[idx].max(alpha);
+            }
+        }
+    }
+
+    /// Blur the drawn lines (if enabled), and crop off the margins.
+    fn finish(&mut self) {
+        self.end_activity();
+
+        let stride = self.stride() as usize;
+        let sigma = self.stroke.blur;
+
+        if self.stroke.fractional() {
+            if sigma > 0.0 {
+                self.coverage = blur(&self.coverage, stride, sigma);
+            }
+        } else if sigma > 0.0 {
+            let counts: Vec<f32> = self.pixels.iter().map(|&px| px as f32).collect();
+            for (px, value) in self.pixels.iter_mut().zip(blur(&counts, stride, sigma)) {
+                *px = value.round().clamp(0.0, u16::MAX as f32) as u16;
+            }
+        }
+
+        if sigma > 0.0 && !self.colors.is_empty() {
+            for channel in 0..4 {
+                let values: Vec<f32> = self.colors.iter().map(|sum| sum[channel]).collect();
+                for (sum, value) in self.colors.iter_mut().zip(blur(&values, stride, sigma)) {
+                    sum[channel] = value;
+                }
+            }
+        }
+
+        if self.margin > 0 {
+            let (margin, width) = (self.margin as usize, self.width as usize);
+            self.pixels = crop(&self.pixels, stride, margin, width);
+            if self.stroke.fractional() {
+                self.coverage = crop(&self.coverage, stride, margin, width);
+            }
+            if !self.colors.is_empty() {
+                self.colors = crop(&self.colors, stride, margin, width);
+            }
+            self.margin = 0;
+        }
+
+        if self.stroke.fractional() {
+            for (px, value) in self.pixels.iter_mut().zip(&self.coverage) {
+                *px = value.ceil().clamp(0.0, u16::MAX as f32) as u16;
+            }
+        }
+    }
 
-                    let idx = (iy as u32 * self.width + ix as u32) as usize;
-                    self.pixels[idx] = self.pixels[idx].saturating_add(1);
+    /// Clear any pixels which fall inside of a privacy mask.
+    ///
+    /// Masks are applied during import, but this also covers masks which were
+    /// added afterwards.
+    fn apply_masks(&mut self, tile: &Tile, masks: &[PrivacyMask]) {
+        let bbox = tile.xy_bounds();
+        let px_size = (bbox.right - bbox.left) / self.width as f64;
+
+        for mask in masks {
+            let mbox = mask.bbox();
+            if mbox.right < bbox.left
+                || mbox.left > bbox.right
+                || mbox.top < bbox.bot
+                || mbox.bot > bbox.top
+            {
+                continue;
+            }
+
+            // Pixel range covered by the mask's bounding box
+            let to_px = |v: f64| (v / px_size).floor().clamp(0.0, (self.width - 1) as f64) as u32;
+            let (x0, x1) = (to_px(mbox.left - bbox.left), to_px(mbox.right - bbox.left));
+            let (y0, y1) = (to_px(bbox.top - mbox.top), to_px(bbox.top - mbox.bot));
+
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    let pt = WebMercator(Point::new(
+                        bbox.left + (x as f64 + 0.5) * px_size,
+                        bbox.top - (y as f64 + 0.5) * px_size,
+                    ));
+
+                    if mask.contains(&pt) {
+                        self.pixels[(y * self.width + x) as usize] = 0;
+                    }
                 }
             }
-            prev = Some(Coord { x, y });
         }
     }
 
-    fn apply_gradient(&self, gradient: &LinearGradient) -> RgbaImage {
+    fn apply_gradient(&self, gradient: &LinearGradient, intensity: Intensity) -> RgbaImage {
+        let levels = intensity.levels(&self.pixels, gradient.saturation_point());
+
         RgbaImage::from_fn(self.width, self.width, |x, y| {
             let idx = (y * self.width + x) as usize;
-            gradient.sample(self.pixels[idx])
+            let count = self.pixels[idx];
+
+            // Blend between the colors for the counts on either side of a
+            // fractional one, which fades out the edges of the line.
+            if self.stroke.fractional() && count > 0 {
+                let value = self.coverage[idx].min(count as f32);
+                let below = value.floor();
+                let (lower, upper) = (
+                    gradient.sample(levels[below as usize]),
+                    gradient.sample(levels[count as usize]),
+                );
+
+                // Fading from transparent should only change the alpha.
+                if lower[3] == 0 {
+                    let mut color = upper;
+                    color[3] = (color[3] as f32 * (value - below)).round() as u8;
+                    return color;
+                }
+
+                return lerp(lower, upper, value - below);
+            }
+
+            gradient.sample(levels[count as usize])
+        })
+    }
+
+    /// Color each pixel by the average color of the activities crossing it,
+    /// becoming more opaque with more visits.
+    fn apply_colors(&self, intensity: Intensity) -> RgbaImage {
+        const MIN_ALPHA: f32 = 96.0;
+        let levels = intensity.levels(&self.pixels, CATEGORY_SATURATION);
+
+        RgbaImage::from_fn(self.width, self.width, |x, y| {
+            let idx = (y * self.width + x) as usize;
+            let count = self.pixels[idx];
+            let [r, g, b, weight] = self.colors.get(idx).copied().unwrap_or_default();
+
+            if count == 0 || weight <= 0.0 {
+                return Rgba([0, 0, 0, 0]);
+            }
+
+            let level = (levels[count as usize] as f32 / CATEGORY_SATURATION as f32).min(1.0);
+            let mut alpha = MIN_ALPHA + (255.0 - MIN_ALPHA) * level;
+            if self.stroke.fractional() {
+                alpha *= self.coverage[idx].min(1.0);
+            }
+
+            Rgba([
+                (r / weight).round() as u8,
+                (g / weight).round() as u8,
+                (b / weight).round() as u8,
+                alpha.round() as u8,
+            ])
+        })
+    }
+}
+
+/// Copy the `width` x `width` region inside of `margin` out of a square
+/// buffer.
+fn crop<T: Copy>(buf: &[T], stride: usize, margin: usize, width: usize) -> Vec<T> {
+    (0..width)
+        .flat_map(|y| {
+            let start = (y + margin) * stride + margin;
+            buf[start..start + width].iter().copied()
+        })
+        .collect()
+}
+
+/// Separable Gaussian blur of a square buffer.
+///
+/// The result is scaled so the center of a 1px line keeps its original
+/// value, fading out to either side, rather than spreading it so thin that
+/// it rounds away.
+fn blur(values: &[f32], stride: usize, sigma: f32) -> Vec<f32> {
+    let radius = (3.0 * sigma).ceil() as i64;
+
+    let kernel: Vec<f32> = (-radius..=radius)
+        .map(|d| (-(d * d) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    let kernel: Vec<f32> = kernel.iter().map(|k| k / sum).collect();
+    let gain = 1.0 / kernel[radius as usize];
+
+    let stride = stride as i64;
+    let convolve = |src: &[f32], step: (i64, i64)| -> Vec<f32> {
+        let mut dst = vec![0.0; src.len()];
+        for y in 0..stride {
+            for x in 0..stride {
+                let mut acc = 0.0;
+                for (k, weight) in kernel.iter().enumerate() {
+                    let d = k as i64 - radius;
+                    let (sx, sy) = (x + d * step.0, y + d * step.1);
+                    if sx >= 0 && sy >= 0 && sx < stride && sy < stride {
+                        acc += weight * src[(sy * stride + sx) as usize];
+                    }
+                }
+                dst[(y * stride + x) as usize] = acc;
+            }
+        }
+        dst
+    };
+
+    convolve(&convolve(values, (1, 0)), (0, 1))
+        .into_iter()
+        .map(|value| value * gain)
+        .collect()
+}
+
+/// Xiaolin Wu's line algorithm, calling `plot` for each pixel along with
+/// how much of it the line covers. Pixel centers are at integer coordinates.
+fn draw_line_aa<F>(start: (f64, f64), end: (f64, f64), mut plot: F)
+where
+    F: FnMut(i64, i64, f32),
+{
+    let steep = (end.1 - start.1).abs() > (end.0 - start.0).abs();
+    let (mut a, mut b) = if steep {
+        ((start.1, start.0), (end.1, end.0))
+    } else {
+        (start, end)
+    };
+
+    if a.0 > b.0 {
+        std::mem::swap(&mut a, &mut b);
+    }
+
+    let dx = b.0 - a.0;
+    let gradient = if dx == 0.0 { 0.0 } else { (b.1 - a.1) / dx };
+
+    for x in (a.0.round() as i64)..=(b.0.round() as i64) {
+        let y = a.1 + gradient * (x as f64 - a.0);
+        let (y0, frac) = (y.floor() as i64, (y - y.floor()) as f32);
+
+        if steep {
+            plot(y0, x, 1.0 - frac);
+            plot(y0 + 1, x, frac);
+        } else {
+            plot(x, y0, 1.0 - frac);
+            plot(x, y0 + 1, frac);
+        }
+    }
+}
+
+/// What a raster tile shows.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderMode {
+    /// Activity lines, colored by how often they overlap.
+    #[default]
+    Heatmap,
+    /// Zoom 14 tiles which contain at least one activity, with the largest
+    /// square of them highlighted.
+    Explorer,
+}
+
+/// How pixel counts (number of activities crossing a pixel) are mapped onto
+/// the gradient.
+///
+/// Apart from `linear`, these spread the counts over the gradient up to the
+/// point where it stops changing color, so busy areas don't all saturate.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Intensity {
+    /// Use counts as gradient stops directly.
+    #[default]
+    Linear,
+    /// Logarithmic scale, up to a count of 1000.
+    Log,
+    /// Square root scale, over the same range as `log`.
+    Sqrt,
+    /// Rank of each count among the pixels in the tile.
+    Percentile,
+    /// Linear scale up to the highest count within the tile.
+    Auto,
+}
+
+/// Count at which the `log` and `sqrt` intensities reach the top of the
+/// gradient. The same for every tile, so that colors match across tile edges.
+const INTENSITY_MAX_COUNT: usize = 1000;
+
+impl Intensity {
+    /// Lookup table from pixel count to gradient stop, for the given pixels
+    /// and gradient saturation point. Covers every count up to the highest
+    /// one in `pixels`, and at least 0..=255.
+    fn levels(&self, pixels: &[u16], top: u8) -> Vec<u8> {
+        let len = pixels.iter().copied().max().unwrap_or(0).max(255) as usize + 1;
+        let mut levels: Vec<u8> = (0..len).map(|count| count.min(255) as u8).collect();
+
+        if *self == Intensity::Linear {
+            return levels;
+        }
+
+        let mut histogram = vec![0u32; len];
+        for &px in pixels {
+            histogram[px as usize] += 1;
+        }
+
+        let max_count = match self {
+            Intensity::Auto => histogram.iter().rposition(|&n| n > 0).unwrap_or(0),
+            _ => INTENSITY_MAX_COUNT,
+        } as f64;
+        let num_nonzero: u32 = histogram[1..].iter().sum();
+
+        // Fraction of the gradient to use for each count, in [0, 1]
+        let mut below = 0;
+        for count in 1..len {
+            let c = count as f64;
+            let t = match self {
+                Intensity::Linear => unreachable!(),
+                Intensity::Log => c.ln() / max_count.ln(),
+                Intensity::Sqrt => ((c - 1.0) / (max_count - 1.0)).sqrt(),
+                Intensity::Percentile => below as f64 / num_nonzero.max(1) as f64,
+                Intensity::Auto if max_count > 1.0 => (c - 1.0) / (max_count - 1.0),
+                Intensity::Auto => 0.0,
+            };
+
+            below += histogram[count];
+            let top = top.max(1) as f64;
+            levels[count] = (1.0 + (top - 1.0) * t.clamp(0.0, 1.0)).round() as u8;
+        }
+
+        levels
+    }
+}
+
+/// Number of visits at which pixels become fully opaque when coloring by
+/// property.
+const CATEGORY_SATURATION: u8 = 10;
+
+/// Distinct colors assigned to property values when coloring by property.
+const CATEGORY_PALETTE: [[u8; 3]; 10] = [
+    [0x4e, 0x79, 0xa7],
+    [0xf2, 0x8e, 0x2b],
+    [0xe1, 0x57, 0x59],
+    [0x76, 0xb7, 0xb2],
+    [0x59, 0xa1, 0x4f],
+    [0xed, 0xc9, 0x48],
+    [0xb0, 0x7a, 0xa1],
+    [0xff, 0x9d, 0xa7],
+    [0x9c, 0x75, 0x5f],
+    [0xba, 0xb0, 0xac],
+];
+
+/// Color used for activities without a value for the property.
+const UNCATEGORIZED_COLOR: Rgba<u8> = Rgba([0x88, 0x88, 0x88, 0xff]);
+
+/// Color activities by a property of each activity, rather than by how often
+/// a pixel is visited.
+///
+/// Parsed from `year` (the year the activity started), `{key}` for an
+/// arbitrary property, or `{key}:{step}` to group numeric properties into
+/// buckets of size `step`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ColorBy {
+    Year,
+    Property { key: String, bucket: Option<f64> },
+}
+
+impl ColorBy {
+    /// SQL expression selecting the value to color by, and its parameters.
+    fn as_sql(&self) -> (&'static str, Vec<&dyn ToSql>) {
+        match self {
+            ColorBy::Year => ("substr(start_time, 1, 4)", vec![]),
+            ColorBy::Property { key, .. } => ("properties ->> ?", vec![key as &dyn ToSql]),
+        }
+    }
+
+    /// Name of the category a queried value falls into, if any.
+    fn category(&self, value: Value) -> Option<String> {
+        let bucket = match self {
+            ColorBy::Property {
+                bucket: Some(step), ..
+            } => Some(*step),
+            _ => None,
+        };
+
+        let number = match value {
+            Value::Null | Value::Blob(_) => return None,
+            Value::Integer(v) => v as f64,
+            Value::Real(v) => v,
+            Value::Text(text) => match text.parse::<f64>() {
+                Ok(v) if bucket.is_some() => v,
+                _ => return Some(text),
+            },
+        };
+
+        Some(match bucket {
+            Some(step) => format!("{}", (number / step).floor() * step),
+            None => format!("{}", number),
         })
     }
+
+    /// Colors for the categories of all activities matching `filter`, rather
+    /// than only those within a tile, so that they match across tiles.
+    fn colors(
+        &self,
+        conn: &rusqlite::Connection,
+        filter: &ActivityFilter,
+    ) -> Result<CategoryColors> {
+        let (category, mut params) = self.as_sql();
+        let filter_clause = filter.to_query(&mut params);
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT DISTINCT {} FROM activities WHERE {}",
+            category, filter_clause
+        ))?;
+        let categories = stmt
+            .query_map(
+                params.as_slice(),
+                |row| Ok(self.category(row.get_unwrap(0))),
+            )?
+            .filter_map(|category| category.transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(CategoryColors::new(categories))
+    }
+}
+
+/// Palette colors assigned to categories in sorted order (numerically, where
+/// possible), so that colors only repeat once the palette runs out.
+struct CategoryColors(HashMap<String, Rgba<u8>>);
+
+impl CategoryColors {
+    fn new(mut categories: Vec<String>) -> Self {
+        categories.sort_by(|a, b| match (a.parse::<f64>(), b.parse::<f64>()) {
+            (Ok(a), Ok(b)) => a.total_cmp(&b),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => a.cmp(b),
+        });
+        categories.dedup();
+
+        let colors = categories
+            .into_iter()
+            .zip(CATEGORY_PALETTE.iter().cycle())
+            .map(|(category, &[r, g, b])| (category, Rgba([r, g, b, 0xff])))
+            .collect();
+
+        CategoryColors(colors)
+    }
+
+    fn get(&self, category: Option<&str>) -> Rgba<u8> {
+        category
+            .and_then(|category| self.0.get(category))
+            .copied()
+            .unwrap_or(UNCATEGORIZED_COLOR)
+    }
+}
+
+impl FromStr for ColorBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "year" {
+            return Ok(ColorBy::Year);
+        }
+
+        let (key, bucket) = match s.rsplit_once(':') {
+            Some((key, step)) => {
+                let step: f64 = step
+                    .parse()
+                    .map_err(|_| anyhow!("invalid bucket size: {}", step))?;
+                if step.is_nan() || step <= 0.0 {
+                    return Err(anyhow!("bucket size must be positive"));
+                }
+                (key, Some(step))
+            }
+            None => (s, None),
+        };
+
+        if key.is_empty() {
+            return Err(anyhow!("missing property name"));
+        }
+
+        Ok(ColorBy::Property {
+            key: key.to_string(),
+            bucket,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ColorBy {
+    fn deserialize<D>(deserializer: D) -> Result<ColorBy, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        ColorBy::from_str(&s).map_err(serde::de::Error::custom)
+    }
 }
 
 /// Linearly interpolate between two colors
@@ -167,6 +992,15 @@ impl LinearGradient {
     pub fn sample(&self, val: u8) -> Rgba<u8> {
         self.0[val as usize]
     }
+
+    /// Lowest value from which the gradient no longer changes color.
+    fn saturation_point(&self) -> u8 {
+        let last = self.0[255];
+        self.0
+            .iter()
+            .rposition(|color| *color != last)
+            .map_or(0, |idx| idx as u8 + 1)
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -178,18 +1012,6 @@ impl Display for LinearGradientParseError {
 }
 impl Error for LinearGradientParseError {}
 
-/*
-TODO: support varying stops per-zoom level. Possible format:
-
-   {
-       "palette": ["789", "334455", "ffffff33"],
-       "stops": [
-           [0,  [75, 175, 250]],
-           [10, [25, 50, 75]],
-           [15, [5, 10, 15]]
-       ]
-   }
-*/
 impl FromStr for LinearGradient {
     type Err = LinearGradientParseError;
 
@@ -207,21 +1029,9 @@ impl FromStr for LinearGradient {
                 let threshold = threshold
                     .parse::<u8>()
                     .map_err(|_| LinearGradientParseError)?;
-                let color = {
-                    let rgba = match color.len() {
-                        3 => {
-                            let rgb: String = color.chars().flat_map(|ch| [ch, ch]).collect();
-                            format!("{}FF", rgb)
-                        }
-                        6 => format!("{color}FF"),
-                        8 => color.to_string(),
-                        _ => return Err(LinearGradientParseError),
-                    };
-
-                    u32::from_str_radix(&rgba, 16).map_err(|_| LinearGradientParseError)?
-                };
+                let color = parse_color(color).ok_or(LinearGradientParseError)?;
 
-                Ok((threshold, Rgba::from(color.to_be_bytes())))
+                Ok((threshold, color))
             })
             .collect::<Result<Vec<_>, _>>()?;
 
@@ -229,6 +1039,22 @@ impl FromStr for LinearGradient {
     }
 }
 
+/// Parse a hex color written as `RGB`, `RRGGBB`, or `RRGGBBAA`.
+pub fn parse_color(color: &str) -> Option<Rgba<u8>> {
+    let rgba = match color.len() {
+        3 => {
+            let rgb: String = color.chars().flat_map(|ch| [ch, ch]).collect();
+            format!("{}FF", rgb)
+        }
+        6 => format!("{color}FF"),
+        8 => color.to_string(),
+        _ => return None,
+    };
+
+    let color = u32::from_str_radix(&rgba, 16).ok()?;
+    Some(Rgba::from(color.to_be_bytes()))
+}
+
 impl<'de> Deserialize<'de> for LinearGradient {
     fn deserialize<D>(deserializer: D) -> Result<LinearGradient, D::Error>
     where
@@ -239,21 +1065,144 @@ impl<'de> Deserialize<'de> for LinearGradient {
     }
 }
 
+/// Color gradient for heatmap tiles, either shared by all zoom levels or with
+/// different thresholds per zoom level.
+///
+/// Lines overlap more at low zoom levels, so they usually need higher
+/// thresholds to avoid saturating.
+#[derive(Clone, Debug)]
+pub struct Gradient {
+    /// Gradients ordered by the lowest zoom level they apply to.
+    levels: Vec<(u8, LinearGradient)>,
+}
+
+impl Gradient {
+    /// Gradient to use for tiles at zoom level `z`.
+    pub fn for_zoom(&self, z: u8) -> &LinearGradient {
+        self.levels
+            .iter()
+            .rev()
+            .find(|(min_zoom, _)| *min_zoom <= z)
+            .or(self.levels.first())
+            .map(|(_, gradient)| gradient)
+            .expect("at least one zoom level")
+    }
+}
+
+impl From<LinearGradient> for Gradient {
+    fn from(gradient: LinearGradient) -> Self {
+        Gradient {
+            levels: vec![(0, gradient)],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PerZoomSpec {
+    palette: Vec<String>,
+    stops: Vec<(u8, Vec<u8>)>,
+}
+
+impl FromStr for Gradient {
+    type Err = LinearGradientParseError;
+
+    /// Parse either a linear gradient (see [`LinearGradient::from_str`]), or
+    /// a JSON object giving a palette of colors, and the thresholds to use for
+    /// each color starting at a given zoom level.
+    ///
+    /// For example:
+    ///
+    /// ```json
+    /// {
+    ///     "palette": ["789", "334455", "ffffff33"],
+    ///     "stops": [
+    ///         [0,  [75, 175, 250]],
+    ///         [10, [25, 50, 75]],
+    ///         [15, [5, 10, 15]]
+    ///     ]
+    /// }
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.trim_start().starts_with('{') {
+            return LinearGradient::from_str(s).map(Gradient::from);
+        }
+
+        let spec: PerZoomSpec = serde_json::from_str(s).map_err(|_| LinearGradientParseError)?;
+        let palette = spec
+            .palette
+            .iter()
+            .map(|color| parse_color(color))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(LinearGradientParseError)?;
+
+        if spec.stops.is_empty() {
+            return Err(LinearGradientParseError);
+        }
+
+        let mut levels = spec
+            .stops
+            .into_iter()
+            .map(|(zoom, thresholds)| {
+                let is_ascending = thresholds.windows(2).all(|pair| pair[0] < pair[1]);
+                if thresholds.len() != palette.len() || !is_ascending {
+                    return Err(LinearGradientParseError);
+                }
+
+                let stops: Vec<_> = thresholds.into_iter().zip(palette.clone()).collect();
+                Ok((zoom, LinearGradient::from_stops(&stops)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        levels.sort_by_key(|(zoom, _)| *zoom);
+        Ok(Gradient { levels })
+    }
+}
+
+impl<'de> Deserialize<'de> for Gradient {
+    fn deserialize<D>(deserializer: D) -> Result<Gradient, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Gradient::from_str(&s).map_err(|_| serde::de::Error::custom("invalid gradient"))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn render_view(
     viewport: WebMercatorViewport,
-    gradient: &LinearGradient,
+    gradient: &Gradient,
+    intensity: Intensity,
+    stroke: Stroke,
+    color_by: Option<&ColorBy>,
     width: u32,
     height: u32,
     filter: &ActivityFilter,
     db: &Database,
 ) -> Result<RgbaImage> {
-    let tile_size = 256;
-    let zoom_range = RangeInclusive::new(
-        *db.config.zoom_levels.iter().min().unwrap() as u32,
-        *db.config.zoom_levels.iter().max().unwrap() as u32,
-    );
+    render_mosaic(&viewport, width, height, db.config.zoom_range(), |tile| {
+        render_tile(tile, gradient, intensity, stroke, color_by, 256, filter, db)
+    })
+}
 
-    let tile_bounds = TileBounds::from_viewport(&viewport, width, height, zoom_range);
+/// Assemble an image of `viewport` from 256px tiles returned by `get_tile`,
+/// picking the zoom level from `zoom_range` which best matches the requested
+/// dimensions.
+///
+/// Any two mosaics of the same viewport, size and zoom range line up pixel
+/// for pixel, so they can be composited.
+pub fn render_mosaic<F>(
+    viewport: &WebMercatorViewport,
+    width: u32,
+    height: u32,
+    zoom_range: RangeInclusive<u32>,
+    mut get_tile: F,
+) -> Result<RgbaImage>
+where
+    F: FnMut(Tile) -> Result<Option<RgbaImage>>,
+{
+    let tile_size = 256;
+    let tile_bounds = TileBounds::from_viewport(viewport, width, height, zoom_range);
 
     let num_x = tile_bounds.xmax - tile_bounds.xmin + 1;
     let num_y = tile_bounds.ymax - tile_bounds.ymin + 1;
@@ -293,7 +1242,7 @@ pub fn render_view(
                 tile_bounds.z,
             );
 
-            let sub_img = render_tile(tile, gradient, tile_size, filter, db)?;
+            let sub_img = get_tile(tile)?;
             if let Some(img) = sub_img {
                 for (x, y, pixel) in img.enumerate_pixels() {
                     let x = tile_origin_x + x;
@@ -315,60 +1264,323 @@ pub fn render_view(
     Ok(mosaic)
 }
 
+/// Render a single activity's tracks in a solid color, scaled to fit a
+/// transparent `width` x `height` image.
+pub fn render_activity(
+    tracks: &MultiLineString,
+    color: Rgba<u8>,
+    width: u32,
+    height: u32,
+) -> RgbaImage {
+    const PADDING: f64 = 0.05;
+    const LINE_WIDTH: i32 = 2;
+
+    let lines: Vec<Vec<WebMercator>> = tracks
+        .iter()
+        .map(|line| {
+            line.points()
+                .filter_map(|pt| LngLat::from(pt).xy())
+                .collect()
+        })
+        .collect();
+
+    let mut image = RgbaImage::new(width, height);
+    let Some(bbox) = lines.iter().flatten().fold(None, |bbox: Option<BBox>, pt| {
+        let (x, y) = (pt.0.x(), pt.0.y());
+        Some(match bbox {
+            None => BBox {
+                left: x,
+                right: x,
+                bot: y,
+                top: y,
+            },
+            Some(b) => BBox {
+                left: b.left.min(x),
+                right: b.right.max(x),
+                bot: b.bot.min(y),
+                top: b.top.max(y),
+            },
+        })
+    }) else {
+        return image;
+    };
+
+    // Scale uniformly to fit the padded image, and center the track.
+    let (w, h) = (width as f64, height as f64);
+    let (bbox_w, bbox_h) = (bbox.right - bbox.left, bbox.top - bbox.bot);
+    let scale = f64::min(
+        w * (1.0 - 2.0 * PADDING) / bbox_w.max(1.0),
+        h * (1.0 - 2.0 * PADDING) / bbox_h.max(1.0),
+    );
+    let offset_x = (w - bbox_w * scale) / 2.0;
+    let offset_y = (h - bbox_h * scale) / 2.0;
+
+    let to_pixel = |pt: &WebMercator| {
+        let x = offset_x + (pt.0.x() - bbox.left) * scale;
+        let y = offset_y + (bbox.top - pt.0.y()) * scale;
+        (x as i32, y as i32)
+    };
+
+    for line in &lines {
+        for pair in line.windows(2) {
+            let line_iter = line_drawing::Bresenham::new(to_pixel(&pair[0]), to_pixel(&pair[1]));
+
+            for (ix, iy) in line_iter {
+                for (dx, dy) in
+                    (0..LINE_WIDTH).flat_map(|dx| (0..LINE_WIDTH).map(move |dy| (dx, dy)))
+                {
+                    let (x, y) = (ix + dx - LINE_WIDTH / 2, iy + dy - LINE_WIDTH / 2);
+                    if x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height {
+                        image.put_pixel(x as u32, y as u32, color);
+                    }
+                }
+            }
+        }
+    }
+
+    image
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn render_tile(
     tile: Tile,
-    gradient: &LinearGradient,
+    gradient: &Gradient,
+    intensity: Intensity,
+    stroke: Stroke,
+    color_by: Option<&ColorBy>,
     width: u32,
     filter: &ActivityFilter,
     db: &Database,
 ) -> Result<Option<RgbaImage>> {
+    if tile.z > MAX_ZOOM {
+        return Err(anyhow!("zoom level must be <= {}: {:?}", MAX_ZOOM, tile));
+    }
+
+    // Counts for unfiltered low zoom tiles are precomputed, see `aggregate`.
+    let aggregate = if aggregate::covers(&tile, stroke, color_by, width, filter) {
+        let conn = db.connection()?;
+        aggregate::load(&conn, &tile, width)?
+    } else {
+        None
+    };
+
+    let raster = match aggregate {
+        Some(counts) if counts.is_empty() => None,
+        Some(counts) => Some(TileRaster::from_counts(tile, width, counts, &db.config)),
+        None => rasterize(tile, stroke, color_by, width, filter, db)?,
+    };
+    let Some(mut raster) = raster else {
+        return Ok(None);
+    };
+
+    raster.apply_masks(&tile, &db.config.masks);
+
+    Ok(Some(match color_by {
+        Some(_) => raster.apply_colors(intensity),
+        None => raster.apply_gradient(gradient.for_zoom(tile.z), intensity),
+    }))
+}
+
+/// Visit counts of every pixel in `tile`, drawn with the default stroke, or
+/// `None` if no activities pass through it.
+pub fn count_tile(tile: Tile, width: u32, db: &Database) -> Result<Option<Vec<u16>>> {
+    let raster = rasterize(
+        tile,
+        Stroke::default(),
+        None,
+        width,
+        &ActivityFilter::default(),
+        db,
+    )?;
+
+    Ok(raster.map(|raster| raster.pixels))
+}
+
+/// Draw the activities passing through `tile`, returning `None` if there
+/// aren't any.
+fn rasterize(
+    tile: Tile,
+    stroke: Stroke,
+    color_by: Option<&ColorBy>,
+    width: u32,
+    filter: &ActivityFilter,
+    db: &Database,
+) -> Result<Option<TileRaster>> {
+    // Past the highest stored zoom level, scale up data from that level.
     let zoom_level = db
         .config
         .source_level(tile.z)
-        .ok_or_else(|| anyhow!("no source level for tile: {:?}", tile))?;
+        .unwrap_or(*db.config.zoom_range().end() as u8);
 
-    let bounds = TileBounds::from(zoom_level, &tile);
-    let mut raster = TileRaster::new(tile, bounds, width, db.config.tile_extent);
+    let source = TileBounds::from(zoom_level, &tile);
+    let new_raster = || TileRaster::new(tile, source, width, db.config.tile_extent, stroke);
 
-    let mut have_activity = false;
+    // Wide or blurred lines from neighboring tiles can reach into this one.
+    let bounds = source.expand(new_raster().margin_tiles());
 
-    let conn = db.connection()?;
-    let (mut stmt, params) = prepare_activities_query(&conn, filter, &bounds)?;
-    let mut rows = stmt.query(params.as_slice())?;
-    while let Some(row) = rows.next()? {
-        let source_tile = Tile::new(row.get_unwrap(0), row.get_unwrap(1), row.get_unwrap(2));
+    let now = OffsetDateTime::now_utc();
+    let lines = {
+        let conn = db.connection()?;
+        let colors = match color_by {
+            Some(color_by) => Some(color_by.colors(&conn, filter)?),
+            None => None,
+        };
 
-        let bytes: Vec<u8> = row.get_unwrap(3);
-        raster.add_activity(&source_tile, &decode_line(&bytes)?);
+        let (mut stmt, params) =
+            prepare_activities_query(&conn, filter, &bounds, color_by, stroke.count, &db.config)?;
+        let rows = stmt.query_map(params.as_slice(), |row| {
+            let color = color_by.zip(colors.as_ref()).map(|(color_by, colors)| {
+                let category = color_by.category(row.get_unwrap(4));
+                colors.get(category.as_deref())
+            });
+
+            Ok(SourceLine {
+                tile: Tile::new(row.get_unwrap(0), row.get_unwrap(1), row.get_unwrap(2)),
+                coords: row.get_unwrap(3),
+                color,
+                activity_id: row.get_unwrap(5),
+                weight: stroke.weight(row.get(6).ok().flatten(), now),
+            })
+        })?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
 
-        have_activity = true;
+    if lines.is_empty() {
+        return Ok(None);
     }
 
-    if !have_activity {
+    let mut raster = if lines.len() < PARALLEL_MIN_LINES {
+        let mut raster = new_raster();
+        raster.draw(&lines)?;
+        raster
+    } else {
+        // Draw separate chunks of lines on each thread, and add them up.
+        partition_lines(&lines, rayon::current_num_threads())
+            .into_par_iter()
+            .map(|chunk| {
+                let mut raster = new_raster();
+                raster.draw(chunk)?;
+                raster.end_activity();
+                Ok::<_, anyhow::Error>(raster)
+            })
+            .try_reduce_with(|mut a, b| {
+                a.merge(b);
+                Ok(a)
+            })
+            .expect("at least one chunk of lines")?
+    };
+
+    raster.finish();
+
+    // Activities in the margins may not have reached into the tile.
+    if raster.pixels.iter().all(|&px| px == 0) {
         return Ok(None);
     }
 
-    Ok(Some(raster.apply_gradient(gradient)))
+    Ok(Some(raster))
+}
+
+/// Render each layer of `tile` with its own filter and gradient, drawing
+/// later layers over earlier ones.
+pub fn render_tile_layers(
+    tile: Tile,
+    layers: &[(ActivityFilter, Gradient)],
+    intensity: Intensity,
+    stroke: Stroke,
+    color_by: Option<&ColorBy>,
+    width: u32,
+    db: &Database,
+) -> Result<Option<RgbaImage>> {
+    let mut composite: Option<RgbaImage> = None;
+
+    for (filter, gradient) in layers {
+        let layer = render_tile(
+            tile, gradient, intensity, stroke, color_by, width, filter, db,
+        )?;
+        let Some(layer) = layer else {
+            continue;
+        };
+
+        match composite {
+            Some(ref mut image) => imageops::overlay(image, &layer, 0, 0),
+            None => composite = Some(layer),
+        }
+    }
+
+    Ok(composite)
+}
+
+/// Below this many lines, rendering a tile on a single thread is faster than
+/// splitting it up.
+const PARALLEL_MIN_LINES: usize = 256;
+
+/// Activity line within a single stored tile, waiting to be drawn.
+struct SourceLine {
+    tile: Tile,
+    /// Encoded coordinates, see `decode_line`.
+    coords: Vec<u8>,
+    color: Option<Rgba<u8>>,
+    activity_id: i64,
+    weight: f32,
+}
+
+/// Split `lines` into about `n` chunks to draw in parallel, without
+/// splitting up the lines of an activity.
+fn partition_lines(lines: &[SourceLine], n: usize) -> Vec<&[SourceLine]> {
+    let size = lines.len().div_ceil(n.max(1));
+
+    let mut chunks = vec![];
+    let mut rest = lines;
+    while !rest.is_empty() {
+        let mut end = size.min(rest.len());
+        while end < rest.len() && rest[end].activity_id == rest[end - 1].activity_id {
+            end += 1;
+        }
+
+        let (chunk, remaining) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = remaining;
+    }
+
+    chunks
 }
 
 fn prepare_activities_query<'a>(
     conn: &'a rusqlite::Connection,
     filter: &'a ActivityFilter,
     bounds: &'a TileBounds,
+    color_by: Option<&'a ColorBy>,
+    count: Count,
+    config: &Config,
 ) -> Result<(rusqlite::Statement<'a>, Vec<&'a dyn ToSql>)> {
-    let mut params = params![bounds.z, bounds.xmin, bounds.xmax, bounds.ymin, bounds.ymax].to_vec();
+    let (category, mut params) = match color_by {
+        Some(color_by) => color_by.as_sql(),
+        None => ("NULL", vec![]),
+    };
+    params.extend(params![
+        bounds.z,
+        bounds.xmin,
+        bounds.xmax,
+        bounds.ymin,
+        bounds.ymax
+    ]);
     let filter_clause = filter.to_query(&mut params);
+    let order = match count {
+        Count::Passes => "",
+        Count::Activities => "ORDER BY activity_id",
+    };
 
     let stmt = conn.prepare(&format!(
         "\
-        SELECT x, y, z, coords \
+        SELECT x, y, z, coords, {}, activity_id, start_time \
         FROM activity_tiles \
         JOIN activities ON activities.id = activity_tiles.activity_id \
-        WHERE z = ? \
-            AND (x >= ? AND x < ?) \
-            AND (y >= ? AND y < ?) \
-            AND {};",
+        WHERE {} AND {} \
+        {};",
+        category,
+        spatial_index::range_clause(config),
         filter_clause,
+        order,
     ))?;
 
     Ok((stmt, params))
@@ -377,6 +1589,7 @@ fn prepare_activities_query<'a>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use geo_types::line_string;
 
     #[test]
     fn test_linear_gradient_parse() {
@@ -390,4 +1603,354 @@ mod tests {
         // Last value should be copied to end
         assert_eq!(gradient.0[255], Rgba::from([0xff, 0xff, 0xff, 0x33]));
     }
+
+    #[test]
+    fn test_gradient_per_zoom_parse() {
+        let gradient: Gradient = r#"{
+            "palette": ["000", "fff"],
+            "stops": [[10, [5, 10]], [0, [50, 100]]]
+        }"#
+        .parse()
+        .unwrap();
+
+        let white = Rgba::from([0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(gradient.for_zoom(2).sample(100), white);
+        assert_ne!(gradient.for_zoom(2).sample(10), white);
+        assert_eq!(gradient.for_zoom(10).sample(10), white);
+        assert_eq!(gradient.for_zoom(16).sample(10), white);
+
+        // Thresholds must line up with the palette.
+        assert!(r#"{"palette": ["000"], "stops": [[0, [1, 2]]]}"#.parse::<Gradient>().is_err());
+        assert!("1:000;10:fff".parse::<Gradient>().is_ok());
+    }
+
+    #[test]
+    fn test_named_gradients() {
+        let stored = BTreeMap::from([
+            ("red".to_string(), "1:fff".to_string()),
+            ("sunset".to_string(), "1:ff0000;10:ffaa00".to_string()),
+        ]);
+
+        let gradients = named_gradients(&stored).unwrap();
+        assert!(gradients.contains_key("orange"));
+        assert!(gradients.contains_key("sunset"));
+
+        // Stored gradients replace the built in ones of the same name.
+        let white = Rgba::from([0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(gradients["red"].for_zoom(0).sample(1), white);
+
+        let invalid = BTreeMap::from([("bad".to_string(), "nope".to_string())]);
+        assert!(named_gradients(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_intensity_levels() {
+        assert_eq!(PINKISH.for_zoom(0).saturation_point(), 50);
+
+        let pixels = [0, 1, 1, 2, 4];
+        assert_eq!(Intensity::Linear.levels(&pixels, 50)[4], 4);
+
+        // Highest count in the tile reaches the top of the gradient.
+        let auto = Intensity::Auto.levels(&pixels, 50);
+        assert_eq!((auto[0], auto[1], auto[4]), (0, 1, 50));
+
+        // Half of the non-zero pixels have a count below 2
+        let percentile = Intensity::Percentile.levels(&pixels, 50);
+        assert_eq!((percentile[1], percentile[2]), (1, 26));
+
+        let log = Intensity::Log.levels(&pixels, 50);
+        assert_eq!(log[1], 1);
+        assert!(log[2] > 2 && log[2] < log[4] && log[255] < 50);
+
+        // Counts past 255 are no longer all the same.
+        let busy = [0, 1, 300, 1000, 2000];
+        assert_eq!(Intensity::Linear.levels(&busy, 50)[1000], 255);
+        let auto = Intensity::Auto.levels(&busy, 50);
+        assert!(auto[300] < auto[1000] && auto[2000] == 50);
+        let busy_log = Intensity::Log.levels(&busy, 50);
+        assert!(busy_log[255] < busy_log[300] && busy_log[1000] == 50);
+        assert_eq!(busy_log[2000], 50);
+
+        // Unlike `auto`, the same for every tile.
+        assert_eq!(busy_log[..=255], log[..=255]);
+        assert_eq!(
+            Intensity::Sqrt.levels(&busy, 50)[..=255],
+            Intensity::Sqrt.levels(&pixels, 50)[..]
+        );
+    }
+
+    #[test]
+    fn test_tile_raster_upscale() {
+        let tile = Tile::new(1, 1, 2);
+        let mut raster = TileRaster::new(
+            tile,
+            TileBounds::from(2, &tile),
+            1024,
+            256,
+            Stroke::default(),
+        );
+        raster.add_activity(
+            &tile,
+            &[Coord { x: 0, y: 128 }, Coord { x: 256, y: 128 }],
+            None,
+        );
+
+        // Horizontal line across the middle, scaled up 4x.
+        assert_eq!(raster.pixels[512 * 1024 + 500], 1);
+        assert_eq!(raster.pixels[128 * 1024 + 500], 0);
+    }
+
+    #[test]
+    fn test_tile_raster_overzoom() {
+        let source = Tile::new(1, 1, 2);
+        // Top left quarter of the source tile
+        let tile = Tile::new(2, 2, 3);
+        let bounds = TileBounds::from(2, &tile);
+        assert_eq!((bounds.xmin, bounds.xmax, bounds.ymin), (1, 2, 1));
+
+        let mut raster = TileRaster::new(tile, bounds, 256, 256, Stroke::default());
+        raster.add_activity(
+            &source,
+            &[Coord { x: 0, y: 192 }, Coord { x: 256, y: 192 }],
+            None,
+        );
+
+        assert_eq!(raster.pixels[128 * 256 + 100], 1);
+        assert_eq!(raster.pixels[64 * 256 + 100], 0);
+    }
+
+    #[test]
+    fn test_tile_raster_stroke() {
+        let tile = Tile::new(1, 1, 2);
+        let bounds = TileBounds::from(2, &tile);
+        let line = [Coord { x: 0, y: 128 }, Coord { x: 100, y: 128 }];
+
+        let mut raster =
+            TileRaster::new(tile, bounds, 256, 256, Stroke::new(3, 0.0, false).unwrap());
+        assert_eq!((raster.margin, raster.margin_tiles()), (1, 1));
+
+        // Overlapping segments of a single activity are only counted once.
+        raster.add_activity(&tile, &[line[0], line[1], line[0]], None);
+        raster.finish();
+        assert_eq!(raster.pixels.len(), 256 * 256);
+        assert_eq!(raster.pixels[127 * 256 + 50], 1);
+        assert_eq!(raster.pixels[129 * 256 + 50], 1);
+        assert_eq!(raster.pixels[130 * 256 + 50], 0);
+
+        let mut raster =
+            TileRaster::new(tile, bounds, 256, 256, Stroke::new(1, 2.0, false).unwrap());
+        for _ in 0..4 {
+            raster.add_activity(&tile, &line, None);
+        }
+        raster.finish();
+
+        // Blurring keeps the center of the line, and fades out to the sides.
+        let column: Vec<_> = (123..=133).map(|y| raster.pixels[y * 256 + 50]).collect();
+        assert_eq!(column[5], 4);
+        assert!(column[3] > 0 && column[3] < 4);
+        assert_eq!(column[0], 0);
+
+        assert!(Stroke::new(0, 0.0, false).is_err());
+        assert!(Stroke::new(1, -1.0, false).is_err());
+    }
+
+    #[test]
+    fn test_tile_raster_count_activities() {
+        let tile = Tile::new(1, 1, 2);
+        let bounds = TileBounds::from(2, &tile);
+        // Doubling back over the same road, e.g. from GPS jitter.
+        let line = [
+            Coord { x: 0, y: 128 },
+            Coord { x: 100, y: 128 },
+            Coord { x: 0, y: 128 },
+        ];
+
+        let mut raster = TileRaster::new(tile, bounds, 256, 256, Stroke::default());
+        raster.add_activity(&tile, &line, None);
+        raster.finish();
+        assert_eq!(raster.pixels[128 * 256 + 50], 2);
+
+        let stroke = Stroke::default().counting(Count::Activities);
+        let mut raster = TileRaster::new(tile, bounds, 256, 256, stroke);
+        // The same activity across several calls only counts once, until
+        // the next one starts.
+        raster.add_activity(&tile, &line, None);
+        raster.add_activity(&tile, &line, None);
+        raster.end_activity();
+        raster.add_activity(&tile, &line[..2], None);
+        raster.finish();
+        assert_eq!(raster.pixels[128 * 256 + 50], 2);
+        assert_eq!(raster.pixels[129 * 256 + 50], 0);
+    }
+
+    #[test]
+    fn test_tile_raster_half_life() {
+        let stroke = Stroke::default().decaying(Some(10.0)).unwrap();
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let days_ago = |days: i64| Some(now - time::Duration::days(days));
+
+        assert_eq!(stroke.weight(days_ago(0), now), 1.0);
+        assert!((stroke.weight(days_ago(20), now) - 0.25).abs() < 1e-6);
+        assert_eq!(stroke.weight(days_ago(-5), now), 1.0);
+        assert_eq!(stroke.weight(None, now), 1.0);
+        assert_eq!(Stroke::default().weight(days_ago(20), now), 1.0);
+
+        let tile = Tile::new(1, 1, 2);
+        let bounds = TileBounds::from(2, &tile);
+        let line = [Coord { x: 0, y: 128 }, Coord { x: 100, y: 128 }];
+
+        let mut raster = TileRaster::new(tile, bounds, 256, 256, stroke);
+        for days in [0, 20] {
+            raster.weight = stroke.weight(days_ago(days), now);
+            raster.add_activity(&tile, &line, None);
+        }
+        raster.finish();
+        assert!((raster.coverage[128 * 256 + 50] - 1.25).abs() < 1e-6);
+        assert_eq!(raster.pixels[128 * 256 + 50], 2);
+
+        assert!(Stroke::default().decaying(Some(0.0)).is_err());
+        assert!(Stroke::default().decaying(Some(f32::NAN)).is_err());
+    }
+
+    #[test]
+    fn test_tile_raster_parallel() {
+        let tile = Tile::new(1, 1, 2);
+        let bounds = TileBounds::from(2, &tile);
+        let stroke = Stroke::new(3, 0.0, false)
+            .unwrap()
+            .counting(Count::Activities);
+
+        // Each activity is spread over two rows.
+        let line = |activity_id, y| SourceLine {
+            tile,
+            coords: crate::db::encode_line(&line_string![(x: 0u32, y: y), (x: 100, y: y)]).unwrap(),
+            color: None,
+            activity_id,
+            weight: 1.0,
+        };
+        let lines: Vec<_> = (0..10)
+            .flat_map(|id| [line(id, 128), line(id, 130)])
+            .collect();
+
+        let chunks = partition_lines(&lines, 3);
+        assert_eq!(
+            chunks.iter().map(|c| c.len()).collect::<Vec<_>>(),
+            vec![8, 8, 4]
+        );
+
+        let mut serial = TileRaster::new(tile, bounds, 256, 256, stroke);
+        serial.draw(&lines).unwrap();
+        serial.finish();
+
+        let mut merged = TileRaster::new(tile, bounds, 256, 256, stroke);
+        for chunk in chunks {
+            let mut raster = TileRaster::new(tile, bounds, 256, 256, stroke);
+            raster.draw(chunk).unwrap();
+            raster.end_activity();
+            merged.merge(raster);
+        }
+        merged.finish();
+
+        assert_eq!(serial.pixels, merged.pixels);
+        assert_eq!(merged.pixels[127 * 256 + 50], 10);
+    }
+
+    #[test]
+    fn test_tile_raster_antialias() {
+        let tile = Tile::new(1, 1, 2);
+        let bounds = TileBounds::from(2, &tile);
+        let stroke = Stroke::new(1, 0.0, true).unwrap();
+
+        // Horizontal line along the boundary between two rows of pixels.
+        let mut raster = TileRaster::new(tile, bounds, 256, 256, stroke);
+        raster.add_activity(
+            &tile,
+            &[Coord { x: 0, y: 128 }, Coord { x: 100, y: 128 }],
+            None,
+        );
+        raster.finish();
+
+        let idx = |x: usize, y: usize| y * 256 + x;
+        assert_eq!(raster.coverage[idx(50, 127)], 0.5);
+        assert_eq!(raster.coverage[idx(50, 128)], 0.5);
+        assert_eq!(raster.pixels[idx(50, 128)], 1);
+
+        let image = raster.apply_gradient(&"1:f00;2:00f".parse().unwrap(), Intensity::Linear);
+        assert_eq!(image.get_pixel(50, 128), &Rgba([0xff, 0x00, 0x00, 0x80]));
+        assert_eq!(image.get_pixel(50, 129), &Rgba([0x00, 0x00, 0x00, 0x00]));
+        assert!(Stroke::new(1, -1.0, false).is_err());
+    }
+
+    #[test]
+    fn test_color_by() {
+        assert_eq!("year".parse::<ColorBy>().unwrap(), ColorBy::Year);
+        assert_eq!(
+            "average_speed:2.5".parse::<ColorBy>().unwrap(),
+            ColorBy::Property {
+                key: "average_speed".into(),
+                bucket: Some(2.5)
+            }
+        );
+        assert!("average_speed:0".parse::<ColorBy>().is_err());
+
+        let speed: ColorBy = "average_speed:2.5".parse().unwrap();
+        assert_eq!(speed.category(Value::Real(6.1)).as_deref(), Some("5"));
+        assert_eq!(
+            speed.category(Value::Text("7.6".into())).as_deref(),
+            Some("7.5")
+        );
+        assert_eq!(speed.category(Value::Null), None);
+
+        let kind: ColorBy = "activity_type".parse().unwrap();
+        assert_eq!(
+            kind.category(Value::Text("ride".into())).as_deref(),
+            Some("ride")
+        );
+
+        // Sorted, with numbers in numeric order, so distinct up to the size
+        // of the palette.
+        let categories = ["run", "10", "ride", "9", "run"];
+        let colors = CategoryColors::new(categories.map(String::from).to_vec());
+        let [r, g, b] = CATEGORY_PALETTE[0];
+        assert_eq!(colors.get(Some("9")), Rgba([r, g, b, 0xff]));
+        let [r, g, b] = CATEGORY_PALETTE[3];
+        assert_eq!(colors.get(Some("run")), Rgba([r, g, b, 0xff]));
+        assert_ne!(colors.get(Some("10")), colors.get(Some("ride")));
+        assert_eq!(colors.get(None), UNCATEGORIZED_COLOR);
+        assert_eq!(colors.get(Some("swim")), UNCATEGORIZED_COLOR);
+    }
+
+    #[test]
+    fn test_tile_raster_colors() {
+        let tile = Tile::new(1, 1, 2);
+        let bounds = TileBounds::from(2, &tile);
+        let line = [Coord { x: 0, y: 128 }, Coord { x: 100, y: 128 }];
+
+        let mut raster = TileRaster::new(tile, bounds, 256, 256, Stroke::default());
+        raster.add_activity(&tile, &line, Some(Rgba([0xff, 0x00, 0x00, 0xff])));
+        raster.add_activity(&tile, &line, Some(Rgba([0x00, 0x00, 0xff, 0xff])));
+        raster.finish();
+
+        // Overlapping activities blend their colors.
+        let image = raster.apply_colors(Intensity::Linear);
+        let [r, g, b, a] = image.get_pixel(50, 128).0;
+        assert_eq!((r, g, b), (0x80, 0x00, 0x80));
+        assert!(a > 96 && a < 255);
+        assert_eq!(image.get_pixel(50, 100).0[3], 0);
+    }
+
+    #[test]
+    fn test_render_activity() {
+        let tracks = MultiLineString::new(vec![line_string![
+            (x: 10.0, y: 50.0),
+            (x: 10.01, y: 50.0),
+        ]]);
+        let red = Rgba::from([0xff, 0, 0, 0xff]);
+        let image = render_activity(&tracks, red, 100, 50);
+
+        // Track should be centered, corners left transparent.
+        assert_eq!(image.get_pixel(50, 25), &red);
+        assert_eq!(image.get_pixel(0, 0), &Rgba::from([0, 0, 0, 0]));
+        assert_eq!(image.get_pixel(99, 49), &Rgba::from([0, 0, 0, 0]));
+    }
 }
@@ -0,0 +1,307 @@
+//! Per client rate limits for the expensive routes, so that a single scraper
+//! can't bring a public server to its knees.
+//!
+//! Each client gets a token bucket per route, which holds up to a limit's
+//! number of requests and refills at the same rate over its period. Clients
+//! are told when to come back with `Retry-After`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, MatchedPath, State};
+use axum::http::{header, HeaderMap, HeaderName, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+/// Clients to keep track of per route. Past that, those which are back to a
+/// full bucket are forgotten first, then the least recently seen.
+const MAX_CLIENTS: usize = 10_000;
+
+/// Once full, clients are forgotten down to this many at once, rather than
+/// one at a time for every new client.
+const EVICT_TO: usize = MAX_CLIENTS * 9 / 10;
+
+/// A number of requests per period, like `600/m`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+impl FromStr for Limit {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const EXPECTED: &str = "expected requests per second, minute or hour, e.g. 600/m";
+
+        let (requests, unit) = s.split_once('/').ok_or(EXPECTED)?;
+        let requests = requests.trim().parse().map_err(|_| EXPECTED)?;
+        let per = match unit.trim() {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            _ => return Err(EXPECTED),
+        };
+
+        if requests == 0 {
+            return Err("rate limit must allow at least one request");
+        }
+
+        Ok(Limit { requests, per })
+    }
+}
+
+/// Limits for each kind of route, unlimited if not set.
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    /// Map tiles, including those of views and users.
+    pub tiles: Option<Limit>,
+    pub render: Option<Limit>,
+    /// `/upload`, `/api/ingest` and `/api/owntracks`.
+    pub upload: Option<Limit>,
+    /// Take the client's address from this header, as set by a reverse
+    /// proxy, rather than limiting the proxy as a whole.
+    pub client_ip_header: Option<HeaderName>,
+}
+
+impl RateLimits {
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_none() && self.render.is_none() && self.upload.is_none()
+    }
+}
+
+/// Buckets for each client of the rate limited routes.
+pub struct Limiters {
+    tiles: Option<RateLimiter>,
+    render: Option<RateLimiter>,
+    upload: Option<RateLimiter>,
+    client_ip_header: Option<HeaderName>,
+}
+
+impl Limiters {
+    pub fn new(limits: &RateLimits) -> Self {
+        Limiters {
+            tiles: limits.tiles.map(RateLimiter::new),
+            render: limits.render.map(RateLimiter::new),
+            upload: limits.upload.map(RateLimiter::new),
+            client_ip_header: limits.client_ip_header.clone(),
+        }
+    }
+
+    fn for_route(&self, path: &str) -> Option<&RateLimiter> {
+        match path {
+            "/render" | "/render/signed" => self.render.as_ref(),
+            "/upload" | "/api/ingest" | "/api/owntracks" => self.upload.as_ref(),
+            path if path.ends_with("/tile/:z/:x/:y") => self.tiles.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+struct RateLimiter {
+    limit: Limit,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    fn new(limit: Limit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a request from `client`'s bucket, or if it's empty, how long
+    /// until it has one again.
+    fn check(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let capacity = self.limit.requests as f64;
+        let per_sec = capacity / self.limit.per.as_secs_f64();
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated);
+            (bucket.tokens + elapsed.as_secs_f64() * per_sec).min(capacity)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&client) {
+            buckets.retain(|_, bucket| refill(bucket) < capacity);
+
+            if buckets.len() > EVICT_TO {
+                let mut by_age: Vec<_> = buckets.iter().map(|(ip, b)| (b.updated, *ip)).collect();
+                by_age.sort_unstable();
+                for (_, ip) in &by_age[..buckets.len() - EVICT_TO] {
+                    buckets.remove(ip);
+                }
+            }
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+}
+
+/// Address a request came from: the one in the proxy `header` if set, or
+/// otherwise the peer's.
+pub fn remote_ip(
+    headers: &HeaderMap,
+    peer: Option<IpAddr>,
+    header: Option<&HeaderName>,
+) -> Option<IpAddr> {
+    // Proxies append to `X-Forwarded-For`, so the last address is the one
+    // added by ours.
+    let forwarded = header.and_then(|header| {
+        headers
+            .get(header)?
+            .to_str()
+            .ok()?
+            .rsplit(',')
+            .next()?
+            .trim()
+            .parse()
+            .ok()
+    });
+
+    forwarded.or(peer).map(|ip| ip.to_canonical())
+}
+
+/// Address to rate limit a request by, see [`remote_ip`]. IPv6 clients
+/// usually have a whole /64 network to themselves, so they're limited as one.
+fn client_ip(
+    headers: &HeaderMap,
+    peer: Option<IpAddr>,
+    header: Option<&HeaderName>,
+) -> Option<IpAddr> {
+    Some(match remote_ip(headers, peer, header)? {
+        IpAddr::V6(ip) => {
+            let network = u128::from(ip) & !(u128::MAX >> 64);
+            IpAddr::V6(Ipv6Addr::from(network))
+        }
+        ip => ip,
+    })
+}
+
+/// Middleware which responds with `429 Too Many Requests` once a client goes
+/// over the limit for a route.
+pub async fn limit_requests<B>(
+    State(limiters): State<Arc<Limiters>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let limiter = req
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|path| limiters.for_route(path.as_str()));
+    let Some(limiter) = limiter else {
+        return next.run(req).await;
+    };
+
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let client = client_ip(req.headers(), peer, limiters.client_ip_header.as_ref());
+    let Some(client) = client else {
+        // Without an address there's nothing to go by, which is only the
+        // case without connection info (i.e. not served over TCP).
+        tracing::warn!("can't rate limit request without client address");
+        return next.run(req).await;
+    };
+
+    match limiter.check(client, Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            tracing::debug!(%client, ?wait, "rate limited");
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                "too many requests",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        assert_eq!(
+            "600/m".parse(),
+            Ok(Limit {
+                requests: 600,
+                per: Duration::from_secs(60)
+            })
+        );
+        assert!("600".parse::<Limit>().is_err());
+        assert!("0/s".parse::<Limit>().is_err());
+        assert!("10/d".parse::<Limit>().is_err());
+
+        let limiter = RateLimiter::new("2/s".parse().unwrap());
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+
+        assert_eq!(limiter.check(a, start), Ok(()));
+        assert_eq!(limiter.check(a, start), Ok(()));
+        assert_eq!(limiter.check(a, start), Err(Duration::from_millis(500)));
+        assert_eq!(limiter.check(b, start), Ok(()));
+
+        // Half a second later, there's room for one more.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check(a, later), Ok(()));
+        assert!(limiter.check(a, later).is_err());
+
+        let mut headers = HeaderMap::new();
+        let forwarded = HeaderName::from_static("x-forwarded-for");
+        headers.insert(&forwarded, "1.2.3.4, 10.0.0.3".parse().unwrap());
+        assert_eq!(
+            client_ip(&headers, Some(a), Some(&forwarded)),
+            Some(IpAddr::from([10, 0, 0, 3]))
+        );
+        assert_eq!(client_ip(&headers, Some(a), None), Some(a));
+        assert_eq!(
+            client_ip(&HeaderMap::new(), Some(a), Some(&forwarded)),
+            Some(a)
+        );
+        assert_eq!(client_ip(&HeaderMap::new(), None, Some(&forwarded)), None);
+        assert_eq!(
+            client_ip(&headers, Some("2001:db8::1234".parse().unwrap()), None),
+            Some("2001:db8::".parse().unwrap())
+        );
+
+        // Once full, the least recently seen clients are forgotten.
+        let limiter = RateLimiter::new("1/h".parse().unwrap());
+        let client = |i: usize| IpAddr::from((i as u32).to_be_bytes());
+        for i in 0..=MAX_CLIENTS {
+            let now = start + Duration::from_millis(i as u64);
+            assert_eq!(limiter.check(client(i), now), Ok(()));
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        let evicted = MAX_CLIENTS - EVICT_TO;
+        assert_eq!(buckets.len(), EVICT_TO + 1);
+        assert!(!buckets.contains_key(&client(0)));
+        assert!(!buckets.contains_key(&client(evicted - 1)));
+        assert!(buckets.contains_key(&client(evicted)));
+        assert!(buckets.contains_key(&client(MAX_CLIENTS)));
+    }
+}
@@ -0,0 +1,232 @@
+//! Import activity files from HTTP(S) URLs, e.g. a self-hosted storage
+//! bucket.
+//!
+//! Activities are stored under their URL. The `ETag` and `Last-Modified`
+//! headers of each response are remembered, so that importing the same URLs
+//! again only downloads files which changed.
+
+use std::io::Cursor;
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{StatusCode, Url};
+use rusqlite::{params, OptionalExtension};
+
+use crate::activity::{self, ImportFailure, ImportSummary, Upserted};
+use crate::db::Database;
+
+/// Number of files to download at once.
+const MAX_CONCURRENT_FETCHES: usize = 8;
+
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+/// Read a list of URLs, one per line. Blank lines and `#` comments are
+/// ignored.
+pub fn parse_url_list(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Cache validators from a previous download.
+#[derive(Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+enum Fetched {
+    NotModified,
+    Body(Vec<u8>, Validators),
+}
+
+enum Outcome {
+    Imported,
+    Skipped,
+    Unsupported,
+}
+
+/// Validators are only useful if we still have the activity.
+fn cached_validators(conn: &rusqlite::Connection, url: &str) -> Result<Validators> {
+    Ok(conn
+        .query_row(
+            "\
+            SELECT etag, last_modified \
+            FROM remote_files \
+            WHERE url = ? AND EXISTS (SELECT 1 FROM activities WHERE file = url)",
+            params![url],
+            |row| {
+                Ok(Validators {
+                    etag: row.get(0)?,
+                    last_modified: row.get(1)?,
+                })
+            },
+        )
+        .optional()?
+        .unwrap_or_default())
+}
+
+async fn fetch(client: &reqwest::Client, db: &Database, url: &str) -> Result<Fetched> {
+    let cached = cached_validators(&*db.connection()?, url)?;
+
+    let mut req = client.get(url);
+    if let Some(etag) = cached.etag {
+        req = req.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = cached.last_modified {
+        req = req.header(IF_MODIFIED_SINCE, last_modified);
+    }
+
+    let res = req.send().await?;
+    if res.status() == StatusCode::NOT_MODIFIED {
+        return Ok(Fetched::NotModified);
+    } else if !res.status().is_success() {
+        return Err(anyhow!("HTTP request failed with status {}", res.status()));
+    }
+
+    let header = |name| {
+        res.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let validators = Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+
+    Ok(Fetched::Body(res.bytes().await?.to_vec(), validators))
+}
+
+fn import_body(
+    db: &Database,
+    url: &str,
+    bytes: Vec<u8>,
+    validators: Validators,
+) -> Result<Outcome> {
+    // Ignore any query string (e.g. signed URLs) when looking at the extension.
+    let path = Url::parse(url)?.path().to_string();
+    let Some((media_type, comp)) = activity::get_file_type(&path) else {
+        return Ok(Outcome::Unsupported);
+    };
+
+    let activities = activity::read(Cursor::new(bytes), media_type, comp)?;
+    let Some(first) = activities.first() else {
+        return Ok(Outcome::Unsupported);
+    };
+
+    let mut conn = db.connection()?;
+    conn.execute(
+        "\
+        INSERT OR REPLACE INTO remote_files (url, etag, last_modified) \
+        VALUES (?, ?, ?)",
+        params![url, validators.etag, validators.last_modified],
+    )?;
+
+    // The server may not support validators, so also compare the contents.
+    let unchanged: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM activities WHERE file = ? AND content_hash = ?)",
+        params![url, first.content_hash],
+        |row| row.get(0),
+    )?;
+    if unchanged {
+        return Ok(Outcome::Skipped);
+    }
+
+    let mut outcome = Outcome::Skipped;
+    for (i, activity) in activities.iter().enumerate() {
+        let name = activity::session_name(url, i);
+        if let Upserted::Inserted(_) = activity::upsert(&mut conn, &name, activity, &db.config)? {
+            outcome = Outcome::Imported;
+        }
+    }
+
+    Ok(outcome)
+}
+
+/// Download and import the given URLs, skipping files which haven't changed
+/// since they were last imported.
+///
+/// With `show_progress`, a progress bar is drawn to the terminal.
+pub async fn import_urls(
+    urls: Vec<String>,
+    db: &Database,
+    show_progress: bool,
+) -> Result<ImportSummary> {
+    let progress = if show_progress {
+        ProgressBar::new(urls.len() as u64)
+    } else {
+        ProgressBar::hidden()
+    }
+    .with_style(
+        ProgressStyle::with_template("{wide_bar} {pos}/{len} files ({eta} remaining)")
+            .expect("valid template"),
+    );
+
+    let client = reqwest::Client::new();
+    let mut fetches = futures::stream::iter(urls)
+        .map(|url| {
+            let client = &client;
+            async move {
+                let result = fetch(client, db, &url).await;
+                (url, result)
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_FETCHES);
+
+    let mut summary = ImportSummary::default();
+    while let Some((url, result)) = fetches.next().await {
+        let outcome = result.and_then(|fetched| match fetched {
+            Fetched::NotModified => Ok(Outcome::Skipped),
+            Fetched::Body(bytes, validators) => import_body(db, &url, bytes, validators),
+        });
+
+        match outcome {
+            Ok(Outcome::Imported) => summary.imported.push(url),
+            Ok(Outcome::Skipped) => summary.skipped.push(url),
+            Ok(Outcome::Unsupported) => summary.unsupported.push(url),
+            Err(err) => {
+                tracing::error!(url, ?err, "failed to import activity");
+                summary.failed.push(ImportFailure {
+                    file: url,
+                    error: format!("{:#}", err),
+                });
+            }
+        }
+
+        progress.inc(1);
+    }
+
+    progress.finish();
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url_list() {
+        let text = "\
+            # Bucket\n\
+            https://example.com/a.gpx\n\
+            \n\
+            \thttps://example.com/b.fit.gz?sig=123  \n";
+
+        assert_eq!(
+            parse_url_list(text),
+            vec![
+                "https://example.com/a.gpx",
+                "https://example.com/b.fit.gz?sig=123"
+            ]
+        );
+        assert!(is_url("https://example.com/a.gpx"));
+        assert!(!is_url("activities/a.gpx"));
+    }
+}
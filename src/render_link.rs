@@ -0,0 +1,159 @@
+//! Signed links to `/render` with fixed parameters, which anyone can open
+//! until they expire, without being able to render anything else.
+//!
+//! The signature is an HMAC of the query string and expiry time, keyed with
+//! a secret which is either given to the server or generated once and kept
+//! in the database, so links survive restarts.
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use rusqlite::{params, OptionalExtension};
+use sha1::Sha1;
+use time::OffsetDateTime;
+
+use crate::db::{Database, RENDER_SECRET_KEY};
+use crate::tokens;
+
+/// Why a signed link was turned down.
+#[derive(Debug, PartialEq)]
+pub enum InvalidLink {
+    Expired,
+    BadSignature,
+}
+
+pub struct RenderLinks {
+    secret: Vec<u8>,
+}
+
+impl RenderLinks {
+    pub fn new(secret: &str) -> Self {
+        RenderLinks {
+            secret: secret.as_bytes().to_vec(),
+        }
+    }
+
+    /// Use the given secret, or else the one saved in the database,
+    /// generating it first if needed.
+    pub fn load(db: &Database, secret: Option<&str>) -> Result<Self> {
+        if let Some(secret) = secret {
+            return Ok(Self::new(secret));
+        }
+
+        let conn = db.connection()?;
+        let saved: Option<String> = conn
+            .query_row(
+                "SELECT value FROM config WHERE key = ?",
+                [RENDER_SECRET_KEY],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let secret = match saved {
+            Some(secret) => secret,
+            None => {
+                let secret = tokens::generate_token()?;
+                conn.execute(
+                    "INSERT INTO config (key, value) VALUES (?, ?)",
+                    params![RENDER_SECRET_KEY, secret],
+                )
+                .context("failed to save render link secret, set HOTPOT_RENDER_SECRET instead")?;
+                secret
+            }
+        };
+
+        Ok(Self::new(&secret))
+    }
+
+    /// Query string of a link to `/render` with `query`, which is valid
+    /// until `expires`.
+    pub fn sign(&self, query: &str, expires: OffsetDateTime) -> String {
+        let message = match query {
+            "" => format!("expires={}", expires.unix_timestamp()),
+            query => format!("{}&expires={}", query, expires.unix_timestamp()),
+        };
+        let signature = self.mac(&message).finalize().into_bytes();
+
+        format!("{}&signature={}", message, encode_hex(&signature))
+    }
+
+    /// Check a signed link's query string, returning the `/render` query it
+    /// was signed for.
+    pub fn verify<'a>(&self, signed: &'a str, now: OffsetDateTime) -> Result<&'a str, InvalidLink> {
+        let (message, signature) = signed
+            .rsplit_once("&signature=")
+            .ok_or(InvalidLink::BadSignature)?;
+        let signature = decode_hex(signature).ok_or(InvalidLink::BadSignature)?;
+        self.mac(message)
+            .verify_slice(&signature)
+            .map_err(|_| InvalidLink::BadSignature)?;
+
+        // Signed along with the query, so it can be trusted from here on.
+        let (query, expires) = match message.rsplit_once("&expires=") {
+            Some(parts) => parts,
+            None => (
+                "",
+                message
+                    .strip_prefix("expires=")
+                    .ok_or(InvalidLink::BadSignature)?,
+            ),
+        };
+        let expires: i64 = expires.parse().map_err(|_| InvalidLink::BadSignature)?;
+        if now.unix_timestamp() >= expires {
+            return Err(InvalidLink::Expired);
+        }
+
+        Ok(query)
+    }
+
+    fn mac(&self, message: &str) -> Hmac<Sha1> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).expect("any key size is valid");
+        mac.update(message.as_bytes());
+        mac
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Duration;
+
+    #[test]
+    fn test_render_links() {
+        let links = RenderLinks::new("secret");
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let query = "bounds=1,2,3,4&width=800&height=600";
+
+        let signed = links.sign(query, now + Duration::days(7));
+        assert!(signed.starts_with("bounds=1,2,3,4&width=800&height=600&expires=1700604800&"));
+        assert_eq!(links.verify(&signed, now), Ok(query));
+        assert_eq!(
+            links.verify(&signed, now + Duration::days(7)),
+            Err(InvalidLink::Expired)
+        );
+
+        // Changing anything, including the expiry, breaks the signature.
+        let tampered = signed.replace("width=800", "width=3000");
+        assert_eq!(links.verify(&tampered, now), Err(InvalidLink::BadSignature));
+        let extended = signed.replace("expires=1700604800", "expires=1800000000");
+        assert_eq!(links.verify(&extended, now), Err(InvalidLink::BadSignature));
+        assert_eq!(links.verify(query, now), Err(InvalidLink::BadSignature));
+        assert_eq!(
+            RenderLinks::new("other").verify(&signed, now),
+            Err(InvalidLink::BadSignature)
+        );
+
+        let empty = links.sign("", now + Duration::hours(1));
+        assert_eq!(links.verify(&empty, now), Ok(""));
+    }
+}
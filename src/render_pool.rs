@@ -0,0 +1,171 @@
+//! Dedicated threads for rendering tiles and images, so that CPU heavy
+//! rasterization doesn't hold up the async runtime serving other requests.
+
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::{oneshot, Semaphore};
+
+/// Renders allowed to wait or run at once, per thread.
+const QUEUE_PER_THREAD: usize = 32;
+
+#[derive(Debug, PartialEq)]
+pub enum PoolError {
+    /// Too many renders are already waiting.
+    Busy,
+    /// The render didn't get its turn in time.
+    TimedOut,
+    /// The render panicked.
+    Failed,
+}
+
+impl Display for PoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PoolError::Busy => f.write_str("render queue is full"),
+            PoolError::TimedOut => f.write_str("timed out waiting to render"),
+            PoolError::Failed => f.write_str("render failed"),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+pub struct RenderPool {
+    pool: rayon::ThreadPool,
+    /// One permit per render which is queued or running.
+    queue: Arc<Semaphore>,
+    /// One permit per render which is running.
+    running: Arc<Semaphore>,
+    /// How long renders wait for their turn before giving up.
+    queue_timeout: Duration,
+}
+
+impl RenderPool {
+    /// Start `threads` render threads, or one per CPU if zero, running up to
+    /// `max_concurrent` renders at once (one per thread if zero).
+    pub fn new(threads: usize, max_concurrent: usize, queue_timeout: Duration) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|idx| format!("render-{}", idx))
+            // Rayon aborts on panics by default, which would take down the
+            // whole server.
+            .panic_handler(|_| tracing::error!("render thread panicked"))
+            .build()?;
+
+        let queue = Arc::new(Semaphore::new(
+            pool.current_num_threads() * QUEUE_PER_THREAD,
+        ));
+        let max_concurrent = match max_concurrent {
+            0 => pool.current_num_threads(),
+            n => n,
+        };
+
+        Ok(RenderPool {
+            pool,
+            queue,
+            running: Arc::new(Semaphore::new(max_concurrent)),
+            queue_timeout,
+        })
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    /// Run `render` on one of the render threads. Parallel iterators used
+    /// within it stay on this pool.
+    ///
+    /// Fails right away when the queue is full, or once the render has
+    /// waited too long for its turn, so that an overloaded server sheds
+    /// requests rather than piling them up.
+    pub async fn run<F, T>(&self, render: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let rx = self.spawn(render).await?;
+        rx.await.map_err(|_| PoolError::Failed)
+    }
+
+    /// Wait for a turn to run `render`, returning a channel which receives
+    /// its result. Renders for requests which are cancelled while waiting
+    /// (e.g. tiles which were panned away from) never start.
+    async fn spawn<F, T>(&self, render: F) -> Result<oneshot::Receiver<T>, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let queued = self
+            .queue
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| PoolError::Busy)?;
+
+        // The semaphore is polled before the timeout, so with no timeout a
+        // render still starts if there's room right away.
+        let running =
+            tokio::time::timeout(self.queue_timeout, self.running.clone().acquire_owned())
+                .await
+                .map_err(|_| PoolError::TimedOut)?
+                .expect("semaphore is never closed");
+
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let result = render();
+            drop((running, queued));
+            // The request may have been cancelled in the meantime.
+            let _ = tx.send(result);
+        });
+
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_render_pool() {
+        let pool = Arc::new(RenderPool::new(1, 0, Duration::from_secs(10)).unwrap());
+        assert_eq!(pool.run(|| 1 + 1).await, Ok(2));
+        assert_eq!(
+            pool.run(|| -> u32 { panic!("oops") }).await,
+            Err(PoolError::Failed)
+        );
+
+        // Block the only thread, and fill up the queue behind it.
+        let (unblock, blocked) = mpsc::channel::<()>();
+        let first = pool.spawn(move || blocked.recv().unwrap()).await.unwrap();
+        let waiting: Vec<_> = (1..QUEUE_PER_THREAD)
+            .map(|_| {
+                let pool = pool.clone();
+                tokio::spawn(async move { pool.run(|| ()).await })
+            })
+            .collect();
+        while pool.queue.available_permits() > 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(pool.run(|| ()).await, Err(PoolError::Busy));
+
+        unblock.send(()).unwrap();
+        first.await.unwrap();
+        for task in waiting {
+            assert_eq!(task.await.unwrap(), Ok(()));
+        }
+
+        // Renders which don't get their turn in time give up.
+        let pool = RenderPool::new(2, 1, Duration::from_millis(10)).unwrap();
+        let (unblock, blocked) = mpsc::channel::<()>();
+        let first = pool.spawn(move || blocked.recv().unwrap()).await.unwrap();
+        assert_eq!(pool.run(|| ()).await, Err(PoolError::TimedOut));
+
+        unblock.send(()).unwrap();
+        first.await.unwrap();
+        assert_eq!(pool.run(|| ()).await, Ok(()));
+    }
+}
@@ -0,0 +1,166 @@
+//! Options for `hotpot serve` read from a TOML file (`--config`).
+//!
+//! Any of the command's flags can be set in the file under the same name,
+//! e.g. `tile_cache_size = 5000` for `--tile-cache-size 5000`. They're
+//! passed on as extra arguments, so they're checked the same way, and flags
+//! given on the command line take precedence. Settings which otherwise come
+//! from environment variables or the database have their own keys.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use serde::Deserialize;
+
+use crate::strava;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ServerConfigFile {
+    /// Same as `HOTPOT_UPLOAD_TOKEN`, which takes precedence.
+    pub upload_token: Option<String>,
+    /// Same as `HOTPOT_RENDER_SECRET`, which takes precedence.
+    pub render_secret: Option<String>,
+    /// Strava API credentials, instead of the `STRAVA_*` environment
+    /// variables or those saved with `strava-setup`.
+    #[serde(default)]
+    pub strava: strava::Credentials,
+    /// Named gradients, in addition to (or replacing) those in the database.
+    #[serde(default)]
+    pub gradients: BTreeMap<String, String>,
+    /// Everything else, which should be flags of `serve`.
+    #[serde(flatten)]
+    flags: toml::Table,
+}
+
+impl ServerConfigFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("failed to read {}: {}", path.display(), err))?;
+
+        toml::from_str(&text).map_err(|err| anyhow!("invalid config {}: {}", path.display(), err))
+    }
+
+    /// Arguments for the flags set in the file which weren't given on the
+    /// command line, going by `matches` of `command`.
+    pub fn args(&self, command: &Command, matches: &ArgMatches) -> Result<Vec<String>> {
+        let mut args = vec![];
+        for (key, value) in &self.flags {
+            let id = key.replace('-', "_");
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_id() == id.as_str() && id != "config");
+            let Some((arg, long)) = arg.and_then(|arg| Some((arg, arg.get_long()?))) else {
+                return Err(anyhow!("unknown option in config file: {}", key));
+            };
+
+            let given = matches!(
+                matches.value_source(&id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            );
+            if given {
+                continue;
+            }
+
+            let flag = format!("--{}", long);
+            match (arg.get_action(), value) {
+                (ArgAction::SetTrue, toml::Value::Boolean(enabled)) => {
+                    if *enabled {
+                        args.push(flag);
+                    }
+                }
+                (ArgAction::SetTrue, _) => {
+                    return Err(anyhow!("expected true or false for {} in config file", key));
+                }
+                (ArgAction::Append, toml::Value::Array(values)) => {
+                    for value in values {
+                        args.push(flag.clone());
+                        args.push(to_arg(key, value)?);
+                    }
+                }
+                (_, value) => {
+                    args.push(flag);
+                    args.push(to_arg(key, value)?);
+                }
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+fn to_arg(key: &str, value: &toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        _ => Err(anyhow!("unexpected value for {} in config file", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction};
+
+    #[test]
+    fn test_config_file_args() {
+        let command = Command::new("serve")
+            .arg(Arg::new("config").long("config"))
+            .arg(Arg::new("port").long("port").default_value("8080"))
+            .arg(Arg::new("tile_cache_size").long("tile-cache-size"))
+            .arg(Arg::new("upload").long("upload").action(ArgAction::SetTrue))
+            .arg(Arg::new("render").long("render").action(ArgAction::SetTrue))
+            .arg(
+                Arg::new("acme_domain")
+                    .long("acme-domain")
+                    .action(ArgAction::Append),
+            );
+        let matches = command
+            .clone()
+            .try_get_matches_from(["serve", "--port", "9000"])
+            .unwrap();
+
+        let file: ServerConfigFile = toml::from_str(
+            r#"
+            port = 80
+            tile-cache-size = 5000
+            upload = true
+            render = false
+            acme_domain = ["a.example.com", "b.example.com"]
+            upload_token = "secret"
+
+            [gradients]
+            fire = "1:f00;5:fff"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.upload_token.as_deref(), Some("secret"));
+        assert_eq!(file.gradients.len(), 1);
+
+        // The port was given on the command line.
+        assert_eq!(
+            file.args(&command, &matches).unwrap(),
+            vec![
+                "--acme-domain",
+                "a.example.com",
+                "--acme-domain",
+                "b.example.com",
+                "--tile-cache-size",
+                "5000",
+                "--upload",
+            ]
+        );
+
+        for text in [
+            "prot = 80",
+            "config = 'other.toml'",
+            "upload = 'yes'",
+            "tile_cache_size = {}",
+        ] {
+            let file: ServerConfigFile = toml::from_str(text).unwrap();
+            assert!(file.args(&command, &matches).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Optional R*Tree index over `activity_tiles`, for looking up the stored
+//! tiles within a range.
+//!
+//! The `(z, x, y)` B-tree index can only narrow down `x`, so wide ranges of
+//! tiles (e.g. when rendering at low zoom levels) end up checking the `y` of
+//! most rows in between. The R*Tree narrows down both at once, at the cost
+//! of a larger database and slower imports. Triggers keep it up to date.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use rusqlite::params;
+
+use crate::db::{Config, Database};
+use crate::tile::{Tile, TileBounds};
+
+const CREATE_INDEX: &str = "\
+CREATE VIRTUAL TABLE activity_tiles_rtree USING rtree_i32 (id, z0, z1, x0, x1, y0, y1);
+
+INSERT INTO activity_tiles_rtree
+SELECT id, z, z, x, x, y, y FROM activity_tiles;
+
+CREATE TRIGGER activity_tiles_rtree_insert AFTER INSERT ON activity_tiles BEGIN
+    INSERT INTO activity_tiles_rtree VALUES (new.id, new.z, new.z, new.x, new.x, new.y, new.y);
+END;
+
+CREATE TRIGGER activity_tiles_rtree_update AFTER UPDATE OF z, x, y ON activity_tiles BEGIN
+    UPDATE activity_tiles_rtree
+    SET z0 = new.z, z1 = new.z, x0 = new.x, x1 = new.x, y0 = new.y, y1 = new.y
+    WHERE id = new.id;
+END;
+
+CREATE TRIGGER activity_tiles_rtree_delete AFTER DELETE ON activity_tiles BEGIN
+    DELETE FROM activity_tiles_rtree WHERE id = old.id;
+END;
+";
+
+const DROP_INDEX: &str = "\
+DROP TRIGGER IF EXISTS activity_tiles_rtree_insert;
+DROP TRIGGER IF EXISTS activity_tiles_rtree_update;
+DROP TRIGGER IF EXISTS activity_tiles_rtree_delete;
+DROP TABLE IF EXISTS activity_tiles_rtree;
+";
+
+/// Condition on `activity_tiles` (taking `z, xmin, xmax, ymin, ymax` as
+/// parameters) matching the tiles within a `TileBounds`.
+const BTREE_CLAUSE: &str = "(activity_tiles.z = ? \
+    AND (activity_tiles.x >= ? AND activity_tiles.x < ?) \
+    AND (activity_tiles.y >= ? AND activity_tiles.y < ?))";
+
+/// Same as `BTREE_CLAUSE`, but going through the R*Tree.
+const RTREE_CLAUSE: &str = "activity_tiles.id IN ( \
+    SELECT id FROM activity_tiles_rtree \
+    WHERE z0 = ? AND (x0 >= ? AND x0 < ?) AND (y0 >= ? AND y0 < ?))";
+
+/// Condition on `activity_tiles` matching the tiles within a `TileBounds`,
+/// using the R*Tree index if there is one. Takes the parameters `z, xmin,
+/// xmax, ymin, ymax`, in that order.
+pub fn range_clause(config: &Config) -> &'static str {
+    if config.spatial_index {
+        RTREE_CLAUSE
+    } else {
+        BTREE_CLAUSE
+    }
+}
+
+/// Whether the database has an R*Tree index which can be queried.
+pub fn exists(conn: &rusqlite::Connection) -> Result<bool> {
+    let found: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'activity_tiles_rtree')",
+        [],
+        |row| row.get(0),
+    )?;
+
+    // The database may have been copied over from a machine with a SQLite
+    // build including the R*Tree module.
+    if found && conn.prepare("SELECT 1 FROM activity_tiles_rtree").is_err() {
+        tracing::warn!("R*Tree index is not supported by this SQLite build, ignoring it");
+        return Ok(false);
+    }
+
+    Ok(found)
+}
+
+/// Build the index, returning the number of tiles indexed.
+pub fn create(db: &Database) -> Result<usize> {
+    let mut conn = db.connection()?;
+    if exists(&conn)? {
+        return Err(anyhow!("spatial index already exists"));
+    }
+
+    let tx = conn.transaction()?;
+    tx.execute_batch(CREATE_INDEX)
+        .map_err(|err| anyhow!("failed to create spatial index: {}", err))?;
+    let num_tiles: usize =
+        tx.query_row("SELECT count(*) FROM activity_tiles_rtree", [], |row| {
+            row.get(0)
+        })?;
+    tx.commit()?;
+
+    Ok(num_tiles)
+}
+
+pub fn remove(db: &Database) -> Result<()> {
+    db.connection()?.execute_batch(DROP_INDEX)?;
+    Ok(())
+}
+
+/// Time taken to look up the stored tiles for a sample of rendered ones.
+pub struct BenchResult {
+    pub zoom: u8,
+    pub num_tiles: usize,
+    /// Total number of stored tiles found.
+    pub num_rows: usize,
+    pub btree: Duration,
+    pub rtree: Duration,
+}
+
+/// Compare tile lookups with and without the R*Tree index, for up to
+/// `samples` tiles with activities at each zoom level.
+pub fn bench(db: &Database, samples: usize) -> Result<Vec<BenchResult>> {
+    let conn = db.connection()?;
+    if !exists(&conn)? {
+        return Err(anyhow!("no spatial index, create one first"));
+    }
+
+    let max_zoom = *db.config.zoom_range().end() as u8;
+    let mut stmt = conn.prepare(
+        "\
+        SELECT DISTINCT x, y FROM activity_tiles \
+        WHERE z = ? \
+        ORDER BY random() \
+        LIMIT ?",
+    )?;
+    let sampled: Vec<(u32, u32)> = stmt
+        .query_map(params![max_zoom, samples], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<_, _>>()?;
+
+    let lookup = |clause: &str, bounds: &TileBounds| -> Result<(usize, Duration)> {
+        let start = Instant::now();
+        let num_rows: usize = conn
+            .prepare_cached(&format!(
+                "SELECT count(*), sum(length(coords)) FROM activity_tiles WHERE {}",
+                clause
+            ))?
+            .query_row(
+                params![bounds.z, bounds.xmin, bounds.xmax, bounds.ymin, bounds.ymax],
+                |row| row.get(0),
+            )?;
+        Ok((num_rows, start.elapsed()))
+    };
+
+    let mut results = vec![];
+    for zoom in 0..=max_zoom {
+        let shift = max_zoom - zoom;
+        let mut tiles: Vec<Tile> = sampled
+            .iter()
+            .map(|&(x, y)| Tile::new(x >> shift, y >> shift, zoom))
+            .collect();
+        tiles.sort_by_key(|tile| (tile.x, tile.y));
+        tiles.dedup();
+
+        let source_zoom = db.config.source_level(zoom).unwrap_or(max_zoom);
+        let mut result = BenchResult {
+            zoom,
+            num_tiles: tiles.len(),
+            num_rows: 0,
+            btree: Duration::ZERO,
+            rtree: Duration::ZERO,
+        };
+
+        for tile in tiles {
+            let bounds = TileBounds::from(source_zoom, &tile);
+            let (btree_rows, btree) = lookup(BTREE_CLAUSE, &bounds)?;
+            let (rtree_rows, rtree) = lookup(RTREE_CLAUSE, &bounds)?;
+            if btree_rows != rtree_rows {
+                return Err(anyhow!(
+                    "spatial index is out of date (found {} tiles instead of {} for {:?})",
+                    rtree_rows,
+                    btree_rows,
+                    tile
+                ));
+            }
+
+            result.num_rows += btree_rows;
+            result.btree += btree;
+            result.rtree += rtree;
+        }
+
+        results.push(result);
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spatial_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = Database::new(&dir.path().join("db.sqlite3")).unwrap();
+
+        let execute = |db: &Database, sql: &str| {
+            db.connection().unwrap().execute_batch(sql).unwrap();
+        };
+        let insert = |db: &Database, z: u8, x: u32, y: u32| {
+            execute(
+                db,
+                &format!(
+                    "INSERT INTO activity_tiles (activity_id, z, x, y, coords) \
+                    VALUES (1, {}, {}, {}, x'')",
+                    z, x, y
+                ),
+            );
+        };
+        insert(&db, 16, 5, 5);
+        insert(&db, 16, 5, 50);
+
+        assert_eq!(create(&db).unwrap(), 2);
+        assert!(create(&db).is_err());
+
+        // Kept up to date by triggers.
+        insert(&db, 16, 6, 6);
+        insert(&db, 14, 6, 6);
+        execute(&db, "DELETE FROM activity_tiles WHERE y = 50");
+
+        let count = |db: &Database| -> usize {
+            db.connection()
+                .unwrap()
+                .query_row(
+                    &format!(
+                        "SELECT count(*) FROM activity_tiles WHERE {}",
+                        range_clause(&db.config)
+                    ),
+                    params![16, 0, 10, 0, 10],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+
+        assert!(!db.config.spatial_index);
+        assert_eq!(count(&db), 2);
+        db.config.spatial_index = exists(&db.connection().unwrap()).unwrap();
+        assert!(db.config.spatial_index);
+        assert_eq!(count(&db), 2);
+
+        let results = bench(&db, 10).unwrap();
+        assert_eq!(results.len(), 17);
+        assert_eq!((results[16].num_tiles, results[16].num_rows), (2, 2));
+
+        remove(&db).unwrap();
+        assert!(!exists(&db.connection().unwrap()).unwrap());
+    }
+}
@@ -1,23 +1,28 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
-use anyhow::{anyhow, Result};
-use axum::extract::{Query, State};
+use anyhow::{anyhow, Context, Result};
+use axum::extract::{ConnectInfo, Query, State};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Redirect};
 use axum::routing::{get, post};
 use axum::{headers, Json, Router, TypedHeader};
 use geo_types::MultiLineString;
-use reqwest::Response;
-use rusqlite::params;
+use reqwest::header::HeaderMap;
+use reqwest::{Response, StatusCode as ResponseStatus};
+use rusqlite::{params, OptionalExtension};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use time::OffsetDateTime;
+use time::{Duration, OffsetDateTime, Time};
 
 use crate::activity;
 use crate::activity::RawActivity;
-use crate::db::Database;
+use crate::db::{self, Database};
 use crate::web::AppState;
+use crate::{jobs, rate_limit, users};
 
 #[derive(Deserialize)]
 struct AuthToken {
@@ -102,6 +107,14 @@ struct SummaryActivity {
 }
 
 impl SummaryActivity {
+    /// Whether the activity is only visible to the athlete ("Only You").
+    fn is_private(&self) -> bool {
+        let private = self.properties.get("private").and_then(Value::as_bool);
+        let visibility = self.properties.get("visibility").and_then(Value::as_str);
+
+        private == Some(true) || visibility == Some("only_me")
+    }
+
     /// Merge the activity's properties with the gear's properties.
     fn properties(&self) -> HashMap<String, Value> {
         // TODO: use custom serializer instead
@@ -123,28 +136,294 @@ impl SummaryActivity {
     }
 }
 
+/// How to handle activities which are private on Strava.
+///
+/// Strava doesn't expose whether an activity hides its start/end, so these
+/// only look at the `private` and `visibility` fields.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PrivacyOptions {
+    /// Don't import private activities, and remove activities which are
+    /// changed to private.
+    pub skip_private: bool,
+    /// Trim distance (in meters) to use for private activities instead of
+    /// the configured `trim_dist`.
+    pub private_trim: Option<f64>,
+}
+
+/// Strava's API usage, as reported in the `X-RateLimit-*` headers of the
+/// most recent response.
+///
+/// There's a short term limit which resets every 15 minutes (on the quarter
+/// hour), and a daily limit which resets at midnight UTC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct RateLimitUsage {
+    /// (15 minute, daily) request limits
+    limit: (u32, u32),
+    /// (15 minute, daily) requests made so far
+    usage: (u32, u32),
+    updated_at: OffsetDateTime,
+}
+
+impl RateLimitUsage {
+    fn from_headers(headers: &HeaderMap, now: OffsetDateTime) -> Option<Self> {
+        let parse = |name: &str| -> Option<(u32, u32)> {
+            let value = headers.get(name)?.to_str().ok()?;
+            let (short, daily) = value.split_once(',')?;
+            Some((short.trim().parse().ok()?, daily.trim().parse().ok()?))
+        };
+
+        Some(Self {
+            limit: parse("x-ratelimit-limit")?,
+            usage: parse("x-ratelimit-usage")?,
+            updated_at: now,
+        })
+    }
+
+    /// When requests can be made again, if a limit has been reached.
+    fn reset_at(&self) -> Option<OffsetDateTime> {
+        let ts = self.updated_at;
+
+        if self.usage.1 >= self.limit.1 {
+            Some(ts.replace_time(Time::MIDNIGHT) + Duration::DAY)
+        } else if self.usage.0 >= self.limit.0 {
+            let quarter = ts.minute() / 15 * 15;
+            let start = ts.replace_time(Time::from_hms(ts.hour(), quarter, 0).ok()?);
+            Some(start + Duration::minutes(15))
+        } else {
+            None
+        }
+    }
+}
+
+/// Keeps track of Strava's rate limits across requests.
+#[derive(Default)]
+pub struct RateLimiter {
+    last_usage: Mutex<Option<RateLimitUsage>>,
+}
+
+impl RateLimiter {
+    fn update(&self, headers: &HeaderMap) {
+        let now = OffsetDateTime::now_utc();
+        if let Some(usage) = RateLimitUsage::from_headers(headers, now) {
+            *self.last_usage.lock().unwrap() = Some(usage);
+        }
+    }
+
+    /// If no more requests can be made right now, when that changes.
+    fn blocked_until(&self, now: OffsetDateTime) -> Option<OffsetDateTime> {
+        self.last_usage
+            .lock()
+            .unwrap()
+            .and_then(|usage| usage.reset_at())
+            .filter(|reset_at| *reset_at > now)
+    }
+}
+
+/// Error for requests which weren't made because the rate limit has been
+/// reached.
+#[derive(Debug)]
+struct RateLimited {
+    until: OffsetDateTime,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Strava rate limit reached until {}", self.until)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
 #[derive(Clone)]
 pub struct StravaAuth {
     client_id: u64,
     client_secret: String,
     webhook_secret: String,
+    /// ID of our webhook subscription, once known. Events for other
+    /// subscriptions are rejected.
+    subscription_id: Option<u64>,
+    /// Shared by all requests made with these credentials.
+    rate_limiter: Arc<RateLimiter>,
+}
+
+/// API credentials given in a config file, see [`StravaAuth::load_with`].
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
+    pub client_id: Option<u64>,
+    pub client_secret: Option<String>,
+    pub webhook_secret: Option<String>,
+}
+
+/// Value saved in the `config` table with [`db::STRAVA_KEY_PREFIX`].
+fn saved_value(conn: &rusqlite::Connection, key: &str) -> Result<Option<String>> {
+    Ok(conn
+        .query_row(
+            "SELECT value FROM config WHERE key = ?",
+            params![format!("{}{}", db::STRAVA_KEY_PREFIX, key)],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+fn save_value(conn: &rusqlite::Connection, key: &str, value: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO config (key, value) VALUES (?, ?)",
+        params![format!("{}{}", db::STRAVA_KEY_PREFIX, key), value],
+    )?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct PushSubscription {
+    id: u64,
+    #[serde(default)]
+    callback_url: String,
 }
 
 impl StravaAuth {
-    pub fn from_env() -> Result<StravaAuth> {
-        let get_env =
-            |k| std::env::var(k).map_err(|_| anyhow!("environment variable not set: {}", k));
+    /// Load API credentials from environment variables, falling back to the
+    /// ones saved with `hotpot strava-setup`.
+    pub fn load(db: &Database) -> Result<StravaAuth> {
+        Self::load_with(db, &Credentials::default())
+    }
 
-        let client_id = get_env("STRAVA_CLIENT_ID")?.parse()?;
-        let client_secret = get_env("STRAVA_CLIENT_SECRET")?;
-        let webhook_secret = get_env("STRAVA_WEBHOOK_SECRET")?;
+    /// Like [`StravaAuth::load`], but preferring the given credentials over
+    /// saved ones. Environment variables still come first.
+    pub fn load_with(db: &Database, credentials: &Credentials) -> Result<StravaAuth> {
+        let conn = db.connection()?;
+        let get = |env_key: &str, given: Option<String>, db_key: &str| -> Result<Option<String>> {
+            match std::env::var(env_key) {
+                Ok(value) => Ok(Some(value)),
+                Err(_) if given.is_some() => Ok(given),
+                Err(_) => saved_value(&conn, db_key),
+            }
+        };
+        let require = |env_key: &str, given: Option<String>, db_key: &str| -> Result<String> {
+            get(env_key, given, db_key)?.ok_or_else(|| {
+                anyhow!(
+                    "Strava credentials not set, run `hotpot strava-setup` or set {}",
+                    env_key
+                )
+            })
+        };
+
+        let client_id = require(
+            "STRAVA_CLIENT_ID",
+            credentials.client_id.map(|id| id.to_string()),
+            "client_id",
+        )?
+        .parse()
+        .context("invalid STRAVA_CLIENT_ID")?;
+        let client_secret = require(
+            "STRAVA_CLIENT_SECRET",
+            credentials.client_secret.clone(),
+            "client_secret",
+        )?;
+        let webhook_secret = require(
+            "STRAVA_WEBHOOK_SECRET",
+            credentials.webhook_secret.clone(),
+            "webhook_secret",
+        )?;
+        let subscription_id = get("STRAVA_SUBSCRIPTION_ID", None, "subscription_id")?
+            .map(|id| id.parse())
+            .transpose()
+            .context("invalid STRAVA_SUBSCRIPTION_ID")?;
 
         Ok(Self {
             client_id,
             client_secret,
             webhook_secret,
+            subscription_id,
+            rate_limiter: Arc::default(),
         })
     }
+
+    /// Save API credentials to the database, so they don't need to be given
+    /// as environment variables.
+    pub fn save(
+        db: &Database,
+        client_id: u64,
+        client_secret: &str,
+        webhook_secret: &str,
+    ) -> Result<()> {
+        let conn = db.connection()?;
+        save_value(&conn, "client_id", &client_id.to_string())?;
+        save_value(&conn, "client_secret", client_secret)?;
+        save_value(&conn, "webhook_secret", webhook_secret)?;
+
+        Ok(())
+    }
+
+    pub fn subscription_id(&self) -> Option<u64> {
+        self.subscription_id
+    }
+
+    /// Whether a webhook event was sent for our subscription. Nothing is
+    /// until the subscription is known (see [`StravaAuth::subscribe`]), which
+    /// may have been saved since these credentials were loaded.
+    fn is_subscribed(&self, db: &Database, subscription_id: Option<u64>) -> Result<bool> {
+        let expected = match self.subscription_id {
+            Some(id) => Some(id),
+            None => saved_value(&*db.connection()?, "subscription_id")?
+                .map(|id| id.parse())
+                .transpose()
+                .context("invalid saved subscription ID")?,
+        };
+
+        Ok(expected.is_some() && subscription_id == expected)
+    }
+
+    /// Subscribe to webhook events sent to `callback_url` (unless already
+    /// subscribed), and save the subscription ID to verify events with.
+    ///
+    /// Strava checks the callback before creating the subscription, so the
+    /// server needs to be running with `--strava-webhook`.
+    pub async fn subscribe(&self, db: &Database, callback_url: &str) -> Result<u64> {
+        const URL: &str = "https://www.strava.com/api/v3/push_subscriptions";
+        let client = reqwest::Client::new();
+
+        // Only one subscription is allowed per application.
+        let res = client
+            .get(URL)
+            .query(&[
+                ("client_id", self.client_id.to_string()),
+                ("client_secret", self.client_secret.clone()),
+            ])
+            .send()
+            .await?;
+        let existing: Vec<PushSubscription> = unwrap_response(res).await?;
+
+        let id = match existing.first() {
+            Some(sub) if sub.callback_url == callback_url => sub.id,
+            Some(sub) => {
+                return Err(anyhow!(
+                    "already subscribed with a different callback URL: {}",
+                    sub.callback_url
+                ))
+            }
+            None => {
+                let res = client
+                    .post(URL)
+                    .form(&[
+                        ("client_id", self.client_id.to_string()),
+                        ("client_secret", self.client_secret.clone()),
+                        ("callback_url", callback_url.to_string()),
+                        ("verify_token", self.webhook_secret.clone()),
+                    ])
+                    .send()
+                    .await?;
+                let created: PushSubscription = unwrap_response(res).await?;
+                created.id
+            }
+        };
+
+        let conn = db.connection()?;
+        save_value(&conn, "subscription_id", &id.to_string())?;
+
+        Ok(id)
+    }
 }
 
 struct StravaClient<'a> {
@@ -173,6 +452,12 @@ impl<'a> StravaClient<'a> {
         Ok(token.token)
     }
     async fn get_activity(&self, athlete_id: u64, activity_id: u64) -> Result<SummaryActivity> {
+        let rate_limiter = &self.auth.rate_limiter;
+        let now = OffsetDateTime::now_utc();
+        if let Some(until) = rate_limiter.blocked_until(now) {
+            return Err(RateLimited { until }.into());
+        }
+
         let token = self.get_token(athlete_id).await?;
         let client = reqwest::Client::new();
 
@@ -185,10 +470,29 @@ impl<'a> StravaClient<'a> {
             .send()
             .await?;
 
+        rate_limiter.update(res.headers());
+        if res.status() == ResponseStatus::TOO_MANY_REQUESTS {
+            // Headers should tell us when to retry, otherwise assume the
+            // short term limit was hit.
+            let until = rate_limiter
+                .blocked_until(now)
+                .unwrap_or(now + Duration::minutes(15));
+            return Err(RateLimited { until }.into());
+        }
+
         let activity: SummaryActivity = unwrap_response(res).await?;
         Ok(activity)
     }
 
+    fn has_token(&self, athlete_id: u64) -> Result<bool> {
+        let conn = self.db.connection()?;
+        let exists = conn
+            .prepare("SELECT 1 FROM strava_tokens WHERE athlete_id = ?")?
+            .exists([athlete_id])?;
+
+        Ok(exists)
+    }
+
     async fn get_token(&self, athlete_id: u64) -> Result<AuthToken> {
         let token = {
             let conn = self.db.connection()?;
@@ -324,13 +628,10 @@ async fn exchange_token(
         format!(
             "Successfully authenticated with Strava.
 
-Next, make sure the webhook is set up to be called for new activities:
+Next, make sure the webhook is set up to be called for new activities. With
+the server running with `--strava-webhook`, run:
 
-    curl https://www.strava.com/api/v3/push_subscriptions \\
-         -F \"client_id={0}\" \\
-         -F \"client_secret={1}\" \\
-         -F \"callback_url=https://[example.com]/strava/webhook\" \\
-         -F \"verify_token={2}\"
+    hotpot strava-subscribe https://[example.com]/strava/webhook
 
 Confirm the webhook was set up correctly with:
 
@@ -340,7 +641,7 @@ Confirm the webhook was set up correctly with:
 
 More information: https://developers.strava.com/docs/getting-started
 ",
-            strava.client_id, strava.client_secret, strava.webhook_secret,
+            strava.client_id, strava.client_secret,
         ),
     )
         .into_response()
@@ -381,58 +682,360 @@ async fn confirm_webhook(
     .into_response()
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum AspectType {
+    Create,
+    Update,
+    Delete,
+}
+
+#[derive(Deserialize, Serialize)]
 struct WebhookBody {
+    /// Subscription the event was sent for (missing for events queued by
+    /// older versions).
+    #[serde(default)]
+    subscription_id: Option<u64>,
     /// Athlete ID
     owner_id: u64,
     /// Activity or Athlete ID
     object_id: u64,
     /// "activity", "athlete"
     object_type: String,
-    // TODO: handle these
-    // "create", "update", "delete"
-    // aspect_type: String,
+    /// "create", "update", "delete"
+    aspect_type: AspectType,
 }
 
-// TODO: look at subscription_id or something to verify request.
+/// Job kind for webhook events, which are processed in the background.
+const WEBHOOK_JOB: &str = "strava_webhook";
+
+/// How often to check for queued events when there's nothing scheduled.
+const QUEUE_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Queue the event to be processed in the background, since Strava expects
+/// a response within 2 seconds and fetching the activity may take a while
+/// (or fail, and need to be retried).
 async fn receive_webhook(
-    State(AppState { db, strava, .. }): State<AppState>,
+    State(AppState {
+        db,
+        strava,
+        config,
+        job_added,
+        ..
+    }): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(body): Json<WebhookBody>,
 ) -> impl IntoResponse {
     let strava = strava.expect("strava auth creds missing");
+
+    let allowed_ips = &config.strava_webhook_ips;
+    let header = config.rate_limits.client_ip_header.as_ref();
+    let ip = rate_limit::remote_ip(&headers, Some(peer.ip()), header).unwrap_or(peer.ip());
+    if !allowed_ips.is_empty() && !allowed_ips.iter().any(|net| net.contains(&ip)) {
+        tracing::warn!(%ip, "rejecting webhook event from unexpected address");
+        return (StatusCode::FORBIDDEN, "address not allowed");
+    }
+
+    match strava.is_subscribed(&db, body.subscription_id) {
+        Ok(true) => {}
+        Ok(false) => {
+            tracing::warn!(
+                subscription_id = ?body.subscription_id,
+                "rejecting webhook event for unknown subscription"
+            );
+            return (StatusCode::FORBIDDEN, "unknown subscription");
+        }
+        Err(e) => {
+            tracing::error!("error checking subscription: {:#}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "error checking subscription",
+            );
+        }
+    }
+
     if body.object_type != "activity" {
         return (StatusCode::OK, "nothing to do");
     }
 
-    let client = StravaClient {
-        auth: &strava,
-        db: &db,
-    };
-    let activity = match client.get_activity(body.owner_id, body.object_id).await {
-        Ok(a) => a,
+    let queued = db
+        .connection()
+        .and_then(|conn| jobs::enqueue(&conn, WEBHOOK_JOB, &body, OffsetDateTime::now_utc()));
+
+    match queued {
+        Ok(_) => {
+            job_added.notify_one();
+            (StatusCode::OK, "queued")
+        }
         Err(e) => {
-            tracing::error!("error getting activity: {}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "error getting activity");
+            tracing::error!("error queueing activity: {:#}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "error queueing activity")
+        }
+    }
+}
+
+/// Fetch and store (or delete) the activity a webhook event refers to.
+async fn process_event(
+    AppState {
+        db,
+        strava,
+        tile_cache,
+        config,
+        ..
+    }: &AppState,
+    body: &WebhookBody,
+) -> Result<&'static str> {
+    let strava = strava.as_ref().expect("strava auth creds missing");
+    let privacy = config.strava_privacy;
+
+    if body.object_type != "activity" {
+        return Ok("nothing to do");
+    }
+
+    let name = format!("strava:{}", body.object_id);
+    let client = StravaClient { auth: strava, db };
+
+    // Only act on activities of athletes who authorized this instance.
+    if !client.has_token(body.owner_id)? {
+        tracing::warn!(
+            athlete_id = body.owner_id,
+            "no token for athlete, ignoring event"
+        );
+        return Ok("unknown athlete");
+    }
+
+    if body.aspect_type == AspectType::Delete {
+        let mut conn = db.connection()?;
+        let deleted = activity::delete(&mut conn, &name).context("error deleting activity")?;
+
+        if !deleted {
+            return Ok("nothing to do");
         }
-    };
 
-    let polyline = polyline::decode_polyline(&activity.map.polyline, 5).expect("valid polyline");
-    let properties = activity.properties();
+        tile_cache.clear();
+        return Ok("deleted!");
+    }
+
+    let activity = client
+        .get_activity(body.owner_id, body.object_id)
+        .await
+        .context("error getting activity")?;
+
+    if privacy.skip_private && activity.is_private() {
+        // The activity may have been public when we first imported it.
+        let mut conn = db.connection()?;
+        let deleted = activity::delete(&mut conn, &name).context("error deleting activity")?;
+
+        if deleted {
+            tile_cache.clear();
+        }
+
+        return Ok("skipped private activity");
+    }
+
+    let mut properties = activity.properties();
+    if activity::is_virtual_source(&activity.kind) {
+        properties.insert(activity::VIRTUAL_PROPERTY.to_string(), true.into());
+    }
+    if let Some(trim) = privacy.private_trim.filter(|_| activity.is_private()) {
+        properties.insert(activity::TRIM_PROPERTY.to_string(), trim.into());
+    }
 
-    if let Err(e) = activity::upsert(
-        &mut db.connection().unwrap(),
-        &format!("strava:{}", activity.id),
+    // Updates can only change metadata (title, type, visibility, ...), so
+    // there's no need to re-process the tracks if we already have them.
+    // Unless private activities are trimmed differently, since changing the
+    // visibility changes which tiles the activity covers.
+    if body.aspect_type == AspectType::Update && privacy.private_trim.is_none() {
+        let mut conn = db.connection()?;
+        let updated =
+            activity::update_metadata(&mut conn, &name, Some(&activity.name), &properties)
+                .context("error updating activity")?;
+
+        if updated {
+            tile_cache.clear();
+            return Ok("updated!");
+        }
+
+        // We haven't seen this activity before, so treat it as new.
+    }
+
+    let polyline = polyline::decode_polyline(&activity.map.polyline, 5)
+        .map_err(|e| anyhow!("invalid polyline: {}", e))?;
+
+    let mut conn = db.connection()?;
+    let user_id = users::for_strava_athlete(&conn, body.owner_id)?;
+    activity::upsert(
+        &mut conn,
+        &name,
         &RawActivity {
             title: Some(activity.name),
             start_time: Some(activity.start_date),
             tracks: MultiLineString::from(polyline),
             properties,
+            content_hash: None,
+            athlete_id: Some(body.owner_id),
+            user_id,
         },
         &db.config,
-    ) {
-        tracing::error!("error writing activity: {}", e);
-        return (StatusCode::INTERNAL_SERVER_ERROR, "error writing activity");
+    )
+    .context("error writing activity")?;
+
+    tile_cache.clear();
+    Ok("added!")
+}
+
+/// Process queued webhook events as they become due, with the server state
+/// returned by `state` at the time. Runs forever.
+pub async fn process_queue(state: impl Fn() -> AppState) {
+    loop {
+        let state = state();
+        let now = OffsetDateTime::now_utc();
+        let next_run = state
+            .db
+            .connection()
+            .and_then(|conn| jobs::next_run(&conn, WEBHOOK_JOB));
+
+        let wait = match next_run {
+            Ok(Some(ts)) if ts <= now => None,
+            Ok(Some(ts)) => Some(std::time::Duration::try_from(ts - now).unwrap_or_default()),
+            Ok(None) => Some(QUEUE_POLL_INTERVAL),
+            Err(err) => {
+                tracing::error!(?err, "failed to check queued strava events");
+                Some(QUEUE_POLL_INTERVAL)
+            }
+        };
+
+        if let Some(wait) = wait {
+            tokio::select! {
+                _ = tokio::time::sleep(wait.min(QUEUE_POLL_INTERVAL)) => {}
+                _ = state.job_added.notified() => {}
+            }
+            continue;
+        }
+
+        if let Err(err) = process_due_events(&state, now).await {
+            tracing::error!(?err, "failed to process queued strava events");
+            tokio::time::sleep(QUEUE_POLL_INTERVAL).await;
+        }
     }
+}
+
+async fn process_due_events(state: &AppState, now: OffsetDateTime) -> Result<()> {
+    let conn = state.db.connection()?;
+    let due: Vec<jobs::Job<WebhookBody>> = jobs::due(&conn, WEBHOOK_JOB, now)?;
+    drop(conn);
+
+    for (i, job) in due.iter().enumerate() {
+        let result = process_event(state, &job.payload).await;
+        let conn = state.db.connection()?;
+        let activity_id = job.payload.object_id;
 
-    (StatusCode::OK, "added!")
+        match result {
+            Ok(msg) => {
+                tracing::info!(activity_id, msg, "processed webhook event");
+                jobs::remove(&conn, job.id)?;
+            }
+            Err(err) => match err.downcast_ref::<RateLimited>() {
+                // Leave the rest for when the limit resets again.
+                Some(RateLimited { until }) => {
+                    tracing::info!(%until, "rate limited, postponing webhook events");
+                    for job in &due[i..] {
+                        jobs::reschedule(&conn, job.id, *until)?;
+                    }
+                    break;
+                }
+                None => {
+                    let retry = jobs::fail(&conn, job, &format!("{:#}", err), now)?;
+                    tracing::error!(?err, activity_id, retry, "failed to process webhook event");
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_private() {
+        let parse = |extra: &str| -> SummaryActivity {
+            serde_json::from_str(&format!(
+                r#"{{
+                    "id": 1,
+                    "name": "Morning Ride",
+                    "map": {{"polyline": ""}},
+                    "start_date": "2024-05-01T12:00:00Z",
+                    "total_elevation_gain": 10.0,
+                    "type": "Ride"
+                    {}
+                }}"#,
+                extra
+            ))
+            .unwrap()
+        };
+
+        assert!(!parse("").is_private());
+        assert!(!parse(r#", "private": false, "visibility": "everyone""#).is_private());
+        assert!(parse(r#", "private": true"#).is_private());
+        assert!(parse(r#", "visibility": "only_me""#).is_private());
+    }
+
+    #[test]
+    fn test_is_subscribed() {
+        let mut auth = StravaAuth {
+            client_id: 1,
+            client_secret: "secret".into(),
+            webhook_secret: "verify".into(),
+            subscription_id: None,
+            rate_limiter: Arc::default(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(&dir.path().join("db.sqlite3")).unwrap();
+
+        // Nothing is accepted until subscribed.
+        assert!(!auth.is_subscribed(&db, None).unwrap());
+        assert!(!auth.is_subscribed(&db, Some(5)).unwrap());
+
+        // Subscribed since the credentials were loaded.
+        save_value(&db.connection().unwrap(), "subscription_id", "5").unwrap();
+        assert!(auth.is_subscribed(&db, Some(5)).unwrap());
+        assert!(!auth.is_subscribed(&db, Some(6)).unwrap());
+
+        auth.subscription_id = Some(7);
+        assert!(auth.is_subscribed(&db, Some(7)).unwrap());
+        assert!(!auth.is_subscribed(&db, Some(5)).unwrap());
+        assert!(!auth.is_subscribed(&db, None).unwrap());
+    }
+
+    #[test]
+    fn test_rate_limit_reset() {
+        use time::format_description::well_known::Rfc3339;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-limit", "100,1000".parse().unwrap());
+        headers.insert("x-ratelimit-usage", "42,500".parse().unwrap());
+
+        let now = OffsetDateTime::parse("2024-05-01T12:20:30Z", &Rfc3339).unwrap();
+        let mut usage = RateLimitUsage::from_headers(&headers, now).unwrap();
+        assert_eq!(usage.limit, (100, 1000));
+        assert_eq!(usage.reset_at(), None);
+
+        // Short term limit resets on the next quarter hour.
+        usage.usage = (100, 500);
+        assert_eq!(
+            usage.reset_at(),
+            Some(OffsetDateTime::parse("2024-05-01T12:30:00Z", &Rfc3339).unwrap())
+        );
+
+        // Daily limit resets at midnight UTC.
+        usage.usage = (100, 1000);
+        assert_eq!(
+            usage.reset_at(),
+            Some(OffsetDateTime::parse("2024-05-02T00:00:00Z", &Rfc3339).unwrap())
+        );
+    }
 }
@@ -0,0 +1,362 @@
+//! Activity metadata as a table with one row per activity, for analysis in
+//! pandas, DuckDB and friends without going through the SQLite schema.
+//!
+//! Properties are flattened into their own columns, typed by the values seen
+//! across all activities. Anything which isn't consistently a number or
+//! boolean is written as text, with non-string values encoded as JSON.
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use geo_types::MultiLineString;
+use parquet::basic::{Compression, LogicalType, Repetition, TimeUnit, Type as PhysicalType};
+use parquet::column::writer::ColumnWriterImpl;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DataType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::schema::types::Type;
+use serde_json::Value;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::activity::ActivitySummary;
+use crate::track_stats;
+
+/// Columns which are always present, and take precedence over properties
+/// with the same name.
+const BUILTIN_COLUMNS: &[&str] = &[
+    "id",
+    "file",
+    "title",
+    "start_time",
+    "athlete_id",
+    "user_id",
+    "distance",
+    "track_distance",
+    "track_points",
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TableFormat {
+    Csv,
+    Parquet,
+}
+
+/// An activity along with the stats computed from its stored track.
+pub struct ActivityRow {
+    activity: ActivitySummary,
+    /// From the `distance` property, falling back to the track length.
+    distance: Option<f64>,
+    track_distance: Option<f64>,
+    track_points: Option<i64>,
+}
+
+impl ActivityRow {
+    pub fn new(activity: ActivitySummary, tracks: Option<&MultiLineString>) -> Self {
+        ActivityRow {
+            distance: track_stats::activity_distance(&activity, tracks),
+            track_distance: tracks.map(track_stats::track_distance),
+            track_points: tracks.map(|t| t.iter().map(|line| line.0.len() as i64).sum()),
+            activity,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Values {
+    Int(Vec<Option<i64>>),
+    Float(Vec<Option<f64>>),
+    Bool(Vec<Option<bool>>),
+    Text(Vec<Option<String>>),
+    Time(Vec<Option<OffsetDateTime>>),
+}
+
+impl Values {
+    /// Pick the narrowest type which fits every value of a property.
+    fn from_json(values: Vec<Option<&Value>>) -> Self {
+        let present = || values.iter().flatten();
+
+        if present().all(|v| v.is_boolean()) {
+            Values::Bool(values.iter().map(|v| v.and_then(Value::as_bool)).collect())
+        } else if present().all(|v| v.is_i64()) {
+            Values::Int(values.iter().map(|v| v.and_then(Value::as_i64)).collect())
+        } else if present().all(|v| v.is_number()) {
+            Values::Float(values.iter().map(|v| v.and_then(Value::as_f64)).collect())
+        } else {
+            let text = |v: &Value| match v {
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
+            };
+            Values::Text(values.iter().map(|v| v.map(text)).collect())
+        }
+    }
+
+    fn csv_field(&self, row: usize) -> Option<String> {
+        match self {
+            Values::Int(v) => v[row].map(|x| x.to_string()),
+            Values::Float(v) => v[row].map(|x| x.to_string()),
+            Values::Bool(v) => v[row].map(|x| x.to_string()),
+            Values::Text(v) => v[row].clone(),
+            Values::Time(v) => v[row].and_then(|ts| ts.format(&Rfc3339).ok()),
+        }
+    }
+
+    fn parquet_type(&self) -> (PhysicalType, Option<LogicalType>) {
+        match self {
+            Values::Int(_) => (PhysicalType::INT64, None),
+            Values::Float(_) => (PhysicalType::DOUBLE, None),
+            Values::Bool(_) => (PhysicalType::BOOLEAN, None),
+            Values::Text(_) => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+            Values::Time(_) => (
+                PhysicalType::INT64,
+                Some(LogicalType::Timestamp {
+                    is_adjusted_to_u_t_c: true,
+                    unit: TimeUnit::MILLIS(Default::default()),
+                }),
+            ),
+        }
+    }
+
+    fn write_parquet(&self, column: &mut SerializedColumnWriter) -> Result<()> {
+        fn write<T: DataType, V>(
+            writer: &mut ColumnWriterImpl<T>,
+            values: &[Option<V>],
+            convert: impl Fn(&V) -> T::T,
+        ) -> Result<()> {
+            let levels: Vec<i16> = values.iter().map(|v| v.is_some() as i16).collect();
+            let present: Vec<T::T> = values.iter().flatten().map(convert).collect();
+            writer.write_batch(&present, Some(&levels), None)?;
+            Ok(())
+        }
+
+        match self {
+            Values::Int(v) => write(column.typed::<Int64Type>(), v, |x| *x),
+            Values::Float(v) => write(column.typed::<DoubleType>(), v, |x| *x),
+            Values::Bool(v) => write(column.typed::<BoolType>(), v, |x| *x),
+            Values::Text(v) => write(column.typed::<ByteArrayType>(), v, |s| {
+                ByteArray::from(s.as_str())
+            }),
+            Values::Time(v) => write(column.typed::<Int64Type>(), v, |ts| {
+                (ts.unix_timestamp_nanos() / 1_000_000) as i64
+            }),
+        }
+    }
+}
+
+struct Column {
+    name: String,
+    values: Values,
+}
+
+fn columns(rows: &[ActivityRow]) -> Vec<Column> {
+    let column = |name: &str, values| Column {
+        name: name.to_string(),
+        values,
+    };
+    let activities = || rows.iter().map(|row| &row.activity);
+
+    let mut columns = vec![
+        column(
+            "id",
+            Values::Int(activities().map(|a| Some(a.id)).collect()),
+        ),
+        column(
+            "file",
+            Values::Text(activities().map(|a| Some(a.file.clone())).collect()),
+        ),
+        column(
+            "title",
+            Values::Text(activities().map(|a| a.title.clone()).collect()),
+        ),
+        column(
+            "start_time",
+            Values::Time(activities().map(|a| a.start_time).collect()),
+        ),
+        column(
+            "athlete_id",
+            Values::Int(
+                activities()
+                    .map(|a| a.athlete_id.map(|id| id as i64))
+                    .collect(),
+            ),
+        ),
+        column(
+            "user_id",
+            Values::Int(activities().map(|a| a.user_id).collect()),
+        ),
+        column(
+            "distance",
+            Values::Float(rows.iter().map(|row| row.distance).collect()),
+        ),
+        column(
+            "track_distance",
+            Values::Float(rows.iter().map(|row| row.track_distance).collect()),
+        ),
+        column(
+            "track_points",
+            Values::Int(rows.iter().map(|row| row.track_points).collect()),
+        ),
+    ];
+
+    let keys: BTreeSet<&str> = activities()
+        .flat_map(|a| a.properties.keys())
+        .map(|key| key.as_str())
+        .filter(|key| !BUILTIN_COLUMNS.contains(key))
+        .collect();
+
+    for key in keys {
+        let values = activities()
+            .map(|a| a.properties.get(key).filter(|v| !v.is_null()))
+            .collect();
+        columns.push(column(key, Values::from_json(values)));
+    }
+
+    columns
+}
+
+/// Write one row per activity in the given format.
+pub fn write<W: Write + Send>(w: W, format: TableFormat, rows: &[ActivityRow]) -> Result<()> {
+    let columns = columns(rows);
+    match format {
+        TableFormat::Csv => write_csv(w, &columns, rows.len()),
+        TableFormat::Parquet => write_parquet(w, &columns),
+    }
+}
+
+fn write_csv<W: Write>(w: W, columns: &[Column], num_rows: usize) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(w);
+    writer.write_record(columns.iter().map(|c| &c.name))?;
+
+    for row in 0..num_rows {
+        writer.write_record(
+            columns
+                .iter()
+                .map(|c| c.values.csv_field(row).unwrap_or_default()),
+        )?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_parquet<W: Write + Send>(w: W, columns: &[Column]) -> Result<()> {
+    let fields = columns
+        .iter()
+        .map(|column| {
+            let (physical, logical) = column.values.parquet_type();
+            let field = Type::primitive_type_builder(&column.name, physical)
+                .with_repetition(Repetition::OPTIONAL)
+                .with_logical_type(logical)
+                .build()?;
+            Ok(Arc::new(field))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let schema = Type::group_type_builder("activity")
+        .with_fields(fields)
+        .build()?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+
+    let mut writer = SerializedFileWriter::new(w, Arc::new(schema), Arc::new(properties))?;
+    let mut row_group = writer.next_row_group()?;
+    for column in columns {
+        let mut column_writer = row_group
+            .next_column()?
+            .ok_or_else(|| anyhow!("missing writer for column {}", column.name))?;
+        column.values.write_parquet(&mut column_writer)?;
+        column_writer.close()?;
+    }
+    row_group.close()?;
+    writer.close()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo_types::line_string;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    fn rows() -> Vec<ActivityRow> {
+        let activity = |id, properties: &str| ActivitySummary {
+            id,
+            file: format!("{}.gpx", id),
+            title: None,
+            start_time: Some(OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap()),
+            properties: serde_json::from_str(properties).unwrap(),
+            athlete_id: None,
+            user_id: None,
+        };
+        let tracks = MultiLineString::new(vec![line_string![(x: 0.0, y: 0.0), (x: 0.01, y: 0.0)]]);
+
+        vec![
+            ActivityRow::new(
+                activity(1, r#"{"gear": "canyon", "commute": true, "kudos": 3}"#),
+                Some(&tracks),
+            ),
+            ActivityRow::new(
+                activity(2, r#"{"gear": {"id": 1}, "kudos": 1.5, "distance": 5000}"#),
+                None,
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_property_columns() {
+        let columns = columns(&rows());
+        let find = |name: &str| {
+            &columns
+                .iter()
+                .find(|c| c.name == name)
+                .unwrap_or_else(|| panic!("no column {}", name))
+                .values
+        };
+
+        assert_eq!(*find("commute"), Values::Bool(vec![Some(true), None]));
+        assert_eq!(*find("kudos"), Values::Float(vec![Some(3.0), Some(1.5)]));
+        assert_eq!(
+            *find("gear"),
+            Values::Text(vec![Some("canyon".into()), Some(r#"{"id":1}"#.into())])
+        );
+        assert_eq!(find("track_points"), &Values::Int(vec![Some(2), None]));
+
+        // The distance property feeds into the builtin column.
+        assert_eq!(columns.iter().filter(|c| c.name == "distance").count(), 1);
+        let Values::Float(ref distance) = find("distance") else {
+            panic!("distance should be a float column");
+        };
+        assert!((distance[0].unwrap() - 1112.0).abs() < 1.0);
+        assert_eq!(distance[1], Some(5000.0));
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let mut buf = vec![];
+        write(&mut buf, TableFormat::Csv, &rows()).unwrap();
+
+        let csv = String::from_utf8(buf).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "id,file,title,start_time,athlete_id,user_id,distance,track_distance,track_points,commute,gear,kudos"
+        );
+        assert!(lines[2].starts_with("2,2.gpx,,2023-11-14T22:13:20Z,,,5000,,,,"));
+        assert!(lines[2].ends_with(r#","{""id"":1}",1.5"#));
+    }
+
+    #[test]
+    fn test_write_parquet() {
+        let mut file = tempfile::tempfile().unwrap();
+        write(&mut file, TableFormat::Parquet, &rows()).unwrap();
+
+        let reader = SerializedFileReader::new(file).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.file_metadata().num_rows(), 2);
+        assert_eq!(metadata.file_metadata().schema_descr().num_columns(), 12);
+    }
+}
@@ -0,0 +1,175 @@
+//! Minimal bitmap text rendering, enough to stamp attribution lines onto
+//! rendered images without pulling in a font rasterizer.
+
+use image::{Rgba, RgbaImage};
+
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+
+/// 3x5 glyphs for printable ASCII (`' '..='~'`), one row per byte with the
+/// leftmost pixel in bit 2.
+#[rustfmt::skip]
+const FONT: [[u8; 5]; 95] = [
+    [0b000, 0b000, 0b000, 0b000, 0b000], // ' '
+    [0b010, 0b010, 0b010, 0b000, 0b010], // !
+    [0b101, 0b101, 0b000, 0b000, 0b000], // "
+    [0b101, 0b111, 0b101, 0b111, 0b101], // #
+    [0b011, 0b110, 0b010, 0b011, 0b110], // $
+    [0b101, 0b001, 0b010, 0b100, 0b101], // %
+    [0b010, 0b101, 0b010, 0b101, 0b011], // &
+    [0b010, 0b010, 0b000, 0b000, 0b000], // '
+    [0b001, 0b010, 0b010, 0b010, 0b001], // (
+    [0b100, 0b010, 0b010, 0b010, 0b100], // )
+    [0b000, 0b101, 0b010, 0b101, 0b000], // *
+    [0b000, 0b010, 0b111, 0b010, 0b000], // +
+    [0b000, 0b000, 0b000, 0b010, 0b100], // ,
+    [0b000, 0b000, 0b111, 0b000, 0b000], // -
+    [0b000, 0b000, 0b000, 0b000, 0b010], // .
+    [0b001, 0b001, 0b010, 0b100, 0b100], // /
+    [0b111, 0b101, 0b101, 0b101, 0b111], // 0
+    [0b010, 0b110, 0b010, 0b010, 0b111], // 1
+    [0b111, 0b001, 0b111, 0b100, 0b111], // 2
+    [0b111, 0b001, 0b111, 0b001, 0b111], // 3
+    [0b101, 0b101, 0b111, 0b001, 0b001], // 4
+    [0b111, 0b100, 0b111, 0b001, 0b111], // 5
+    [0b111, 0b100, 0b111, 0b101, 0b111], // 6
+    [0b111, 0b001, 0b001, 0b001, 0b001], // 7
+    [0b111, 0b101, 0b111, 0b101, 0b111], // 8
+    [0b111, 0b101, 0b111, 0b001, 0b111], // 9
+    [0b000, 0b010, 0b000, 0b010, 0b000], // :
+    [0b000, 0b010, 0b000, 0b010, 0b100], // ;
+    [0b001, 0b010, 0b100, 0b010, 0b001], // <
+    [0b000, 0b111, 0b000, 0b111, 0b000], // =
+    [0b100, 0b010, 0b001, 0b010, 0b100], // >
+    [0b111, 0b001, 0b010, 0b000, 0b010], // ?
+    [0b010, 0b101, 0b111, 0b100, 0b011], // @
+    [0b010, 0b101, 0b111, 0b101, 0b101], // A
+    [0b110, 0b101, 0b110, 0b101, 0b110], // B
+    [0b011, 0b100, 0b100, 0b100, 0b011], // C
+    [0b110, 0b101, 0b101, 0b101, 0b110], // D
+    [0b111, 0b100, 0b110, 0b100, 0b111], // E
+    [0b111, 0b100, 0b110, 0b100, 0b100], // F
+    [0b011, 0b100, 0b101, 0b101, 0b011], // G
+    [0b101, 0b101, 0b111, 0b101, 0b101], // H
+    [0b111, 0b010, 0b010, 0b010, 0b111], // I
+    [0b001, 0b001, 0b001, 0b101, 0b010], // J
+    [0b101, 0b101, 0b110, 0b101, 0b101], // K
+    [0b100, 0b100, 0b100, 0b100, 0b111], // L
+    [0b101, 0b111, 0b111, 0b101, 0b101], // M
+    [0b110, 0b101, 0b101, 0b101, 0b101], // N
+    [0b010, 0b101, 0b101, 0b101, 0b010], // O
+    [0b110, 0b101, 0b110, 0b100, 0b100], // P
+    [0b010, 0b101, 0b101, 0b110, 0b011], // Q
+    [0b110, 0b101, 0b110, 0b101, 0b101], // R
+    [0b011, 0b100, 0b010, 0b001, 0b110], // S
+    [0b111, 0b010, 0b010, 0b010, 0b010], // T
+    [0b101, 0b101, 0b101, 0b101, 0b111], // U
+    [0b101, 0b101, 0b101, 0b101, 0b010], // V
+    [0b101, 0b101, 0b111, 0b111, 0b101], // W
+    [0b101, 0b101, 0b010, 0b101, 0b101], // X
+    [0b101, 0b101, 0b010, 0b010, 0b010], // Y
+    [0b111, 0b001, 0b010, 0b100, 0b111], // Z
+    [0b110, 0b100, 0b100, 0b100, 0b110], // [
+    [0b100, 0b100, 0b010, 0b001, 0b001], // \
+    [0b011, 0b001, 0b001, 0b001, 0b011], // ]
+    [0b010, 0b101, 0b000, 0b000, 0b000], // ^
+    [0b000, 0b000, 0b000, 0b000, 0b111], // _
+    [0b100, 0b010, 0b000, 0b000, 0b000], // `
+    [0b000, 0b011, 0b101, 0b101, 0b011], // a
+    [0b100, 0b110, 0b101, 0b101, 0b110], // b
+    [0b000, 0b011, 0b100, 0b100, 0b011], // c
+    [0b001, 0b011, 0b101, 0b101, 0b011], // d
+    [0b000, 0b010, 0b111, 0b100, 0b011], // e
+    [0b001, 0b010, 0b111, 0b010, 0b010], // f
+    [0b011, 0b101, 0b011, 0b001, 0b110], // g
+    [0b100, 0b110, 0b101, 0b101, 0b101], // h
+    [0b010, 0b000, 0b010, 0b010, 0b010], // i
+    [0b001, 0b000, 0b001, 0b101, 0b010], // j
+    [0b100, 0b101, 0b110, 0b110, 0b101], // k
+    [0b110, 0b010, 0b010, 0b010, 0b111], // l
+    [0b000, 0b111, 0b111, 0b111, 0b101], // m
+    [0b000, 0b110, 0b101, 0b101, 0b101], // n
+    [0b000, 0b010, 0b101, 0b101, 0b010], // o
+    [0b000, 0b110, 0b101, 0b110, 0b100], // p
+    [0b000, 0b011, 0b101, 0b011, 0b001], // q
+    [0b000, 0b011, 0b100, 0b100, 0b100], // r
+    [0b000, 0b011, 0b110, 0b011, 0b110], // s
+    [0b010, 0b111, 0b010, 0b010, 0b011], // t
+    [0b000, 0b101, 0b101, 0b101, 0b011], // u
+    [0b000, 0b101, 0b101, 0b111, 0b010], // v
+    [0b000, 0b101, 0b111, 0b111, 0b111], // w
+    [0b000, 0b101, 0b010, 0b010, 0b101], // x
+    [0b000, 0b101, 0b011, 0b001, 0b110], // y
+    [0b000, 0b111, 0b011, 0b110, 0b111], // z
+    [0b011, 0b010, 0b110, 0b010, 0b011], // {
+    [0b010, 0b010, 0b010, 0b010, 0b010], // |
+    [0b110, 0b010, 0b011, 0b010, 0b110], // }
+    [0b000, 0b001, 0b111, 0b100, 0b000], // ~
+];
+
+fn glyph(ch: char) -> &'static [u8; 5] {
+    match ch {
+        ' '..='~' => &FONT[ch as usize - ' ' as usize],
+        _ => &FONT['?' as usize - ' ' as usize],
+    }
+}
+
+/// Characters outside of ASCII which have a reasonable spelling within it.
+fn normalize(text: &str) -> String {
+    text.replace('©', "(c)")
+}
+
+/// Size in pixels of `text` when drawn at the given scale.
+pub fn measure(text: &str, scale: u32) -> (u32, u32) {
+    let len = normalize(text).chars().count() as u32;
+    let width = (len * (GLYPH_WIDTH + 1)).saturating_sub(1);
+    (width * scale, GLYPH_HEIGHT * scale)
+}
+
+/// Draw `text` with its top left corner at `(x, y)`, with each font pixel
+/// covering `scale` x `scale` image pixels. Clipped to the image bounds.
+pub fn draw(image: &mut RgbaImage, x: u32, y: u32, text: &str, scale: u32, color: Rgba<u8>) {
+    for (i, ch) in normalize(text).chars().enumerate() {
+        let origin_x = x + i as u32 * (GLYPH_WIDTH + 1) * scale;
+
+        for (row, bits) in glyph(ch).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let px = origin_x + col * scale + dx;
+                        let py = y + row as u32 * scale + dy;
+
+                        if px < image.width() && py < image.height() {
+                            image.put_pixel(px, py, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_draw() {
+        let color = Rgba([0, 0, 0, 255]);
+        let mut image = RgbaImage::new(16, 8);
+        draw(&mut image, 0, 0, "-1", 1, color);
+
+        assert_eq!(measure("-1", 1), (7, 5));
+        // Middle row of `-`
+        assert_eq!(image.get_pixel(0, 2), &color);
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 0, 0, 0]));
+        // Top of `1` starts in the second glyph column.
+        assert_eq!(image.get_pixel(5, 0), &color);
+        assert_eq!(image.get_pixel(4, 0), &Rgba([0, 0, 0, 0]));
+        assert_eq!(measure("©", 2), (22, 10));
+    }
+}
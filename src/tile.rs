@@ -5,6 +5,7 @@ use std::{f64::consts::PI, ops::RangeInclusive};
 use anyhow::{anyhow, Result};
 use derive_more::{From, Into};
 use geo_types::{Coord, Point};
+use serde::{Deserialize, Deserializer};
 
 const EARTH_RADIUS_METERS: f64 = 6_378_137.0;
 const EARTH_CIRCUMFERENCE: f64 = 2.0 * PI * EARTH_RADIUS_METERS;
@@ -27,11 +28,21 @@ pub struct TileBounds {
 }
 
 impl TileBounds {
+    /// Tiles at `source_zoom` covering `tile`. If `source_zoom` is lower than
+    /// the tile's zoom level, this is the single tile containing it.
     pub fn from(source_zoom: u8, tile: &Tile) -> TileBounds {
-        assert!(
-            source_zoom >= tile.z,
-            "source level must be >= target level"
-        );
+        if source_zoom < tile.z {
+            let zoom_steps = tile.z - source_zoom;
+            let (x, y) = (tile.x >> zoom_steps, tile.y >> zoom_steps);
+
+            return TileBounds {
+                z: source_zoom,
+                xmin: x,
+                ymin: y,
+                xmax: x + 1,
+                ymax: y + 1,
+            };
+        }
 
         let zoom_steps = source_zoom - tile.z;
 
@@ -44,6 +55,20 @@ impl TileBounds {
         }
     }
 
+    /// Grow the bounds by `n` tiles in every direction, staying within the
+    /// tile grid.
+    pub fn expand(&self, n: u32) -> TileBounds {
+        let num_tiles = 1 << self.z;
+
+        TileBounds {
+            z: self.z,
+            xmin: self.xmin.saturating_sub(n),
+            ymin: self.ymin.saturating_sub(n),
+            xmax: (self.xmax + n).min(num_tiles),
+            ymax: (self.ymax + n).min(num_tiles),
+        }
+    }
+
     pub fn from_viewport(
         viewport: &WebMercatorViewport,
         viewport_width: u32,
@@ -125,6 +150,85 @@ impl FromStr for WebMercatorViewport {
     }
 }
 
+impl<'de> Deserialize<'de> for WebMercatorViewport {
+    fn deserialize<D>(deserializer: D) -> Result<WebMercatorViewport, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        WebMercatorViewport::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl WebMercatorViewport {
+    /// Viewport covering the entire map.
+    pub fn world() -> Self {
+        // Just inside the edges, so the corners stay within the tile grid.
+        let edge = ORIGIN_OFFSET - 1.0;
+
+        WebMercatorViewport {
+            sw: WebMercator((-edge, -edge).into()),
+            ne: WebMercator((edge, edge).into()),
+        }
+    }
+
+    /// Square viewport covering at least `radius` meters around `center`.
+    pub fn around(center: LngLat, radius: f64) -> Option<Self> {
+        // Web Mercator stretches distances by 1 / cos(lat).
+        let r = radius / center.0.y().to_radians().cos();
+        let center = center.xy()?;
+        let clamp = |v: f64| v.clamp(-ORIGIN_OFFSET, ORIGIN_OFFSET);
+
+        Some(WebMercatorViewport {
+            sw: WebMercator((clamp(center.0.x() - r), clamp(center.0.y() - r)).into()),
+            ne: WebMercator((clamp(center.0.x() + r), clamp(center.0.y() + r)).into()),
+        })
+    }
+
+    /// Range of tiles at the given zoom level which intersect the viewport.
+    pub fn tile_bounds(&self, zoom: u8) -> TileBounds {
+        let num_tiles = 1u32 << zoom;
+        let sw_tile = self.sw.tile(zoom);
+        let ne_tile = self.ne.tile(zoom);
+
+        TileBounds {
+            z: zoom,
+            xmin: sw_tile.x.min(num_tiles - 1),
+            xmax: (ne_tile.x + 1).min(num_tiles),
+            ymin: ne_tile.y.min(num_tiles - 1),
+            ymax: (sw_tile.y + 1).min(num_tiles),
+        }
+    }
+
+    /// All tiles at the given zoom level which intersect the viewport.
+    pub fn tiles(&self, zoom: u8) -> impl Iterator<Item = Tile> {
+        let max_idx = (1u32 << zoom) - 1;
+        let sw_tile = self.sw.tile(zoom);
+        let ne_tile = self.ne.tile(zoom);
+
+        let (xmin, xmax) = (sw_tile.x.min(max_idx), ne_tile.x.min(max_idx));
+        let (ymin, ymax) = (ne_tile.y.min(max_idx), sw_tile.y.min(max_idx));
+
+        (ymin..=ymax).flat_map(move |y| (xmin..=xmax).map(move |x| Tile::new(x, y, zoom)))
+    }
+
+    /// Viewport bounds as `[west, south, east, north]`.
+    pub fn lnglat_bounds(&self) -> [f64; 4] {
+        let (sw, ne) = (self.sw.lnglat().0, self.ne.lnglat().0);
+        [sw.x(), sw.y(), ne.x(), ne.y()]
+    }
+
+    /// Viewport bounds in Web Mercator meters.
+    pub fn xy_bounds(&self) -> BBox {
+        BBox {
+            left: self.sw.0.x(),
+            bot: self.sw.0.y(),
+            right: self.ne.0.x(),
+            top: self.ne.0.y(),
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub struct BBox {
     pub left: f64,
@@ -266,6 +370,25 @@ impl WebMercator {
 
         TilePixel((px, py).into())
     }
+
+    /// Inverse of `LngLat::xy`.
+    pub fn lnglat(&self) -> LngLat {
+        let lng = (self.0.x() / EARTH_RADIUS_METERS).to_degrees();
+        let lat = (2.0 * (self.0.y() / EARTH_RADIUS_METERS).exp().atan() - PI / 2.0).to_degrees();
+
+        LngLat(Point::new(lng, lat))
+    }
+
+    /// Inverse of `to_tile_pixel`.
+    pub fn from_tile_pixel(px: Coord<u32>, bbox: &BBox, tile_width: u32) -> WebMercator {
+        let width = bbox.right - bbox.left;
+        let height = bbox.top - bbox.bot;
+
+        let x = bbox.left + (px.x as f64 / tile_width as f64) * width;
+        let y = bbox.bot + (px.y as f64 / tile_width as f64) * height;
+
+        WebMercator(Point::new(x, y))
+    }
 }
 
 impl LngLat {
@@ -370,6 +493,10 @@ mod tests {
             // Going to be off by a bit, but is this too much?
             close_enough!(xy.0.x(), *x, 2.0);
             close_enough!(xy.0.y(), *y, 2.0);
+
+            let ll = xy.lnglat();
+            close_enough!(ll.0.x(), *lng, 0.000001);
+            close_enough!(ll.0.y(), *lat, 0.000001);
         }
     }
 
@@ -394,6 +521,45 @@ mod tests {
         assert_eq!(tile, Tile::new(285, 193, 9));
     }
 
+    #[test]
+    fn test_viewport_tiles() {
+        let world = WebMercatorViewport::from_str("-180,-85,180,85").unwrap();
+        assert_eq!(world.tiles(0).collect::<Vec<_>>(), vec![Tile::new(0, 0, 0)]);
+        assert_eq!(world.tiles(2).count(), 16);
+
+        let small = WebMercatorViewport::from_str("20.68,40.12,20.69,40.13").unwrap();
+        assert_eq!(
+            small.tiles(9).collect::<Vec<_>>(),
+            vec![Tile::new(285, 193, 9)]
+        );
+    }
+
+    #[test]
+    fn test_viewport_tile_bounds() {
+        let world = WebMercatorViewport::from_str("-180,-85,180,85").unwrap();
+        let bounds = world.tile_bounds(2);
+        assert_eq!((bounds.xmin, bounds.xmax), (0, 4));
+        assert_eq!((bounds.ymin, bounds.ymax), (0, 4));
+
+        let bounds = WebMercatorViewport::world().tile_bounds(24);
+        assert_eq!((bounds.xmin, bounds.xmax), (0, 1 << 24));
+        assert_eq!((bounds.ymin, bounds.ymax), (0, 1 << 24));
+
+        let viewport = WebMercatorViewport::from_str("20.6,40.1,20.7,40.2").unwrap();
+        let bounds = viewport.tile_bounds(10);
+        assert_eq!((bounds.xmin, bounds.xmax), (570, 571));
+        assert_eq!((bounds.ymin, bounds.ymax), (386, 388));
+
+        // ~100m either way, both at the equator and further north.
+        let viewport = WebMercatorViewport::around(LngLat::new(0.0, 0.0), 100.0).unwrap();
+        let [w, s, e, n] = viewport.lnglat_bounds();
+        assert!((e - w - 0.0018).abs() < 0.0001 && (n - s - 0.0018).abs() < 0.0001);
+
+        let viewport = WebMercatorViewport::around(LngLat::new(13.4, 60.0), 100.0).unwrap();
+        let [w, s, e, n] = viewport.lnglat_bounds();
+        assert!((e - w - 0.0036).abs() < 0.0001 && (n - s - 0.0018).abs() < 0.0001);
+    }
+
     #[test]
     fn test_bbox_clipping() {
         let bbox = BBox {
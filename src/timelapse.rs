@@ -0,0 +1,203 @@
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{imageops, Delay, Frame, Rgba, RgbaImage};
+use time::{Date, Month};
+
+use crate::activity;
+use crate::db::{ActivityFilter, Database, PropertyFilter};
+use crate::raster::{self, Gradient, Intensity, Stroke};
+use crate::tile::WebMercatorViewport;
+
+/// Time span covered by each frame.
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum FrameStep {
+    Week,
+    Month,
+    Year,
+}
+
+impl FrameStep {
+    /// Start of the step containing `date`.
+    fn floor(&self, date: Date) -> Date {
+        match self {
+            FrameStep::Week => {
+                date - time::Duration::days(date.weekday().number_days_from_monday() as i64)
+            }
+            FrameStep::Month => date.replace_day(1).expect("valid date"),
+            FrameStep::Year => {
+                Date::from_calendar_date(date.year(), Month::January, 1).expect("valid date")
+            }
+        }
+    }
+
+    fn next(&self, date: Date) -> Date {
+        match self {
+            FrameStep::Week => date + time::Duration::weeks(1),
+            FrameStep::Month => {
+                let year = date.year() + (date.month() == Month::December) as i32;
+                Date::from_calendar_date(year, date.month().next(), 1).expect("valid date")
+            }
+            FrameStep::Year => {
+                Date::from_calendar_date(date.year() + 1, Month::January, 1).expect("valid date")
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct FrameSpan {
+    start: Date,
+    end: Date,
+}
+
+/// Split `[from, to]` into consecutive frames, aligned to the step size.
+fn frame_spans(from: Date, to: Date, step: FrameStep) -> Vec<FrameSpan> {
+    let mut spans = vec![];
+    let mut start = step.floor(from);
+
+    while start <= to {
+        let end = step.next(start);
+        spans.push(FrameSpan { start, end });
+        start = end;
+    }
+
+    spans
+}
+
+pub struct Timelapse {
+    pub viewport: WebMercatorViewport,
+    pub width: u32,
+    pub height: u32,
+    pub step: FrameStep,
+    /// Include all activities up to the end of each frame, rather than only
+    /// the ones within it.
+    pub cumulative: bool,
+    pub intensity: Intensity,
+    pub stroke: Stroke,
+    pub background: Option<Rgba<u8>>,
+    /// Time each frame is shown in animated output.
+    pub frame_delay_ms: u32,
+}
+
+impl Timelapse {
+    /// Render one frame per step between `from` and `to` (defaulting to the
+    /// dates of the first and last activity).
+    ///
+    /// If `output` ends with `.gif`, frames are written as an animated GIF,
+    /// otherwise as numbered PNGs into the `output` directory.
+    ///
+    /// Returns the number of frames rendered.
+    pub fn render(
+        &self,
+        db: &Database,
+        gradient: &Gradient,
+        from: Option<Date>,
+        to: Option<Date>,
+        props: Option<PropertyFilter>,
+        output: &Path,
+    ) -> Result<usize> {
+        let (from, to) = match (from, to) {
+            (Some(from), Some(to)) => (from, to),
+            _ => {
+                let all = ActivityFilter::new(None, None, props.clone());
+                let (first, last) = activity::date_range(db, &all)?
+                    .ok_or_else(|| anyhow!("no activities match the given filter"))?;
+                (from.unwrap_or(first), to.unwrap_or(last))
+            }
+        };
+
+        let spans = frame_spans(from, to, self.step);
+        let is_gif = output.extension().is_some_and(|ext| ext == "gif");
+
+        let mut gif = if is_gif {
+            let mut encoder = GifEncoder::new(File::create(output)?);
+            encoder.set_repeat(Repeat::Infinite)?;
+            Some(encoder)
+        } else {
+            std::fs::create_dir_all(output)?;
+            None
+        };
+
+        for (i, span) in spans.iter().enumerate() {
+            tracing::info!(start = %span.start, end = %span.end, "rendering frame");
+
+            let after = if self.cumulative { from } else { span.start };
+            let filter = ActivityFilter::new(Some(span.end), Some(after), props.clone());
+
+            let heatmap = raster::render_view(
+                self.viewport.clone(),
+                gradient,
+                self.intensity,
+                self.stroke,
+                None,
+                self.width,
+                self.height,
+                &filter,
+                db,
+            )?;
+
+            let frame = match self.background {
+                Some(color) => {
+                    let mut image = RgbaImage::from_pixel(heatmap.width(), heatmap.height(), color);
+                    imageops::overlay(&mut image, &heatmap, 0, 0);
+                    image
+                }
+                None => heatmap,
+            };
+
+            match gif {
+                Some(ref mut encoder) => encoder.encode_frame(Frame::from_parts(
+                    frame,
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(self.frame_delay_ms, 1),
+                ))?,
+                None => {
+                    let path = output.join(format!("{:04}_{}.png", i + 1, span.start));
+                    frame.save(path)?;
+                }
+            }
+        }
+
+        Ok(spans.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u8, day: u8) -> Date {
+        Date::from_calendar_date(year, Month::try_from(month).unwrap(), day).unwrap()
+    }
+
+    #[test]
+    fn test_frame_spans() {
+        let spans = frame_spans(date(2023, 11, 15), date(2024, 1, 1), FrameStep::Month);
+        assert_eq!(
+            spans,
+            vec![
+                FrameSpan {
+                    start: date(2023, 11, 1),
+                    end: date(2023, 12, 1)
+                },
+                FrameSpan {
+                    start: date(2023, 12, 1),
+                    end: date(2024, 1, 1)
+                },
+                FrameSpan {
+                    start: date(2024, 1, 1),
+                    end: date(2024, 2, 1)
+                },
+            ]
+        );
+
+        let spans = frame_spans(date(2024, 5, 1), date(2024, 5, 6), FrameStep::Week);
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].start, date(2024, 4, 29));
+    }
+}
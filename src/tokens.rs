@@ -0,0 +1,177 @@
+//! Scoped API tokens, so that different devices can be given different
+//! credentials (e.g. upload-only for a phone, render-only for a dashboard).
+//!
+//! Like user tokens, these are only shown once when created and the database
+//! stores a hash of them.
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use time::OffsetDateTime;
+
+use crate::db::Database;
+
+/// What a token may be used for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Upload and delete activities
+    Upload,
+    /// Render tiles and images
+    Render,
+    /// Everything
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Upload => "upload",
+            Scope::Render => "render",
+            Scope::Admin => "admin",
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self> {
+        Scope::from_str(s, false).map_err(|_| anyhow!("unknown token scope: {}", s))
+    }
+
+    /// Whether a token with this scope can be used where `required` is needed.
+    pub fn allows(&self, required: Scope) -> bool {
+        *self == Scope::Admin || *self == required
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub scope: Scope,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+impl ApiToken {
+    fn from_row(row: &rusqlite::Row) -> Result<Self> {
+        Ok(ApiToken {
+            id: row.get_unwrap(0),
+            name: row.get_unwrap(1),
+            scope: Scope::parse(row.get_ref_unwrap(2).as_str()?)?,
+            created_at: OffsetDateTime::from_unix_timestamp(row.get_unwrap(3))?,
+        })
+    }
+}
+
+const TOKEN_COLUMNS: &str = "id, name, scope, created_at";
+
+/// Generate a random (hex encoded) API token.
+pub fn generate_token() -> Result<String> {
+    let mut bytes = [0u8; 24];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("failed to generate token: {}", e))?;
+
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Tokens are random, so a plain hash is enough to avoid storing them as-is.
+pub fn hash_token(token: &str) -> String {
+    Sha1::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Issue a new token, returning it along with the secret value.
+pub fn create(db: &Database, name: &str, scope: Scope) -> Result<(ApiToken, String)> {
+    if name.trim().is_empty() {
+        return Err(anyhow!("token name can't be empty"));
+    }
+
+    let token = generate_token()?;
+    let conn = db.connection()?;
+    conn.execute(
+        "\
+        INSERT INTO tokens (name, scope, token_hash, created_at) \
+        VALUES (?, ?, ?, ?)",
+        params![
+            name,
+            scope.as_str(),
+            hash_token(&token),
+            OffsetDateTime::now_utc().unix_timestamp()
+        ],
+    )
+    .map_err(|err| match err {
+        rusqlite::Error::SqliteFailure(e, _)
+            if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            anyhow!("token already exists: {}", name)
+        }
+        err => err.into(),
+    })?;
+
+    let api_token = ApiToken {
+        id: conn.last_insert_rowid(),
+        name: name.to_string(),
+        scope,
+        created_at: OffsetDateTime::now_utc(),
+    };
+    Ok((api_token, token))
+}
+
+/// Revoke the named token, returning whether it existed.
+pub fn revoke(db: &Database, name: &str) -> Result<bool> {
+    let conn = db.connection()?;
+    let num_deleted = conn.execute("DELETE FROM tokens WHERE name = ?", params![name])?;
+
+    Ok(num_deleted > 0)
+}
+
+pub fn list(db: &Database) -> Result<Vec<ApiToken>> {
+    let conn = db.connection()?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM tokens ORDER BY name",
+        TOKEN_COLUMNS
+    ))?;
+    let mut rows = stmt.query([])?;
+
+    let mut tokens = vec![];
+    while let Some(row) = rows.next()? {
+        tokens.push(ApiToken::from_row(row)?);
+    }
+
+    Ok(tokens)
+}
+
+/// Look up an issued token by its secret value.
+pub fn authenticate(conn: &rusqlite::Connection, token: &str) -> Result<Option<ApiToken>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM tokens WHERE token_hash = ?",
+        TOKEN_COLUMNS
+    ))?;
+
+    let mut rows = stmt.query(params![hash_token(token)])?;
+    rows.next()?.map(ApiToken::from_row).transpose()
+}
+
+/// Whether any tokens have been issued.
+pub fn any_issued(conn: &rusqlite::Connection) -> Result<bool> {
+    Ok(conn.query_row("SELECT EXISTS (SELECT 1 FROM tokens)", [], |row| row.get(0))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_allows() {
+        assert!(Scope::Upload.allows(Scope::Upload));
+        assert!(!Scope::Upload.allows(Scope::Render));
+        assert!(!Scope::Render.allows(Scope::Upload));
+        assert!(Scope::Admin.allows(Scope::Upload));
+        assert!(Scope::Admin.allows(Scope::Render));
+
+        assert_eq!(Scope::parse("render").unwrap(), Scope::Render);
+        assert!(Scope::parse("everything").is_err());
+    }
+}
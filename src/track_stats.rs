@@ -0,0 +1,161 @@
+//! Summary statistics over activities, such as total distance per year.
+//!
+//! Stored tracks only contain coordinates, so times and elevation gain come
+//! from activity properties (as set by the Strava integration or a metadata
+//! CSV). Distance uses the `distance` property when present, falling back to
+//! the length of the stored track.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use geo::HaversineLength;
+use geo_types::MultiLineString;
+use serde::{Deserialize, Serialize};
+
+use crate::activity::{self, ActivitySummary};
+use crate::db::{ActivityFilter, Database};
+
+/// Key used for activities which are missing the value being grouped on.
+const UNKNOWN_GROUP: &str = "unknown";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupBy {
+    /// Calendar year of the start time (`2024`)
+    Year,
+    /// Calendar month of the start time (`2024-05`)
+    Month,
+    /// Value of the `activity_type` property
+    ActivityType,
+}
+
+impl GroupBy {
+    fn key(&self, activity: &ActivitySummary) -> String {
+        let key = match self {
+            GroupBy::Year => activity.start_time.map(|ts| ts.year().to_string()),
+            GroupBy::Month => activity
+                .start_time
+                .map(|ts| format!("{}-{:02}", ts.year(), ts.month() as u8)),
+            GroupBy::ActivityType => activity
+                .properties
+                .get("activity_type")
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string()),
+        };
+
+        key.unwrap_or_else(|| UNKNOWN_GROUP.to_string())
+    }
+}
+
+/// Totals over a group of activities. Distance is in meters, times in
+/// seconds and elevation gain in meters.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Totals {
+    pub key: String,
+    pub activities: usize,
+    pub distance: f64,
+    pub elapsed_time: f64,
+    pub moving_time: f64,
+    pub elevation_gain: f64,
+}
+
+impl Totals {
+    fn add(&mut self, activity: &ActivitySummary, tracks: Option<&MultiLineString>) {
+        let prop = |key: &str| activity.properties.get(key).and_then(|v| v.as_f64());
+
+        self.activities += 1;
+        self.distance += activity_distance(activity, tracks).unwrap_or_default();
+        self.elapsed_time += prop("elapsed_time").unwrap_or_default();
+        self.moving_time += prop("moving_time").unwrap_or_default();
+        self.elevation_gain += prop("elevation_gain").unwrap_or_default();
+    }
+}
+
+/// Distance of an activity in meters, from the `distance` property if set or
+/// the length of the stored track otherwise.
+pub fn activity_distance(
+    activity: &ActivitySummary,
+    tracks: Option<&MultiLineString>,
+) -> Option<f64> {
+    activity
+        .properties
+        .get("distance")
+        .and_then(|v| v.as_f64())
+        .or_else(|| tracks.map(track_distance))
+}
+
+/// Length of all lines in `tracks`, in meters.
+pub fn track_distance(tracks: &MultiLineString) -> f64 {
+    tracks.iter().map(|line| line.haversine_length()).sum()
+}
+
+/// Compute totals for all activities matching `filter`, either as a single
+/// group (keyed `all`) or split by `group_by`, ordered by key.
+pub fn summarize(
+    db: &Database,
+    filter: &ActivityFilter,
+    group_by: Option<GroupBy>,
+) -> Result<Vec<Totals>> {
+    let mut groups: BTreeMap<String, Totals> = BTreeMap::new();
+
+    for activity in activity::list(db, filter, None)? {
+        let key = match group_by {
+            Some(group_by) => group_by.key(&activity),
+            None => "all".to_string(),
+        };
+
+        // Only decode the track when we actually need it.
+        let tracks = if activity.properties.contains_key("distance") {
+            None
+        } else {
+            activity::load_tracks(db, activity.id)?
+        };
+
+        groups
+            .entry(key.clone())
+            .or_insert_with(|| Totals {
+                key,
+                ..Default::default()
+            })
+            .add(&activity, tracks.as_ref());
+    }
+
+    Ok(groups.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo_types::line_string;
+    use time::format_description::well_known::Rfc3339;
+    use time::OffsetDateTime;
+
+    #[test]
+    fn test_totals() {
+        let activity = ActivitySummary {
+            id: 1,
+            file: "a.gpx".into(),
+            title: None,
+            start_time: Some(OffsetDateTime::parse("2024-05-01T12:00:00Z", &Rfc3339).unwrap()),
+            properties: serde_json::from_str(r#"{"moving_time": 60, "activity_type": "Ride"}"#)
+                .unwrap(),
+            athlete_id: None,
+            user_id: None,
+        };
+
+        assert_eq!(GroupBy::Month.key(&activity), "2024-05");
+        assert_eq!(GroupBy::ActivityType.key(&activity), "Ride");
+
+        // One degree of longitude along the equator.
+        let tracks = MultiLineString::new(vec![line_string![(x: 0.0, y: 0.0), (x: 1.0, y: 0.0)]]);
+
+        let mut totals = Totals::default();
+        totals.add(&activity, Some(&tracks));
+        totals.add(&activity, None);
+
+        assert_eq!(totals.activities, 2);
+        assert_eq!(totals.moving_time, 120.0);
+        assert!((totals.distance - 111_195.0).abs() < 1.0);
+    }
+}
@@ -0,0 +1,156 @@
+//! User accounts for hosting a shared instance (e.g. for a cycling club).
+//!
+//! Each user gets an API token for uploading their activities, and a page at
+//! `/u/:name/` showing only their own heatmap. Tokens are only shown once
+//! when created, the database stores a hash of them.
+
+use anyhow::{anyhow, Result};
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::db::Database;
+use crate::tokens::{generate_token, hash_token};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct User {
+    pub id: i64,
+    pub name: String,
+    /// Strava athlete whose webhook activities belong to this user.
+    pub strava_athlete_id: Option<u64>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+impl User {
+    fn from_row(row: &rusqlite::Row) -> Result<Self> {
+        Ok(User {
+            id: row.get_unwrap(0),
+            name: row.get_unwrap(1),
+            strava_athlete_id: row.get_unwrap(2),
+            created_at: OffsetDateTime::from_unix_timestamp(row.get_unwrap(3))?,
+        })
+    }
+}
+
+const USER_COLUMNS: &str = "id, name, strava_athlete_id, created_at";
+
+/// Names are used in URLs, so keep them simple.
+fn validate_name(name: &str) -> Result<()> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || !name.chars().all(valid_char) {
+        return Err(anyhow!(
+            "user names may only contain letters, numbers, '-' and '_'"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Create a new user, returning it along with its API token.
+pub fn create(db: &Database, name: &str, strava_athlete_id: Option<u64>) -> Result<(User, String)> {
+    validate_name(name)?;
+
+    let token = generate_token()?;
+    let conn = db.connection()?;
+    conn.execute(
+        "\
+        INSERT INTO users (name, token_hash, strava_athlete_id, created_at) \
+        VALUES (?, ?, ?, ?)",
+        params![
+            name,
+            hash_token(&token),
+            strava_athlete_id,
+            OffsetDateTime::now_utc().unix_timestamp()
+        ],
+    )
+    .map_err(|err| match err {
+        rusqlite::Error::SqliteFailure(e, _)
+            if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            anyhow!("user already exists: {}", name)
+        }
+        err => err.into(),
+    })?;
+
+    let user = find_by_name(&conn, name)?.expect("just inserted");
+    Ok((user, token))
+}
+
+/// Remove a user. Their activities are kept, but no longer belong to anyone.
+pub fn remove(db: &Database, name: &str) -> Result<bool> {
+    let mut conn = db.connection()?;
+    let tx = conn.transaction()?;
+
+    let Some(user) = find_by_name(&tx, name)? else {
+        return Ok(false);
+    };
+
+    tx.execute(
+        "UPDATE activities SET user_id = NULL WHERE user_id = ?",
+        params![user.id],
+    )?;
+    tx.execute("DELETE FROM users WHERE id = ?", params![user.id])?;
+    tx.commit()?;
+
+    Ok(true)
+}
+
+pub fn list(db: &Database) -> Result<Vec<User>> {
+    let conn = db.connection()?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM users ORDER BY name", USER_COLUMNS))?;
+    let mut rows = stmt.query([])?;
+
+    let mut users = vec![];
+    while let Some(row) = rows.next()? {
+        users.push(User::from_row(row)?);
+    }
+
+    Ok(users)
+}
+
+pub fn find_by_name(conn: &rusqlite::Connection, name: &str) -> Result<Option<User>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM users WHERE name = ?",
+        USER_COLUMNS
+    ))?;
+
+    let mut rows = stmt.query(params![name])?;
+    rows.next()?.map(User::from_row).transpose()
+}
+
+/// Look up the user a token was issued to.
+pub fn authenticate(conn: &rusqlite::Connection, token: &str) -> Result<Option<User>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {} FROM users WHERE token_hash = ?",
+        USER_COLUMNS
+    ))?;
+
+    let mut rows = stmt.query(params![hash_token(token)])?;
+    rows.next()?.map(User::from_row).transpose()
+}
+
+/// ID of the user linked to the given Strava athlete, if any.
+pub fn for_strava_athlete(conn: &rusqlite::Connection, athlete_id: u64) -> Result<Option<i64>> {
+    Ok(conn
+        .query_row(
+            "SELECT id FROM users WHERE strava_athlete_id = ?",
+            params![athlete_id],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("alice").is_ok());
+        assert!(validate_name("club-member_2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("a/b").is_err());
+        assert!(validate_name("bob smith").is_err());
+    }
+}
@@ -0,0 +1,301 @@
+//! Resolution independent output of activity tracks, for printing large
+//! posters without pixelation.
+//!
+//! Instead of the rasterized tiles, this draws the stored tracks of every
+//! matching activity as a separate path, so overlapping activities still
+//! build up in intensity with a translucent stroke color.
+
+use std::io::Write;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::Rgba;
+
+use crate::activity;
+use crate::db::{ActivityFilter, Database};
+use crate::tile::{BBox, LngLat, WebMercatorViewport};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum RenderFormat {
+    #[default]
+    Png,
+    Svg,
+    Pdf,
+}
+
+/// Points closer than this to the previous one are dropped, which keeps
+/// output size reasonable without any visible difference.
+const MIN_POINT_DISTANCE: f64 = 0.25;
+
+/// Activity tracks projected into output coordinates, with the origin in the
+/// top left corner.
+struct Drawing {
+    width: f64,
+    height: f64,
+    /// Stroke width, in output units (pixels for SVG, points for PDF).
+    line_width: f64,
+    lines: Vec<Vec<(f64, f64)>>,
+}
+
+impl Drawing {
+    /// Project the visible tracks of all activities matching `filter`,
+    /// scaling `viewport` uniformly to fit and centering it.
+    fn new(
+        db: &Database,
+        viewport: &WebMercatorViewport,
+        width: u32,
+        height: u32,
+        line_width: u32,
+        filter: &ActivityFilter,
+    ) -> Result<Self> {
+        let bounds = viewport.xy_bounds();
+        let (width, height) = (width as f64, height as f64);
+        let (bounds_w, bounds_h) = (bounds.right - bounds.left, bounds.top - bounds.bot);
+
+        let scale = f64::min(width / bounds_w, height / bounds_h);
+        let offset_x = (width - bounds_w * scale) / 2.0;
+        let offset_y = (height - bounds_h * scale) / 2.0;
+
+        let mut lines = vec![];
+        for summary in activity::list(db, filter, None)? {
+            let Some(tracks) = activity::load_tracks(db, summary.id)? else {
+                continue;
+            };
+
+            for line in activity::visible_tracks(&tracks, &summary, &db.config).iter() {
+                let points: Vec<_> = line
+                    .points()
+                    .filter_map(|pt| LngLat::from(pt).xy())
+                    .map(|pt| (pt.0.x(), pt.0.y()))
+                    .collect();
+
+                if !intersects(&points, &bounds) {
+                    continue;
+                }
+
+                let mut projected: Vec<(f64, f64)> = Vec::with_capacity(points.len());
+                for (x, y) in points {
+                    let pt = (
+                        offset_x + (x - bounds.left) * scale,
+                        offset_y + (bounds.top - y) * scale,
+                    );
+
+                    let is_near = projected.last().is_some_and(|prev| {
+                        (pt.0 - prev.0).hypot(pt.1 - prev.1) < MIN_POINT_DISTANCE
+                    });
+
+                    if !is_near {
+                        projected.push(pt);
+                    }
+                }
+
+                if projected.len() >= 2 {
+                    lines.push(projected);
+                }
+            }
+        }
+
+        Ok(Drawing {
+            width,
+            height,
+            line_width: line_width as f64,
+            lines,
+        })
+    }
+
+    fn write_svg<W: Write>(&self, mut w: W, color: Rgba<u8>) -> Result<()> {
+        let [r, g, b, a] = color.0;
+
+        writeln!(
+            w,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}">"#,
+            self.width, self.height
+        )?;
+        writeln!(
+            w,
+            r##"<g fill="none" stroke="#{:02x}{:02x}{:02x}" stroke-opacity="{:.3}" stroke-width="{}" stroke-linecap="round" stroke-linejoin="round">"##,
+            r,
+            g,
+            b,
+            a as f64 / 255.0,
+            self.line_width
+        )?;
+
+        for line in &self.lines {
+            write!(w, r#"<path d=""#)?;
+            for (i, (x, y)) in line.iter().enumerate() {
+                let cmd = if i == 0 { 'M' } else { 'L' };
+                write!(w, "{}{:.2} {:.2}", cmd, x, y)?;
+            }
+            writeln!(w, r#""/>"#)?;
+        }
+
+        writeln!(w, "</g>")?;
+        writeln!(w, "</svg>")?;
+
+        Ok(())
+    }
+
+    /// Single page PDF, with the page size matching the drawing in points.
+    fn write_pdf<W: Write>(&self, mut w: W, color: Rgba<u8>) -> Result<()> {
+        let [r, g, b, a] = color.0.map(|c| c as f64 / 255.0);
+
+        let mut content = format!(
+            "/GS1 gs {:.3} {:.3} {:.3} RG {} w 1 J 1 j\n",
+            r, g, b, self.line_width
+        );
+        for line in &self.lines {
+            for (i, (x, y)) in line.iter().enumerate() {
+                let op = if i == 0 { "m" } else { "l" };
+                // PDF puts the origin in the bottom left corner.
+                content.push_str(&format!("{:.2} {:.2} {}\n", x, self.height - y, op));
+            }
+            content.push_str("S\n");
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content.as_bytes())?;
+        let content = encoder.finish()?;
+
+        let mut objects: Vec<Vec<u8>> = vec![
+            b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+            b"<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_vec(),
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+                 /Resources << /ExtGState << /GS1 5 0 R >> >> /Contents 4 0 R >>",
+                self.width, self.height
+            )
+            .into_bytes(),
+        ];
+
+        let mut stream = format!(
+            "<< /Length {} /Filter /FlateDecode >>\nstream\n",
+            content.len()
+        )
+        .into_bytes();
+        stream.extend_from_slice(&content);
+        stream.extend_from_slice(b"\nendstream");
+        objects.push(stream);
+        objects.push(format!("<< /Type /ExtGState /CA {:.3} >>", a).into_bytes());
+
+        let mut out = b"%PDF-1.4\n".to_vec();
+        let mut offsets = vec![];
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+            out.extend_from_slice(object);
+            out.extend_from_slice(b"\nendobj\n");
+        }
+
+        let xref_offset = out.len();
+        out.extend_from_slice(format!("xref\n0 {}\n", objects.len() + 1).as_bytes());
+        out.extend_from_slice(b"0000000000 65535 f \n");
+        for offset in offsets {
+            out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        out.extend_from_slice(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                objects.len() + 1,
+                xref_offset
+            )
+            .as_bytes(),
+        );
+
+        w.write_all(&out)?;
+        Ok(())
+    }
+}
+
+/// Whether the bounding box of `points` overlaps `bounds`.
+fn intersects(points: &[(f64, f64)], bounds: &BBox) -> bool {
+    let mut left = f64::INFINITY;
+    let mut right = f64::NEG_INFINITY;
+    let mut bot = f64::INFINITY;
+    let mut top = f64::NEG_INFINITY;
+
+    for &(x, y) in points {
+        left = left.min(x);
+        right = right.max(x);
+        bot = bot.min(y);
+        top = top.max(y);
+    }
+
+    left <= bounds.right && right >= bounds.left && bot <= bounds.top && top >= bounds.bot
+}
+
+/// Draw the tracks of all activities matching `filter` within `viewport` as
+/// vector paths in the given `format`.
+///
+/// Privacy masks and trimming are always applied, as with rendered tiles.
+#[allow(clippy::too_many_arguments)]
+pub fn render<W: Write>(
+    db: &Database,
+    viewport: &WebMercatorViewport,
+    width: u32,
+    height: u32,
+    line_width: u32,
+    filter: &ActivityFilter,
+    color: Rgba<u8>,
+    format: RenderFormat,
+    w: W,
+) -> Result<()> {
+    let drawing = Drawing::new(db, viewport, width, height, line_width, filter)?;
+
+    match format {
+        RenderFormat::Svg => drawing.write_svg(w, color),
+        RenderFormat::Pdf => drawing.write_pdf(w, color),
+        RenderFormat::Png => unreachable!("PNG output is rasterized"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drawing() -> Drawing {
+        Drawing {
+            width: 100.0,
+            height: 50.0,
+            line_width: 1.0,
+            lines: vec![vec![(10.0, 10.0), (90.0, 40.0)]],
+        }
+    }
+
+    #[test]
+    fn test_write_svg() {
+        let mut out = vec![];
+        drawing()
+            .write_svg(&mut out, Rgba([0xff, 0x00, 0x80, 0x80]))
+            .unwrap();
+
+        let svg = String::from_utf8(out).unwrap();
+        assert!(svg.contains(r#"viewBox="0 0 100 50""#));
+        assert!(svg.contains(r##"stroke="#ff0080" stroke-opacity="0.502""##));
+        assert!(svg.contains(r#"<path d="M10.00 10.00L90.00 40.00"/>"#));
+    }
+
+    #[test]
+    fn test_write_pdf() {
+        let mut out = vec![];
+        drawing()
+            .write_pdf(&mut out, Rgba([0xff, 0x00, 0x80, 0xff]))
+            .unwrap();
+
+        assert!(out.starts_with(b"%PDF-1.4\n"));
+        assert!(out.ends_with(b"%%EOF\n"));
+
+        // The cross reference table must point at the start of each object.
+        let tail = String::from_utf8(out[out.len() - 32..].to_vec()).unwrap();
+        let startxref: usize = tail.lines().rev().nth(1).unwrap().parse().unwrap();
+        let xref = String::from_utf8(out[startxref..].to_vec()).unwrap();
+        assert!(xref.starts_with("xref\n0 6\n"));
+
+        for (i, entry) in xref.lines().skip(3).take(5).enumerate() {
+            let offset: usize = entry[..10].parse().unwrap();
+            assert!(out[offset..].starts_with(format!("{} 0 obj\n", i + 1).as_bytes()));
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Saved views: named sets of tile parameters (filter, colors, dates), so
+//! maps can link to `/view/:name/tile/...` and pick up changes to the view
+//! without updating every URL.
+
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+
+use crate::date;
+use crate::db::{Database, PropertyFilter};
+use crate::raster::Gradient;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct View {
+    /// Listed separately, as the view's key.
+    #[serde(skip)]
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    /// Name of a gradient, as used in `?color=`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gradient: Option<String>,
+    /// Kept as given, so relative dates like `-90d` stay relative.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+}
+
+impl View {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(View {
+            name: row.get(0)?,
+            filter: row.get(1)?,
+            color: row.get(2)?,
+            gradient: row.get(3)?,
+            before: row.get(4)?,
+            after: row.get(5)?,
+        })
+    }
+
+    /// Check that the view's parameters would be accepted by the tile server.
+    fn validate(&self) -> Result<()> {
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if self.name.is_empty() || !self.name.chars().all(valid_char) {
+            return Err(anyhow!(
+                "view names may only contain letters, numbers, '-' and '_'"
+            ));
+        }
+
+        if self.color.is_some() && self.gradient.is_some() {
+            return Err(anyhow!("cannot specify both gradient and color"));
+        }
+
+        if let Some(ref filter) = self.filter {
+            PropertyFilter::from_str(filter)?;
+        }
+        if let Some(ref gradient) = self.gradient {
+            Gradient::from_str(gradient).map_err(|_| anyhow!("invalid gradient"))?;
+        }
+        for date in [&self.before, &self.after].into_iter().flatten() {
+            date::parse_date(date)?;
+        }
+
+        Ok(())
+    }
+
+    /// The view's settings as tile query parameters.
+    fn query_pairs(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("filter", &self.filter),
+            ("color", &self.color),
+            ("gradient", &self.gradient),
+            ("before", &self.before),
+            ("after", &self.after),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value.as_deref()?)))
+    }
+
+    /// Add the view's settings to a request's query string. Parameters given
+    /// in the request take precedence, with `color` and `gradient` counting
+    /// as one.
+    pub fn apply_to_query(&self, query: &str) -> Result<String> {
+        let mut pairs: Vec<(String, String)> = serde_urlencoded::from_str(query)?;
+
+        let mut given: HashSet<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+        if given.contains("color") || given.contains("gradient") {
+            given.extend(["color", "gradient"]);
+        }
+
+        let defaults: Vec<_> = self
+            .query_pairs()
+            .filter(|(key, _)| !given.contains(key))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        pairs.extend(defaults);
+
+        Ok(serde_urlencoded::to_string(pairs)?)
+    }
+}
+
+const VIEW_COLUMNS: &str = "name, filter, color, gradient, before, after";
+
+/// Add a view, or replace the one with the same name.
+pub fn save(db: &Database, view: &View) -> Result<()> {
+    view.validate()?;
+
+    db.connection()?.execute(
+        &format!(
+            "INSERT OR REPLACE INTO views ({}) VALUES (?, ?, ?, ?, ?, ?)",
+            VIEW_COLUMNS
+        ),
+        params![
+            view.name,
+            view.filter,
+            view.color,
+            view.gradient,
+            view.before,
+            view.after
+        ],
+    )?;
+
+    Ok(())
+}
+
+pub fn remove(db: &Database, name: &str) -> Result<bool> {
+    let removed = db
+        .connection()?
+        .execute("DELETE FROM views WHERE name = ?", params![name])?;
+    Ok(removed > 0)
+}
+
+pub fn list(db: &Database) -> Result<Vec<View>> {
+    let conn = db.connection()?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM views ORDER BY name", VIEW_COLUMNS))?;
+    let views = stmt
+        .query_map([], View::from_row)?
+        .collect::<Result<_, _>>()?;
+
+    Ok(views)
+}
+
+pub fn find(db: &Database, name: &str) -> Result<Option<View>> {
+    let view = db
+        .connection()?
+        .query_row(
+            &format!("SELECT {} FROM views WHERE name = ?", VIEW_COLUMNS),
+            params![name],
+            View::from_row,
+        )
+        .optional()?;
+
+    Ok(view)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_view_to_query() {
+        let view = View {
+            name: "commute".to_string(),
+            filter: Some("commute=true".to_string()),
+            color: Some("blue-red".to_string()),
+            after: Some("-90d".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            view.apply_to_query("").unwrap(),
+            "filter=commute%3Dtrue&color=blue-red&after=-90d"
+        );
+
+        // Anything given in the request wins, and a gradient replaces the
+        // view's color.
+        assert_eq!(
+            view.apply_to_query("after=2024-01-01&gradient=0:fff")
+                .unwrap(),
+            "after=2024-01-01&gradient=0%3Afff&filter=commute%3Dtrue"
+        );
+    }
+
+    #[test]
+    fn test_validate_view() {
+        let view = |filter: &str| View {
+            name: "rides".to_string(),
+            filter: Some(filter.to_string()),
+            ..Default::default()
+        };
+
+        assert!(view("activity_type=Ride").validate().is_ok());
+        assert!(view("{not json").validate().is_err());
+        assert!(View {
+            name: "no spaces".to_string(),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
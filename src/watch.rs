@@ -0,0 +1,118 @@
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use anyhow::Result;
+use notify_debouncer_mini::new_debouncer;
+use notify_debouncer_mini::notify::RecursiveMode;
+
+use crate::activity::{self, DedupeBy, PropertySource};
+use crate::db::Database;
+
+/// How long to wait for writes to a file to settle before importing it.
+/// Sync tools tend to write files in several steps.
+const DEBOUNCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Import new or modified activity files in `dir` as they appear, calling
+/// `on_import` whenever activities were added. `db` is called for each batch
+/// of files, so that changed settings are picked up.
+///
+/// Files already in the directory are imported first. Blocks forever, unless
+/// the watcher fails.
+pub fn watch_dir(dir: &Path, db: impl Fn() -> Arc<Database>, on_import: impl Fn()) -> Result<()> {
+    // Use absolute paths so file names are stable across restarts, and match
+    // what the watcher reports.
+    let dir = dir.canonicalize()?;
+
+    // Catch up on anything added while we weren't running.
+    if !activity::import_path(
+        &dir,
+        &db(),
+        &PropertySource::default(),
+        DedupeBy::default(),
+        false,
+    )?
+    .imported
+    .is_empty()
+    {
+        on_import();
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let mut debouncer = new_debouncer(DEBOUNCE_TIMEOUT, tx)?;
+    debouncer.watcher().watch(&dir, RecursiveMode::Recursive)?;
+
+    tracing::info!(?dir, "watching for new activities");
+
+    for result in rx {
+        let events = match result {
+            Ok(events) => events,
+            Err(err) => {
+                tracing::error!(?err, "error watching directory");
+                continue;
+            }
+        };
+
+        let db = db();
+        let mut conn = db.connection()?;
+        let mut num_imported = 0;
+
+        for event in events {
+            let path = event.path;
+            let Some(name) = path.to_str().filter(|_| path.is_file()) else {
+                continue;
+            };
+
+            // Not an activity file if empty.
+            let activities = match activity::read_file(&path) {
+                Ok(activities) => activities,
+                Err(err) => {
+                    tracing::error!(?path, ?err, "failed to read activity");
+                    continue;
+                }
+            };
+
+            for (i, activity) in activities.iter().enumerate() {
+                let name = activity::session_name(name, i);
+                match activity::upsert(&mut conn, &name, activity, &db.config) {
+                    Ok(_) => {
+                        tracing::info!(?path, "imported activity");
+                        num_imported += 1;
+                    }
+                    Err(err) => tracing::error!(?path, ?err, "failed to insert activity"),
+                }
+            }
+        }
+
+        if num_imported > 0 {
+            on_import();
+        }
+    }
+
+    Ok(())
+}
+
+/// Rescan `path` for new activity files every `interval`, calling `on_import`
+/// whenever activities were added. Blocks forever.
+pub fn reimport_periodically(
+    path: &Path,
+    interval: Duration,
+    db: impl Fn() -> Arc<Database>,
+    on_import: impl Fn(),
+) {
+    loop {
+        match activity::import_path(
+            path,
+            &db(),
+            &PropertySource::default(),
+            DedupeBy::default(),
+            false,
+        ) {
+            Ok(summary) if summary.imported.is_empty() => {}
+            Ok(_) => on_import(),
+            Err(err) => tracing::error!(?path, ?err, "failed to reimport activities"),
+        }
+
+        std::thread::sleep(interval);
+    }
+}
@@ -1,47 +1,189 @@
-use std::io::Cursor;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Seek};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use anyhow::Result;
-use axum::body::HttpBody;
-use axum::extract::{DefaultBodyLimit, Multipart, Path, Query, State};
-use axum::headers::authorization::Bearer;
-use axum::http::{header, Method, Request, StatusCode, Uri};
+use anyhow::{anyhow, Context, Result};
+use axum::body::{Bytes, HttpBody, StreamBody};
+use axum::extract::multipart::Field;
+use axum::extract::{
+    DefaultBodyLimit, FromRequestParts, MatchedPath, Multipart, Path, Query, RawQuery, State,
+};
+use axum::headers::authorization::{Basic, Bearer};
+use axum::headers::{Authorization, HeaderMapExt, IfModifiedSince, LastModified};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode, Uri};
 use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
-use axum::routing::{get, post};
-use axum::{Router, Server, TypedHeader};
+use axum::routing::{delete, get, post};
+use axum::{Extension, Json, Router, Server};
+use axum_server::tls_rustls::RustlsConfig;
+use futures::StreamExt;
+use geo::BoundingRect;
+use geo_types::Coord;
 use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use ipnet::IpNet;
 use rust_embed::Embed;
+use rustls_acme::caches::DirCache;
+use rustls_acme::AcmeConfig;
 use serde::{Deserialize, Deserializer, Serialize};
-use time::Date;
+use time::{Date, OffsetDateTime};
+use tokio::io::AsyncWriteExt;
 use tokio::runtime::Runtime;
-use tower_http::cors::{Any, CorsLayer};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tower::ServiceExt;
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::set_header::SetResponseHeaderLayer;
 use tower_http::trace::{DefaultOnFailure, TraceLayer};
 
+use crate::activity::{ActivitySummary, ImportSummary, SortOrder};
 use crate::db::{ActivityFilter, Database, PropertyFilter};
-use crate::raster::LinearGradient;
+use crate::explorer::ExplorerTiles;
+use crate::export::ExportFormat;
+use crate::garmin::GarminAuth;
+use crate::ingest::IngestBody;
+use crate::live::OwnTracksMessage;
+use crate::raster::{ColorBy, Count, Gradient, Intensity, RenderMode, Stroke};
+use crate::rate_limit::{Limiters, RateLimits};
+use crate::render_link::{InvalidLink, RenderLinks};
+use crate::render_pool::{PoolError, RenderPool};
 use crate::strava;
 use crate::strava::StravaAuth;
 use crate::tile::{Tile, WebMercatorViewport};
-use crate::{activity, raster};
+use crate::tokens::{self, Scope};
+use crate::track_stats::GroupBy;
+use crate::users::{self, User};
+use crate::{
+    activity, aggregate, backup, date, db, export, garmin, heat, jobs, komoot, live, mvt, raster,
+    rate_limit, track_stats, views, watch,
+};
+
+/// Uploads are streamed to disk, so this can be generous enough to fit bulk
+/// exports of all activities.
+const MAX_UPLOAD_SIZE: usize = 2 * 1024 * 1024 * 1024;
+
+/// How often to check whether a read-only database file was replaced. Long
+/// enough for connections to the previous file to have been closed by the
+/// next check.
+const REPLICA_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Clone)]
 pub struct Config {
-    pub cors: bool,
+    /// Allow cross origin requests.
+    pub cors: Option<CorsConfig>,
+    /// Require a token with the render scope to view anything.
+    pub private: bool,
     pub upload_token: Option<String>,
+    /// Secret to sign render links with, instead of the one saved in the
+    /// database.
+    pub render_secret: Option<String>,
+    /// Maximum number of rendered tiles to keep in memory, 0 to disable.
+    pub tile_cache_size: usize,
+    /// Threads to render tiles and images on, 0 for one per CPU.
+    pub render_threads: usize,
+    /// Renders to run at once, 0 for one per render thread.
+    pub max_concurrent_renders: usize,
+    /// How long a render can wait for its turn before the request fails.
+    pub render_queue_timeout: Duration,
+    /// Directory to automatically import new activity files from.
+    pub watch_dir: Option<PathBuf>,
+    /// Path to periodically rescan for new activity files, and how often.
+    pub reimport: Option<(PathBuf, Duration)>,
+    /// How long a device can go without sending its location before its live
+    /// activity is saved.
+    pub live_timeout: Duration,
+    /// How often to import new tours from Komoot.
+    pub komoot_sync: Option<Duration>,
+    /// How to handle private activities received from the Strava webhook.
+    pub strava_privacy: strava::PrivacyOptions,
+    /// Only accept Strava webhook events from these addresses (any if empty).
+    pub strava_webhook_ips: Vec<IpNet>,
+    pub rate_limits: RateLimits,
+    /// Serve HTTPS rather than plain HTTP.
+    pub tls: Option<TlsConfig>,
+    /// The database was opened read-only, so nothing should write to it.
+    pub read_only: bool,
+    pub backup: Option<backup::Schedule>,
+    /// Named gradients in addition to those saved in the database, which
+    /// they replace if they have the same name.
+    pub gradients: BTreeMap<String, String>,
+    /// Strava API credentials, preferred over those saved in the database.
+    pub strava_credentials: strava::Credentials,
+    /// Reads the configuration again when reloading, if it came from a file.
+    /// Only some of it (routes, tokens, gradients, credentials) can change
+    /// without restarting.
+    pub reload_config: Option<LoadConfig>,
     pub routes: RouteConfig,
 }
 
+pub type LoadConfig = Arc<dyn Fn() -> Result<Config> + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct CorsConfig {
+    /// Origins allowed to make requests, any if empty.
+    pub origins: Vec<HeaderValue>,
+    /// Allowed methods, just `GET` if empty.
+    pub methods: Vec<Method>,
+    /// Request headers allowed besides the basic ones, e.g. `Authorization`
+    /// for uploads with a token.
+    pub headers: Vec<HeaderName>,
+}
+
+impl CorsConfig {
+    fn layer(&self) -> CorsLayer {
+        let origin = match self.origins.as_slice() {
+            [] => AllowOrigin::any(),
+            origins => AllowOrigin::list(origins.iter().cloned()),
+        };
+        let methods = match self.methods.as_slice() {
+            [] => vec![Method::GET],
+            methods => methods.to_vec(),
+        };
+
+        CorsLayer::new()
+            .allow_origin(origin)
+            .allow_methods(methods)
+            .allow_headers(self.headers.clone())
+    }
+}
+
+#[derive(Clone)]
+pub enum TlsConfig {
+    /// Certificate chain and private key (PEM files).
+    Files { cert: PathBuf, key: PathBuf },
+    /// Certificates provisioned (and renewed) automatically using ACME.
+    Acme(AcmeOptions),
+}
+
+#[derive(Clone)]
+pub struct AcmeOptions {
+    pub domains: Vec<String>,
+    /// Email address given to the certificate authority for notices.
+    pub contact: Option<String>,
+    /// Where to store the account key and certificates.
+    pub cache_dir: PathBuf,
+    /// Use Let's Encrypt's staging environment, for testing.
+    pub staging: bool,
+}
+
 #[derive(Clone)]
 pub struct RouteConfig {
     pub tiles: bool,
     pub strava_webhook: bool,
     pub strava_auth: bool,
+    pub garmin_webhook: bool,
+    pub garmin_auth: bool,
     pub upload: bool,
     pub render: bool,
+    /// Signed links to `/render`, see [`render_link`].
+    pub render_links: bool,
+    /// Per-user pages and tiles under `/u/:user/`, and uploads with user
+    /// tokens.
+    pub users: bool,
 }
 
 #[derive(Embed)]
@@ -52,85 +194,632 @@ struct StaticAsset;
 pub struct AppState {
     pub db: Arc<Database>,
     pub strava: Option<StravaAuth>,
+    pub garmin: Option<GarminAuth>,
+    pub tile_cache: Arc<TileCache>,
+    pub gradients: Arc<Gradients>,
     pub config: Config,
+    /// Wakes up the background worker when a job is queued.
+    pub job_added: Arc<Notify>,
+    pub render_pool: Arc<RenderPool>,
+    /// Reloads the server's settings, see [`App::reload`].
+    pub reload: mpsc::UnboundedSender<ReloadRequest>,
+    pub render_links: Option<Arc<RenderLinks>>,
+}
+
+/// Tile coordinates, tile size, format, user (for `/u/:user/` routes), and
+/// the raw query string (filters, gradient, ...) of a tile request.
+type TileCacheKey = (Tile, u32, TileFormat, Option<i64>, String);
+
+/// User and raw query string of an explorer tile request.
+type ExplorerCacheKey = (Option<i64>, String);
+
+/// Number of distinct explorer tile sets kept in memory.
+const MAX_EXPLORER_ENTRIES: usize = 16;
+
+/// In-memory LRU cache of rendered tiles.
+///
+/// Must be cleared whenever activities are added, updated, or removed.
+pub struct TileCache {
+    capacity: usize,
+    inner: Mutex<TileCacheInner>,
+    /// Visited explorer tiles, shared by every tile rendered with the same
+    /// user and query string.
+    explorer: Mutex<HashMap<ExplorerCacheKey, Arc<ExplorerTiles>>>,
+}
+
+#[derive(Default)]
+struct TileCacheInner {
+    tick: u64,
+    /// Empty tiles are cached as `None`.
+    entries: HashMap<TileCacheKey, (u64, Option<Bytes>)>,
+    /// Keys ordered by last access, oldest first.
+    recency: BTreeMap<u64, TileCacheKey>,
+}
+
+impl TileCache {
+    pub fn new(capacity: usize) -> Self {
+        TileCache {
+            capacity,
+            inner: Mutex::new(TileCacheInner::default()),
+            explorer: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &TileCacheKey) -> Option<Option<Bytes>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+
+        let tick = inner.tick;
+        let (last_used, value) = inner.entries.get_mut(key)?;
+        let prev = std::mem::replace(last_used, tick);
+        let value = value.clone();
+
+        let key = inner
+            .recency
+            .remove(&prev)
+            .expect("cache entry in recency list");
+        inner.recency.insert(tick, key);
+
+        Some(value)
+    }
+
+    fn insert(&self, key: TileCacheKey, value: Option<Bytes>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+
+        let tick = inner.tick;
+        if let Some((prev, _)) = inner.entries.insert(key.clone(), (tick, value)) {
+            inner.recency.remove(&prev);
+        }
+        inner.recency.insert(tick, key);
+
+        while inner.entries.len() > self.capacity {
+            let (_, oldest) = inner.recency.pop_first().expect("non-empty cache");
+            inner.entries.remove(&oldest);
+        }
+    }
+
+    /// Explorer tiles for the given user and query, loading them if needed.
+    fn explorer_tiles<F>(&self, key: ExplorerCacheKey, load: F) -> Result<Arc<ExplorerTiles>>
+    where
+        F: FnOnce() -> Result<ExplorerTiles>,
+    {
+        if let Some(tiles) = self.explorer.lock().unwrap().get(&key) {
+            return Ok(tiles.clone());
+        }
+
+        let tiles = Arc::new(load()?);
+        if self.capacity > 0 {
+            let mut explorer = self.explorer.lock().unwrap();
+            // Only a handful of filters are in use at once, so rather than
+            // tracking recency, start over when there are too many.
+            if explorer.len() >= MAX_EXPLORER_ENTRIES {
+                explorer.clear();
+            }
+            explorer.insert(key, tiles.clone());
+        }
+        Ok(tiles)
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        inner.recency.clear();
+        self.explorer.lock().unwrap().clear();
+    }
 }
 
 impl Config {
-    fn build_router<S>(&self, db: Database) -> Result<Router<S>> {
+    fn build_state(
+        &self,
+        db: Database,
+        reload: mpsc::UnboundedSender<ReloadRequest>,
+    ) -> Result<AppState> {
+        let render_pool = RenderPool::new(
+            self.render_threads,
+            self.max_concurrent_renders,
+            self.render_queue_timeout,
+        )?;
+        tracing::info!("rendering on {} threads", render_pool.num_threads());
+
+        Ok(AppState {
+            config: self.clone(),
+            strava: self.load_strava(&db)?,
+            garmin: self.load_garmin()?,
+            tile_cache: Arc::new(TileCache::new(self.tile_cache_size)),
+            gradients: Arc::new(self.load_gradients(&db)?),
+            job_added: Arc::new(Notify::new()),
+            render_pool: Arc::new(render_pool),
+            reload,
+            render_links: self.load_render_links(&db)?,
+            db: Arc::new(db),
+        })
+    }
+
+    fn load_strava(&self, db: &Database) -> Result<Option<StravaAuth>> {
+        if !(self.routes.strava_webhook || self.routes.strava_auth) {
+            return Ok(None);
+        }
+
+        match StravaAuth::load_with(db, &self.strava_credentials) {
+            Ok(auth) => Ok(Some(auth)),
+            // Keep serving the map rather than failing to start, e.g. when
+            // the Docker image's default `--strava-webhook` isn't wanted.
+            Err(err) if self.routes.tiles => {
+                tracing::warn!("Strava integration disabled: {:#}", err);
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn load_render_links(&self, db: &Database) -> Result<Option<Arc<RenderLinks>>> {
+        if !self.routes.render_links {
+            return Ok(None);
+        }
+
+        let links = RenderLinks::load(db, self.render_secret.as_deref())?;
+        Ok(Some(Arc::new(links)))
+    }
+
+    fn load_gradients(&self, db: &Database) -> Result<Gradients> {
+        let mut gradients = Gradients::from_config(&db.config)?;
+        gradients
+            .named
+            .extend(raster::named_gradients(&self.gradients)?);
+        Ok(gradients)
+    }
+
+    fn load_garmin(&self) -> Result<Option<GarminAuth>> {
+        if self.routes.garmin_webhook || self.routes.garmin_auth {
+            Ok(Some(GarminAuth::from_env()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn build_router(&self, state: AppState) -> Result<Router> {
         let trace = TraceLayer::new_for_http()
             .on_response(trace_request)
             .on_failure(DefaultOnFailure::new());
 
+        let db = &state.db;
         let mut router = Router::new();
         if self.routes.tiles {
             router = router
                 .route("/", get(index))
                 .route("/static/*path", get(static_file))
                 .route("/tile/:z/:x/:y", get(render_tile))
-                .route("/api/activity-count", get(get_activity_count));
+                .route("/api/activity-count", get(get_activity_count))
+                .route("/api/activities", get(list_activities))
+                .route("/api/activities/at", get(list_activities_at))
+                .route("/api/activities.geojson", get(activities_geojson))
+                .route("/api/heat", get(get_heat))
+                .route("/api/properties", get(get_properties))
+                .route("/api/stats", get(get_stats))
+                .route("/api/activities/:id", get(get_activity))
+                .route("/api/activities/:id/export", get(export_activity))
+                .route("/api/activities/:id/map.png", get(render_activity));
+
+            // Same as the top level tiles, but with a saved view's settings.
+            let view_routes = Router::new()
+                .route("/view/:view/tile/:z/:x/:y", get(render_tile))
+                .route("/view/:view/api/activity-count", get(get_activity_count))
+                .route_layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    apply_view,
+                ));
+
+            router = router.merge(view_routes);
         }
 
-        let mut use_strava_auth = false;
-        if self.routes.strava_webhook {
-            router = router.nest("/strava", strava::webhook_routes());
-            use_strava_auth = true;
+        if self.routes.render {
+            router = router.route("/render", get(render_viewport));
+        }
+
+        if self.routes.render_links {
+            let link_routes = Router::new()
+                .route("/api/render-links", post(create_render_link))
+                .route_layer(axum::middleware::from_fn_with_state(
+                    (state.clone(), Scope::Render),
+                    require_scope,
+                ));
+
+            router = router.merge(link_routes);
+        }
+
+        // Same as the top level routes, but only showing a single user's
+        // activities.
+        if self.routes.users {
+            router = router
+                .route("/u/:user", get(index))
+                .route("/u/:user/", get(index))
+                .route("/u/:user/tile/:z/:x/:y", get(render_tile))
+                .route("/u/:user/api/activity-count", get(get_activity_count))
+                .route("/u/:user/api/activities/at", get(list_activities_at))
+                .route("/u/:user/api/activities/:id", get(get_activity))
+                .route("/u/:user/api/activities.geojson", get(activities_geojson))
+                .route("/u/:user/api/heat", get(get_heat));
+        }
+
+        // API responses only change along with the database, so clients can
+        // check whether they're still up to date rather than fetching them
+        // again.
+        if self.routes.tiles {
+            router = router.route_layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                set_last_modified,
+            ));
+        }
+
+        // Everything added so far shows activities, so needs a token on
+        // private servers.
+        if self.private {
+            if self.upload_token.is_none() && !tokens::any_issued(&*db.connection()?)? {
+                tracing::warn!(
+                    "HOTPOT_UPLOAD_TOKEN not set and no tokens issued, \
+                    nobody will be able to view the map"
+                );
+            }
+
+            router = router.route_layer(axum::middleware::from_fn_with_state(
+                (state.clone(), Scope::Render),
+                require_scope,
+            ));
+        }
+
+        match state.strava {
+            Some(ref auth) if self.routes.strava_webhook => {
+                if auth.subscription_id().is_none() {
+                    tracing::warn!(
+                        "Strava webhook subscription unknown, events can't be verified \
+                        (see `hotpot strava-subscribe`)"
+                    );
+                }
+
+                router = router.nest("/strava", strava::webhook_routes());
+            }
+            _ => {}
+        }
+
+        // The signature is all the authentication needed.
+        if self.routes.render_links {
+            router = router.route("/render/signed", get(render_signed));
         }
 
-        if self.routes.strava_auth {
+        if self.routes.strava_auth && state.strava.is_some() {
             router = router.nest("/strava", strava::auth_routes());
-            use_strava_auth = true;
+        }
+
+        if self.routes.garmin_webhook {
+            router = router.nest("/garmin", garmin::webhook_routes());
+        }
+
+        if self.routes.garmin_auth {
+            router = router.nest("/garmin", garmin::auth_routes());
         }
 
         if self.routes.upload {
-            if self.upload_token.is_none() {
+            if is_open(db, self)? {
                 tracing::warn!(
-                    "HOTPOT_UPLOAD_TOKEN not set, unauthenticated uploads will be allowed"
+                    "HOTPOT_UPLOAD_TOKEN not set and no tokens issued, \
+                    unauthenticated uploads will be allowed"
                 );
             }
 
-            router = router
+            let upload_routes = Router::new()
                 .route("/upload", post(upload_activity))
-                .layer(DefaultBodyLimit::max(15 * 1024 * 1024));
+                .route("/api/ingest", post(ingest_activity))
+                .route("/api/owntracks", post(owntracks_location))
+                .route("/api/activities/:id", delete(delete_activity))
+                .route_layer(axum::middleware::from_fn_with_state(
+                    (state.clone(), Scope::Upload),
+                    require_scope,
+                ))
+                .layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE));
+
+            router = router.merge(upload_routes);
         }
 
-        if self.routes.render {
-            router = router.route("/render", get(render_viewport));
+        if self.routes.tiles {
+            let admin_routes = Router::new()
+                .route("/api/admin/reload", post(reload_settings))
+                .route("/api/jobs", get(list_jobs))
+                .route_layer(axum::middleware::from_fn_with_state(
+                    (state.clone(), Scope::Admin),
+                    require_scope,
+                ));
+
+            router = router.merge(admin_routes);
         }
 
-        if self.cors {
-            let cors = CorsLayer::new()
-                .allow_methods([Method::GET])
-                .allow_origin(Any);
+        // Added last, so clients going over the limit are turned away
+        // before anything else is done.
+        if !self.rate_limits.is_empty() {
+            let limiters = Arc::new(Limiters::new(&self.rate_limits));
+            router = router.route_layer(axum::middleware::from_fn_with_state(
+                limiters,
+                rate_limit::limit_requests,
+            ));
+        }
 
-            router = router.layer(cors);
+        if let Some(cors) = &self.cors {
+            router = router.layer(cors.layer());
         }
 
-        // TODO: possibly better better as an Option
-        let strava = if use_strava_auth {
-            Some(StravaAuth::from_env()?)
-        } else {
-            None
-        };
+        // Images are already compressed, and left alone.
+        router = router
+            .layer(CompressionLayer::new())
+            .layer(SetResponseHeaderLayer::appending(
+                header::VARY,
+                HeaderValue::from_static("accept-encoding"),
+            ));
 
         let router = router
             .layer(axum::middleware::from_fn(store_request_data))
             .layer(trace)
-            .with_state(AppState {
-                config: self.clone(),
-                strava,
-                db: Arc::new(db),
-            });
+            .with_state(state);
 
         Ok(router)
     }
 }
 
+impl AppState {
+    /// Load the settings stored in the database again, keeping the caches,
+    /// render threads and background tasks.
+    fn reload(&self) -> Result<AppState> {
+        let db = self.db.reload()?;
+        let config = match &self.config.reload_config {
+            Some(load) => Config {
+                reload_config: Some(load.clone()),
+                ..load()?
+            },
+            None => self.config.clone(),
+        };
+
+        Ok(AppState {
+            strava: config.load_strava(&db)?,
+            garmin: config.load_garmin()?,
+            gradients: Arc::new(config.load_gradients(&db)?),
+            render_links: config.load_render_links(&db)?,
+            db: Arc::new(db),
+            config,
+            ..self.clone()
+        })
+    }
+}
+
+/// Asks the server to reload its settings, receiving the outcome.
+type ReloadRequest = oneshot::Sender<Result<()>>;
+
+/// State and routes of a running server. Reloading swaps them out, while
+/// requests which are already being handled finish with the previous ones.
+struct App {
+    current: Mutex<(AppState, Router)>,
+}
+
+impl App {
+    fn new(state: AppState) -> Result<Self> {
+        let router = state.config.build_router(state.clone())?;
+        Ok(App {
+            current: Mutex::new((state, router)),
+        })
+    }
+
+    fn state(&self) -> AppState {
+        self.current.lock().unwrap().0.clone()
+    }
+
+    fn router(&self) -> Router {
+        self.current.lock().unwrap().1.clone()
+    }
+
+    /// Pick up changed settings, gradients, and privacy masks from the
+    /// database, and start over with an empty tile cache.
+    fn reload(&self) -> Result<()> {
+        let prev = self.state();
+        let state = prev.reload()?;
+        let router = state.config.build_router(state.clone())?;
+
+        *self.current.lock().unwrap() = (state, router);
+        prev.tile_cache.clear();
+
+        Ok(())
+    }
+
+    /// Reload whenever requested, or when receiving `SIGHUP`. Runs forever.
+    async fn handle_reloads(self: Arc<Self>, mut requests: mpsc::UnboundedReceiver<ReloadRequest>) {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let reload = self.state().reload;
+            match signal(SignalKind::hangup()) {
+                Ok(mut hangup) => {
+                    tokio::spawn(async move {
+                        while hangup.recv().await.is_some() {
+                            // Nobody is waiting for the outcome.
+                            let (reply, _) = oneshot::channel();
+                            let _ = reload.send(reply);
+                        }
+                    });
+                }
+                Err(err) => tracing::warn!(?err, "can't reload on SIGHUP"),
+            }
+        }
+
+        while let Some(reply) = requests.recv().await {
+            let app = self.clone();
+            let result = tokio::task::spawn_blocking(move || app.reload())
+                .await
+                .expect("reload task panicked");
+
+            match result {
+                Ok(()) => tracing::info!("reloaded settings"),
+                Err(ref err) => tracing::error!(?err, "failed to reload settings"),
+            }
+            let _ = reply.send(result);
+        }
+    }
+
+    /// Start background tasks, which look up the current state each time they
+    /// run.
+    fn spawn_tasks(self: &Arc<Self>) {
+        let state = self.state();
+        let config = &state.config;
+        let db = {
+            let app = self.clone();
+            move || app.state().db
+        };
+
+        if let Some(dir) = config.watch_dir.clone() {
+            let (db, tile_cache) = (db.clone(), state.tile_cache.clone());
+            std::thread::spawn(move || {
+                if let Err(err) = watch::watch_dir(&dir, db, || tile_cache.clear()) {
+                    tracing::error!(?err, "stopped watching directory");
+                }
+            });
+        }
+
+        if let Some((path, interval)) = config.reimport.clone() {
+            let (db, tile_cache) = (db.clone(), state.tile_cache.clone());
+            std::thread::spawn(move || {
+                watch::reimport_periodically(&path, interval, db, || tile_cache.clear())
+            });
+        }
+
+        if config.routes.upload {
+            let tile_cache = state.tile_cache.clone();
+            tokio::spawn(live::flush_periodically(
+                db.clone(),
+                config.live_timeout,
+                move || tile_cache.clear(),
+            ));
+        }
+
+        if let Some(interval) = config.komoot_sync {
+            let (db, tile_cache) = (db.clone(), state.tile_cache.clone());
+            tokio::spawn(async move {
+                komoot::sync_periodically(db, interval, || tile_cache.clear()).await
+            });
+        }
+
+        if config.routes.strava_webhook && state.strava.is_some() {
+            let app = self.clone();
+            tokio::spawn(strava::process_queue(move || app.state()));
+        }
+
+        if let Some(schedule) = config.backup.clone() {
+            tokio::spawn(backup::backup_periodically(state.db.clone(), schedule));
+        }
+
+        if config.read_only {
+            let path = state.db.path().to_path_buf();
+            tokio::spawn(watch_replica(
+                path,
+                state.reload.clone(),
+                state.tile_cache.clone(),
+            ));
+        } else if config.routes.tiles {
+            tokio::spawn(aggregate::update_periodically(db));
+        }
+    }
+}
+
+/// Reload settings and clear cached tiles whenever a read-only database file
+/// is replaced by a newer copy. Runs forever.
+async fn watch_replica(
+    path: PathBuf,
+    reload: mpsc::UnboundedSender<ReloadRequest>,
+    tile_cache: Arc<TileCache>,
+) {
+    let modified = || {
+        std::fs::metadata(&path)
+            .and_then(|meta| Ok((meta.modified()?, meta.len())))
+            .ok()
+    };
+
+    let mut last = modified();
+    let mut reopening = false;
+    let mut interval = tokio::time::interval(REPLICA_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let current = modified();
+        if current != last {
+            tracing::info!("database file changed, reloading");
+            last = current;
+            reopening = true;
+            let (reply, _) = oneshot::channel();
+            let _ = reload.send(reply);
+        } else if reopening {
+            // Connections to the previous file may have rendered more tiles
+            // until they were closed.
+            reopening = false;
+            tile_cache.clear();
+        }
+    }
+}
+
 async fn run_async(addr: SocketAddr, db: Database, config: Config) -> Result<()> {
-    tracing::info!("starting server on http://{}", addr);
-    let router = config.build_router(db)?;
-    Server::bind(&addr)
-        .serve(router.into_make_service())
-        .await?;
+    let (reload, reload_requests) = mpsc::unbounded_channel();
+    let app = Arc::new(App::new(config.build_state(db, reload)?)?);
+    app.spawn_tasks();
+    tokio::spawn(app.clone().handle_reloads(reload_requests));
+
+    // Look up the current routes for each request.
+    let router = Router::new().fallback_service(tower::service_fn(move |req| {
+        let router = app.router();
+        async move { router.oneshot(req).await }
+    }));
+
+    match config.tls {
+        Some(TlsConfig::Files { ref cert, ref key }) => {
+            let tls = RustlsConfig::from_pem_file(cert, key)
+                .await
+                .with_context(|| format!("failed to load TLS certificate {:?}", cert))?;
+
+            tracing::info!("starting server on https://{}", addr);
+            axum_server::bind_rustls(addr, tls)
+                .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        Some(TlsConfig::Acme(ref acme)) => {
+            let mut state = AcmeConfig::new(&acme.domains)
+                .contact(acme.contact.iter().map(|email| format!("mailto:{}", email)))
+                .cache(DirCache::new(acme.cache_dir.clone()))
+                .directory_lets_encrypt(!acme.staging)
+                .state();
+            let acceptor = state.axum_acceptor(state.default_rustls_config());
+
+            // Ordering and renewing certificates happens as the state is
+            // polled, challenges are answered by the acceptor.
+            tokio::spawn(async move {
+                while let Some(event) = state.next().await {
+                    match event {
+                        Ok(event) => tracing::info!(?event, "acme"),
+                        Err(err) => tracing::error!(?err, "failed to provision certificate"),
+                    }
+                }
+            });
+
+            tracing::info!("starting server on https://{}", addr);
+            axum_server::bind(addr)
+                .acceptor(acceptor)
+                .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        None => {
+            tracing::info!("starting server on http://{}", addr);
+            Server::bind(&addr)
+                .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+    }
 
     Ok(())
 }
@@ -142,17 +831,43 @@ pub fn run_blocking(addr: SocketAddr, db: Database, config: Config) -> Result<()
     Ok(())
 }
 
-async fn index(State(AppState { config, db, .. }): State<AppState>) -> impl IntoResponse {
+async fn index(
+    State(AppState {
+        config,
+        db,
+        gradients,
+        ..
+    }): State<AppState>,
+    scope: UserScope,
+) -> impl IntoResponse {
     let index_file = StaticAsset::get("index.html").expect("missing file");
     let html = std::str::from_utf8(&index_file.data).expect("valid utf8");
-    let properties = load_activity_properties(&db)
-        .await
+    let properties = activity::describe_properties(&db)
         .and_then(|props| Ok(serde_json::to_string(&props)?))
         .unwrap_or_else(|err| {
             tracing::error!("failed to generate activity properties: {:?}", err);
             "{}".to_string()
         });
 
+    // Bounds for the date slider.
+    let date_range = activity::date_range(&db, &scope.apply(ActivityFilter::default()))
+        .map(|range| range.map(|(first, last)| [first.to_string(), last.to_string()]))
+        .unwrap_or_else(|err| {
+            tracing::error!("failed to find activity date range: {:?}", err);
+            None
+        });
+
+    // Views show everyone's activities, so aren't offered on user pages.
+    let view_names = match scope.0 {
+        Some(_) => vec![],
+        None => views::list(&db)
+            .map(|views| views.into_iter().map(|view| view.name).collect())
+            .unwrap_or_else(|err| {
+                tracing::error!("failed to list views: {:?}", err);
+                vec![]
+            }),
+    };
+
     // Dynamically inject config
     let html = html.replace(
         "// $INJECT$",
@@ -161,8 +876,19 @@ async fn index(State(AppState { config, db, .. }): State<AppState>) -> impl Into
             globalThis.UPLOADS_ENABLED = {};
             globalThis.RENDER_ENABLED = {};
             globalThis.ACTIVITY_PROPERTIES = {};
+            globalThis.GRADIENTS = {};
+            globalThis.VIEWS = {};
+            globalThis.BASE_PATH = {};
+            globalThis.DATE_RANGE = {};
         ",
-            config.routes.upload, config.routes.render, properties,
+            config.routes.upload,
+            config.routes.render,
+            properties,
+            serde_json::to_string(&gradients.named.keys().collect::<Vec<_>>())
+                .expect("serializable"),
+            serde_json::to_string(&view_names).expect("serializable"),
+            serde_json::to_string(&scope.base_path()).expect("serializable"),
+            serde_json::to_string(&date_range).expect("serializable"),
         )
         .as_str(),
     );
@@ -192,13 +918,59 @@ struct RenderQueryParams {
     #[serde(default)]
     color: Option<String>,
     #[serde(default)]
-    gradient: Option<LinearGradient>,
+    gradient: Option<Gradient>,
     #[serde(default, with = "crate::date::parse")]
     before: Option<Date>,
     #[serde(default, with = "crate::date::parse")]
     after: Option<Date>,
     #[serde(default)]
     filter: Option<PropertyFilter>,
+    /// Only include activities passing through `west,south,east,north`.
+    #[serde(default)]
+    bbox: Option<WebMercatorViewport>,
+    #[serde(default)]
+    intensity: Intensity,
+    #[serde(default)]
+    line_width: Option<u32>,
+    #[serde(default)]
+    blur: Option<f32>,
+    #[serde(default)]
+    antialias: bool,
+    #[serde(default)]
+    count: Count,
+    #[serde(default)]
+    half_life: Option<f32>,
+    #[serde(default)]
+    color_by: Option<ColorBy>,
+    #[serde(default, deserialize_with = "parse_layers")]
+    layers: Option<Vec<Layer>>,
+    #[serde(default)]
+    mode: RenderMode,
+}
+
+/// One of several filters rendered separately and composited into a single
+/// tile, given as a JSON array in the `layers` query parameter.
+#[derive(Debug, Deserialize)]
+struct Layer {
+    #[serde(default)]
+    filter: Option<PropertyFilter>,
+    #[serde(default)]
+    color: Option<String>,
+    #[serde(default)]
+    gradient: Option<Gradient>,
+}
+
+/// Each layer is rendered separately, so keep the cost of a tile bounded.
+const MAX_LAYERS: usize = 8;
+
+fn parse_layers<'de, D>(deserializer: D) -> Result<Option<Vec<Layer>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    serde_json::from_str(&s)
+        .map(Some)
+        .map_err(|err| serde::de::Error::custom(format!("invalid layers: {}", err)))
 }
 
 #[derive(Debug, Deserialize)]
@@ -210,19 +982,43 @@ struct RenderViewQueryParams {
     #[serde(default)]
     color: Option<String>,
     #[serde(default)]
-    gradient: Option<LinearGradient>,
+    gradient: Option<Gradient>,
     #[serde(default, with = "crate::date::parse")]
     before: Option<Date>,
     #[serde(default, with = "crate::date::parse")]
     after: Option<Date>,
     #[serde(default)]
     filter: Option<PropertyFilter>,
+    #[serde(default)]
+    intensity: Intensity,
+    #[serde(default)]
+    line_width: Option<u32>,
+    #[serde(default)]
+    blur: Option<f32>,
+    #[serde(default)]
+    antialias: bool,
+    #[serde(default)]
+    count: Count,
+    #[serde(default)]
+    half_life: Option<f32>,
+    #[serde(default)]
+    color_by: Option<ColorBy>,
 }
 
-/// Handle the `y` part of an `/z/x/y` or `/z/x/y@2x` URL
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum TileFormat {
+    Png,
+    Mvt,
+}
+
+/// Handle the `y` part of an `/z/x/y`, `/z/x/y@2x` or `/z/x/y.mvt` URL
+///
+/// Besides the named sizes, `@{pixels}` requests any power of two width, e.g.
+/// `/z/x/y@128`.
 struct TileYParam {
     y: u32,
     tile_size: u32,
+    format: TileFormat,
 }
 
 impl<'de> Deserialize<'de> for TileYParam {
@@ -231,153 +1027,996 @@ impl<'de> Deserialize<'de> for TileYParam {
         D: Deserializer<'de>,
     {
         let param = String::deserialize(deserializer)?;
-        let (y_str, size) = param.split_once('@').unwrap_or((&param, "1x"));
+        let (param, format) = match param.rsplit_once('.') {
+            None => (param.as_str(), TileFormat::Png),
+            Some((rest, "png")) => (rest, TileFormat::Png),
+            Some((rest, "mvt")) => (rest, TileFormat::Mvt),
+            Some((_, ext)) => {
+                return Err(serde::de::Error::custom(format!(
+                    "invalid tile format: {}",
+                    ext
+                )))
+            }
+        };
+
+        let (y_str, size) = param.split_once('@').unwrap_or((param, "1x"));
 
         let y = u32::from_str(y_str).map_err(serde::de::Error::custom)?;
         let tile_size = match size {
             "small" => 256,
             "1x" => 512,
             "2x" => 1024,
-            _ => {
-                return Err(serde::de::Error::custom(format!(
-                    "invalid tile size: {}",
-                    size
-                )))
-            }
+            "4x" => 2048,
+            _ => match u32::from_str(size) {
+                Ok(px) if px.is_power_of_two() => px,
+                _ => {
+                    return Err(serde::de::Error::custom(format!(
+                        "invalid tile size: {}",
+                        size
+                    )))
+                }
+            },
         };
 
-        Ok(TileYParam { tile_size, y })
+        Ok(TileYParam {
+            tile_size,
+            y,
+            format,
+        })
     }
 }
 
-#[derive(Debug, Serialize)]
-struct ActivityProperty {
-    key: String,
-    activity_count: usize,
-}
-
-#[derive(Debug, Serialize)]
-struct ActivityProperties(Vec<ActivityProperty>);
-
-// TODO: shouldn't live here
-async fn load_activity_properties(db: &Database) -> Result<ActivityProperties> {
-    let conn = db.connection()?;
-    let mut stmt = conn.prepare(
-        "\
-            SELECT
-                key,
-                sum(count) as num_activities
-            FROM (
-                SELECT
-                    prop.key,
-                    count(*) as count
-                FROM (
-                    SELECT props.*
-                    FROM activities, json_each(properties) props
-                ) prop
-                GROUP BY 1
-            )
-            GROUP BY 1
-            ORDER BY 1;
-        ",
-    )?;
-    let mut rows = stmt.query([])?;
-
-    let mut properties = vec![];
-    while let Some(row) = rows.next()? {
-        properties.push(ActivityProperty {
-            key: row.get_unwrap(0),
-            activity_count: row.get_unwrap(1),
-        });
+async fn get_properties(State(AppState { db, .. }): State<AppState>) -> impl IntoResponse {
+    match activity::describe_properties(&db) {
+        Ok(properties) => (StatusCode::OK, Json(properties)).into_response(),
+        Err(err) => {
+            tracing::error!("error loading activity properties: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
     }
-
-    Ok(ActivityProperties(properties))
 }
 
 async fn get_activity_count(
     State(AppState { db, .. }): State<AppState>,
+    scope: UserScope,
     Query(params): Query<RenderQueryParams>,
 ) -> impl IntoResponse {
-    let filter = ActivityFilter::new(params.before, params.after, params.filter);
+    let filter = scope.apply(within_bbox(
+        ActivityFilter::new(params.before, params.after, params.filter),
+        params.bbox.as_ref(),
+        &db.config,
+    ));
     let num_activities = filter.count(&db).unwrap();
 
     (StatusCode::OK, num_activities.to_string()).into_response()
 }
 
-async fn render_viewport(
+/// Largest page size allowed when listing activities.
+const MAX_PAGE_SIZE: u32 = 500;
+
+fn default_page() -> u32 {
+    1
+}
+
+fn default_page_size() -> u32 {
+    50
+}
+
+#[derive(Debug, Deserialize)]
+struct ListActivitiesQueryParams {
+    #[serde(default, with = "crate::date::parse")]
+    before: Option<Date>,
+    #[serde(default, with = "crate::date::parse")]
+    after: Option<Date>,
+    #[serde(default)]
+    filter: Option<PropertyFilter>,
+    #[serde(default)]
+    bbox: Option<WebMercatorViewport>,
+    /// Words to search for in titles and text properties.
+    #[serde(default)]
+    q: Option<String>,
+    #[serde(default)]
+    sort: SortOrder,
+    /// 1-based page number
+    #[serde(default = "default_page")]
+    page: u32,
+    #[serde(default = "default_page_size")]
+    per_page: u32,
+}
+
+#[derive(Serialize)]
+struct ActivityPage {
+    activities: Vec<ActivitySummary>,
+    /// Number of activities matching the filter, across all pages.
+    total: usize,
+    page: u32,
+    per_page: u32,
+}
+
+async fn list_activities(
     State(AppState { db, .. }): State<AppState>,
-    Query(params): Query<RenderViewQueryParams>,
+    Query(params): Query<ListActivitiesQueryParams>,
 ) -> impl IntoResponse {
-    let viewport = match WebMercatorViewport::from_str(&params.bounds) {
-        Ok(viewport) => viewport,
-        Err(err) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                format!("invalid viewport given: {:?}", err),
-            )
-                .into_response();
-        }
-    };
-
-    if params.height == 0 || params.height > 3000 || params.width == 0 || params.width > 3000 {
+    if params.page == 0 || params.per_page == 0 || params.per_page > MAX_PAGE_SIZE {
         return (
             StatusCode::BAD_REQUEST,
-            "width/height must be in bounds [1, 3000]",
+            format!("page must be >= 1, per_page in [1, {}]", MAX_PAGE_SIZE),
         )
             .into_response();
     }
 
-    let filter = ActivityFilter::new(params.before, params.after, params.filter);
-    let gradient = match choose_gradient(&params.gradient, params.color) {
-        Ok(value) => value,
-        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
-    };
+    let mut filter = within_bbox(
+        ActivityFilter::new(params.before, params.after, params.filter),
+        params.bbox.as_ref(),
+        &db.config,
+    );
+    if let Some(ref text) = params.q {
+        filter = filter.search(text);
+    }
+    let offset = (params.page - 1).saturating_mul(params.per_page);
 
-    raster::render_view(
-        viewport,
-        gradient,
-        params.width,
-        params.height,
-        &filter,
-        &db,
-    )
-    .and_then(render_image_response)
-    .unwrap_or_else(|err| {
-        tracing::error!("error rendering tile: {:?}", err);
-        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    let result = filter.count(&db).and_then(|total| {
+        let activities =
+            activity::list_page(&db, &filter, params.sort, Some(params.per_page), offset)?;
+
+        Ok(ActivityPage {
+            activities,
+            total,
+            page: params.page,
+            per_page: params.per_page,
+        })
+    });
+
+    match result {
+        Ok(page) => (StatusCode::OK, Json(page)).into_response(),
+        Err(err) => {
+            tracing::error!("error listing activities: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Largest search radius (in meters) allowed when looking up activities at a
+/// point.
+const MAX_AT_RADIUS: f64 = 1000.0;
+
+fn default_at_radius() -> f64 {
+    25.0
+}
+
+#[derive(Debug, Deserialize)]
+struct ActivitiesAtQueryParams {
+    lng: f64,
+    lat: f64,
+    /// Meters around the point
+    #[serde(default = "default_at_radius")]
+    radius: f64,
+    #[serde(default, with = "crate::date::parse")]
+    before: Option<Date>,
+    #[serde(default, with = "crate::date::parse")]
+    after: Option<Date>,
+    #[serde(default)]
+    filter: Option<PropertyFilter>,
+}
+
+/// Activities passing within `radius` meters of the given point, e.g. for
+/// "what went through here?" when clicking on the map.
+async fn list_activities_at(
+    State(AppState { db, .. }): State<AppState>,
+    scope: UserScope,
+    Query(params): Query<ActivitiesAtQueryParams>,
+) -> impl IntoResponse {
+    if !(params.radius > 0.0 && params.radius <= MAX_AT_RADIUS) {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("radius must be in (0, {}]", MAX_AT_RADIUS),
+        )
+            .into_response();
+    }
+
+    let filter = scope.apply(ActivityFilter::new(
+        params.before,
+        params.after,
+        params.filter,
+    ));
+    let point = Coord {
+        x: params.lng,
+        y: params.lat,
+    };
+
+    match activity::list_near(&db, filter, point, params.radius) {
+        Ok(activities) => (StatusCode::OK, Json(activities)).into_response(),
+        Err(err) => {
+            tracing::error!("error finding activities at point: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Largest heat map image (in cells) along either side.
+const MAX_HEAT_IMAGE_SIZE: u32 = 4096;
+
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HeatFormat {
+    #[default]
+    Json,
+    Png,
+}
+
+#[derive(Debug, Deserialize)]
+struct HeatQueryParams {
+    bbox: WebMercatorViewport,
+    /// Zoom level of the cells to count visits in.
+    z: u8,
+    #[serde(default, with = "crate::date::parse")]
+    before: Option<Date>,
+    #[serde(default, with = "crate::date::parse")]
+    after: Option<Date>,
+    #[serde(default)]
+    filter: Option<PropertyFilter>,
+    #[serde(default)]
+    format: HeatFormat,
+    /// Only return the most visited cells (JSON only).
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct HeatResponse {
+    z: u8,
+    cells: Vec<heat::HeatCell>,
+}
+
+/// Number of activities passing through each cell of the given area, either
+/// as JSON or as a 16-bit grayscale PNG with one pixel per cell.
+async fn get_heat(
+    State(AppState { db, .. }): State<AppState>,
+    scope: UserScope,
+    Query(params): Query<HeatQueryParams>,
+) -> impl IntoResponse {
+    if params.z > raster::MAX_ZOOM {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("z must be <= {}", raster::MAX_ZOOM),
+        )
+            .into_response();
+    }
+
+    let bounds = params.bbox.tile_bounds(params.z);
+    let too_large = bounds.xmax - bounds.xmin > MAX_HEAT_IMAGE_SIZE
+        || bounds.ymax - bounds.ymin > MAX_HEAT_IMAGE_SIZE;
+    if matches!(params.format, HeatFormat::Png) && too_large {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "bbox covers more than {} cells across at this zoom level",
+                MAX_HEAT_IMAGE_SIZE
+            ),
+        )
+            .into_response();
+    }
+
+    let filter = scope.apply(ActivityFilter::new(
+        params.before,
+        params.after,
+        params.filter,
+    ));
+
+    let result = tokio::task::spawn_blocking(move || {
+        heat::count_visits(&db, &filter, &params.bbox, params.z)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|heat_map| {
+        let heat_map = heat_map?;
+
+        Ok(match params.format {
+            HeatFormat::Json => {
+                let mut cells = heat_map.cells();
+                if let Some(limit) = params.limit {
+                    cells.truncate(limit);
+                }
+
+                Json(HeatResponse { z: params.z, cells }).into_response()
+            }
+            HeatFormat::Png => {
+                let mut bytes = vec![];
+                image::DynamicImage::ImageLuma16(heat_map.to_image())
+                    .write_to(&mut Cursor::new(&mut bytes), image::ImageOutputFormat::Png)?;
+                ([(header::CONTENT_TYPE, "image/png")], bytes).into_response()
+            }
+        })
+    });
+
+    result.unwrap_or_else(|err| {
+        tracing::error!("error counting visits: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
     })
 }
 
-async fn render_tile(
+/// Default tolerance (in meters) when simplifying tracks for GeoJSON.
+fn default_geojson_simplify() -> f64 {
+    5.0
+}
+
+#[derive(Debug, Deserialize)]
+struct GeoJsonQueryParams {
+    #[serde(default, with = "crate::date::parse")]
+    before: Option<Date>,
+    #[serde(default, with = "crate::date::parse")]
+    after: Option<Date>,
+    #[serde(default)]
+    filter: Option<PropertyFilter>,
+    #[serde(default)]
+    bbox: Option<WebMercatorViewport>,
+    #[serde(default)]
+    q: Option<String>,
+    /// Meters, 0 to keep every point.
+    #[serde(default = "default_geojson_simplify")]
+    simplify: f64,
+}
+
+/// Passes everything written to it along as chunks of a response body.
+struct ChannelWriter(tokio::sync::mpsc::Sender<Result<Bytes>>);
+
+impl std::io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Matching activities as a GeoJSON FeatureCollection, streamed as they are
+/// loaded so large collections don't need to be built up in memory.
+async fn activities_geojson(
+    State(AppState { db, .. }): State<AppState>,
+    scope: UserScope,
+    Query(params): Query<GeoJsonQueryParams>,
+) -> impl IntoResponse {
+    if !params.simplify.is_finite() || params.simplify < 0.0 {
+        return (StatusCode::BAD_REQUEST, "simplify must be >= 0").into_response();
+    }
+
+    let mut filter = scope.apply(within_bbox(
+        ActivityFilter::new(params.before, params.after, params.filter),
+        params.bbox.as_ref(),
+        &db.config,
+    ));
+    if let Some(ref text) = params.q {
+        filter = filter.search(text);
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        let writer = std::io::BufWriter::with_capacity(64 * 1024, ChannelWriter(tx.clone()));
+        if let Err(err) = export::write_feature_collection(writer, &db, &filter, params.simplify) {
+            // Too late for an error status, so abort the response instead.
+            tracing::error!("error streaming GeoJSON: {:?}", err);
+            let _ = tx.blocking_send(Err(err));
+        }
+    });
+
+    let body = StreamBody::new(futures::stream::poll_fn(move |cx| rx.poll_recv(cx)));
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/geo+json")],
+        body,
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct StatsQueryParams {
+    #[serde(default, with = "crate::date::parse")]
+    before: Option<Date>,
+    #[serde(default, with = "crate::date::parse")]
+    after: Option<Date>,
+    #[serde(default)]
+    filter: Option<PropertyFilter>,
+    #[serde(default)]
+    bbox: Option<WebMercatorViewport>,
+    group_by: Option<GroupBy>,
+}
+
+async fn get_stats(
+    State(AppState { db, .. }): State<AppState>,
+    Query(params): Query<StatsQueryParams>,
+) -> impl IntoResponse {
+    let filter = within_bbox(
+        ActivityFilter::new(params.before, params.after, params.filter),
+        params.bbox.as_ref(),
+        &db.config,
+    );
+
+    match track_stats::summarize(&db, &filter, params.group_by) {
+        Ok(totals) => (StatusCode::OK, Json(totals)).into_response(),
+        Err(err) => {
+            tracing::error!("error computing activity stats: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn list_jobs(State(AppState { db, .. }): State<AppState>) -> impl IntoResponse {
+    match db.connection().and_then(|conn| jobs::list(&conn)) {
+        Ok(jobs) => (StatusCode::OK, Json(jobs)).into_response(),
+        Err(err) => {
+            tracing::error!("error listing jobs: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ActivityPath {
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ActivityQueryParams {
+    /// Meters, 0 to keep every point.
+    #[serde(default = "default_geojson_simplify")]
+    simplify: f64,
+}
+
+/// An activity along with its track, e.g. for showing it on the map once
+/// it's been clicked.
+#[derive(Serialize)]
+struct ActivityDetail {
+    #[serde(flatten)]
+    activity: ActivitySummary,
+    /// Meters, from the `distance` property if set or the track otherwise.
+    distance: Option<f64>,
+    /// `[west, south, east, north]` of the track.
+    bbox: Option<[f64; 4]>,
+    /// GeoJSON MultiLineString, with trimming and privacy masks applied.
+    geometry: Option<serde_json::Value>,
+}
+
+async fn get_activity(
+    State(AppState { db, .. }): State<AppState>,
+    scope: UserScope,
+    Path(ActivityPath { id }): Path<ActivityPath>,
+    Query(params): Query<ActivityQueryParams>,
+) -> impl IntoResponse {
+    if !params.simplify.is_finite() || params.simplify < 0.0 {
+        return (StatusCode::BAD_REQUEST, "simplify must be >= 0").into_response();
+    }
+
+    let result = activity::get(&db, id).and_then(|summary| {
+        let Some(activity) = summary else {
+            return Ok(None);
+        };
+
+        // Other users' activities don't exist as far as a user page is
+        // concerned.
+        if let Some(ref user) = scope.0 {
+            if activity.user_id != Some(user.id) {
+                return Ok(None);
+            }
+        }
+
+        let tracks = activity::load_tracks(&db, id)?.map(|tracks| {
+            let tracks = activity::visible_tracks(&tracks, &activity, &db.config);
+            export::simplify_tracks(&tracks, params.simplify)
+        });
+
+        Ok(Some(ActivityDetail {
+            distance: track_stats::activity_distance(&activity, tracks.as_ref()),
+            bbox: tracks
+                .as_ref()
+                .and_then(|tracks| tracks.bounding_rect())
+                .map(|rect| [rect.min().x, rect.min().y, rect.max().x, rect.max().y]),
+            geometry: tracks.as_ref().map(export::geojson_geometry),
+            activity,
+        }))
+    });
+
+    match result {
+        Ok(Some(detail)) => (StatusCode::OK, Json(detail)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "no such activity").into_response(),
+        Err(err) => {
+            tracing::error!("failed to load activity: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQueryParams {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+async fn export_activity(
     State(AppState { db, .. }): State<AppState>,
-    Path((z, x, y_param)): Path<(u8, u32, TileYParam)>,
+    Path(id): Path<i64>,
+    Query(params): Query<ExportQueryParams>,
+) -> impl IntoResponse {
+    let result = activity::get(&db, id).and_then(|summary| {
+        let Some(summary) = summary else {
+            return Ok(None);
+        };
+
+        let Some(tracks) = activity::load_tracks(&db, id)? else {
+            return Ok(None);
+        };
+
+        // Never hand out raw tracks over HTTP.
+        let tracks = activity::visible_tracks(&tracks, &summary, &db.config);
+        let mut bytes = vec![];
+        export::write(&mut bytes, params.format, &summary, &tracks)?;
+
+        Ok(Some(bytes))
+    });
+
+    match result {
+        Ok(Some(bytes)) => {
+            let disposition = format!(
+                "attachment; filename=\"{}.{}\"",
+                id,
+                params.format.extension()
+            );
+
+            (
+                StatusCode::OK,
+                [
+                    (
+                        header::CONTENT_TYPE,
+                        params.format.content_type().to_string(),
+                    ),
+                    (header::CONTENT_DISPOSITION, disposition),
+                ],
+                bytes,
+            )
+                .into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "no track stored for activity").into_response(),
+        Err(err) => {
+            tracing::error!("failed to export activity: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "something went wrong").into_response()
+        }
+    }
+}
+
+fn default_thumbnail_size() -> u32 {
+    256
+}
+
+#[derive(Debug, Deserialize)]
+struct RenderActivityQueryParams {
+    #[serde(default = "default_thumbnail_size")]
+    width: u32,
+    #[serde(default = "default_thumbnail_size")]
+    height: u32,
+    #[serde(default)]
+    color: Option<String>,
+}
+
+async fn render_activity(
+    State(AppState {
+        db, render_pool, ..
+    }): State<AppState>,
+    Path(id): Path<i64>,
+    Query(params): Query<RenderActivityQueryParams>,
+) -> impl IntoResponse {
+    if params.height == 0 || params.height > 3000 || params.width == 0 || params.width > 3000 {
+        return (
+            StatusCode::BAD_REQUEST,
+            "width/height must be in bounds [1, 3000]",
+        )
+            .into_response();
+    }
+
+    let color = params
+        .color
+        .as_deref()
+        .unwrap_or(raster::DEFAULT_ACTIVITY_COLOR);
+    let Some(color) = raster::parse_color(color) else {
+        return (StatusCode::BAD_REQUEST, "invalid color").into_response();
+    };
+
+    let loaded = activity::get(&db, id).and_then(|summary| {
+        let Some(summary) = summary else {
+            return Ok(None);
+        };
+
+        Ok(activity::load_tracks(&db, id)?.map(|tracks| (summary, tracks)))
+    });
+
+    let (summary, tracks) = match loaded {
+        Ok(Some(loaded)) => loaded,
+        Ok(None) => return (StatusCode::NOT_FOUND, "no track stored for activity").into_response(),
+        Err(err) => {
+            tracing::error!("failed to load activity: {:?}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // Never hand out raw tracks over HTTP.
+    let tracks = activity::visible_tracks(&tracks, &summary, &db.config);
+    let render = move || {
+        let image = raster::render_activity(&tracks, color, params.width, params.height);
+        render_image_response(image)
+    };
+
+    run_render(&render_pool, render)
+        .await
+        .unwrap_or_else(|response| response)
+}
+
+async fn render_viewport(
+    State(state): State<AppState>,
+    Query(params): Query<RenderViewQueryParams>,
+) -> impl IntoResponse {
+    render_view(&state, params).await
+}
+
+/// Viewport to render, if the image's bounds and size are acceptable.
+fn parse_render_viewport(params: &RenderViewQueryParams) -> Result<WebMercatorViewport, String> {
+    let viewport = WebMercatorViewport::from_str(&params.bounds)
+        .map_err(|err| format!("invalid viewport given: {:?}", err))?;
+
+    if params.height == 0 || params.height > 3000 || params.width == 0 || params.width > 3000 {
+        return Err("width/height must be in bounds [1, 3000]".to_string());
+    }
+
+    Ok(viewport)
+}
+
+/// Render an image of the requested viewport, for both `/render` and signed
+/// links to it.
+async fn render_view(
+    AppState {
+        db,
+        gradients,
+        render_pool,
+        ..
+    }: &AppState,
+    params: RenderViewQueryParams,
+) -> Response {
+    let viewport = match parse_render_viewport(&params) {
+        Ok(viewport) => viewport,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+
+    let filter = ActivityFilter::new(params.before, params.after, params.filter);
+    let gradient = match gradients.choose(&params.gradient, params.color) {
+        Ok(value) => value.clone(),
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let stroke = match parse_stroke(
+        params.line_width,
+        params.blur,
+        params.antialias,
+        params.count,
+        params.half_life,
+    ) {
+        Ok(value) => value,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+
+    let db = db.clone();
+    let render = move || {
+        raster::render_view(
+            viewport,
+            &gradient,
+            params.intensity,
+            stroke,
+            params.color_by.as_ref(),
+            params.width,
+            params.height,
+            &filter,
+            &db,
+        )
+        .and_then(render_image_response)
+    };
+
+    run_render(render_pool, render)
+        .await
+        .unwrap_or_else(|response| response)
+}
+
+/// Default lifetime of a render link: a week.
+fn default_render_link_expiry() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+/// Longest a render link can be valid for: a year.
+const MAX_RENDER_LINK_EXPIRY: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Debug, Deserialize)]
+struct CreateRenderLinkBody {
+    /// Query string for `/render`, or the whole URL.
+    query: String,
+    /// Seconds until the link stops working.
+    #[serde(default = "default_render_link_expiry")]
+    expires_in: u64,
+}
+
+#[derive(Serialize)]
+struct RenderLink {
+    url: String,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: OffsetDateTime,
+}
+
+/// Sign a link to `/render` with the given parameters, which can then be
+/// shared with anyone.
+async fn create_render_link(
+    State(AppState { render_links, .. }): State<AppState>,
+    Json(body): Json<CreateRenderLinkBody>,
+) -> impl IntoResponse {
+    let Some(links) = render_links else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    if body.expires_in == 0 || body.expires_in > MAX_RENDER_LINK_EXPIRY {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("expires_in must be in [1, {}]", MAX_RENDER_LINK_EXPIRY),
+        )
+            .into_response();
+    }
+
+    // Better to find out about mistakes now than once the link is shared.
+    let query = body
+        .query
+        .split_once('?')
+        .map_or(body.query.as_str(), |(_, query)| query);
+    let valid = serde_urlencoded::from_str::<RenderViewQueryParams>(query)
+        .map_err(|err| err.to_string())
+        .and_then(|params| parse_render_viewport(&params));
+    if let Err(err) = valid {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("invalid render query: {}", err),
+        )
+            .into_response();
+    }
+
+    let expires_at = OffsetDateTime::now_utc() + Duration::from_secs(body.expires_in);
+    let link = RenderLink {
+        url: format!("/render/signed?{}", links.sign(query, expires_at)),
+        expires_at,
+    };
+
+    (StatusCode::OK, Json(link)).into_response()
+}
+
+async fn render_signed(State(state): State<AppState>, uri: Uri) -> impl IntoResponse {
+    let Some(links) = &state.render_links else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let query = match links.verify(uri.query().unwrap_or_default(), OffsetDateTime::now_utc()) {
+        Ok(query) => query,
+        Err(InvalidLink::Expired) => return (StatusCode::GONE, "link expired").into_response(),
+        Err(InvalidLink::BadSignature) => {
+            return (StatusCode::FORBIDDEN, "invalid signature").into_response()
+        }
+    };
+
+    match serde_urlencoded::from_str(query) {
+        Ok(params) => render_view(&state, params).await,
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct TilePath {
+    z: u8,
+    x: u32,
+    y: TileYParam,
+}
+
+/// Relative dates (e.g. `after=-7d`) are resolved when the query is parsed,
+/// so tiles for queries using them are only cached for the current day.
+fn tile_cache_query(mut query: String) -> String {
+    let relative = serde_urlencoded::from_str::<Vec<(String, String)>>(&query)
+        .map(|pairs| pairs.iter().any(|(_, v)| date::contains_relative_date(v)))
+        .unwrap_or(false);
+
+    if relative {
+        query.push_str(&format!("#{}", OffsetDateTime::now_utc().date()));
+    }
+
+    query
+}
+
+async fn render_tile(
+    State(AppState {
+        db,
+        tile_cache,
+        gradients,
+        render_pool,
+        ..
+    }): State<AppState>,
+    scope: UserScope,
+    Path(TilePath { z, x, y: y_param }): Path<TilePath>,
+    RawQuery(query): RawQuery,
     Query(params): Query<RenderQueryParams>,
 ) -> impl IntoResponse {
-    // Fail fast when tile is higher zoom level than we store data for.
-    if db.config.source_level(z).is_none() {
+    // Fail fast when tile is higher zoom level than we can render. Raster
+    // tiles are overzoomed, vector tiles are left to the client to scale.
+    let max_zoom = match y_param.format {
+        TileFormat::Png => raster::MAX_ZOOM,
+        TileFormat::Mvt => *db.config.zoom_range().end() as u8,
+    };
+    if z > max_zoom {
         return StatusCode::NOT_FOUND.into_response();
     }
 
-    let filter = ActivityFilter::new(params.before, params.after, params.filter);
+    // Arbitrary sizes are limited to the stored resolution, but `@4x` is
+    // always allowed (upscaled if needed).
+    if y_param.tile_size > db.config.tile_extent.max(2048) {
+        return (
+            StatusCode::BAD_REQUEST,
+            "tile size exceeds stored resolution",
+        )
+            .into_response();
+    }
+
     let tile = Tile::new(x, y_param.y, z);
-    let gradient = match choose_gradient(&params.gradient, params.color) {
-        Ok(value) => value,
-        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    let cache_key = (
+        tile,
+        y_param.tile_size,
+        y_param.format,
+        scope.0.as_ref().map(|user| user.id),
+        tile_cache_query(query.unwrap_or_default()),
+    );
+
+    let content_type = match y_param.format {
+        TileFormat::Png => "image/png",
+        TileFormat::Mvt => "application/vnd.mapbox-vector-tile",
     };
 
-    raster::render_tile(tile, gradient, y_param.tile_size, &filter, &db)
-        .and_then(|image| {
-            image
-                .map(render_image_response)
-                .unwrap_or_else(|| Ok(StatusCode::NO_CONTENT.into_response()))
-        })
-        .unwrap_or_else(|err| {
-            tracing::error!("error rendering tile: {:?}", err);
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
-        })
+    let bytes = match tile_cache.get(&cache_key) {
+        Some(bytes) => bytes,
+        None => {
+            if params.layers.is_some() && params.filter.is_some() {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "filter cannot be combined with layers, add it to each layer instead",
+                )
+                    .into_response();
+            }
+
+            let filter = scope.apply(within_bbox(
+                ActivityFilter::new(params.before, params.after, params.filter),
+                params.bbox.as_ref(),
+                &db.config,
+            ));
+            let render: Box<dyn FnOnce() -> Result<Option<Vec<u8>>> + Send> = match y_param.format {
+                TileFormat::Mvt => Box::new(move || mvt::render_tile(tile, &filter, &db)),
+                TileFormat::Png if params.mode == RenderMode::Explorer => {
+                    if params.layers.is_some() {
+                        return (
+                            StatusCode::BAD_REQUEST,
+                            "layers are not supported in explorer mode",
+                        )
+                            .into_response();
+                    }
+
+                    let (_, _, _, user_id, query) = &cache_key;
+                    let key = (*user_id, query.clone());
+                    let tile_cache = tile_cache.clone();
+                    Box::new(move || {
+                        tile_cache
+                            .explorer_tiles(key, || ExplorerTiles::load(&db, &filter))
+                            .and_then(|explorer| {
+                                explorer
+                                    .render_tile(tile, y_param.tile_size)
+                                    .map(encode_solid_png)
+                                    .transpose()
+                            })
+                    })
+                }
+                TileFormat::Png => {
+                    let stroke = match parse_stroke(
+                        params.line_width,
+                        params.blur,
+                        params.antialias,
+                        params.count,
+                        params.half_life,
+                    ) {
+                        Ok(value) => value,
+                        Err(err) => {
+                            return (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+                        }
+                    };
+                    let (intensity, color_by) = (params.intensity, params.color_by);
+
+                    match params.layers {
+                        Some(layers) => {
+                            let layers =
+                                match gradients.choose_layers(&layers, params.before, params.after)
+                                {
+                                    Ok(value) => value,
+                                    Err(err) => {
+                                        return (StatusCode::BAD_REQUEST, err).into_response()
+                                    }
+                                };
+                            let layers: Vec<_> = layers
+                                .into_iter()
+                                .map(|(filter, gradient)| {
+                                    let filter =
+                                        within_bbox(filter, params.bbox.as_ref(), &db.config);
+                                    (scope.apply(filter), gradient)
+                                })
+                                .collect();
+
+                            Box::new(move || {
+                                raster::render_tile_layers(
+                                    tile,
+                                    &layers,
+                                    intensity,
+                                    stroke,
+                                    color_by.as_ref(),
+                                    y_param.tile_size,
+                                    &db,
+                                )?
+                                .map(encode_png)
+                                .transpose()
+                            })
+                        }
+                        None => {
+                            let gradient = match gradients.choose(&params.gradient, params.color) {
+                                Ok(value) => value.clone(),
+                                Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+                            };
+
+                            Box::new(move || {
+                                raster::render_tile(
+                                    tile,
+                                    &gradient,
+                                    intensity,
+                                    stroke,
+                                    color_by.as_ref(),
+                                    y_param.tile_size,
+                                    &filter,
+                                    &db,
+                                )?
+                                .map(encode_png)
+                                .transpose()
+                            })
+                        }
+                    }
+                }
+            };
+
+            match run_render(&render_pool, render).await {
+                Ok(bytes) => {
+                    let bytes = bytes.map(Bytes::from);
+                    tile_cache.insert(cache_key, bytes.clone());
+                    bytes
+                }
+                Err(response) => return response,
+            }
+        }
+    };
+
+    match bytes {
+        Some(bytes) => (
+            [
+                (header::CONTENT_TYPE, content_type),
+                (header::CACHE_CONTROL, "max-age=86400"),
+            ],
+            bytes,
+        )
+            .into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
 }
 
-fn render_image_response(image: image::ImageBuffer<image::Rgba<u8>, Vec<u8>>) -> Result<Response> {
+fn encode_png(image: image::ImageBuffer<image::Rgba<u8>, Vec<u8>>) -> Result<Vec<u8>> {
     let mut bytes = Vec::new();
     let mut cursor = Cursor::new(&mut bytes);
 
@@ -387,6 +2026,51 @@ fn render_image_response(image: image::ImageBuffer<image::Rgba<u8>, Vec<u8>>) ->
         FilterType::NoFilter,
     ))?;
 
+    Ok(bytes)
+}
+
+/// Like `encode_png`, but compressing harder. The fast encoder only does well
+/// with runs of transparent pixels, not large areas of solid color.
+fn encode_solid_png(image: image::ImageBuffer<image::Rgba<u8>, Vec<u8>>) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    image.write_with_encoder(PngEncoder::new_with_quality(
+        &mut Cursor::new(&mut bytes),
+        CompressionType::Default,
+        FilterType::Sub,
+    ))?;
+
+    Ok(bytes)
+}
+
+/// Run a CPU heavy render on the render pool, turning failures into error
+/// responses.
+async fn run_render<F, T>(pool: &RenderPool, render: F) -> Result<T, Response>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match pool.run(render).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(err)) => {
+            tracing::error!("error rendering: {:?}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+        Err(PoolError::Busy | PoolError::TimedOut) => Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, "1")],
+            "too many renders in progress",
+        )
+            .into_response()),
+        Err(err) => {
+            tracing::error!("error rendering: {}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+fn render_image_response(image: image::ImageBuffer<image::Rgba<u8>, Vec<u8>>) -> Result<Response> {
+    let bytes = encode_png(image)?;
+
     Ok(axum::response::Response::builder()
         .header(header::CONTENT_TYPE, "image/png")
         .header(header::CACHE_CONTROL, "max-age=86400")
@@ -395,81 +2079,611 @@ fn render_image_response(image: image::ImageBuffer<image::Rgba<u8>, Vec<u8>>) ->
         .into_response())
 }
 
-fn choose_gradient(
-    gradient: &Option<LinearGradient>,
-    color: Option<String>,
-) -> Result<&LinearGradient, &'static str> {
-    match (gradient, color.as_deref()) {
-        (Some(gradient), None) => Ok(gradient),
-        (Some(_), Some(_)) => Err("cannot specify both gradient and color"),
-        (None, None) => Ok(&raster::ORANGE),
-        (None, Some("pinkish")) => Ok(&raster::PINKISH),
-        (None, Some("blue-red")) => Ok(&raster::BLUE_RED),
-        (None, Some("red")) => Ok(&raster::RED),
-        (None, Some("orange")) => Ok(&raster::ORANGE),
-        (None, Some(_)) => Err("invalid color name"),
+/// Gradients available to tile requests.
+pub struct Gradients {
+    /// Used when neither `color` nor `gradient` is given.
+    default: Gradient,
+    /// Selectable via `color`.
+    named: BTreeMap<String, Gradient>,
+}
+
+impl Gradients {
+    fn from_config(config: &db::Config) -> Result<Self> {
+        let default = match &config.default_gradient {
+            Some(spec) => Gradient::from_str(spec)
+                .map_err(|_| anyhow!("invalid default gradient stored in config: {}", spec))?,
+            None => raster::ORANGE.clone(),
+        };
+
+        Ok(Gradients {
+            default,
+            named: raster::named_gradients(&config.gradients)?,
+        })
+    }
+
+    fn choose<'a>(
+        &'a self,
+        gradient: &'a Option<Gradient>,
+        color: Option<String>,
+    ) -> Result<&'a Gradient, &'static str> {
+        match (gradient, color.as_deref()) {
+            (Some(gradient), None) => Ok(gradient),
+            (Some(_), Some(_)) => Err("cannot specify both gradient and color"),
+            (None, None) => Ok(&self.default),
+            (None, Some(name)) => self.named.get(name).ok_or("invalid color name"),
+        }
+    }
+
+    /// Filter and gradient for each of the given layers.
+    fn choose_layers(
+        &self,
+        layers: &[Layer],
+        before: Option<Date>,
+        after: Option<Date>,
+    ) -> Result<Vec<(ActivityFilter, Gradient)>, &'static str> {
+        if layers.is_empty() || layers.len() > MAX_LAYERS {
+            return Err("must give between 1 and 8 layers");
+        }
+
+        layers
+            .iter()
+            .map(|layer| {
+                let gradient = self.choose(&layer.gradient, layer.color.clone())?;
+                let filter = ActivityFilter::new(before, after, layer.filter.clone());
+                Ok((filter, gradient.clone()))
+            })
+            .collect()
+    }
+}
+
+fn parse_stroke(
+    line_width: Option<u32>,
+    blur: Option<f32>,
+    antialias: bool,
+    count: Count,
+    half_life: Option<f32>,
+) -> Result<Stroke> {
+    let default = Stroke::default();
+    Stroke::new(
+        line_width.unwrap_or(default.width),
+        blur.unwrap_or(default.blur),
+        antialias,
+    )?
+    .counting(count)
+    .decaying(half_life)
+}
+
+/// Limit the filter to activities passing through `bbox`, if one was given.
+fn within_bbox(
+    filter: ActivityFilter,
+    bbox: Option<&WebMercatorViewport>,
+    config: &db::Config,
+) -> ActivityFilter {
+    match bbox {
+        Some(bbox) => filter.within(bbox, config),
+        None => filter,
+    }
+}
+
+/// The user whose activities a request is limited to, for routes under
+/// `/u/:user/`.
+struct UserScope(Option<User>);
+
+impl UserScope {
+    fn apply(&self, filter: ActivityFilter) -> ActivityFilter {
+        match self.0 {
+            Some(ref user) => filter.for_user(user.id),
+            None => filter,
+        }
+    }
+
+    /// Prefix for URLs of the web UI.
+    fn base_path(&self) -> String {
+        match self.0 {
+            Some(ref user) => format!("/u/{}", user.name),
+            None => String::new(),
+        }
+    }
+}
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for UserScope {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Response> {
+        // Routes without any parameters fail to extract, rather than being empty.
+        let params = Path::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map(|Path(params)| params)
+            .unwrap_or_default();
+
+        let Some(name) = params.get("user") else {
+            return Ok(UserScope(None));
+        };
+
+        match state
+            .db
+            .connection()
+            .and_then(|conn| users::find_by_name(&conn, name))
+        {
+            Ok(Some(user)) => Ok(UserScope(Some(user))),
+            Ok(None) => Err((StatusCode::NOT_FOUND, "no such user").into_response()),
+            Err(err) => {
+                tracing::error!("failed to look up user: {:?}", err);
+                Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+            }
+        }
+    }
+}
+
+/// Who made a request, as determined by its bearer token.
+#[derive(Clone)]
+enum Caller {
+    /// Holder of `HOTPOT_UPLOAD_TOKEN` or an issued token, or anyone if no
+    /// way of authenticating is set up.
+    Anyone,
+    User(User),
+}
+
+/// Whether requests are allowed without a token, which is the case until
+/// some way of authenticating is set up.
+fn is_open(db: &Database, config: &Config) -> Result<bool> {
+    // With user accounts, the shared token is needed for anonymous uploads.
+    if config.upload_token.is_some() || config.routes.users {
+        return Ok(false);
+    }
+
+    let conn = db.connection()?;
+    Ok(!tokens::any_issued(&conn)?)
+}
+
+/// Check the request's bearer token, returning `None` if it doesn't allow
+/// `scope`.
+fn authenticate(
+    db: &Database,
+    config: &Config,
+    token: Option<&str>,
+    scope: Scope,
+) -> Result<Option<Caller>> {
+    if let Some(token) = token {
+        // Predates scoped tokens, so it's allowed everything (documented as
+        // an admin credential).
+        if config.upload_token.as_deref() == Some(token) {
+            return Ok(Some(Caller::Anyone));
+        }
+
+        let conn = db.connection()?;
+        if let Some(api_token) = tokens::authenticate(&conn, token)? {
+            if api_token.scope.allows(scope) {
+                return Ok(Some(Caller::Anyone));
+            }
+        } else if config.routes.users && scope == Scope::Upload {
+            // User tokens can only be used for uploading their own activities.
+            if let Some(user) = users::authenticate(&conn, token)? {
+                return Ok(Some(Caller::User(user)));
+            }
+        }
+    }
+
+    // Private servers always need a token, even before any are issued, and
+    // so do admin routes.
+    if matches!(scope, Scope::Render | Scope::Admin) {
+        return Ok(None);
     }
+
+    Ok(is_open(db, config)?.then_some(Caller::Anyone))
 }
 
-fn is_authenticated(
-    config: Config,
-    auth_header: Option<TypedHeader<axum::headers::Authorization<Bearer>>>,
-) -> bool {
-    match (config.upload_token, auth_header) {
-        (Some(expected), Some(actual)) => actual.0.token() == expected.as_str(),
-        (Some(_), None) => false,
-        (None, _) => true,
+/// Token given either as a bearer token, or as the password for HTTP basic
+/// auth. Browsers prompt for the latter, so the web UI works on private
+/// servers without any changes.
+fn request_token(headers: &HeaderMap) -> Option<String> {
+    if let Some(Authorization(bearer)) = headers.typed_get::<Authorization<Bearer>>() {
+        return Some(bearer.token().to_string());
+    }
+
+    headers
+        .typed_get::<Authorization<Basic>>()
+        .map(|Authorization(basic)| basic.password().to_string())
+}
+
+/// Middleware for routes under `/view/:view/`, adding the saved view's
+/// settings to the query string before the usual handlers see it. Since the
+/// tile cache is keyed by query string, changes to a view apply right away.
+async fn apply_view<B>(
+    State(AppState { db, .. }): State<AppState>,
+    Path(params): Path<HashMap<String, String>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let name = params.get("view").map(String::as_str).unwrap_or_default();
+    let view = match views::find(&db, name) {
+        Ok(Some(view)) => view,
+        Ok(None) => return (StatusCode::NOT_FOUND, "no such view").into_response(),
+        Err(err) => {
+            tracing::error!("failed to look up view: {:?}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let uri = view
+        .apply_to_query(req.uri().query().unwrap_or_default())
+        .and_then(|query| Ok(format!("{}?{}", req.uri().path(), query).parse::<Uri>()?));
+
+    match uri {
+        Ok(uri) => {
+            *req.uri_mut() = uri;
+            next.run(req).await
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+/// Middleware rejecting requests whose token doesn't allow the given scope.
+/// Handlers can get the [`Caller`] from the request extensions.
+async fn require_scope<B>(
+    State((state, scope)): State<(AppState, Scope)>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let token = request_token(req.headers());
+
+    match authenticate(&state.db, &state.config, token.as_deref(), scope) {
+        Ok(Some(caller)) => {
+            req.extensions_mut().insert(caller);
+            next.run(req).await
+        }
+        // Have browsers prompt for a token when viewing the map.
+        Ok(None) if scope == Scope::Render => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"hotpot\"")],
+            "bad token",
+        )
+            .into_response(),
+        Ok(None) => (StatusCode::UNAUTHORIZED, "bad token").into_response(),
+        Err(err) => {
+            tracing::error!("failed to authenticate request: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
     }
 }
 
 async fn upload_activity(
-    State(AppState { db, config, .. }): State<AppState>,
-    auth_header: Option<TypedHeader<axum::headers::Authorization<Bearer>>>,
+    State(AppState { db, tile_cache, .. }): State<AppState>,
+    Extension(caller): Extension<Caller>,
     mut multipart: Multipart,
 ) -> impl IntoResponse {
-    if !is_authenticated(config, auth_header) {
-        return (StatusCode::UNAUTHORIZED, "bad token");
-    }
+    let user = match caller {
+        Caller::User(user) => Some(user),
+        Caller::Anyone => None,
+    };
+
+    // Keep each user's uploads apart, since file names are likely to clash.
+    let (name_prefix, user_id) = match user {
+        Some(ref user) => (format!("upload:{}/", user.name), Some(user.id)),
+        None => ("upload:".to_string(), None),
+    };
+
+    let mut summary = ImportSummary::default();
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        };
 
-    while let Some(field) = multipart.next_field().await.expect("to get form field") {
         if field.name() != Some("file") {
             continue;
         }
 
         let file_name = match field.file_name() {
             Some(f) => f.to_string(),
-            None => return (StatusCode::BAD_REQUEST, "no filename"),
+            None => return (StatusCode::BAD_REQUEST, "no filename").into_response(),
         };
 
-        let Some((media_type, comp)) = activity::get_file_type(&file_name) else {
-            return (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unrecognized file type");
-        };
+        let is_archive = file_name.ends_with(".zip");
+        let file_type = activity::get_file_type(&file_name);
+        if !is_archive && file_type.is_none() {
+            return (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unrecognized file type").into_response();
+        }
 
-        tracing::info!(
-            "uploading file: {} (type: {:?}, compression: {:?})",
-            file_name,
-            media_type,
-            comp
-        );
+        tracing::info!("uploading file: {}", file_name);
 
-        let bytes = field.bytes().await.unwrap();
-        let reader = Cursor::new(bytes);
-        let Ok(Some(activity)) = activity::read(reader, media_type, comp) else {
-            return (StatusCode::UNPROCESSABLE_ENTITY, "couldn't read file");
+        let file = match spool_field(field).await {
+            Ok(file) => file,
+            Err(err) => {
+                tracing::error!("failed to receive upload: {:?}", err);
+                return (StatusCode::BAD_REQUEST, "failed to receive file").into_response();
+            }
         };
 
-        let activity_id = format!("upload:{}", file_name);
+        // Parsing large files is slow, keep it off the async runtime.
+        let db = db.clone();
+        let name_prefix = name_prefix.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            // Archives of many activities, e.g. a Strava bulk export.
+            if is_archive {
+                let prefix = format!("{}{}/", name_prefix, file_name);
+                return activity::import_zip(file, &prefix, user_id, &db).map_err(|err| {
+                    tracing::error!("failed to import archive: {:?}", err);
+                    (StatusCode::UNPROCESSABLE_ENTITY, "couldn't read archive")
+                });
+            }
 
-        if let Err(err) = db
-            .connection()
-            .and_then(|mut conn| activity::upsert(&mut conn, &activity_id, &activity, &db.config))
-        {
+            let (media_type, comp) = file_type.expect("checked above");
+            let activities = match activity::read(file, media_type, comp) {
+                Ok(activities) if !activities.is_empty() => activities,
+                _ => return Err((StatusCode::UNPROCESSABLE_ENTITY, "couldn't read file")),
+            };
+
+            let activity_id = format!("{}{}", name_prefix, file_name);
+            db.connection()
+                .and_then(|mut conn| {
+                    for (i, mut activity) in activities.into_iter().enumerate() {
+                        activity.user_id = user_id;
+                        let name = activity::session_name(&activity_id, i);
+                        activity::upsert(&mut conn, &name, &activity, &db.config)?;
+                    }
+                    Ok(())
+                })
+                .map_err(|err| {
+                    tracing::error!("failed to insert activity: {:?}", err);
+                    (StatusCode::INTERNAL_SERVER_ERROR, "something went wrong")
+                })?;
+
+            Ok(ImportSummary {
+                imported: vec![file_name],
+                ..Default::default()
+            })
+        })
+        .await
+        .expect("upload task panicked");
+
+        match result {
+            Ok(result) => summary.extend(result),
+            Err(err) => return err.into_response(),
+        }
+
+        tile_cache.clear();
+    }
+
+    (StatusCode::OK, Json(summary)).into_response()
+}
+
+/// Stream a multipart field into an anonymous temporary file, rather than
+/// buffering (potentially huge) uploads in memory.
+async fn spool_field(mut field: Field<'_>) -> Result<std::fs::File> {
+    let mut file = tokio::fs::File::from_std(tempfile::tempfile()?);
+    while let Some(chunk) = field.chunk().await? {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    let mut file = file.into_std().await;
+    file.rewind()?;
+
+    Ok(file)
+}
+
+#[derive(Serialize)]
+struct IngestResponse {
+    id: i64,
+}
+
+/// Add (or replace) an activity given as GeoJSON or an encoded polyline,
+/// rather than as an activity file.
+async fn ingest_activity(
+    State(AppState { db, tile_cache, .. }): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(body): Json<IngestBody>,
+) -> impl IntoResponse {
+    if body.id.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "id can't be empty").into_response();
+    }
+
+    // Same naming as uploads, so IDs of different users don't clash.
+    let name = match caller {
+        Caller::User(ref user) => format!("ingest:{}/{}", user.name, body.id),
+        Caller::Anyone => format!("ingest:{}", body.id),
+    };
+
+    let mut activity = match body.into_activity() {
+        Ok(activity) => activity,
+        Err(err) => return (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()).into_response(),
+    };
+    if let Caller::User(user) = caller {
+        activity.user_id = Some(user.id);
+    }
+
+    let result = tokio::task::spawn_blocking(move || {
+        let mut conn = db.connection()?;
+        activity::upsert(&mut conn, &name, &activity, &db.config)
+    })
+    .await
+    .expect("ingest task panicked");
+
+    match result {
+        Ok(activity::Upserted::Inserted(id) | activity::Upserted::Linked(id)) => {
+            tile_cache.clear();
+            (StatusCode::OK, Json(IngestResponse { id })).into_response()
+        }
+        Ok(activity::Upserted::Excluded) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "virtual activities are excluded",
+        )
+            .into_response(),
+        Err(err) => {
             tracing::error!("failed to insert activity: {:?}", err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "something went wrong");
+            (StatusCode::INTERNAL_SERVER_ERROR, "something went wrong").into_response()
         }
     }
+}
+
+/// Location updates from OwnTracks (in HTTP mode), collected into a live
+/// activity per device.
+async fn owntracks_location(
+    State(AppState {
+        db,
+        tile_cache,
+        config,
+        ..
+    }): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    headers: HeaderMap,
+    Json(msg): Json<OwnTracksMessage>,
+) -> impl IntoResponse {
+    // OwnTracks expects a list of messages for the device in response, which
+    // we don't have any of.
+    let ok = Json(serde_json::json!([]));
+
+    let OwnTracksMessage::Location(location) = msg else {
+        return ok.into_response();
+    };
+    if !location.is_accurate() {
+        return ok.into_response();
+    }
 
-    (StatusCode::OK, "activity added")
+    // The user and device names configured in the app are sent as headers.
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let device = match (header("x-limit-u"), header("x-limit-d")) {
+        (Some(user), Some(device)) => format!("{}/{}", user, device),
+        (None, Some(device)) => device.to_string(),
+        _ => location
+            .tid
+            .clone()
+            .unwrap_or_else(|| "default".to_string()),
+    };
+    let (device, user_id) = match caller {
+        Caller::User(user) => (format!("{}/{}", user.name, device), Some(user.id)),
+        Caller::Anyone => (device, None),
+    };
+
+    let result = tokio::task::spawn_blocking(move || {
+        let mut conn = db.connection()?;
+        live::add_point(
+            &mut conn,
+            &device,
+            user_id,
+            &location,
+            config.live_timeout,
+            &db.config,
+        )
+    })
+    .await
+    .expect("owntracks task panicked");
+
+    match result {
+        Ok(flushed) => {
+            if flushed {
+                tile_cache.clear();
+            }
+            ok.into_response()
+        }
+        Err(err) => {
+            tracing::error!("failed to add location: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "something went wrong").into_response()
+        }
+    }
+}
+
+async fn delete_activity(
+    State(AppState { db, tile_cache, .. }): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    // Users can only delete their own activities.
+    if let Caller::User(user) = caller {
+        match activity::get(&db, id) {
+            Ok(Some(activity)) if activity.user_id == Some(user.id) => {}
+            Ok(_) => return (StatusCode::NOT_FOUND, "no such activity"),
+            Err(err) => {
+                tracing::error!("failed to look up activity: {:?}", err);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "something went wrong");
+            }
+        }
+    }
+
+    match db
+        .connection()
+        .and_then(|mut conn| activity::delete_by_id(&mut conn, id))
+    {
+        Ok(true) => {
+            tile_cache.clear();
+            (StatusCode::OK, "activity deleted")
+        }
+        Ok(false) => (StatusCode::NOT_FOUND, "no such activity"),
+        Err(err) => {
+            tracing::error!("failed to delete activity: {:?}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "something went wrong")
+        }
+    }
+}
+
+async fn reload_settings(State(AppState { reload, .. }): State<AppState>) -> impl IntoResponse {
+    let (reply, outcome) = oneshot::channel();
+    if reload.send(reply).is_err() {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "something went wrong".into(),
+        );
+    }
+
+    match outcome.await {
+        Ok(Ok(())) => (StatusCode::OK, "reloaded".to_string()),
+        // Only admins get here, and they'll want to know what to fix.
+        Ok(Err(err)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to reload: {}", err),
+        ),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "something went wrong".into(),
+        ),
+    }
+}
+
+/// Respond to `GET` requests for the activity APIs with `Last-Modified`,
+/// going by the database file, and `304 Not Modified` if that's no later
+/// than `If-Modified-Since`.
+async fn set_last_modified<B>(
+    State(AppState { db, .. }): State<AppState>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let is_api = req
+        .extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|path| path.as_str().contains("/api/") && !path.as_str().ends_with("/jobs"));
+    if req.method() != Method::GET || !is_api {
+        return next.run(req).await;
+    }
+
+    // Checked before handling the request, so that changes made meanwhile
+    // count as newer than the response.
+    let Some(modified) = db.last_modified() else {
+        return next.run(req).await;
+    };
+
+    let not_modified = req
+        .headers()
+        .typed_get::<IfModifiedSince>()
+        .is_some_and(|since| !since.is_modified(modified));
+    let mut res = if not_modified {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        next.run(req).await
+    };
+
+    if matches!(res.status(), StatusCode::OK | StatusCode::NOT_MODIFIED) {
+        let headers = res.headers_mut();
+        headers.typed_insert(LastModified::from(modified));
+        headers
+            .entry(header::CACHE_CONTROL)
+            .or_insert(HeaderValue::from_static("no-cache"));
+    }
+
+    res
 }
 
 struct RequestData {
@@ -501,3 +2715,113 @@ fn trace_request(res: &Response, latency: Duration, _span: &tracing::Span) {
         "response"
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_layers() {
+        let gradients = Gradients::from_config(&db::Config::default()).unwrap();
+        let layers: Vec<Layer> = serde_json::from_str(
+            r#"[
+                {"filter": "activity_type=ride", "color": "blue-red"},
+                {"filter": {"activity_type": {"any_of": ["run", "hike"]}}},
+                {"gradient": "1:fff"}
+            ]"#,
+        )
+        .unwrap();
+
+        let chosen = gradients.choose_layers(&layers, None, None).unwrap();
+        assert_eq!(chosen.len(), 3);
+
+        let unknown: Vec<Layer> = serde_json::from_str(r#"[{"color": "nope"}]"#).unwrap();
+        assert!(gradients.choose_layers(&unknown, None, None).is_err());
+        assert!(gradients.choose_layers(&[], None, None).is_err());
+    }
+
+    #[test]
+    fn test_tile_cache() {
+        let key = |x| {
+            (
+                Tile::new(x, 0, 2),
+                256,
+                TileFormat::Png,
+                None,
+                String::new(),
+            )
+        };
+        let cache = TileCache::new(2);
+
+        cache.insert(key(0), None);
+        cache.insert(key(1), Some(Bytes::from_static(b"1")));
+
+        // Touch 0, so that 1 is evicted first.
+        assert_eq!(cache.get(&key(0)), Some(None));
+        cache.insert(key(2), Some(Bytes::from_static(b"2")));
+
+        assert_eq!(cache.get(&key(1)), None);
+        assert_eq!(cache.get(&key(0)), Some(None));
+        assert_eq!(cache.get(&key(2)), Some(Some(Bytes::from_static(b"2"))));
+
+        cache.clear();
+        assert_eq!(cache.get(&key(0)), None);
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let cors = CorsConfig {
+            origins: vec![HeaderValue::from_static("https://example.com")],
+            methods: vec![Method::GET, Method::POST],
+            headers: vec![header::AUTHORIZATION],
+        };
+        let router: Router = Router::new()
+            .route("/upload", post(|| async { "ok" }))
+            .layer(cors.layer());
+
+        let preflight = |origin: &'static str| {
+            let request = Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/upload")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            router.clone().oneshot(request)
+        };
+
+        let res = preflight("https://example.com").await.unwrap();
+        let headers = res.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET,POST");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "authorization"
+        );
+
+        let res = preflight("https://other.example.com").await.unwrap();
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // Anything goes for `GET` by default.
+        let layer = CorsConfig::default().layer();
+        let res = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(layer)
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(header::ORIGIN, "https://other.example.com")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+}
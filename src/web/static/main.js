@@ -168,8 +168,10 @@ function createUploadModal() {
         code({}, ".gpx"),
         ", ",
         code({}, ".tcx"),
-        ", and ",
+        ", ",
         code({}, ".fit"),
+        ", and ",
+        code({}, ".zip"),
         " files here",
       ]),
     ]),
@@ -446,66 +448,596 @@ class UploadButton {
   }
 }
 
-function createPropertyModal(props) {
-  const { "modal-dialog": modal, div, style } = createElement;
+
+// Slider with a handle for each end of the range of months between `first`
+// and `last` (as `YYYY-MM-DD`). Once a handle is let go, `onChange` is called
+// with the new `after` and `before` dates, or null for either end.
+class DateRangeSlider {
+  constructor([first, last], onChange) {
+    const { div, input } = createElement;
+
+    this.onChange = onChange;
+    this.start = DateRangeSlider.monthOf(first);
+    this.max = Math.max(DateRangeSlider.monthOf(last) - this.start, 0);
+
+    const range = (value) =>
+      input({
+        type: "range",
+        min: 0,
+        max: this.max,
+        step: 1,
+        value,
+        input: (ev) => this._onInput(ev.target),
+        change: () => this._onCommit(),
+      });
+
+    this.from = range(0);
+    this.to = range(this.max);
+    this.label = div({ class: "__label" });
+    this.node = div({ class: "date-slider" }, [
+      div({ class: "__track" }, [this.from, this.to]),
+      this.label,
+    ]);
+
+    this._updateLabel();
+  }
+
+  // Months since year 0 for a `YYYY-MM-DD` date
+  static monthOf(date) {
+    const [year, month] = date.split("-").map(Number);
+    return year * 12 + month - 1;
+  }
+
+  static dateOf(month) {
+    const mm = String((month % 12) + 1).padStart(2, "0");
+    return `${Math.floor(month / 12)}-${mm}-01`;
+  }
+
+  // Move the handles to match the given dates
+  set(after, before) {
+    const isDate = (date) => /^\d{4}-\d{2}-\d{2}$/.test(date ?? "");
+    const clamp = (n) => Math.min(Math.max(n, 0), this.max);
+
+    this.from.value = isDate(after)
+      ? clamp(DateRangeSlider.monthOf(after) - this.start)
+      : 0;
+
+    if (isDate(before)) {
+      // `before` is exclusive, so the range ends with the day before it
+      const end = new Date(`${before}T00:00:00Z`);
+      end.setUTCDate(end.getUTCDate() - 1);
+      const month = end.getUTCFullYear() * 12 + end.getUTCMonth();
+      this.to.value = clamp(month - this.start);
+    } else {
+      this.to.value = this.max;
+    }
+
+    this._updateLabel();
+  }
+
+  _onInput(handle) {
+    // Don't let the handles cross
+    if (+this.from.value > +this.to.value) {
+      handle === this.from
+        ? (this.to.value = this.from.value)
+        : (this.from.value = this.to.value);
+    }
+    this._updateLabel();
+  }
+
+  _onCommit() {
+    const from = +this.from.value;
+    const to = +this.to.value;
+
+    this.onChange(
+      from === 0 ? null : DateRangeSlider.dateOf(this.start + from),
+      to === this.max ? null : DateRangeSlider.dateOf(this.start + to + 1),
+    );
+  }
+
+  _updateLabel() {
+    const fmt = new Intl.DateTimeFormat(undefined, {
+      month: "short",
+      year: "numeric",
+      timeZone: "UTC",
+    });
+    const format = (value) =>
+      fmt.format(
+        new Date(`${DateRangeSlider.dateOf(this.start + +value)}T00:00:00Z`),
+      );
+
+    this.label.innerText = `${format(this.from.value)} – ${format(this.to.value)}`;
+  }
+}
+
+const NUMERIC_OPERATORS = [">", ">=", "<", "<=", "=", "!="];
+const TEXT_OPERATORS = ["=", "!=", "like", "matches", "any_of", "none_of"];
+// Operators without a value, which both map onto `exists`
+const PRESENCE_OPERATORS = ["exists", "missing"];
+
+const OPERATOR_LABELS = {
+  like: "is like",
+  matches: "matches",
+  any_of: "is one of",
+  none_of: "is none of",
+  exists: "exists",
+  missing: "is missing",
+};
+
+// Filter given as a JSON object, or null if it's missing or isn't one (e.g.
+// a single `key=value` predicate)
+function parseFilterObject(filter) {
+  try {
+    const obj = JSON.parse(filter);
+    const isObject = (v) => typeof v === "object" && v !== null && !Array.isArray(v);
+    return isObject(obj) && Object.values(obj).every(isObject) ? obj : null;
+  } catch (_err) {
+    return null;
+  }
+}
+
+// {"distance": {">": 10, "<": 50}} => [{key: "distance", op: ">", value: "10"}, ...]
+function filterToConditions(filter) {
+  const conditions = [];
+
+  for (const [key, expr] of Object.entries(parseFilterObject(filter) ?? {})) {
+    for (const [op, value] of Object.entries(expr)) {
+      if (op === "exists") {
+        conditions.push({ key, op: value ? "exists" : "missing", value: "" });
+      } else {
+        conditions.push({ key, op, value: [value].flat().join(", ") });
+      }
+    }
+  }
+
+  return conditions;
+}
+
+// Inverse of `filterToConditions`, null if there aren't any conditions
+function conditionsToFilter(conditions) {
+  const obj = {};
+
+  for (const { key, op, value } of conditions) {
+    const isPresence = PRESENCE_OPERATORS.includes(op);
+    if (!key || (!isPresence && value.trim() === "")) continue;
+
+    const expr = (obj[key] ??= {});
+    if (isPresence) {
+      expr.exists = op === "exists";
+    } else if (op === "any_of" || op === "none_of") {
+      expr[op] = value
+        .split(",")
+        .map((v) => v.trim())
+        .filter((v) => v !== "");
+    } else if ([">", ">=", "<", "<="].includes(op) && !isNaN(+value)) {
+      expr[op] = +value;
+    } else {
+      expr[op] = value;
+    }
+  }
+
+  return Object.keys(obj).length ? JSON.stringify(obj) : null;
+}
+
+// Both filters combined, or undefined if they can't be (one of them isn't
+// JSON)
+function mergeFilters(a, b) {
+  if (!a?.trim()) return b || null;
+  if (!b?.trim()) return a;
+
+  const [objA, objB] = [a, b].map(parseFilterObject);
+  if (objA == null || objB == null) return undefined;
+
+  const merged = { ...objA };
+  for (const [key, expr] of Object.entries(objB)) {
+    merged[key] = { ...merged[key], ...expr };
+  }
+  return JSON.stringify(merged);
+}
+
+// Build up a property filter from the properties found on activities (as
+// returned by `/api/properties`), calling `onApply` with the result.
+function createFilterModal(props, filter, onApply) {
+  const {
+    "modal-dialog": modal,
+    div,
+    p,
+    select,
+    option,
+    input,
+    button,
+    style,
+  } = createElement;
+
+  props = Array.isArray(props) ? props : [];
 
   const fmt = new Intl.NumberFormat();
+  const byKey = Object.fromEntries(props.map((prop) => [prop.key, prop]));
+
+  const operatorsFor = (key) => {
+    const types = byKey[key]?.types ?? [];
+    const isNumeric = (type) => type === "integer" || type === "real";
+    const ops = [
+      ...(types.some(isNumeric) ? NUMERIC_OPERATORS : []),
+      ...(types.length === 0 || !types.every(isNumeric) ? TEXT_OPERATORS : []),
+      ...PRESENCE_OPERATORS,
+    ];
+    return [...new Set(ops)];
+  };
+
+  const placeholderFor = (key, op) => {
+    const { min, max } = byKey[key] ?? {};
+    if (op === "any_of" || op === "none_of") return "a, b, c";
+    if (op === "like") return "%gravel%";
+    if (op === "matches") return "regex";
+    if (min != null && max != null) return `${min} – ${max}`;
+    return "value";
+  };
+
+  const conditionRow = ({ key, op, value }) => {
+    const keys = props.map((prop) => prop.key);
+    if (!keys.includes(key)) keys.unshift(key);
+
+    const keySelect = select(
+      { class: "__key", change: () => updateOperators() },
+      keys.map((k) =>
+        option(
+          { value: k },
+          byKey[k] ? `${k} (${fmt.format(byKey[k].activity_count)})` : k,
+        ),
+      ),
+    );
+    const opSelect = select({ class: "__op", change: () => updateValue() });
+    const valueInput = input({ class: "__value", value });
+
+    const updateValue = () => {
+      const isPresence = PRESENCE_OPERATORS.includes(opSelect.value);
+      valueInput.style.visibility = isPresence ? "hidden" : "visible";
+      valueInput.placeholder = placeholderFor(keySelect.value, opSelect.value);
+    };
+
+    const updateOperators = () => {
+      const current = opSelect.value || op;
+      const ops = operatorsFor(keySelect.value);
+      opSelect.replaceChildren(
+        ...ops.map((o) => option({ value: o }, OPERATOR_LABELS[o] ?? o)),
+      );
+      opSelect.value = ops.includes(current) ? current : ops[0];
+      updateValue();
+    };
 
-  // TODO: maybe display property type
-  const rows = props.map(({ key, activity_count }) => {
-    return div({ class: "__row" }, [
-      div({ class: "__prop", title: key }, key),
-      div({ class: "__count" }, fmt.format(activity_count)),
+    keySelect.value = key;
+    updateOperators();
+
+    const row = div({ class: "__row" }, [
+      keySelect,
+      opSelect,
+      valueInput,
+      button(
+        { type: "button", title: "Remove", click: () => row.remove() },
+        "×",
+      ),
     ]);
-  });
+    row.$$read = () => ({
+      key: keySelect.value,
+      op: opSelect.value,
+      value: valueInput.value,
+    });
+
+    return row;
+  };
+
+  const conditions = filterToConditions(filter);
+  const replacesFilter =
+    filter?.trim() && conditions.length === 0 && parseFilterObject(filter) == null;
+  const addCondition = () =>
+    rows.append(conditionRow({ key: props[0]?.key ?? "", op: null, value: "" }));
+
+  const rows = div({ class: "__rows" }, conditions.map(conditionRow));
+  if (conditions.length === 0) addCondition();
 
   const node = modal({}, [
     style(
       {},
       `
-      .property-table {
-        width: 100%;
+      .filter-builder {
         font-size: small;
 
         .__row {
           display: grid;
-          justify-content: space-between;
-          grid-template-columns: 1fr 25%;
+          grid-template-columns: 1fr 25% 30% auto;
+          grid-gap: 0.25em;
+          margin: 0.25em 0;
 
-          &:nth-child(even) {
-            background-color: #fafafa;
+          select, input {
+            min-width: 0;
           }
+        }
 
-          .__prop {
-            font-family: monospace;
-            text-overflow: ellipsis;
-            white-space: nowrap;
-            overflow: hidden;
-          }
+        .__actions {
+          display: flex;
+          gap: 0.5em;
+          margin-top: 1em;
 
-          .__count {
-            text-align: right;
+          :last-child {
+            margin-left: auto;
           }
         }
-
-        .__header {
-          font-weight: bold;
-        }
       }
     `,
     ),
-    div({ slot: "header" }, "Filterable Properties"),
-    div({ slot: "body" }, [
-      // TODO: Add docs about how filters work etc.
-      div({ class: "property-table" }, [
-        div({ class: "__header __row" }, [
-          div({}, "Key"),
-          div({}, "Num Activities"),
-        ]),
-        div({ class: "__body" }, rows),
+    div({ slot: "header" }, "Property Filter"),
+    div({ slot: "body", class: "filter-builder" }, [
+      p(
+        {},
+        "Only activities matching all of these conditions are shown. Values " +
+          "of number properties are compared as numbers, others as text.",
+      ),
+      replacesFilter
+        ? p({}, "The current filter can't be edited here, and will be replaced.")
+        : null,
+      rows,
+      div({ class: "__actions" }, [
+        button({ type: "button", click: addCondition }, "+ Condition"),
+        button({ type: "button", click: () => rows.replaceChildren() }, "Clear"),
+        button(
+          {
+            type: "button",
+            click: () => {
+              onApply(conditionsToFilter([...rows.children].map((row) => row.$$read())));
+              node.remove();
+            },
+          },
+          "Apply",
+        ),
       ]),
     ]),
   ]);
 
   document.body.appendChild(node);
 }
+
+// `threshold:color;...` => [{threshold, color: "#rrggbb", alpha: "aa"}], or
+// null if it can't be edited as stops (e.g. gradients given per zoom level)
+function parseGradientStops(gradient) {
+  if (!gradient?.trim()) return [];
+
+  const stops = gradient
+    .split(";")
+    .filter((stop) => stop.trim() !== "")
+    .map((stop) => {
+      const [threshold, color = ""] = stop.split(":").map((s) => s.trim());
+      let hex = color.replace(/^#/, "");
+      if (/^[0-9a-f]{3}$/i.test(hex)) {
+        hex = [...hex].map((c) => c + c).join("");
+      }
+
+      if (!/^\d+$/.test(threshold) || +threshold > 255) return null;
+      if (!/^([0-9a-f]{6}|[0-9a-f]{8})$/i.test(hex)) return null;
+
+      return {
+        threshold: +threshold,
+        color: `#${hex.slice(0, 6).toLowerCase()}`,
+        alpha: hex.slice(6),
+      };
+    });
+
+  return stops.includes(null) ? null : stops;
+}
+
+function formatGradientStops(stops) {
+  return [...stops]
+    .sort((a, b) => a.threshold - b.threshold)
+    .map(({ threshold, color, alpha }) => `${threshold}:${color.slice(1)}${alpha}`)
+    .join(";");
+}
+
+// Edit a gradient's stops with color pickers, calling `onChange` with the
+// gradient whenever one of them changes.
+class GradientEditor {
+  constructor(onChange) {
+    const { div, button } = createElement;
+
+    this.onChange = onChange;
+    this.value = null;
+    this.stops = [];
+
+    this.preview = div({ class: "__preview" });
+    this.rows = div({ class: "__stops" });
+    this.note = div(
+      { class: "__note" },
+      "This gradient can only be edited as text.",
+    );
+    this.addButton = button(
+      { type: "button", click: () => this._addStop() },
+      "+ Stop",
+    );
+    this.node = div({ class: "gradient-editor" }, [
+      this.preview,
+      this.rows,
+      this.note,
+      this.addButton,
+    ]);
+  }
+
+  set(gradient) {
+    if (gradient === this.value) return;
+    this.value = gradient;
+
+    const stops = parseGradientStops(gradient);
+    const editable = stops != null;
+
+    this.note.style.display = editable ? "none" : "";
+    this.addButton.style.display = editable ? "" : "none";
+    this.stops = stops ?? [];
+    this._render();
+  }
+
+  _render() {
+    const { div, input, button } = createElement;
+
+    this.rows.replaceChildren(
+      ...this.stops.map((stop) =>
+        div({ class: "__stop" }, [
+          input({
+            type: "number",
+            min: 0,
+            max: 255,
+            value: stop.threshold,
+            title: "Activities per pixel",
+            input: (ev) => {
+              stop.threshold = Math.min(Math.max(+ev.target.value || 0, 0), 255);
+              this._emit();
+            },
+          }),
+          input({
+            type: "color",
+            value: stop.color,
+            input: (ev) => {
+              stop.color = ev.target.value;
+              this._emit();
+            },
+          }),
+          button(
+            {
+              type: "button",
+              title: "Remove",
+              click: () => {
+                this.stops = this.stops.filter((s) => s !== stop);
+                this._render();
+                this._emit();
+              },
+            },
+            "×",
+          ),
+        ]),
+      ),
+    );
+    this._updatePreview();
+  }
+
+  _addStop() {
+    const last = this.stops.at(-1);
+    this.stops.push({
+      threshold: Math.min((last?.threshold ?? 0) + 10, 255),
+      color: last?.color ?? "#ffffff",
+      alpha: "",
+    });
+    this._render();
+    this._emit();
+  }
+
+  _updatePreview() {
+    const colors = [...this.stops]
+      .sort((a, b) => a.threshold - b.threshold)
+      .map(({ color }) => color);
+
+    this.preview.style.background =
+      colors.length > 1
+        ? `linear-gradient(to right, ${colors.join(", ")})`
+        : colors[0] ?? "transparent";
+  }
+
+  _emit() {
+    this.value = formatGradientStops(this.stops);
+    this._updatePreview();
+    this.onChange(this.value);
+  }
+}
+
+// Editable list of layers drawn over each other in different colors, in the
+// form taken by the `layers` tile parameter: [{filter, color, enabled}].
+class LayerList {
+  static MAX_LAYERS = 8;
+
+  constructor(gradients, onChange) {
+    const { div, button } = createElement;
+
+    this.gradients = gradients;
+    this.onChange = onChange;
+    this.layers = [];
+
+    this.rows = div({ class: "__layers" });
+    this.addButton = button(
+      {
+        type: "button",
+        click: () => {
+          const color = this.gradients[this.layers.length % this.gradients.length];
+          this.layers = [...this.layers, { enabled: true, color, filter: "" }];
+          this._render();
+          this._emit();
+        },
+      },
+      "+ Color Layer",
+    );
+    this.node = div({ class: "layer-list" }, [this.rows, this.addButton]);
+  }
+
+  set(layers) {
+    if (JSON.stringify(layers) === JSON.stringify(this.layers)) return;
+    this.layers = layers.map((layer) => ({ ...layer }));
+    this._render();
+  }
+
+  _render() {
+    const { div, input, select, option, button } = createElement;
+
+    this.rows.replaceChildren(
+      ...this.layers.map((layer) => {
+        const filterInput = input({
+          value: layer.filter ?? "",
+          placeholder: "activity_type=Ride",
+          input: debounce(() => {
+            layer.filter = filterInput.value;
+            this._emit();
+          }),
+        });
+
+        const colorSelect = select(
+          {
+            change: (ev) => {
+              layer.color = ev.target.value;
+              this._emit();
+            },
+          },
+          this.gradients.map((name) => option({ value: name }, name)),
+        );
+        colorSelect.value = layer.color;
+
+        const checkbox = input({
+          type: "checkbox",
+          title: "Show layer",
+          change: (ev) => {
+            layer.enabled = ev.target.checked;
+            this._emit();
+          },
+        });
+        checkbox.checked = layer.enabled !== false;
+
+        return div({ class: "__layer" }, [
+          checkbox,
+          colorSelect,
+          button(
+            {
+              type: "button",
+              title: "Remove",
+              click: () => {
+                this.layers = this.layers.filter((l) => l !== layer);
+                this._render();
+                this._emit();
+              },
+            },
+            "×",
+          ),
+          filterInput,
+        ]);
+      }),
+    );
+
+    this.addButton.disabled = this.layers.length >= LayerList.MAX_LAYERS;
+  }
+
+  _emit() {
+    this.onChange(this.layers.map((layer) => ({ ...layer })));
+  }
+}